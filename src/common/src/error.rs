@@ -1,4 +1,4 @@
-use crate::ids::TransactionId;
+use crate::ids::{TransactionId, ValueId};
 use regex::Error as RegexError;
 use std::error::Error;
 use std::fmt;
@@ -33,6 +33,12 @@ pub enum CrustyError {
     ContainerDoesNotExist,
     /// Invalid Operation
     InvalidOperation,
+    /// Key already present in a unique index
+    DuplicateKey(String),
+    /// A page read from disk failed its checksum or has an unknown format
+    CorruptPage(String),
+    /// No record is stored at the value id
+    ValueNotFound(ValueId),
 }
 
 impl fmt::Display for CrustyError {
@@ -53,6 +59,9 @@ impl fmt::Display for CrustyError {
                 CrustyError::StorageError => format!("Storage Error"),
                 CrustyError::ContainerDoesNotExist => format!("Container Does Not Exist"),
                 CrustyError::InvalidOperation => format!("Invalid Operation"),
+                CrustyError::DuplicateKey(s) => format!("Duplicate Key: {}", s),
+                CrustyError::CorruptPage(s) => format!("Corrupt Page: {}", s),
+                CrustyError::ValueNotFound(v_id) => format!("Value Not Found: {:?}", v_id),
             }
         )
     }
@@ -32,6 +32,11 @@ impl TransactionId {
         Self { id: 0 }
     }
 
+    /// Rebuild a transaction id from its number, such as one read back from a log.
+    pub fn from_id(id: TidType) -> Self {
+        Self { id }
+    }
+
     /// Returns the transaction id.
     pub fn id(&self) -> u64 {
         self.id
@@ -63,6 +68,8 @@ pub enum StateType {
     BaseTable,
     MatView,
     Tree,
+    /// A base table of variable size records
+    VarTable,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -256,6 +263,9 @@ impl fmt::Debug for ValueId {
     }
 }
 
+/// Log sequence number. Log records are kept in slotted log pages, so a
+/// record is named by its log page and slot, which order like the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Lsn {
     pub page_id: PageId,
     pub slot_id: SlotId,
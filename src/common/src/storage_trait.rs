@@ -106,6 +106,10 @@ pub trait StorageTrait {
         perm: Permissions,
     ) -> Result<Vec<u8>, CrustyError>;
 
+    /// Finish a transaction: commit its changes if `committed`, otherwise roll
+    /// them back. Either way the locks it holds are released.
+    fn transaction_finished(&self, tid: TransactionId, committed: bool) -> Result<(), CrustyError>;
+
     /// Get the base storage path for the storage manager
     fn get_storage_path(&self) -> &Path;
 
@@ -1,59 +1,182 @@
 use crate::fixed_page::FixedPage;
+use common::ids::{Lsn, ValueId};
+use common::CrustyError;
 use std::fmt::Debug;
-use std::ops::DerefMut;
+use std::ops::{Deref, DerefMut};
 use std::sync::atomic::Ordering::Relaxed;
-use std::{cell::UnsafeCell, ops::Deref, sync::atomic::AtomicU8};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 pub struct BufferFrame {
-    pub page: UnsafeCell<FixedPage>,
+    /// The page held by the frame, protected by the frame's shared/exclusive latch
+    pub(crate) page: RwLock<FixedPage>,
     pub(crate) frame_id: usize,
+    /// Pinned frames are never evicted. A frame is pinned before its latch is taken.
     pub(crate) pin_count: AtomicU8,
+    /// Set when the page has been modified since it was read from disk
+    pub(crate) dirty: AtomicBool,
+    /// The last LSN logged before the page became dirty. Recovery redoes the
+    /// page's changes after it.
+    pub(crate) rec_lsn: Mutex<Option<Lsn>>,
+    /// The container/page held by this frame, if any
+    pub(crate) v_id: Mutex<Option<ValueId>>,
+    /// When the page was last hit, by the pool's access clock. Zero once the
+    /// replacement policy has been told about the hit.
+    pub(crate) last_access: AtomicU64,
+    /// Set while the page is being read in from disk, with the latch held
+    /// exclusively by the reader. Left set if the read failed.
+    pub(crate) loading: AtomicBool,
 }
 
-pub struct FrameGuard<'a> {
+/// A pinned frame with its latch held shared
+pub struct FrameReadGuard<'a> {
     pub(crate) buffer_frame: &'a BufferFrame,
+    page: RwLockReadGuard<'a, FixedPage>,
+}
+
+/// A pinned frame with its latch held exclusively
+pub struct FrameWriteGuard<'a> {
+    pub(crate) buffer_frame: &'a BufferFrame,
+    page: RwLockWriteGuard<'a, FixedPage>,
+}
+
+/// A guard returned when the latch mode is picked at runtime by a `Permissions`
+#[derive(Debug)]
+pub enum FrameGuard<'a> {
+    Read(FrameReadGuard<'a>),
+    Write(FrameWriteGuard<'a>),
 }
 
 impl BufferFrame {
     pub fn new(frame_id: usize) -> Self {
         BufferFrame {
-            page: UnsafeCell::new(FixedPage::empty()),
+            page: RwLock::new(FixedPage::empty()),
             frame_id,
             pin_count: AtomicU8::new(0),
+            dirty: AtomicBool::new(false),
+            rec_lsn: Mutex::new(None),
+            v_id: Mutex::new(None),
+            last_access: AtomicU64::new(0),
+            loading: AtomicBool::new(false),
         }
     }
-    pub fn read(&self) -> FrameGuard {
-        self.pin_count.fetch_add(1, Relaxed);
-        FrameGuard { buffer_frame: self }
+
+    /// Latch an already pinned frame shared. The guard releases the pin on drop.
+    pub(crate) fn latch_shared(&self) -> FrameReadGuard<'_> {
+        FrameReadGuard {
+            buffer_frame: self,
+            page: self.page.read().unwrap(),
+        }
+    }
+
+    /// Latch an already pinned frame exclusively. The guard releases the pin on drop.
+    pub(crate) fn latch_exclusive(&self) -> FrameWriteGuard<'_> {
+        FrameWriteGuard {
+            buffer_frame: self,
+            page: self.page.write().unwrap(),
+        }
     }
 }
 
-impl Deref for FrameGuard<'_> {
+impl FrameReadGuard<'_> {
+    pub fn frame_id(&self) -> usize {
+        self.buffer_frame.frame_id
+    }
+}
+
+impl FrameWriteGuard<'_> {
+    pub fn frame_id(&self) -> usize {
+        self.buffer_frame.frame_id
+    }
+}
+
+impl FrameGuard<'_> {
+    /// The page for modification. Errors if the frame was latched read only.
+    pub fn page_mut(&mut self) -> Result<&mut FixedPage, CrustyError> {
+        match self {
+            FrameGuard::Read(_) => Err(CrustyError::InvalidOperation),
+            FrameGuard::Write(g) => Ok(g),
+        }
+    }
+}
+
+impl Deref for FrameReadGuard<'_> {
+    type Target = FixedPage;
+
+    fn deref(&self) -> &Self::Target {
+        &self.page
+    }
+}
+
+impl Deref for FrameWriteGuard<'_> {
     type Target = FixedPage;
 
     fn deref(&self) -> &Self::Target {
-        // SAFETY: This is safe because the latch is held shared.
-        unsafe { &*self.buffer_frame.page.get() }
+        &self.page
     }
 }
 
-impl DerefMut for FrameGuard<'_> {
+impl DerefMut for FrameWriteGuard<'_> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        // SAFETY: This is safe because the latch is held exclusively.
-        unsafe { &mut *self.buffer_frame.page.get() }
+        if !self.buffer_frame.dirty.load(Relaxed) {
+            *self.buffer_frame.rec_lsn.lock().unwrap() = self
+                .page
+                .log
+                .as_ref()
+                .and_then(|page_log| page_log.log.last_lsn());
+            self.buffer_frame.dirty.store(true, Relaxed);
+        }
+        &mut self.page
     }
 }
 
-impl Drop for FrameGuard<'_> {
+impl Deref for FrameGuard<'_> {
+    type Target = FixedPage;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            FrameGuard::Read(g) => g,
+            FrameGuard::Write(g) => g,
+        }
+    }
+}
+
+impl Drop for FrameReadGuard<'_> {
     fn drop(&mut self) {
         self.buffer_frame.pin_count.fetch_sub(1, Relaxed);
     }
 }
-impl Debug for FrameGuard<'_> {
+
+impl Drop for FrameWriteGuard<'_> {
+    fn drop(&mut self) {
+        // A header record is a few bytes, so it always fits on a log page.
+        // Panicking here could abort a thread that is already unwinding.
+        if let Err(e) = self.page.log_header_changes() {
+            error!(
+                "Error logging the header of page {}: {:?}",
+                self.page.p_id, e
+            );
+        }
+        self.buffer_frame.pin_count.fetch_sub(1, Relaxed);
+    }
+}
+
+impl Debug for FrameReadGuard<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FrameReadGuard")
+            .field("frame", &self.buffer_frame.frame_id)
+            .field("pin", &self.buffer_frame.pin_count.load(Relaxed))
+            .field("dirty", &self.buffer_frame.dirty.load(Relaxed))
+            .finish()
+    }
+}
+
+impl Debug for FrameWriteGuard<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("FrameGuard")
+        f.debug_struct("FrameWriteGuard")
             .field("frame", &self.buffer_frame.frame_id)
             .field("pin", &self.buffer_frame.pin_count.load(Relaxed))
+            .field("dirty", &self.buffer_frame.dirty.load(Relaxed))
             .finish()
     }
 }
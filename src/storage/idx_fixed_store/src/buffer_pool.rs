@@ -1,29 +1,63 @@
-use std::cell::UnsafeCell;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::sync::atomic::{AtomicBool, AtomicUsize};
-use std::sync::Arc;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
 
 use std::sync::atomic::Ordering::Relaxed;
 use txn_manager::lockmanager::LockManager;
 
-use crate::buffer_frame::{BufferFrame, FrameGuard};
+use crate::buffer_frame::{BufferFrame, FrameGuard, FrameReadGuard, FrameWriteGuard};
+use crate::container_file::ContainerFile;
 use crate::fixed_page::FixedPage;
+use crate::heap::var_heap_page::VarHeapPage;
+use crate::manifest::{read_manifest, write_manifest};
 use crate::prelude::*;
+use crate::replacement_policy::{ReplacementPolicy, ReplacementPolicyType};
+use crate::wal::log_manager::{LogManager, PageLog};
+use crate::wal::log_record::LogRecord;
 use common::prelude::*;
+use serde::{Deserialize, Serialize};
 
 pub const FRAMES: usize = 500;
 pub const MAX_CONTAINERS: usize = 256;
+/// How many independently latched partitions the frame map is split into
+pub const FRAME_MAP_SHARDS: usize = 16;
+/// The file in the storage directory listing the registered containers
+pub const CONTAINER_MANIFEST: &str = "containers.json";
 
-unsafe impl Sync for BufferFrame {}
-unsafe impl Send for BufferFrame {}
-unsafe impl Sync for BufferPool {}
-unsafe impl Send for BufferPool {}
+type FrameMap = HashMap<[u8; ValueId::CP_BYTES], usize>;
+
+/// The key and value sizes of the records on a container's pages
+pub(crate) fn slot_sizes(state: &StateType) -> (usize, usize) {
+    // hack for fixed size structures
+    match state {
+        StateType::HashTable => (SEARCH_KEY_SIZE, INDEX_POINTER_SIZE),
+        StateType::Tree => (SEARCH_KEY_SIZE, INDEX_POINTER_SIZE),
+        StateType::BaseTable => (KEY_SIZE, VALUE_SIZE),
+        StateType::MatView => (KEY_SIZE, VALUE_SIZE),
+        // Slotted pages size each record on its own
+        StateType::VarTable => (0, 0),
+    }
+}
 
 pub trait BufferPoolTrait: Sync + Send {
-    /// Append a new page to a container. Returns the page id and a guard to the frame
-    fn new_page(&self, c_id: ContainerId) -> Result<(PageId, FrameGuard), CrustyError>;
-    /// Get a frame guard for a page (will ignore any slot_id on the ValueId). Increments the pin count
-    fn get_page(&self, v_id: &ValueId, perm: Permissions) -> Result<FrameGuard, CrustyError>;
+    /// Append a new page to a container. Returns the page id and an exclusively latched guard to the frame
+    fn new_page(&self, c_id: ContainerId) -> Result<(PageId, FrameWriteGuard<'_>), CrustyError>;
+    /// Get a frame guard with the latch held shared for a page (will ignore any slot_id on the ValueId).
+    /// Increments the pin count. If the page is not in a frame it is read from the container's file.
+    fn get_page_for_read(&self, v_id: &ValueId) -> Result<FrameReadGuard<'_>, CrustyError>;
+    /// Get a frame guard with the latch held exclusively for a page (will ignore any slot_id on the ValueId).
+    /// Increments the pin count. If the page is not in a frame it is read from the container's file.
+    fn get_page_for_write(&self, v_id: &ValueId) -> Result<FrameWriteGuard<'_>, CrustyError>;
+    /// Get a frame guard for a page, latched shared for ReadOnly and exclusively for ReadWrite.
+    fn get_page(&self, v_id: &ValueId, perm: Permissions) -> Result<FrameGuard<'_>, CrustyError> {
+        match perm {
+            Permissions::ReadOnly => Ok(FrameGuard::Read(self.get_page_for_read(v_id)?)),
+            Permissions::ReadWrite => Ok(FrameGuard::Write(self.get_page_for_write(v_id)?)),
+        }
+    }
     /// Register a new container. Returns the container id.
     fn register_container(
         &self,
@@ -32,6 +66,10 @@ pub trait BufferPoolTrait: Sync + Send {
     ) -> Result<ContainerId, CrustyError>;
     /// Remove this container and delete all pages associated with it.
     fn drop_container(&self, c_id: ContainerId) -> Result<(), CrustyError>;
+    /// Write every dirty page back to its container file.
+    fn flush_all(&self) -> Result<(), CrustyError>;
+    /// The write-ahead log for changes to pages in the pool
+    fn log(&self) -> &Arc<LogManager>;
 }
 
 /// Stores the metadata for a container
@@ -42,134 +80,633 @@ pub struct ContainerMeta {
     pub max_page: PageId,
     pub key_size: usize,
     pub value_size: usize,
+    /// The file the container's pages are written to. Shared so a page can be
+    /// read in without holding the container metadata.
+    pub file: Arc<ContainerFile>,
+}
+
+/// The part of a container's metadata saved in the container manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedContainer {
+    container_id: ContainerId,
+    name: Option<String>,
+    container_type: StateType,
+    max_page: PageId,
+    key_size: usize,
+    value_size: usize,
+}
+
+/// Counters for how well the buffer pool's replacement policy is working
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// get_page calls that found the page in a frame
+    pub hits: usize,
+    /// get_page calls that had to read the page from disk
+    pub misses: usize,
+    /// Pages removed from a frame to make room for another page
+    pub evictions: usize,
 }
 
-/// A in-memory buffer pool for managing pages. no eviction policy
+/// A buffer pool for managing pages that are backed by a file per container.
+/// When every frame is in use the replacement policy picks an unpinned frame
+/// to evict, writing its page back to disk if it is dirty.
+///
+/// Each frame has its own shared/exclusive latch. Finding a resident page only
+/// takes a shared latch on one shard of the frame map, so readers of different
+/// (or the same) pages do not serialize. A hit is recorded on the frame itself
+/// and handed to the replacement policy the next time a frame is placed or
+/// evicted. Placing a page into a frame (a miss, a new page or dropping a
+/// container) is done holding the allocator mutex, so a page is only ever read
+/// into one frame. On a miss the frame is reserved with its latch held and the
+/// page is read from disk after the allocator is released.
+///
+/// Changes to pages in the pool are logged, and a dirty page is only written
+/// back once the log has been flushed up to the page's LSN.
 pub struct BufferPool {
-    // frames: UnsafeCell<[BufferFrame; FRAMES]>,
-    /// The buffer frames. Any changes to the frames should be done with the latch held
-    frames: UnsafeCell<Vec<BufferFrame>>,
+    /// The buffer frames
+    frames: Vec<BufferFrame>,
     /// The lock manager
     _lm: Arc<LockManager>,
-    /// Mapping of container/page to frame offset. CP_BYTES is the valueID in bytes without slot
-    frame_map: UnsafeCell<HashMap<[u8; common::ids::ValueId::CP_BYTES], usize>>,
+    /// Mapping of container/page to frame offset, split into shards. CP_BYTES is the valueID in bytes without slot
+    frame_map: Vec<RwLock<FrameMap>>,
     /// The container metadata as an array.
-    containers: UnsafeCell<[Option<ContainerMeta>; MAX_CONTAINERS]>,
-    /// The mutex latch for the buffer pool
-    latch: AtomicBool,
-    /// The next free frame.
-    free_frame: AtomicUsize,
+    containers: RwLock<[Option<ContainerMeta>; MAX_CONTAINERS]>,
+    /// Frames that do not hold a page (popped from the back). Held while a page is placed into a frame.
+    allocator: Mutex<Vec<usize>>,
+    /// Chooses the frame to evict once there are no free frames
+    replacer: Mutex<Box<dyn ReplacementPolicy>>,
+    /// Orders the hits recorded on the frames
+    access_clock: AtomicU64,
+    /// The directory holding the container files
+    storage_dir: PathBuf,
+    /// The write-ahead log for changes to pages in the pool
+    log: Arc<LogManager>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    evictions: AtomicUsize,
 }
 
 impl BufferPool {
-    pub fn new(lm: Arc<LockManager>) -> Self {
-        info!("Creating a new BP");
-        // let frames = [ BufferFrame::new(0); FRAMES];
-        // let frames: [BufferFrame; FRAMES] = core::array::from_fn(|i| {
-        //     let frame = BufferFrame::new(i);
-        //     frame
-        // });
-        let mut frames = Vec::with_capacity(FRAMES);
-        for i in 0..FRAMES {
+    pub fn new(lm: Arc<LockManager>, storage_dir: &Path, policy: ReplacementPolicyType) -> Self {
+        Self::with_frames(lm, storage_dir, policy, FRAMES)
+    }
+
+    /// Create a buffer pool with a specific number of frames
+    pub fn with_frames(
+        lm: Arc<LockManager>,
+        storage_dir: &Path,
+        policy: ReplacementPolicyType,
+        frame_count: usize,
+    ) -> Self {
+        info!(
+            "Creating a new BP with {} frames using {:?} in {:?}",
+            frame_count, policy, storage_dir
+        );
+        let mut frames = Vec::with_capacity(frame_count);
+        for i in 0..frame_count {
             frames.push(BufferFrame::new(i));
         }
 
         BufferPool {
-            frames: UnsafeCell::new(frames),
-            //external_frames: Vec::new(),
+            frames,
             _lm: lm,
-            frame_map: UnsafeCell::new(HashMap::new()),
-            containers: UnsafeCell::new([const { None }; MAX_CONTAINERS]),
-            latch: AtomicBool::new(false),
-            free_frame: AtomicUsize::new(0),
+            frame_map: (0..FRAME_MAP_SHARDS)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            containers: RwLock::new([const { None }; MAX_CONTAINERS]),
+            allocator: Mutex::new((0..frame_count).rev().collect()),
+            replacer: Mutex::new(policy.build(frame_count)),
+            access_clock: AtomicU64::new(1),
+            storage_dir: storage_dir.to_path_buf(),
+            log: Arc::new(LogManager::new(storage_dir)),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            evictions: AtomicUsize::new(0),
+        }
+    }
+
+    /// Open a buffer pool on a storage directory, reloading any containers
+    /// listed in its manifest.
+    pub fn open(
+        lm: Arc<LockManager>,
+        storage_dir: &Path,
+        policy: ReplacementPolicyType,
+    ) -> Result<Self, CrustyError> {
+        Self::open_with_frames(lm, storage_dir, policy, FRAMES)
+    }
+
+    /// Open a buffer pool with a specific number of frames on a storage directory
+    pub fn open_with_frames(
+        lm: Arc<LockManager>,
+        storage_dir: &Path,
+        policy: ReplacementPolicyType,
+        frame_count: usize,
+    ) -> Result<Self, CrustyError> {
+        let mut bp = Self::with_frames(lm, storage_dir, policy, frame_count);
+        bp.log = Arc::new(LogManager::open(storage_dir)?);
+        let saved: Vec<SavedContainer> =
+            read_manifest(&storage_dir.join(CONTAINER_MANIFEST))?.unwrap_or_default();
+        let mut cm = bp.containers.write().unwrap();
+        for c in saved {
+            let file = ContainerFile::open(storage_dir, c.container_id)?;
+            // Pages added after the manifest was last saved are still in the file
+            let max_page = c.max_page.max(file.page_count()?);
+            debug!(
+                "Reopened container {} with {} pages",
+                c.container_id, max_page
+            );
+            cm[c.container_id as usize] = Some(ContainerMeta {
+                container_id: c.container_id,
+                name: c.name,
+                container_type: c.container_type,
+                max_page,
+                key_size: c.key_size,
+                value_size: c.value_size,
+                file: Arc::new(file),
+            });
         }
+        drop(cm);
+        Ok(bp)
     }
 
-    pub fn acquire_latch(&self) -> Result<(), CrustyError> {
-        const LATCH_TIMEOUT_MS: u64 = 1000;
-        let timeout =
-            std::time::Instant::now() + std::time::Duration::from_millis(LATCH_TIMEOUT_MS);
+    /// The name, type and page count of a registered container
+    pub fn get_container_info(
+        &self,
+        c_id: ContainerId,
+    ) -> Option<(Option<String>, StateType, PageId)> {
+        self.containers.read().unwrap()[c_id as usize]
+            .as_ref()
+            .map(|m| (m.name.clone(), m.container_type.clone(), m.max_page))
+    }
+
+    /// The ids of every registered container, in order
+    pub fn container_ids(&self) -> Vec<ContainerId> {
+        self.containers
+            .read()
+            .unwrap()
+            .iter()
+            .flatten()
+            .map(|m| m.container_id)
+            .collect()
+    }
+
+    /// Save the metadata of every registered container to the manifest
+    fn save_manifest(&self, cm: &[Option<ContainerMeta>]) -> Result<(), CrustyError> {
+        let saved: Vec<SavedContainer> = cm
+            .iter()
+            .flatten()
+            .map(|m| SavedContainer {
+                container_id: m.container_id,
+                name: m.name.clone(),
+                container_type: m.container_type.clone(),
+                max_page: m.max_page,
+                key_size: m.key_size,
+                value_size: m.value_size,
+            })
+            .collect();
+        write_manifest(&self.storage_dir.join(CONTAINER_MANIFEST), &saved)
+    }
+
+    pub fn get_storage_dir(&self) -> &Path {
+        &self.storage_dir
+    }
+
+    /// Write a page back to its container file, flushing the log up to the
+    /// page's LSN first. Returns false without writing if an atomic action
+    /// that changed the page has not finished, so its records cannot be flushed yet.
+    fn write_back(&self, meta: &ContainerMeta, page: &FixedPage) -> Result<bool, CrustyError> {
+        if let Some(lsn) = page.lsn {
+            if !self.log.can_flush(lsn) {
+                return Ok(false);
+            }
+            self.log.flush(lsn)?;
+        }
+        meta.file.write_page(page)?;
+        Ok(true)
+    }
+
+    /// Whether a frame's page can be evicted without waiting on the log
+    fn can_write_back(&self, frame_offset: usize) -> bool {
+        let frame = &self.frames[frame_offset];
+        if !frame.dirty.load(Relaxed) {
+            return true;
+        }
+        match frame.page.try_read() {
+            Ok(page) => page.lsn.is_none_or(|lsn| self.log.can_flush(lsn)),
+            Err(_) => false,
+        }
+    }
+
+    /// Get a page for recovery to redo a change on. Pages past the end of the
+    /// container are added to it.
+    pub(crate) fn get_page_for_redo(
+        &self,
+        c_id: ContainerId,
+        p_id: PageId,
+    ) -> Result<FrameWriteGuard<'_>, CrustyError> {
+        let v_id = ValueId::new_page(c_id, p_id);
+        let on_disk = {
+            let mut cm = self.containers.write().unwrap();
+            let meta = cm[c_id as usize]
+                .as_mut()
+                .ok_or(CrustyError::ContainerDoesNotExist)?;
+            meta.max_page = meta.max_page.max(p_id + 1);
+            p_id < meta.file.page_count()?
+        };
+        if on_disk {
+            return self.get_page_for_write(&v_id);
+        }
+        let mut free_frames = self.allocator.lock().unwrap();
+        if let Some(frame) = self.pin_if_resident(&v_id.to_cp_bytes()) {
+            return Ok(frame.latch_exclusive());
+        }
+        let frame_offset = self.get_free_frame(&mut free_frames)?;
+        let mut page = FixedPage::empty();
+        page.p_id = p_id;
+        let frame = self.install_page(frame_offset, v_id, page, true);
+        drop(free_frames);
+        Ok(frame.latch_exclusive())
+    }
+
+    /// Write a frame's page back if it is dirty
+    fn flush_frame(&self, frame: &BufferFrame) -> Result<(), CrustyError> {
         loop {
-            if std::time::Instant::now() > timeout {
-                return Err(CrustyError::CrustyError("Latch timeout".to_string()));
+            // Wait for the page's records to be flushable without holding
+            // its latch, as an atomic action may still be changing it
+            if let Some(lsn) = frame.page.read().unwrap().lsn {
+                self.log.flush(lsn)?;
             }
-            match self.latch.compare_exchange(false, true, Relaxed, Relaxed) {
-                Ok(_b) => return Ok(()),
-                Err(_b) => {}
+            // Holding the frame's v_id keeps it from being evicted while it is written
+            let v_id = frame.v_id.lock().unwrap();
+            let (Some(v_id), true) = (*v_id, frame.dirty.load(Relaxed)) else {
+                return Ok(());
+            };
+            let page = frame.page.read().unwrap();
+            let cm = self.containers.read().unwrap();
+            if let Some(meta) = cm[v_id.container_id as usize].as_ref() {
+                if !self.write_back(meta, &page)? {
+                    continue;
+                }
             }
-            std::thread::sleep(std::time::Duration::from_millis(2));
+            frame.dirty.store(false, Relaxed);
+            return Ok(());
         }
     }
 
-    pub fn release_latch(&self) {
-        self.latch.store(false, Relaxed);
+    /// Log a fuzzy checkpoint of the dirty pages and unfinished transactions.
+    /// Work carries on while it is taken. Only pages dirty since before the
+    /// last checkpoint are written out, so pages that are never evicted do
+    /// not keep the log from being truncated.
+    pub fn checkpoint(&self) -> Result<(), CrustyError> {
+        let last = self.log.checkpoint_lsn();
+        for frame in self.frames.iter() {
+            if last.is_some() && *frame.rec_lsn.lock().unwrap() < last {
+                self.flush_frame(frame)?;
+            }
+        }
+        let begin = self.log.last_lsn();
+        let mut dirty = vec![];
+        for frame in self.frames.iter() {
+            let v_id = *frame.v_id.lock().unwrap();
+            if let (Some(v_id), true) = (v_id, frame.dirty.load(Relaxed)) {
+                let rec_lsn = *frame.rec_lsn.lock().unwrap();
+                dirty.push((v_id.container_id, v_id.page_id.unwrap(), rec_lsn));
+            }
+        }
+        // Pages written back before now are left out, so they must be on disk
+        for meta in self.containers.read().unwrap().iter().flatten() {
+            meta.file.sync()?;
+        }
+        self.log.checkpoint(begin, dirty)
     }
-}
 
-impl BufferPoolTrait for BufferPool {
-    fn new_page(&self, c_id: ContainerId) -> Result<(PageId, FrameGuard), CrustyError> {
-        self.acquire_latch()?;
-        // Find the next page id
-        let cm = unsafe { &mut *self.containers.get() };
-        if cm[c_id as usize].is_none() {
-            error!(
-                "Trying to create new page for non-registered Container Id {}",
-                c_id
-            );
-            self.release_latch();
-            return Err(CrustyError::StorageError);
+    /// The hit, miss and eviction counts since the pool was created or last reset
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            hits: self.hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            evictions: self.evictions.load(Relaxed),
         }
-        let meta = cm[c_id as usize].as_mut().unwrap();
-        let new_pid = meta.max_page;
-        meta.max_page += 1;
+    }
 
-        // Find the free frame
-        let frame_offset = self.free_frame.fetch_add(1, Relaxed);
-        if frame_offset >= FRAMES {
-            self.release_latch();
-            return Err(CrustyError::CrustyError("Out of free frames".to_string()));
-        }
-        let frames = unsafe { &mut *self.frames.get() };
-        let frame = &frames[frame_offset];
+    pub fn reset_stats(&self) {
+        self.hits.store(0, Relaxed);
+        self.misses.store(0, Relaxed);
+        self.evictions.store(0, Relaxed);
+    }
 
-        // Set the frame's meta data to match the container
-        let page = unsafe { &mut *frame.page.get() };
-        page.update_settings(new_pid, meta.key_size, meta.value_size);
+    /// Write back every dirty page and empty every unpinned frame, so later
+    /// requests have to read their pages from disk.
+    pub fn clear_cache(&self) -> Result<(), CrustyError> {
+        self.flush_all()?;
+        let mut free_frames = self.allocator.lock().unwrap();
+        for frame_offset in 0..self.frames.len() {
+            if self.frames[frame_offset].v_id.lock().unwrap().is_none() {
+                continue;
+            }
+            if self.evict_frame(frame_offset)? {
+                self.forget_frame(&mut **self.replacer.lock().unwrap(), frame_offset);
+                free_frames.push(frame_offset);
+            }
+        }
+        Ok(())
+    }
 
-        // Add the cid/vid to frame offset to map
-        let cp_bytes = ValueId::new_page(c_id, new_pid).to_cp_bytes();
-        let frame_map = unsafe { &mut *self.frame_map.get() };
-        frame_map.insert(cp_bytes, frame_offset);
+    fn shard(&self, cp_bytes: &[u8; ValueId::CP_BYTES]) -> &RwLock<FrameMap> {
+        let mut hasher = DefaultHasher::new();
+        cp_bytes.hash(&mut hasher);
+        &self.frame_map[hasher.finish() as usize % FRAME_MAP_SHARDS]
+    }
 
-        // Create the frame guard and track the pin
+    /// Pin the frame holding the page if it is in the pool. The pin is taken with the
+    /// shard latch held, which is what keeps an evictor from removing it.
+    fn pin_if_resident(&self, cp_bytes: &[u8; ValueId::CP_BYTES]) -> Option<&BufferFrame> {
+        let shard = self.shard(cp_bytes).read().unwrap();
+        let frame = &self.frames[*shard.get(cp_bytes)?];
         frame.pin_count.fetch_add(1, Relaxed);
-        let guard = FrameGuard {
-            buffer_frame: frame,
-        };
-        self.release_latch();
-        Ok((new_pid, guard))
+        Some(frame)
     }
 
-    fn get_page(&self, v_id: &ValueId, _perm: Permissions) -> Result<FrameGuard, CrustyError> {
+    /// Find the page's frame and pin it, reading the page from disk on a miss.
+    fn fetch_frame(&self, v_id: &ValueId) -> Result<&BufferFrame, CrustyError> {
         let cp_bytes = v_id.to_cp_bytes();
-        self.acquire_latch()?;
-        //Find the frame
-        let frame_map = unsafe { &mut *self.frame_map.get() };
-        let frame_offset = frame_map.get(&cp_bytes);
-        if frame_offset.is_none() {
-            self.release_latch();
-            return Err(CrustyError::CrustyError(
-                "Trying to get page that does not exist".to_string(),
-            ));
+        loop {
+            if let Some(frame) = self.pin_if_resident(&cp_bytes) {
+                if frame.loading.load(Relaxed) && !self.wait_for_load(frame) {
+                    // The read failed, retry once the frame has been released
+                    std::thread::yield_now();
+                    continue;
+                }
+                self.record_access(frame);
+                self.hits.fetch_add(1, Relaxed);
+                return Ok(frame);
+            }
+
+            let mut free_frames = self.allocator.lock().unwrap();
+            // Another thread may have placed the page while we waited
+            if self
+                .shard(&cp_bytes)
+                .read()
+                .unwrap()
+                .contains_key(&cp_bytes)
+            {
+                continue;
+            }
+            let (p_id, file) = {
+                let cm = self.containers.read().unwrap();
+                match (cm[v_id.container_id as usize].as_ref(), v_id.page_id) {
+                    (Some(meta), Some(p_id)) if p_id < meta.max_page => (p_id, meta.file.clone()),
+                    _ => {
+                        return Err(CrustyError::CrustyError(
+                            "Trying to get page that does not exist".to_string(),
+                        ));
+                    }
+                }
+            };
+            let frame_offset = self.get_free_frame(&mut free_frames)?;
+            let page_v_id = ValueId::new_page(v_id.container_id, p_id);
+            let (frame, mut latch) = self.place_frame(frame_offset, page_v_id, false, true);
+            drop(free_frames);
+
+            // Not in the pool, read the page in from the container's file
+            return match file.read_page(p_id) {
+                Ok(page) => {
+                    *latch = self.with_log(page_v_id, page);
+                    frame.loading.store(false, Relaxed);
+                    drop(latch);
+                    self.misses.fetch_add(1, Relaxed);
+                    Ok(frame)
+                }
+                Err(e) => {
+                    error!("Error reading page {:?} from disk {:?}", v_id, e);
+                    drop(latch);
+                    self.abandon_load(frame, page_v_id);
+                    Err(e)
+                }
+            };
+        }
+    }
+
+    /// Wait for the page of a pinned frame to be read in by another thread.
+    /// Returns false and unpins the frame if the read failed. A pinned frame
+    /// is not placed again, so once loaded it still holds the page.
+    fn wait_for_load(&self, frame: &BufferFrame) -> bool {
+        let _latch = frame.page.read().unwrap();
+        if frame.loading.load(Relaxed) {
+            frame.pin_count.fetch_sub(1, Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Release a frame whose page could not be read in. Threads that pinned
+    /// it while waiting see it still loading and retry.
+    fn abandon_load(&self, frame: &BufferFrame, v_id: ValueId) {
+        let mut frame_v_id = frame.v_id.lock().unwrap();
+        let cp_bytes = v_id.to_cp_bytes();
+        self.shard(&cp_bytes).write().unwrap().remove(&cp_bytes);
+        *frame_v_id = None;
+        drop(frame_v_id);
+        let mut free_frames = self.allocator.lock().unwrap();
+        self.forget_frame(&mut **self.replacer.lock().unwrap(), frame.frame_id);
+        frame.pin_count.fetch_sub(1, Relaxed);
+        free_frames.push(frame.frame_id);
+    }
+
+    /// Note a hit on a frame for the replacement policy
+    fn record_access(&self, frame: &BufferFrame) {
+        let tick = self.access_clock.fetch_add(1, Relaxed);
+        frame.last_access.store(tick, Relaxed);
+    }
+
+    /// Hand the hits recorded on the frames to the replacement policy, oldest
+    /// first. Hits of a frame between two calls count as one.
+    fn apply_accesses(&self, replacer: &mut dyn ReplacementPolicy) {
+        let mut accessed: Vec<(u64, usize)> = self
+            .frames
+            .iter()
+            .filter_map(|frame| match frame.last_access.swap(0, Relaxed) {
+                0 => None,
+                tick => Some((tick, frame.frame_id)),
+            })
+            .collect();
+        accessed.sort_unstable();
+        for (_, frame_offset) in accessed {
+            replacer.frame_accessed(frame_offset);
         }
-        let frame_offset = *frame_offset.unwrap();
-        let frames = unsafe { &mut *self.frames.get() };
-        let frame = &frames[frame_offset];
-        self.release_latch();
+    }
+
+    /// Stop tracking a frame whose page was removed
+    fn forget_frame(&self, replacer: &mut dyn ReplacementPolicy, frame_offset: usize) {
+        replacer.frame_removed(frame_offset);
+        self.frames[frame_offset].last_access.store(0, Relaxed);
+    }
+
+    /// Attach the log to a page about to be placed in a frame
+    fn with_log(&self, v_id: ValueId, mut page: FixedPage) -> FixedPage {
+        page.log = Some(PageLog {
+            c_id: v_id.container_id,
+            log: self.log.clone(),
+        });
+        page
+    }
+
+    /// Place a page into a free frame, pin it and make it visible in the frame map.
+    /// Must be called with the allocator held.
+    fn install_page(
+        &self,
+        frame_offset: usize,
+        v_id: ValueId,
+        page: FixedPage,
+        dirty: bool,
+    ) -> &BufferFrame {
+        let (frame, mut latch) = self.place_frame(frame_offset, v_id, dirty, false);
+        *latch = self.with_log(v_id, page);
+        frame
+    }
+
+    /// Pin a free frame for a page and make it visible in the frame map.
+    /// Returns the frame's latch held exclusively, so the page can be put in
+    /// before anyone else sees it. A frame placed while `loading` makes threads
+    /// that find it wait for its read. Must be called with the allocator held.
+    fn place_frame(
+        &self,
+        frame_offset: usize,
+        v_id: ValueId,
+        dirty: bool,
+        loading: bool,
+    ) -> (&BufferFrame, RwLockWriteGuard<'_, FixedPage>) {
+        let frame = &self.frames[frame_offset];
         frame.pin_count.fetch_add(1, Relaxed);
-        Ok(FrameGuard {
-            buffer_frame: frame,
-        })
+        let latch = frame.page.write().unwrap();
+        *frame.rec_lsn.lock().unwrap() = self.log.last_lsn();
+        frame.dirty.store(dirty, Relaxed);
+        frame.loading.store(loading, Relaxed);
+        frame.last_access.store(0, Relaxed);
+        *frame.v_id.lock().unwrap() = Some(v_id);
+        let cp_bytes = v_id.to_cp_bytes();
+        self.shard(&cp_bytes)
+            .write()
+            .unwrap()
+            .insert(cp_bytes, frame_offset);
+        let mut replacer = self.replacer.lock().unwrap();
+        self.apply_accesses(&mut **replacer);
+        replacer.frame_loaded(frame_offset, v_id);
+        (frame, latch)
+    }
+
+    /// Find a frame to hold a new page. Uses a free frame if one is left,
+    /// otherwise evicts the unpinned frame chosen by the replacement policy.
+    /// Must be called with the allocator held, which is passed in as the free list.
+    fn get_free_frame(&self, free_frames: &mut Vec<usize>) -> Result<usize, CrustyError> {
+        // A free frame is still pinned while a thread that waited on a failed read lets go of it
+        if let Some(pos) = free_frames
+            .iter()
+            .rposition(|f| self.frames[*f].pin_count.load(Relaxed) == 0)
+        {
+            return Ok(free_frames.remove(pos));
+        }
+        // A victim can be pinned by a reader before it is removed from the map, so retry
+        for _ in 0..self.frames.len() {
+            let victim = {
+                let mut replacer = self.replacer.lock().unwrap();
+                self.apply_accesses(&mut **replacer);
+                replacer.victim(&|f| {
+                    self.frames[f].pin_count.load(Relaxed) == 0 && self.can_write_back(f)
+                })
+            };
+            let Some(frame_offset) = victim else {
+                break;
+            };
+            if self.evict_frame(frame_offset)? {
+                self.forget_frame(&mut **self.replacer.lock().unwrap(), frame_offset);
+                self.evictions.fetch_add(1, Relaxed);
+                return Ok(frame_offset);
+            }
+        }
+        Err(CrustyError::CrustyError(
+            "Out of free frames, all frames are pinned".to_string(),
+        ))
+    }
+
+    /// Remove the page held by an unpinned frame, writing it back to disk if it is dirty.
+    /// Returns false if the frame was pinned before it could be removed from the map,
+    /// or if its page cannot be written back yet.
+    /// Must be called with the allocator held.
+    fn evict_frame(&self, frame_offset: usize) -> Result<bool, CrustyError> {
+        let frame = &self.frames[frame_offset];
+        let mut v_id = frame.v_id.lock().unwrap();
+        let Some(old_v_id) = *v_id else {
+            return Ok(true);
+        };
+        let cp_bytes = old_v_id.to_cp_bytes();
+        let mut shard = self.shard(&cp_bytes).write().unwrap();
+        if frame.pin_count.load(Relaxed) > 0 {
+            return Ok(false);
+        }
+        shard.remove(&cp_bytes);
+        if frame.dirty.load(Relaxed) {
+            // Nothing can pin the frame anymore, but wait out a guard that is still being dropped
+            let page = frame.page.read().unwrap();
+            let cm = self.containers.read().unwrap();
+            if let Some(meta) = cm[old_v_id.container_id as usize].as_ref() {
+                match self.write_back(meta, &page) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        shard.insert(cp_bytes, frame_offset);
+                        return Ok(false);
+                    }
+                    Err(e) => {
+                        shard.insert(cp_bytes, frame_offset);
+                        return Err(e);
+                    }
+                }
+            }
+            frame.dirty.store(false, Relaxed);
+        }
+        debug!("Evicting {:?} from frame {}", old_v_id, frame_offset);
+        *v_id = None;
+        Ok(true)
+    }
+}
+
+impl BufferPoolTrait for BufferPool {
+    fn new_page(&self, c_id: ContainerId) -> Result<(PageId, FrameWriteGuard<'_>), CrustyError> {
+        let mut free_frames = self.allocator.lock().unwrap();
+        if self.containers.read().unwrap()[c_id as usize].is_none() {
+            error!(
+                "Trying to create new page for non-registered Container Id {}",
+                c_id
+            );
+            return Err(CrustyError::StorageError);
+        }
+        // Find the free frame before taking the page id, so a failure leaves the container unchanged
+        let frame_offset = self.get_free_frame(&mut free_frames)?;
+        let page = {
+            let mut cm = self.containers.write().unwrap();
+            let meta = cm[c_id as usize].as_mut().unwrap();
+            let new_pid = meta.max_page;
+            meta.max_page += 1;
+            match meta.container_type {
+                StateType::VarTable => <FixedPage as VarHeapPage>::new(new_pid),
+                _ => FixedPage::new(new_pid, meta.key_size, meta.value_size),
+            }
+        };
+        let new_pid = page.p_id;
+        // The page has never been written so it is dirty until it is written back
+        let frame = self.install_page(frame_offset, ValueId::new_page(c_id, new_pid), page, true);
+        drop(free_frames);
+        let mut page = frame.latch_exclusive();
+        page.log_format()?;
+        Ok((new_pid, page))
+    }
+
+    fn get_page_for_read(&self, v_id: &ValueId) -> Result<FrameReadGuard<'_>, CrustyError> {
+        Ok(self.fetch_frame(v_id)?.latch_shared())
+    }
+
+    fn get_page_for_write(&self, v_id: &ValueId) -> Result<FrameWriteGuard<'_>, CrustyError> {
+        Ok(self.fetch_frame(v_id)?.latch_exclusive())
     }
 
     fn register_container(
@@ -177,23 +714,19 @@ impl BufferPoolTrait for BufferPool {
         name: Option<String>,
         state: StateType,
     ) -> Result<ContainerId, CrustyError> {
-        self.acquire_latch()?;
-        let cm = unsafe { &mut *self.containers.get() };
+        let mut cm = self.containers.write().unwrap();
         let cid = cm.iter().position(|x| x.is_none());
         if cid.is_none() {
-            self.release_latch();
             let s = "Ran out of container IDs. Up max".to_string();
             error!("{}", s);
             return Err(CrustyError::CrustyError(s));
         }
         let cid = cid.unwrap();
-        // hack for fixed size structures
-        let (key_size, value_size) = match state {
-            StateType::HashTable => (SEARCH_KEY_SIZE, INDEX_POINTER_SIZE),
-            StateType::Tree => (SEARCH_KEY_SIZE, INDEX_POINTER_SIZE),
-            StateType::BaseTable => (KEY_SIZE, VALUE_SIZE),
-            StateType::MatView => (KEY_SIZE, VALUE_SIZE),
-        };
+        let (key_size, value_size) = slot_sizes(&state);
+        let file =
+            ContainerFile::create(&self.storage_dir, cid as ContainerId).inspect_err(|e| {
+                error!("Error creating file for container {}: {:?}", cid, e);
+            })?;
         cm[cid] = Some(ContainerMeta {
             container_id: cid as ContainerId,
             name,
@@ -201,50 +734,76 @@ impl BufferPoolTrait for BufferPool {
             max_page: 0,
             key_size,
             value_size,
+            file: Arc::new(file),
         });
-        self.release_latch();
-        Ok(cid as ContainerId)
+        // Recovery skips the log records of an earlier container with this id
+        let c_id = cid as ContainerId;
+        let lsn = self.log.append(&LogRecord::NewContainer { c_id })?;
+        drop(cm);
+        self.log.flush(lsn)?;
+        self.save_manifest(&self.containers.read().unwrap()[..])?;
+        Ok(c_id)
     }
 
     fn drop_container(&self, c_id: ContainerId) -> Result<(), CrustyError> {
-        self.acquire_latch()?;
-        let cm = unsafe { &mut *self.containers.get() };
-        if cm[c_id as usize].is_none() {
+        let mut free_frames = self.allocator.lock().unwrap();
+        let mut cm = self.containers.write().unwrap();
+        let Some(meta) = cm[c_id as usize].as_ref() else {
             error!("Trying to drop non-registered Container Id {}", c_id);
-            self.release_latch();
             return Err(CrustyError::StorageError);
+        };
+        // Check for pins first so a failed drop leaves the container intact
+        for p in 0..meta.max_page {
+            let cp_bytes = ValueId::new_page(c_id, p).to_cp_bytes();
+            let shard = self.shard(&cp_bytes).read().unwrap();
+            if let Some(frame_offset) = shard.get(&cp_bytes) {
+                if self.frames[*frame_offset].pin_count.load(Relaxed) > 0 {
+                    error!("Trying to drop container with pinned pages");
+                    return Err(CrustyError::StorageError);
+                }
+            }
         }
-        let meta = cm[c_id as usize].as_ref().unwrap();
-        let frames = unsafe { &mut *self.frames.get() };
-        for p in 0..=meta.max_page {
-            let v_id = ValueId::new_page(c_id, p);
-            let cp_bytes = v_id.to_cp_bytes();
-            let frame_map = unsafe { &mut *self.frame_map.get() };
-            let frame_offset = frame_map.get(&cp_bytes);
-            if frame_offset.is_none() {
+        let mut replacer = self.replacer.lock().unwrap();
+        for p in 0..meta.max_page {
+            let cp_bytes = ValueId::new_page(c_id, p).to_cp_bytes();
+            let Some(frame_offset) = self.shard(&cp_bytes).write().unwrap().remove(&cp_bytes)
+            else {
                 continue;
-            }
-            let frame_offset = *frame_offset.unwrap();
-            let frame = &mut frames[frame_offset];
-            let pin_count = frame.pin_count.load(Relaxed);
-            if pin_count > 0 {
-                error!("Trying to drop container with pinned pages");
-                self.release_latch();
-                return Err(CrustyError::StorageError);
-            }
-            frame.pin_count.store(0, Relaxed);
-            frame.page = UnsafeCell::new(FixedPage::empty());
-            frame_map.remove(&cp_bytes);
+            };
+            self.forget_frame(&mut **replacer, frame_offset);
+            free_frames.push(frame_offset);
+            let frame = &self.frames[frame_offset];
+            frame.dirty.store(false, Relaxed);
+            *frame.v_id.lock().unwrap() = None;
+            *frame.page.write().unwrap() = FixedPage::empty();
         }
-        cm[c_id as usize] = None;
-        self.release_latch();
-        Ok(())
+        cm[c_id as usize].take().unwrap().file.remove()?;
+        self.save_manifest(&cm[..])
+    }
+
+    fn log(&self) -> &Arc<LogManager> {
+        &self.log
+    }
+
+    fn flush_all(&self) -> Result<(), CrustyError> {
+        for frame in self.frames.iter() {
+            self.flush_frame(frame)?;
+        }
+        let cm = self.containers.read().unwrap();
+        for meta in cm.iter().flatten() {
+            meta.file.sync()?;
+        }
+        self.save_manifest(&cm[..])
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::heap::fixed_heap_file::FixedHeapFile;
+    use crate::test_util::{
+        gen_random_test_dir, gen_records_ascending_keys, gen_small_rng_with_seed, SearchKeyTypes,
+    };
     use common::testutil::init;
     use txn_manager::{lm_trait::LockManagerTrait, lockmanager::LockManager};
 
@@ -252,7 +811,8 @@ mod test {
     fn test_bp_simple() {
         init();
         let lm = Arc::new(LockManager::new(500));
-        let bp = BufferPool::new(lm);
+        let dir = gen_random_test_dir();
+        let bp = BufferPool::new(lm, &dir, ReplacementPolicyType::default());
         let c1 = bp
             .register_container(None, StateType::BaseTable)
             .expect("Got CID");
@@ -277,7 +837,7 @@ mod test {
         assert!(slot.is_ok());
         drop(g);
         let v_id = ValueId::new_page(c1, p);
-        let g = bp.get_page(&v_id, Permissions::ReadOnly).unwrap();
+        let g = bp.get_page_for_read(&v_id).unwrap();
         let (k, v) = g.get_kv(0).unwrap();
         assert_eq!(k, key);
         assert_eq!(v, val);
@@ -288,7 +848,8 @@ mod test {
     fn test_bp_drop() {
         init();
         let lm = Arc::new(LockManager::new(500));
-        let bp = BufferPool::new(lm);
+        let dir = gen_random_test_dir();
+        let bp = BufferPool::new(lm, &dir, ReplacementPolicyType::default());
         let c1 = bp
             .register_container(None, StateType::BaseTable)
             .expect("Got CID");
@@ -318,4 +879,428 @@ mod test {
         let v_id = ValueId::new_page(c1, 0);
         assert!(bp.get_page(&v_id, Permissions::ReadOnly).is_err());
     }
+
+    const POLICIES: [ReplacementPolicyType; 4] = [
+        ReplacementPolicyType::Lru,
+        ReplacementPolicyType::Clock,
+        ReplacementPolicyType::LruK(2),
+        ReplacementPolicyType::TwoQ,
+    ];
+
+    #[test]
+    fn test_bp_evict_and_read_back() {
+        init();
+        for policy in POLICIES {
+            let lm = Arc::new(LockManager::new(500));
+            let dir = gen_random_test_dir();
+            let bp = BufferPool::with_frames(lm, &dir, policy, 5);
+            let c1 = bp
+                .register_container(None, StateType::BaseTable)
+                .expect("Got CID");
+            // Write a distinct record to 4x more pages than there are frames
+            for i in 0..20u8 {
+                let (p, mut g) = bp.new_page(c1).expect("Got page");
+                assert_eq!(p, i as PageId);
+                g.write(0, false, &[i; KEY_SIZE], &[i; VALUE_SIZE]).unwrap();
+            }
+            for i in (0..20u8).rev() {
+                let v_id = ValueId::new_page(c1, i as PageId);
+                let g = bp.get_page(&v_id, Permissions::ReadOnly).unwrap();
+                let (k, v) = g.get_kv(0).unwrap();
+                assert_eq!(k, [i; KEY_SIZE]);
+                assert_eq!(v, [i; VALUE_SIZE]);
+                assert!(g.get_kv(1).is_none());
+            }
+            // A page past the end of the container does not exist
+            let v_id = ValueId::new_page(c1, 20);
+            assert!(bp.get_page(&v_id, Permissions::ReadOnly).is_err());
+            let stats = bp.stats();
+            assert_eq!(stats.hits + stats.misses, 20);
+            assert!(stats.evictions >= 15);
+        }
+    }
+
+    #[test]
+    fn test_bp_policies_on_heap_workload() {
+        init();
+        let mut rng = gen_small_rng_with_seed(23530);
+        let records = gen_records_ascending_keys(1000, SearchKeyTypes::Card(10), &mut rng);
+        let txn = TransactionId::new();
+        for policy in POLICIES {
+            let lm = Arc::new(LockManager::new(500));
+            let dir = gen_random_test_dir();
+            let bp = Arc::new(BufferPool::with_frames(lm.clone(), &dir, policy, 10));
+            let c_id = bp
+                .register_container(None, StateType::BaseTable)
+                .expect("Got CID");
+            let file = FixedHeapFile::new(c_id, bp.clone(), lm);
+            let mut v_ids = Vec::new();
+            for (key, value) in records.iter() {
+                v_ids.push(file.insert_kv(key, value, &txn).unwrap());
+            }
+            bp.reset_stats();
+            // Repeatedly read a small hot set of records between full passes
+            let mut reads = 0;
+            for round in 0..4 {
+                for (i, v_id) in v_ids.iter().enumerate() {
+                    assert_eq!(file.get_kv(v_id, &txn).unwrap(), records[i]);
+                    reads += 1;
+                    if i % 50 == 0 {
+                        let hot = &v_ids[round % 3];
+                        file.get_kv(hot, &txn).unwrap();
+                        reads += 1;
+                    }
+                }
+            }
+            let stats = bp.stats();
+            info!("{:?} {:?}", policy, stats);
+            assert_eq!(stats.hits + stats.misses, reads);
+            assert!(stats.misses > 0);
+            assert!(stats.evictions > 0);
+        }
+    }
+
+    #[test]
+    fn test_bp_pinned_frames_not_evicted() {
+        init();
+        let lm = Arc::new(LockManager::new(500));
+        let dir = gen_random_test_dir();
+        let bp = BufferPool::with_frames(lm, &dir, ReplacementPolicyType::Lru, 3);
+        let c1 = bp
+            .register_container(None, StateType::BaseTable)
+            .expect("Got CID");
+        let mut guards = Vec::new();
+        for i in 0..3u8 {
+            let (_p, mut g) = bp.new_page(c1).expect("Got page");
+            g.write(0, false, &[i; KEY_SIZE], &[i; VALUE_SIZE]).unwrap();
+            guards.push(g);
+        }
+        // Every frame is pinned
+        assert!(bp.new_page(c1).is_err());
+        let g = guards.remove(1);
+        drop(g);
+        let (p, _g) = bp.new_page(c1).expect("Frame 1 can be evicted");
+        assert_eq!(p, 3);
+        for (g, i) in guards.iter().zip([0u8, 2]) {
+            assert_eq!(g.get_kv(0).unwrap().0, [i; KEY_SIZE]);
+        }
+        drop(guards);
+        // Page 1 was written back on eviction
+        let v_id = ValueId::new_page(c1, 1);
+        let g = bp.get_page(&v_id, Permissions::ReadOnly).unwrap();
+        assert_eq!(g.get_kv(0).unwrap().1, [1; VALUE_SIZE]);
+    }
+
+    #[test]
+    fn test_bp_flush_all() {
+        init();
+        let lm = Arc::new(LockManager::new(500));
+        let dir = gen_random_test_dir();
+        let bp = BufferPool::new(lm, &dir, ReplacementPolicyType::default());
+        let c1 = bp
+            .register_container(None, StateType::BaseTable)
+            .expect("Got CID");
+        let (p, mut g) = bp.new_page(c1).expect("Got page");
+        g.write(3, false, &[7; KEY_SIZE], &[8; VALUE_SIZE]).unwrap();
+        assert!(g.buffer_frame.dirty.load(Relaxed));
+        drop(g);
+        bp.flush_all().unwrap();
+        let v_id = ValueId::new_page(c1, p);
+        let g = bp.get_page_for_read(&v_id).unwrap();
+        assert!(!g.buffer_frame.dirty.load(Relaxed));
+        drop(g);
+        let file = ContainerFile::open(&dir, c1).unwrap();
+        let page = file.read_page(p).unwrap();
+        assert_eq!(
+            page.get_kv(3).unwrap(),
+            (vec![7; KEY_SIZE], vec![8; VALUE_SIZE])
+        );
+        assert_eq!(page.get_filled_slot_count(), 1);
+    }
+
+    #[test]
+    fn test_bp_flushes_log_before_writing_pages() {
+        init();
+        let lm = Arc::new(LockManager::new(500));
+        let dir = gen_random_test_dir();
+        let bp = BufferPool::with_frames(lm, &dir, ReplacementPolicyType::Lru, 3);
+        let c1 = bp
+            .register_container(None, StateType::BaseTable)
+            .expect("Got CID");
+        let (p, mut g) = bp.new_page(c1).expect("Got page");
+        g.write(0, false, &[1; KEY_SIZE], &[2; VALUE_SIZE]).unwrap();
+        g.delete(0).unwrap();
+        let lsn = g.lsn.unwrap();
+        drop(g);
+        assert_eq!(bp.log().last_lsn(), Some(lsn));
+        // Only registering the container has been flushed
+        assert!(bp.log().flushed_lsn() < Some(lsn));
+
+        // Evicting the page writes it back, so its changes must be logged first
+        for _ in 0..3 {
+            bp.new_page(c1).expect("Got page");
+        }
+        assert!(bp.log().flushed_lsn() >= Some(lsn));
+        let page = ContainerFile::open(&dir, c1).unwrap().read_page(p).unwrap();
+        assert_eq!(page.lsn, Some(lsn));
+        assert_eq!(page.get_filled_slot_count(), 0);
+        let flushed = bp.log().read_flushed().unwrap();
+        assert!(flushed.iter().any(|(flushed_lsn, _)| *flushed_lsn == lsn));
+
+        // As does flushing every page
+        let v_id = ValueId::new_page(c1, 3);
+        bp.get_page_for_write(&v_id)
+            .unwrap()
+            .write(1, false, &[3; KEY_SIZE], &[4; VALUE_SIZE])
+            .unwrap();
+        bp.flush_all().unwrap();
+        let page = ContainerFile::open(&dir, c1).unwrap().read_page(3).unwrap();
+        assert!(page.lsn > Some(lsn));
+        assert!(bp.log().flushed_lsn() >= page.lsn);
+    }
+
+    #[test]
+    fn test_bp_reopen() {
+        init();
+        let dir = gen_random_test_dir();
+        let lm = Arc::new(LockManager::new(500));
+        let bp = BufferPool::new(lm.clone(), &dir, ReplacementPolicyType::default());
+        let c1 = bp
+            .register_container(Some("t".to_string()), StateType::BaseTable)
+            .expect("Got CID");
+        let c2 = bp
+            .register_container(None, StateType::Tree)
+            .expect("Got CID");
+        bp.drop_container(c1).unwrap();
+        for i in 0..3u8 {
+            let (_p, mut g) = bp.new_page(c2).expect("Got page");
+            g.write(0, false, &[i; SEARCH_KEY_SIZE], &[i; INDEX_POINTER_SIZE])
+                .unwrap();
+        }
+        bp.flush_all().unwrap();
+        drop(bp);
+
+        let bp = BufferPool::open(lm, &dir, ReplacementPolicyType::default()).unwrap();
+        assert!(bp.get_container_info(c1).is_none());
+        let (name, _state_type, pages) = bp.get_container_info(c2).unwrap();
+        assert_eq!((name, pages), (None, 3));
+        let g = bp.get_page_for_read(&ValueId::new_page(c2, 2)).unwrap();
+        assert_eq!(g.get_kv(0).unwrap().0, vec![2; SEARCH_KEY_SIZE]);
+        drop(g);
+        // Page ids continue where they left off
+        assert_eq!(bp.new_page(c2).unwrap().0, 3);
+        assert_eq!(
+            bp.register_container(None, StateType::BaseTable).unwrap(),
+            c1
+        );
+    }
+
+    #[test]
+    fn test_bp_new_container_discards_stale_file() {
+        init();
+        let dir = gen_random_test_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        // Left behind by an earlier container with the same id
+        std::fs::write(
+            ContainerFile::path_for(&dir, 0),
+            vec![1; 2 * SERIALIZED_PAGE_SIZE],
+        )
+        .unwrap();
+        let lm = Arc::new(LockManager::new(500));
+        let bp = BufferPool::new(lm, &dir, ReplacementPolicyType::default());
+        let c1 = bp
+            .register_container(None, StateType::BaseTable)
+            .expect("Got CID");
+        assert_eq!(c1, 0);
+        assert_eq!(
+            ContainerFile::open(&dir, c1).unwrap().page_count().unwrap(),
+            0
+        );
+        assert_eq!(bp.new_page(c1).unwrap().0, 0);
+    }
+
+    #[test]
+    fn test_bp_detects_corrupt_pages() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        init();
+        let dir = gen_random_test_dir();
+        let lm = Arc::new(LockManager::new(500));
+        let bp = BufferPool::new(lm.clone(), &dir, ReplacementPolicyType::default());
+        let c_id = bp.register_container(None, StateType::BaseTable).unwrap();
+        for i in 0..3u8 {
+            let (_p, mut g) = bp.new_page(c_id).unwrap();
+            g.write(0, false, &[i; KEY_SIZE], &[i; VALUE_SIZE]).unwrap();
+        }
+        bp.flush_all().unwrap();
+        drop(bp);
+
+        // Tear page 1 by overwriting part of its data array, and write page
+        // 0's bytes where page 2 belongs
+        let path = ContainerFile::path_for(&dir, c_id);
+        let bytes = std::fs::read(&path).unwrap();
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start((SERIALIZED_PAGE_SIZE + 500) as u64))
+            .unwrap();
+        file.write_all(&[7; 100]).unwrap();
+        file.seek(SeekFrom::Start(2 * SERIALIZED_PAGE_SIZE as u64))
+            .unwrap();
+        file.write_all(&bytes[..SERIALIZED_PAGE_SIZE]).unwrap();
+        drop(file);
+
+        // A single frame, so a failed read has to give it back
+        let bp =
+            BufferPool::open_with_frames(lm, &dir, ReplacementPolicyType::default(), 1).unwrap();
+        let g = bp.get_page_for_read(&ValueId::new_page(c_id, 0)).unwrap();
+        assert_eq!(g.get_kv(0).unwrap().0, vec![0; KEY_SIZE]);
+        drop(g);
+        for p_id in [1, 2] {
+            assert!(matches!(
+                bp.get_page_for_read(&ValueId::new_page(c_id, p_id)),
+                Err(CrustyError::CorruptPage(_))
+            ));
+        }
+        // A failed read leaves no page behind in the pool
+        assert!(bp.get_page_for_write(&ValueId::new_page(c_id, 1)).is_err());
+        let g = bp.get_page_for_read(&ValueId::new_page(c_id, 0)).unwrap();
+        assert_eq!(g.get_kv(0).unwrap().0, vec![0; KEY_SIZE]);
+    }
+
+    #[test]
+    fn test_bp_file_has_no_holes() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        init();
+        let dir = gen_random_test_dir();
+        let file = ContainerFile::create(&dir, 1).unwrap();
+        file.write_page(&FixedPage::new(3, KEY_SIZE, VALUE_SIZE))
+            .unwrap();
+        assert_eq!(file.page_count().unwrap(), 4);
+        // The pages skipped over are written empty
+        for p_id in 0..3 {
+            let page = file.read_page(p_id).unwrap();
+            assert_eq!(page.p_id, p_id);
+            assert_eq!(page.lsn, None);
+        }
+
+        // So a page of zeros is corrupt
+        let path = ContainerFile::path_for(&dir, 1);
+        let mut raw = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        raw.seek(SeekFrom::Start(SERIALIZED_PAGE_SIZE as u64))
+            .unwrap();
+        raw.write_all(&[0; SERIALIZED_PAGE_SIZE]).unwrap();
+        drop(raw);
+        assert!(matches!(
+            file.read_page(1),
+            Err(CrustyError::CorruptPage(_))
+        ));
+    }
+
+    #[test]
+    fn test_bp_hits_reach_policy() {
+        init();
+        let lm = Arc::new(LockManager::new(500));
+        let dir = gen_random_test_dir();
+        let bp = BufferPool::with_frames(lm, &dir, ReplacementPolicyType::Lru, 3);
+        let c1 = bp
+            .register_container(None, StateType::BaseTable)
+            .expect("Got CID");
+        for _ in 0..3 {
+            bp.new_page(c1).expect("Got page");
+        }
+        // Hitting page 0 makes page 1 the least recently used
+        drop(bp.get_page_for_read(&ValueId::new_page(c1, 0)).unwrap());
+        bp.new_page(c1).expect("Got page");
+        bp.reset_stats();
+        drop(bp.get_page_for_read(&ValueId::new_page(c1, 0)).unwrap());
+        drop(bp.get_page_for_read(&ValueId::new_page(c1, 2)).unwrap());
+        assert_eq!(bp.stats().hits, 2);
+        drop(bp.get_page_for_read(&ValueId::new_page(c1, 1)).unwrap());
+        assert_eq!(bp.stats().misses, 1);
+    }
+
+    #[test]
+    fn test_bp_shared_and_exclusive_latches() {
+        init();
+        let lm = Arc::new(LockManager::new(500));
+        let dir = gen_random_test_dir();
+        let bp = BufferPool::new(lm, &dir, ReplacementPolicyType::default());
+        let c1 = bp
+            .register_container(None, StateType::BaseTable)
+            .expect("Got CID");
+        let (p, mut g) = bp.new_page(c1).expect("Got page");
+        g.write(0, false, &[1; KEY_SIZE], &[2; VALUE_SIZE]).unwrap();
+        drop(g);
+        let v_id = ValueId::new_page(c1, p);
+        // Many readers can hold the same page at once
+        let r1 = bp.get_page_for_read(&v_id).unwrap();
+        let mut r2 = bp.get_page(&v_id, Permissions::ReadOnly).unwrap();
+        assert_eq!(r1.frame_id(), 0);
+        assert_eq!(r1.get_kv(0), r2.get_kv(0));
+        assert_eq!(r1.buffer_frame.pin_count.load(Relaxed), 2);
+        assert!(r2.page_mut().is_err());
+        drop(r1);
+        drop(r2);
+        let mut w = bp.get_page(&v_id, Permissions::ReadWrite).unwrap();
+        w.page_mut()
+            .unwrap()
+            .write(0, true, &[3; KEY_SIZE], &[4; VALUE_SIZE])
+            .unwrap();
+        // A writer blocks other readers until its guard is dropped
+        std::thread::scope(|s| {
+            let reader = s.spawn(|| bp.get_page_for_read(&v_id).unwrap().get_kv(0).unwrap());
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!reader.is_finished());
+            drop(w);
+            assert_eq!(
+                reader.join().unwrap(),
+                (vec![3; KEY_SIZE], vec![4; VALUE_SIZE])
+            );
+        });
+    }
+
+    #[test]
+    fn test_bp_concurrent_access() {
+        init();
+        let lm = Arc::new(LockManager::new(500));
+        let dir = gen_random_test_dir();
+        let bp = BufferPool::with_frames(lm, &dir, ReplacementPolicyType::Lru, 8);
+        let c1 = bp
+            .register_container(None, StateType::BaseTable)
+            .expect("Got CID");
+        // Each thread creates its own pages and then increments a counter on every page,
+        // which forces evictions while other threads hold latches
+        let threads = 4;
+        let pages_per_thread = 6u32;
+        let rounds = 20u8;
+        std::thread::scope(|s| {
+            for _ in 0..threads {
+                s.spawn(|| {
+                    let mut mine = Vec::new();
+                    for _ in 0..pages_per_thread {
+                        let (p, mut g) = bp.new_page(c1).expect("Got page");
+                        g.write(0, false, &[0; KEY_SIZE], &[0; VALUE_SIZE]).unwrap();
+                        mine.push(p);
+                    }
+                    for round in 0..rounds {
+                        for p in mine.iter() {
+                            let v_id = ValueId::new_page(c1, *p);
+                            let mut g = bp.get_page_for_write(&v_id).unwrap();
+                            let (k, v) = g.get_kv(0).unwrap();
+                            assert_eq!(v[0], round);
+                            g.write(0, true, &k, &[round + 1; VALUE_SIZE]).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        let total = threads * pages_per_thread;
+        for p in 0..total {
+            let v_id = ValueId::new_page(c1, p);
+            let g = bp.get_page_for_read(&v_id).unwrap();
+            assert_eq!(g.get_kv(0).unwrap().1, vec![rounds; VALUE_SIZE]);
+            assert_eq!(g.buffer_frame.pin_count.load(Relaxed), 1);
+        }
+        assert!(bp.get_page_for_read(&ValueId::new_page(c1, total)).is_err());
+    }
 }
@@ -0,0 +1,118 @@
+use crate::fault;
+use crate::fixed_page::FixedPage;
+use crate::prelude::*;
+use common::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The file on disk that backs a single container. Pages are stored back to
+/// back, so page `p` lives at offset `p * SERIALIZED_PAGE_SIZE`.
+pub struct ContainerFile {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl ContainerFile {
+    /// The path of the file for a container inside of the storage directory
+    pub fn path_for(storage_dir: &Path, c_id: ContainerId) -> PathBuf {
+        storage_dir.join(format!("{}.ctr", c_id))
+    }
+
+    /// Create the file for a new container, discarding anything left in a
+    /// file of an earlier container with the same id.
+    pub fn create(storage_dir: &Path, c_id: ContainerId) -> Result<Self, CrustyError> {
+        Self::open_with(storage_dir, c_id, true)
+    }
+
+    /// Reopen the file for an existing container. A missing file is created
+    /// empty, as a crash while dropping the container may have removed it.
+    pub fn open(storage_dir: &Path, c_id: ContainerId) -> Result<Self, CrustyError> {
+        Self::open_with(storage_dir, c_id, false)
+    }
+
+    fn open_with(
+        storage_dir: &Path,
+        c_id: ContainerId,
+        truncate: bool,
+    ) -> Result<Self, CrustyError> {
+        std::fs::create_dir_all(storage_dir)?;
+        let path = Self::path_for(storage_dir, c_id);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(truncate)
+            .open(&path)?;
+        Ok(ContainerFile {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Read a page from the file. Errors if the page is past the end of the
+    /// file, and returns `CrustyError::CorruptPage` if it fails its checksum
+    /// or holds another page. Every page before the end of the file was
+    /// written, so a page of zeros is corrupt too.
+    pub fn read_page(&self, p_id: PageId) -> Result<FixedPage, CrustyError> {
+        let mut buf = vec![0; SERIALIZED_PAGE_SIZE];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(p_id as u64 * SERIALIZED_PAGE_SIZE as u64))?;
+        file.read_exact(&mut buf)?;
+        drop(file);
+        let corrupt = |reason: String| {
+            CrustyError::CorruptPage(format!("page {} of {:?}: {}", p_id, self.path, reason))
+        };
+        if buf.iter().all(|b| *b == 0) {
+            return Err(corrupt("page is all zeros".to_string()));
+        }
+        let page = FixedPage::from_bytes(&buf).map_err(|e| match e {
+            CrustyError::CorruptPage(reason) => corrupt(reason),
+            e => e,
+        })?;
+        if page.p_id != p_id {
+            return Err(corrupt(format!("holds page {}", page.p_id)));
+        }
+        Ok(page)
+    }
+
+    /// Write a page to its offset in the file, growing the file if needed.
+    /// Pages skipped over when the file grows are written empty first, so the
+    /// file never has a hole of zeros.
+    pub fn write_page(&self, page: &FixedPage) -> Result<(), CrustyError> {
+        let mut file = self.file.lock().unwrap();
+        let end = (file.metadata()?.len() / SERIALIZED_PAGE_SIZE as u64) as PageId;
+        for p_id in end..page.p_id {
+            let mut hole = FixedPage::empty();
+            hole.p_id = p_id;
+            Self::write_at(&mut file, &hole)?;
+        }
+        Self::write_at(&mut file, page)
+    }
+
+    fn write_at(file: &mut File, page: &FixedPage) -> Result<(), CrustyError> {
+        file.seek(SeekFrom::Start(
+            page.p_id as u64 * SERIALIZED_PAGE_SIZE as u64,
+        ))?;
+        fault::write_all(file, &page.to_bytes())
+    }
+
+    /// The number of pages written to the file
+    pub fn page_count(&self) -> Result<PageId, CrustyError> {
+        let len = self.file.lock().unwrap().metadata()?.len();
+        Ok((len / SERIALIZED_PAGE_SIZE as u64) as PageId)
+    }
+
+    /// Force any written pages to stable storage.
+    pub fn sync(&self) -> Result<(), CrustyError> {
+        self.file.lock().unwrap().sync_all()?;
+        Ok(())
+    }
+
+    /// Delete the file from disk. It is closed once the last reference to it is dropped.
+    pub fn remove(&self) -> Result<(), CrustyError> {
+        std::fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+//! Every page, log and manifest write to disk goes through `write_all`, so
+//! tests can simulate a crash part way through a workload. Outside of tests
+//! it is a plain write.
+use common::prelude::*;
+use std::io::Write;
+
+#[cfg(test)]
+use std::cell::Cell;
+
+#[cfg(test)]
+thread_local! {
+    /// How many more writes this thread may make before it crashes
+    static WRITES_LEFT: Cell<Option<usize>> = const { Cell::new(None) };
+    /// Whether the write the crash happens in is torn
+    static TORN: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Simulate a crash after `writes` more writes on this thread. Every write
+/// after that fails, so the files are left as they were at the crash. `None`
+/// stops simulating a crash.
+#[cfg(test)]
+pub(crate) fn crash_after_writes(writes: Option<usize>) {
+    WRITES_LEFT.with(|left| left.set(writes));
+    TORN.with(|torn| torn.set(false));
+}
+
+/// Like `crash_after_writes`, but the crash happens in the middle of the
+/// next write after `writes`: only the first half of it reaches the file.
+#[cfg(test)]
+pub(crate) fn tear_write_after(writes: usize) {
+    WRITES_LEFT.with(|left| left.set(Some(writes)));
+    TORN.with(|torn| torn.set(true));
+}
+
+/// Write all of `buf`, unless a simulated crash happened first
+#[cfg(test)]
+pub(crate) fn write_all(file: &mut impl Write, buf: &[u8]) -> Result<(), CrustyError> {
+    let crash = || CrustyError::IOError("Simulated crash".to_string());
+    match WRITES_LEFT.with(|left| left.get()) {
+        Some(0) => {
+            if TORN.with(|torn| torn.replace(false)) {
+                file.write_all(&buf[..buf.len() / 2])?;
+                file.flush()?;
+            }
+            Err(crash())
+        }
+        Some(n) => {
+            WRITES_LEFT.with(|left| left.set(Some(n - 1)));
+            file.write_all(buf)?;
+            Ok(())
+        }
+        None => {
+            file.write_all(buf)?;
+            Ok(())
+        }
+    }
+}
+
+/// Write all of `buf`
+#[cfg(not(test))]
+#[inline]
+pub(crate) fn write_all(file: &mut impl Write, buf: &[u8]) -> Result<(), CrustyError> {
+    file.write_all(buf)?;
+    Ok(())
+}
@@ -1,13 +1,24 @@
 use crate::prelude::*;
+use crate::wal::log_manager::PageLog;
+use crate::wal::log_record::{LogRecord, PageOp, TxnOp};
+use common::ids::Lsn;
 use common::prelude::*;
 use std::cmp::min;
 
+/// The most bytes logged in one record for a direct write to the data array,
+/// small enough that the record fits on a log page
+const MAX_LOGGED_BYTES: usize = PAGE_SIZE / 2;
+
+/// The page pointer, overflow pointer, leaf flag and extra value
+type PageHeader = (PagePointer, PagePointer, bool, usize);
+
 /// The fixed page struct for representing data and index pages for fixed size
 /// records. Each record is assumed to have two parts a key and a value.
 /// For the data page, the key is assumed (but not checked) to be unique and
 /// the value is the data associated with the key. For the index page, the key
 /// is the search key and the value is the pointer to a data or index page.
-/// For simplicity we store the page metadata outside of the data array.
+/// For simplicity we store the page metadata outside of the data array. On
+/// disk it is kept in a header ahead of the data array, see `to_bytes`.
 pub struct FixedPage {
     /// The PageId of this page
     pub p_id: PageId,
@@ -32,6 +43,13 @@ pub struct FixedPage {
     pub is_leaf: bool,
     /// If needed, a usize to use for any reason
     pub extra: usize,
+    /// The LSN of the last logged change to this page
+    pub lsn: Option<Lsn>,
+    /// Where changes are logged while the page is in the buffer pool. Pages
+    /// made outside of the buffer pool are not logged.
+    pub log: Option<PageLog>,
+    /// The header fields as of when they were last logged
+    logged_header: PageHeader,
 }
 
 impl FixedPage {
@@ -48,6 +66,9 @@ impl FixedPage {
             overflow_pointer: None,
             is_leaf: false,
             extra: 0,
+            lsn: None,
+            log: None,
+            logged_header: (None, None, false, 0),
         }
     }
     pub fn new(p_id: PageId, key_size: usize, value_size: usize) -> Self {
@@ -89,6 +110,11 @@ impl FixedPage {
             }
             self.data[os..os + self.value_size].copy_from_slice(value);
             self.free[slot] = false;
+            self.log_op(|| PageOp::Write {
+                slot: slot as SlotId,
+                key: key.to_vec(),
+                value: value.to_vec(),
+            })?;
             return Ok(());
         }
         Err(CrustyError::StorageError)
@@ -113,22 +139,110 @@ impl FixedPage {
             self.data[new_os..new_os + self.pair_size].copy_from_slice(&buf);
             self.free[from_slot] = true;
             self.free[to_slot] = false;
+            self.log_op(|| PageOp::MoveIfEmpty {
+                from: from_slot as SlotId,
+                to: to_slot as SlotId,
+            })?;
             return Ok(());
         }
         Err(CrustyError::StorageError)
     }
 
-    pub fn delete(&mut self, slot: SlotId) {
+    pub fn delete(&mut self, slot: SlotId) -> Result<(), CrustyError> {
         if slot > self.slot_capacity {
-            return;
+            return Ok(());
         }
         self.free[slot as usize] = true;
+        self.log_op(|| PageOp::Delete { slot })
     }
 
-    pub fn delete_all(&mut self) {
+    pub fn delete_all(&mut self) -> Result<(), CrustyError> {
         for i in 0..self.slot_capacity {
             self.free[i as usize] = true;
         }
+        self.log_op(|| PageOp::DeleteAll)
+    }
+
+    /// Write bytes straight into the data array. Only the bytes that
+    /// changed are logged.
+    pub fn write_bytes(&mut self, offset: usize, bytes: &[u8]) -> Result<(), CrustyError> {
+        let old = &self.data[offset..offset + bytes.len()];
+        let changed = |(a, b): (&u8, &u8)| a != b;
+        let Some(first) = old.iter().zip(bytes).position(changed) else {
+            return Ok(());
+        };
+        let last = bytes.len() - old.iter().zip(bytes).rev().position(changed).unwrap();
+        self.data[offset + first..offset + last].copy_from_slice(&bytes[first..last]);
+        for start in (first..last).step_by(MAX_LOGGED_BYTES) {
+            let end = min(start + MAX_LOGGED_BYTES, last);
+            self.log_op(|| PageOp::WriteBytes {
+                offset: offset + start,
+                bytes: bytes[start..end].to_vec(),
+            })?;
+        }
+        Ok(())
+    }
+
+    fn header(&self) -> PageHeader {
+        (
+            self.page_pointer,
+            self.overflow_pointer,
+            self.is_leaf,
+            self.extra,
+        )
+    }
+
+    /// Treat the current header fields as logged
+    pub(crate) fn mark_header_logged(&mut self) {
+        self.logged_header = self.header();
+    }
+
+    /// Log the header fields if they were changed since they were last logged.
+    /// They are set directly, so this is called when a write latch is released.
+    pub(crate) fn log_header_changes(&mut self) -> Result<(), CrustyError> {
+        if self.header() == self.logged_header {
+            return Ok(());
+        }
+        self.mark_header_logged();
+        let (page_pointer, overflow_pointer, is_leaf, extra) = self.logged_header;
+        self.log_op(|| PageOp::SetHeader {
+            page_pointer,
+            overflow_pointer,
+            is_leaf,
+            extra,
+        })
+    }
+
+    /// Log that the page was just added to its container
+    pub(crate) fn log_format(&mut self) -> Result<(), CrustyError> {
+        self.mark_header_logged();
+        let (key_size, value_size) = (self.key_size, self.value_size);
+        self.log_op(|| PageOp::Format {
+            key_size,
+            value_size,
+        })
+    }
+
+    /// Log a change a transaction made to this page so it can be undone. The
+    /// page keeps the record's LSN, so the page is not written out before it.
+    pub fn log_txn_op(&mut self, txn: &TransactionId, op: TxnOp) -> Result<(), CrustyError> {
+        if let Some(page_log) = &self.log {
+            self.lsn = Some(page_log.log.log_txn(*txn, op)?);
+        }
+        Ok(())
+    }
+
+    /// Log a change that was just made, if the page is being logged
+    fn log_op(&mut self, op: impl FnOnce() -> PageOp) -> Result<(), CrustyError> {
+        if let Some(page_log) = &self.log {
+            let record = LogRecord::Page {
+                c_id: page_log.c_id,
+                p_id: self.p_id,
+                op: op(),
+            };
+            self.lsn = Some(page_log.log.append(&record)?);
+        }
+        Ok(())
     }
 
     pub fn get_kv(&self, slot: SlotId) -> Option<(Vec<u8>, Vec<u8>)> {
@@ -183,10 +297,13 @@ impl FixedPage {
                 self.data[data_shift_start + os..data_shift_end + os].copy_from_slice(&buf);
                 self.free[slot] = true;
                 self.free[i] = false;
+                self.log_op(|| PageOp::ShiftRight {
+                    slot: slot as SlotId,
+                })?;
                 return Ok(true);
             }
         }
-        return Ok(false);
+        Ok(false)
     }
 
     /// Shift all contiguous records to the left starting from the given slot if possible.    
@@ -219,6 +336,9 @@ impl FixedPage {
         self.data[data_shift_start - os..data_shift_end - os].copy_from_slice(&buf);
         self.free[slot] = false;
         self.free[end - 1] = true;
+        self.log_op(|| PageOp::ShiftLeft {
+            slot: slot as SlotId,
+        })?;
         Ok(moved)
     }
 
@@ -237,12 +357,125 @@ impl FixedPage {
     pub fn get_free_slot_count(&self) -> usize {
         self.slot_capacity as usize - self.get_filled_slot_count()
     }
+
+    /// Serialize the page into a buffer of SERIALIZED_PAGE_SIZE bytes for
+    /// writing to disk. The metadata header comes first, followed by the
+    /// data array. Integers are little endian and header offsets are:
+    ///
+    /// | Offset | Size | Field |
+    /// |--------|------|-------|
+    /// | 0      | 4    | CRC-32 checksum of every byte after it |
+    /// | 4      | 2    | format version, `PAGE_FORMAT_VERSION` |
+    /// | 6      | 7    | page LSN: a set flag, log page id and slot id |
+    /// | 13     | 4    | page id |
+    /// | 17     | 2    | key size |
+    /// | 19     | 2    | value size |
+    /// | 21     | 5    | page pointer: a set flag and page id |
+    /// | 26     | 5    | overflow pointer: a set flag and page id |
+    /// | 31     | 1    | leaf flag |
+    /// | 32     | 8    | extra value |
+    /// | 40     | 128  | free bitmap, one bit per slot |
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(SERIALIZED_PAGE_SIZE);
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&PAGE_FORMAT_VERSION.to_le_bytes());
+        buf.push(self.lsn.is_some() as u8);
+        let lsn = self.lsn.unwrap_or(Lsn {
+            page_id: 0,
+            slot_id: 0,
+        });
+        buf.extend_from_slice(&lsn.page_id.to_le_bytes());
+        buf.extend_from_slice(&lsn.slot_id.to_le_bytes());
+        buf.extend_from_slice(&self.p_id.to_le_bytes());
+        buf.extend_from_slice(&(self.key_size as u16).to_le_bytes());
+        buf.extend_from_slice(&(self.value_size as u16).to_le_bytes());
+        for pointer in [self.page_pointer, self.overflow_pointer] {
+            buf.push(pointer.is_some() as u8);
+            buf.extend_from_slice(&pointer.unwrap_or(0).to_le_bytes());
+        }
+        buf.push(self.is_leaf as u8);
+        buf.extend_from_slice(&(self.extra as u64).to_le_bytes());
+        let mut bitmap = [0u8; PAGE_SLOT_LIMIT / 8];
+        for (i, free) in self.free.iter().enumerate() {
+            if *free {
+                bitmap[i / 8] |= 1 << (i % 8);
+            }
+        }
+        buf.extend_from_slice(&bitmap);
+        buf.resize(PAGE_HEADER_SIZE, 0);
+        buf.extend_from_slice(&self.data);
+        let checksum = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+
+    /// Deserialize a page that was written with `to_bytes`. Returns
+    /// `CrustyError::CorruptPage` if the checksum does not match, such as
+    /// after a torn write, or the format version is unknown.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CrustyError> {
+        if bytes.len() != SERIALIZED_PAGE_SIZE {
+            return Err(CrustyError::SerializationError(format!(
+                "Serialized page must be {} bytes, got {}",
+                SERIALIZED_PAGE_SIZE,
+                bytes.len()
+            )));
+        }
+        let stored = u32::from_le_bytes(bytes[..4].try_into().unwrap());
+        let checksum = crc32fast::hash(&bytes[4..]);
+        if stored != checksum {
+            return Err(CrustyError::CorruptPage(format!(
+                "checksum is {:#010x} but the page hashes to {:#010x}",
+                stored, checksum
+            )));
+        }
+        let u16_at = |os: usize| u16::from_le_bytes(bytes[os..os + 2].try_into().unwrap());
+        let u32_at = |os: usize| u32::from_le_bytes(bytes[os..os + 4].try_into().unwrap());
+        let pointer_at = |os: usize| {
+            if bytes[os] == 1 {
+                Some(u32_at(os + 1))
+            } else {
+                None
+            }
+        };
+        let version = u16_at(4);
+        if version != PAGE_FORMAT_VERSION {
+            return Err(CrustyError::CorruptPage(format!(
+                "unknown page format version {}",
+                version
+            )));
+        }
+        let mut page = FixedPage::empty();
+        if bytes[6] == 1 {
+            page.lsn = Some(Lsn {
+                page_id: u32_at(7),
+                slot_id: u16_at(11),
+            });
+        }
+        page.p_id = u32_at(13);
+        page.key_size = u16_at(17) as usize;
+        page.value_size = u16_at(19) as usize;
+        page.page_pointer = pointer_at(21);
+        page.overflow_pointer = pointer_at(26);
+        page.is_leaf = bytes[31] == 1;
+        page.extra = u64::from_le_bytes(bytes[32..40].try_into().unwrap()) as usize;
+        page.pair_size = page.key_size + page.value_size;
+        if let Some(capacity) = PAGE_SIZE.checked_div(page.pair_size) {
+            page.slot_capacity = min(PAGE_SLOT_LIMIT, capacity) as SlotId;
+        }
+        let bitmap = &bytes[40..40 + PAGE_SLOT_LIMIT / 8];
+        for i in 0..PAGE_SLOT_LIMIT {
+            page.free[i] = bitmap[i / 8] & (1 << (i % 8)) != 0;
+        }
+        page.data.copy_from_slice(&bytes[PAGE_HEADER_SIZE..]);
+        page.mark_header_logged();
+        Ok(page)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::prelude::{KEY_SIZE, VALUE_SIZE};
+    use crate::prelude::{KEY_SIZE, SERIALIZED_PAGE_SIZE, VALUE_SIZE};
 
     #[test]
     fn test_shift_left() {
@@ -275,14 +508,14 @@ mod test {
         assert!(p.shift_all_left(2).is_err());
         assert_eq!(p.shift_all_left(3).unwrap(), 0);
 
-        p.delete(0);
+        p.delete(0).unwrap();
         assert_eq!(p.shift_all_left(0).unwrap(), 2);
         assert_eq!(p.get_kv(1).unwrap().0, k[2].to_vec());
         assert_eq!(p.get_kv(0).unwrap().0, k[1].to_vec());
         assert!(p.get_kv(2).is_none());
 
         // Reset the page
-        p.delete_all();
+        p.delete_all().unwrap();
         for i in 0..p.slot_capacity {
             assert!(p.write(i, false, &k[i as usize], &k[i as usize]).is_ok());
         }
@@ -292,14 +525,14 @@ mod test {
 
         // remove last slot
         let last = p.slot_capacity - 1;
-        p.delete(last);
+        p.delete(last).unwrap();
         assert_eq!(p.shift_all_left(last).unwrap(), 0);
         assert_eq!(p.get_filled_slot_count(), 7);
         assert!(p.get_kv(last).is_none());
         p.write(last, false, &k[last as usize], &k[last as usize])
             .unwrap();
         // remove first slot
-        p.delete(0);
+        p.delete(0).unwrap();
         assert_eq!(p.shift_all_left(0).unwrap(), 7);
         let mut k = k.to_vec();
         k.remove(0);
@@ -310,7 +543,7 @@ mod test {
 
         // remove middle slot
         assert_eq!(p.get_filled_slot_count(), 7);
-        p.delete(3);
+        p.delete(3).unwrap();
         assert_eq!(p.shift_all_left(3).unwrap(), 3);
         k.remove(3);
         for i in 0..6 {
@@ -420,7 +653,7 @@ mod test {
         let (k, v) = p.get_kv(7).unwrap();
         assert_eq!(k, k6);
         assert_eq!(v, k6);
-        p.delete(7);
+        p.delete(7).unwrap();
 
         assert!(p.shift_all_right(3).unwrap());
         let (k, v) = p.get_kv(7).unwrap();
@@ -466,11 +699,57 @@ mod test {
         assert_eq!(all[2].0, 3);
         assert_eq!(all[2].1, k3);
         assert_eq!(all[2].2, v3);
-        p.delete(2);
-        p.delete(3);
+        p.delete(2).unwrap();
+        p.delete(3).unwrap();
         all = p.get_kv_pairs();
         assert_eq!(all[0].0, 1);
         assert_eq!(all[0].1, k2);
         assert_eq!(all[0].2, v2);
     }
+
+    #[test]
+    fn test_page_serialization() {
+        let mut p = FixedPage::new(9, KEY_SIZE, VALUE_SIZE);
+        p.write(0, false, &[1; KEY_SIZE], &[2; VALUE_SIZE]).unwrap();
+        p.write(5, false, &[3; KEY_SIZE], &[4; VALUE_SIZE]).unwrap();
+        p.page_pointer = Some(12);
+        p.is_leaf = true;
+        p.extra = 42;
+        p.lsn = Some(Lsn {
+            page_id: 70000,
+            slot_id: 300,
+        });
+        let bytes = p.to_bytes();
+        assert_eq!(bytes.len(), SERIALIZED_PAGE_SIZE);
+        let p2 = FixedPage::from_bytes(&bytes).unwrap();
+        assert_eq!(p2.p_id, 9);
+        assert_eq!(p2.slot_capacity, p.slot_capacity);
+        assert_eq!(p2.page_pointer, Some(12));
+        assert_eq!(p2.overflow_pointer, None);
+        assert!(p2.is_leaf);
+        assert_eq!(p2.extra, 42);
+        assert_eq!(p2.lsn, p.lsn);
+        assert_eq!(p2.get_kv_pairs(), p.get_kv_pairs());
+        assert_eq!(p2.get_free_slot_count(), p.get_free_slot_count());
+        assert!(FixedPage::from_bytes(&bytes[1..]).is_err());
+
+        // A changed byte anywhere fails the checksum
+        for os in [0, 5, 14, PAGE_HEADER_SIZE + 10, SERIALIZED_PAGE_SIZE - 1] {
+            let mut torn = bytes.clone();
+            torn[os] ^= 0x10;
+            assert!(matches!(
+                FixedPage::from_bytes(&torn),
+                Err(CrustyError::CorruptPage(_))
+            ));
+        }
+        // A page from an unknown format version is rejected too
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(PAGE_FORMAT_VERSION + 1).to_le_bytes());
+        let checksum = crc32fast::hash(&newer[4..]);
+        newer[..4].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(
+            FixedPage::from_bytes(&newer),
+            Err(CrustyError::CorruptPage(_))
+        ));
+    }
 }
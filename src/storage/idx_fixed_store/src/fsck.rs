@@ -0,0 +1,670 @@
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use common::prelude::*;
+use txn_manager::lockmanager::LockManager;
+
+use crate::buffer_frame::FrameReadGuard;
+use crate::buffer_pool::{slot_sizes, BufferPool, BufferPoolTrait};
+use crate::fixed_page::FixedPage;
+use crate::index::fixed_hash::place_key;
+use crate::index::fixed_index_file::FixedIndexFile;
+use crate::index::fixed_index_header::HEADER_PAGE_ID;
+use crate::index::fixed_index_page::{IndexEntry, IndexPage};
+use crate::index::key_extractor::KeyExtractor;
+use crate::prelude::*;
+
+type SearchKey = [u8; SEARCH_KEY_SIZE];
+
+/// An index to match against the records of its table: the index container,
+/// the table container and where the table's values hold the search key
+pub type CheckedIndex = (ContainerId, ContainerId, KeyExtractor);
+
+/// A broken invariant found by `check`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// A page that could not be read back from its container file
+    CorruptPage {
+        c_id: ContainerId,
+        p_id: PageId,
+        reason: String,
+    },
+    /// A page whose slot capacity does not match its container's record
+    /// sizes, or that marks a slot at or past its capacity as free
+    FreeBitmap { c_id: ContainerId, p_id: PageId },
+    /// An index header that cannot be read or does not describe a usable index
+    BadIndexHeader { c_id: ContainerId, reason: String },
+    /// A pointer on page `p_id` to a page outside the container or to a page
+    /// that is already in use elsewhere. `target` is None when a pointer the
+    /// page needs is missing.
+    BadPagePointer {
+        c_id: ContainerId,
+        p_id: PageId,
+        target: Option<PageId>,
+    },
+    /// Keys on a tree page that are out of order or outside the separators of
+    /// its parent, or an ordered hash bucket that is out of order
+    UnorderedKeys { c_id: ContainerId, p_id: PageId },
+    /// A tree leaf at a different depth than the leftmost leaf
+    UnevenLeaf {
+        c_id: ContainerId,
+        p_id: PageId,
+        depth: usize,
+        expected: usize,
+    },
+    /// The sibling pointer of a leaf leads back to a leaf already on the chain
+    SiblingCycle { c_id: ContainerId, p_id: PageId },
+    /// The sibling pointer of a leaf does not lead to the next leaf in key order
+    BrokenSiblingChain { c_id: ContainerId, p_id: PageId },
+    /// A hash bucket whose local depth is above the global depth
+    BadLocalDepth {
+        c_id: ContainerId,
+        p_id: PageId,
+        local_depth: usize,
+    },
+    /// A hash entry in a bucket its key does not map to
+    MisplacedEntry {
+        c_id: ContainerId,
+        p_id: PageId,
+        slot: SlotId,
+    },
+    /// An index entry that does not point to a live heap record with its search key
+    DanglingEntry {
+        i_id: ContainerId,
+        key: SearchKey,
+        pointer: ValueId,
+    },
+    /// A heap record without an entry in an index on its table
+    MissingEntry { i_id: ContainerId, v_id: ValueId },
+}
+
+/// What `check` found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// How many containers were checked
+    pub containers: usize,
+    /// How many pages were read
+    pub pages: usize,
+    /// Every broken invariant, in the order they were found
+    pub violations: Vec<Violation>,
+}
+
+impl FsckReport {
+    /// Whether every invariant holds
+    pub fn is_clean(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Check every container registered in the buffer pool.
+///
+/// Every page is read and its `free` bitmap checked against its slot
+/// capacity, except on variable length record pages which keep their own
+/// slot directory. Index containers are reopened from their header. Trees are
+/// checked for ordered keys, acyclic sibling chains and leaves at one depth,
+/// and hash tables for entries in the bucket their key maps to. Then the
+/// entries of each index in `indexes` are matched one to one with the records
+/// of its table.
+///
+/// Pages are latched one at a time, so nothing else should change the
+/// containers while it runs. Corrupt pages are reported as violations, and
+/// other errors, such as running out of frames, are returned.
+pub fn check(
+    bp: &Arc<BufferPool>,
+    lm: &Arc<LockManager>,
+    indexes: &[CheckedIndex],
+) -> Result<FsckReport, CrustyError> {
+    let mut fsck = Fsck {
+        bp,
+        report: FsckReport::default(),
+        corrupt: HashSet::new(),
+    };
+    let tables: HashSet<ContainerId> = indexes.iter().map(|(_, t_id, _)| *t_id).collect();
+    let mut records = HashMap::new();
+    let mut entries = HashMap::new();
+    for c_id in bp.container_ids() {
+        let Some((_name, state_type, max_page)) = bp.get_container_info(c_id) else {
+            continue;
+        };
+        fsck.report.containers += 1;
+        let is_table = matches!(state_type, StateType::BaseTable | StateType::MatView);
+        let mut live = Vec::new();
+        for p_id in 0..max_page {
+            fsck.report.pages += 1;
+            let Some(page) = fsck.read(c_id, p_id)? else {
+                continue;
+            };
+            if matches!(state_type, StateType::VarTable) {
+                continue;
+            }
+            fsck.check_bitmap(c_id, &state_type, &page);
+            if is_table && tables.contains(&c_id) {
+                live.extend(
+                    page.get_kv_pairs()
+                        .into_iter()
+                        .map(|(slot, _key, value)| (ValueId::new_slot(c_id, p_id, slot), value)),
+                );
+            }
+        }
+        match state_type {
+            StateType::Tree | StateType::HashTable => {
+                if let Some(found) = fsck.check_index(c_id, max_page, lm)? {
+                    entries.insert(c_id, found);
+                }
+            }
+            StateType::BaseTable | StateType::MatView => {
+                records.insert(c_id, live);
+            }
+            StateType::VarTable => {}
+        }
+    }
+    for (i_id, t_id, extractor) in indexes {
+        // An index whose header is broken was already reported
+        if let Some(found) = entries.get(i_id) {
+            let live = records.get(t_id).map(Vec::as_slice).unwrap_or_default();
+            fsck.match_records(*i_id, extractor, found, live);
+        }
+    }
+    Ok(fsck.report)
+}
+
+/// The pages seen by a tree walk
+#[derive(Default)]
+struct TreeWalk {
+    /// Every page reached from the root
+    visited: HashSet<PageId>,
+    /// The leaves in key order
+    leaves: Vec<PageId>,
+    /// The depth of the leftmost leaf
+    leaf_depth: Option<usize>,
+    /// The leaf entries in key order
+    entries: Vec<IndexEntry>,
+}
+
+struct Fsck<'a> {
+    bp: &'a Arc<BufferPool>,
+    report: FsckReport,
+    /// Pages already reported as corrupt
+    corrupt: HashSet<(ContainerId, PageId)>,
+}
+
+impl<'a> Fsck<'a> {
+    fn violation(&mut self, violation: Violation) {
+        debug!("fsck found {:?}", violation);
+        self.report.violations.push(violation);
+    }
+
+    /// Latch a page shared. Returns None if it is corrupt, reporting it the
+    /// first time.
+    fn read(
+        &mut self,
+        c_id: ContainerId,
+        p_id: PageId,
+    ) -> Result<Option<FrameReadGuard<'a>>, CrustyError> {
+        let bp: &'a BufferPool = self.bp;
+        match bp.get_page_for_read(&ValueId::new_page(c_id, p_id)) {
+            Ok(page) => Ok(Some(page)),
+            Err(CrustyError::CorruptPage(reason)) => {
+                if self.corrupt.insert((c_id, p_id)) {
+                    self.violation(Violation::CorruptPage { c_id, p_id, reason });
+                }
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Check a pointer on page `p_id` stays inside the container
+    fn target(
+        &mut self,
+        c_id: ContainerId,
+        max_page: PageId,
+        p_id: PageId,
+        target: Option<PageId>,
+    ) -> Option<PageId> {
+        match target {
+            Some(t) if t < max_page => Some(t),
+            _ => {
+                self.violation(Violation::BadPagePointer { c_id, p_id, target });
+                None
+            }
+        }
+    }
+
+    /// Check the slot capacity matches the container and every slot past it
+    /// is not free
+    fn check_bitmap(&mut self, c_id: ContainerId, state_type: &StateType, page: &FixedPage) {
+        let (key_size, value_size) = slot_sizes(state_type);
+        let capacity = min(PAGE_SLOT_LIMIT, PAGE_SIZE / (key_size + value_size));
+        if page.slot_capacity as usize != capacity || page.free[capacity..].iter().any(|f| *f) {
+            self.violation(Violation::FreeBitmap {
+                c_id,
+                p_id: page.p_id,
+            });
+        }
+    }
+
+    /// Check the structure of an index. Returns its entries, or None if its
+    /// header cannot be read.
+    fn check_index(
+        &mut self,
+        c_id: ContainerId,
+        max_page: PageId,
+        lm: &Arc<LockManager>,
+    ) -> Result<Option<Vec<IndexEntry>>, CrustyError> {
+        let idx = match FixedIndexFile::open(c_id, self.bp.clone(), lm.clone()) {
+            Ok(idx) => idx,
+            Err(e) => {
+                let reason = e.to_string();
+                self.violation(Violation::BadIndexHeader { c_id, reason });
+                return Ok(None);
+            }
+        };
+        let found = if idx.supports_range() {
+            self.check_tree(&idx, max_page)?
+        } else {
+            self.check_hash(&idx, max_page)?
+        };
+        Ok(Some(found))
+    }
+
+    fn check_tree(
+        &mut self,
+        idx: &FixedIndexFile<BufferPool>,
+        max_page: PageId,
+    ) -> Result<Vec<IndexEntry>, CrustyError> {
+        let c_id = idx.c_id;
+        let mut walk = TreeWalk::default();
+        if let Some(root) = self.target(c_id, max_page, HEADER_PAGE_ID, Some(idx.state().root)) {
+            walk.visited.insert(root);
+            self.check_subtree(c_id, max_page, root, (None, None), 0, &mut walk)?;
+        }
+        self.check_siblings(c_id, max_page, &walk.leaves)?;
+        Ok(walk.entries)
+    }
+
+    /// Check the subtree at `p_id` holds ordered keys within `bounds`, adding
+    /// its leaves and their entries to `walk` in key order
+    fn check_subtree(
+        &mut self,
+        c_id: ContainerId,
+        max_page: PageId,
+        p_id: PageId,
+        (low, high): (Option<SearchKey>, Option<SearchKey>),
+        depth: usize,
+        walk: &mut TreeWalk,
+    ) -> Result<(), CrustyError> {
+        let Some(page) = self.read(c_id, p_id)? else {
+            return Ok(());
+        };
+        let entries = page.entries();
+        let in_bounds = |k: &SearchKey| low.is_none_or(|l| l <= *k) && high.is_none_or(|h| *k <= h);
+        if !entries.windows(2).all(|w| w[0].0 <= w[1].0) || !entries.iter().all(|e| in_bounds(&e.0))
+        {
+            self.violation(Violation::UnorderedKeys { c_id, p_id });
+        }
+        if page.is_leaf {
+            match walk.leaf_depth {
+                None => walk.leaf_depth = Some(depth),
+                Some(expected) if expected != depth => {
+                    self.violation(Violation::UnevenLeaf {
+                        c_id,
+                        p_id,
+                        depth,
+                        expected,
+                    });
+                }
+                Some(_) => {}
+            }
+            walk.leaves.push(p_id);
+            walk.entries.extend(entries);
+            return Ok(());
+        }
+
+        // Each child holds keys up to its separator, the rightmost the rest
+        let mut children: Vec<(Option<PageId>, Option<SearchKey>)> = entries
+            .iter()
+            .map(|(sep, ptr)| (ValueId::from_bytes(ptr).page_id, Some(*sep)))
+            .collect();
+        children.push((page.page_pointer, high));
+        drop(page);
+        let mut child_low = low;
+        for (child, child_high) in children {
+            let bounds = (child_low, child_high);
+            child_low = child_high;
+            let Some(child) = self.target(c_id, max_page, p_id, child) else {
+                continue;
+            };
+            if !walk.visited.insert(child) {
+                self.violation(Violation::BadPagePointer {
+                    c_id,
+                    p_id,
+                    target: Some(child),
+                });
+                continue;
+            }
+            self.check_subtree(c_id, max_page, child, bounds, depth + 1, walk)?;
+        }
+        Ok(())
+    }
+
+    /// Follow the sibling pointers from the leftmost leaf, which should visit
+    /// every leaf in key order and then stop
+    fn check_siblings(
+        &mut self,
+        c_id: ContainerId,
+        max_page: PageId,
+        leaves: &[PageId],
+    ) -> Result<(), CrustyError> {
+        let Some(first) = leaves.first() else {
+            return Ok(());
+        };
+        let mut chain = vec![*first];
+        let mut seen = HashSet::from([*first]);
+        loop {
+            let p_id = *chain.last().unwrap();
+            let Some(page) = self.read(c_id, p_id)? else {
+                return Ok(());
+            };
+            let Some(next) = page.page_pointer else {
+                break;
+            };
+            drop(page);
+            if self.target(c_id, max_page, p_id, Some(next)).is_none() {
+                return Ok(());
+            }
+            if !seen.insert(next) {
+                self.violation(Violation::SiblingCycle { c_id, p_id });
+                return Ok(());
+            }
+            chain.push(next);
+        }
+        // The first leaf whose pointer leads somewhere else than the next leaf
+        let matching = chain.iter().zip(leaves).take_while(|(a, b)| a == b).count();
+        if matching < chain.len() || matching < leaves.len() {
+            self.violation(Violation::BrokenSiblingChain {
+                c_id,
+                p_id: chain[matching - 1],
+            });
+        }
+        Ok(())
+    }
+
+    fn check_hash(
+        &mut self,
+        idx: &FixedIndexFile<BufferPool>,
+        max_page: PageId,
+    ) -> Result<Vec<IndexEntry>, CrustyError> {
+        let c_id = idx.c_id;
+        let state = idx.state();
+        let slots = 1usize.checked_shl(state.global_depth as u32);
+        if slots != Some(state.directory.len()) {
+            let reason = format!(
+                "directory has {} slots for global depth {}",
+                state.directory.len(),
+                state.global_depth
+            );
+            self.violation(Violation::BadIndexHeader { c_id, reason });
+            return Ok(Vec::new());
+        }
+        let mut found = Vec::new();
+        let mut heads = HashSet::new();
+        let mut overflow = HashSet::new();
+        for (i, head) in state.directory.iter().enumerate() {
+            let Some(head) = self.target(c_id, max_page, HEADER_PAGE_ID, Some(*head)) else {
+                continue;
+            };
+            // Directory slots that differ above the local depth share a bucket
+            if !heads.insert(head) {
+                continue;
+            }
+            if overflow.contains(&head) {
+                self.violation(Violation::BadPagePointer {
+                    c_id,
+                    p_id: HEADER_PAGE_ID,
+                    target: Some(head),
+                });
+                continue;
+            }
+            let mut mask = None;
+            let mut last_key: Option<SearchKey> = None;
+            let mut p_id = head;
+            loop {
+                let Some(page) = self.read(c_id, p_id)? else {
+                    break;
+                };
+                if p_id == head {
+                    if page.extra > state.global_depth {
+                        self.violation(Violation::BadLocalDepth {
+                            c_id,
+                            p_id,
+                            local_depth: page.extra,
+                        });
+                    } else {
+                        mask = Some((1u64 << page.extra) - 1);
+                    }
+                }
+                let mut ordered = true;
+                for slot in 0..page.slot_capacity {
+                    let Some((key, ptr)) = page.get_entry(slot) else {
+                        continue;
+                    };
+                    let place = place_key(&key, idx.is_ordered());
+                    if mask.is_some_and(|mask| place & mask != i as u64 & mask) {
+                        self.violation(Violation::MisplacedEntry { c_id, p_id, slot });
+                    }
+                    ordered &= last_key.is_none_or(|last| last <= key);
+                    last_key = Some(key);
+                    found.push((key, ptr));
+                }
+                if idx.is_ordered() && !ordered {
+                    self.violation(Violation::UnorderedKeys { c_id, p_id });
+                }
+                let Some(next) = page.overflow_pointer else {
+                    break;
+                };
+                drop(page);
+                let Some(next) = self.target(c_id, max_page, p_id, Some(next)) else {
+                    break;
+                };
+                if heads.contains(&next) || !overflow.insert(next) {
+                    self.violation(Violation::BadPagePointer {
+                        c_id,
+                        p_id,
+                        target: Some(next),
+                    });
+                    break;
+                }
+                p_id = next;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Match the entries of an index one to one with the live records of its table
+    fn match_records(
+        &mut self,
+        i_id: ContainerId,
+        extractor: &KeyExtractor,
+        entries: &[IndexEntry],
+        records: &[(ValueId, Vec<u8>)],
+    ) {
+        let mut unmatched: HashMap<(SearchKey, ValueId), usize> = HashMap::new();
+        for (v_id, value) in records {
+            *unmatched
+                .entry((extractor.extract(value), *v_id))
+                .or_default() += 1;
+        }
+        for (key, ptr) in entries {
+            let pointer = ValueId::from_bytes(ptr);
+            match unmatched.get_mut(&(*key, pointer)) {
+                Some(n) if *n > 0 => *n -= 1,
+                _ => self.violation(Violation::DanglingEntry {
+                    i_id,
+                    key: *key,
+                    pointer,
+                }),
+            }
+        }
+        for (v_id, value) in records {
+            let n = unmatched
+                .get_mut(&(extractor.extract(value), *v_id))
+                .unwrap();
+            if *n > 0 {
+                *n -= 1;
+                self.violation(Violation::MissingEntry { i_id, v_id: *v_id });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::heap::fixed_heap_file::FixedHeapFile;
+    use crate::index::fixed_index_trait::IndexFileTrait;
+    use crate::index::STARTING_PAGE_CAPACITY;
+    use crate::replacement_policy::ReplacementPolicyType;
+    use crate::test_util::{
+        gen_random_test_dir, gen_records_ascending_keys, gen_small_rng_with_seed, SearchKeyTypes,
+    };
+    use common::testutil::init;
+    use txn_manager::lm_trait::LockManagerTrait;
+
+    #[test]
+    fn test_fsck_finds_broken_invariants() {
+        init();
+        let lm = Arc::new(LockManager::new(100));
+        let dir = gen_random_test_dir();
+        let bp = Arc::new(BufferPool::new(
+            lm.clone(),
+            &dir,
+            ReplacementPolicyType::default(),
+        ));
+        let txn = TransactionId::new();
+        let t_id = bp.register_container(None, StateType::BaseTable).unwrap();
+        let heap = FixedHeapFile::new(t_id, bp.clone(), lm.clone());
+        let mut files = Vec::new();
+        for kind in [StateType::Tree, StateType::HashTable, StateType::HashTable] {
+            let i_id = bp.register_container(None, kind).unwrap();
+            let file = match files.len() {
+                0 => FixedIndexFile::create(i_id, bp.clone(), lm.clone(), true, false, 4),
+                1 => FixedIndexFile::create(i_id, bp.clone(), lm.clone(), false, false, 4),
+                _ => FixedIndexFile::create_ordered_hash(
+                    i_id,
+                    bp.clone(),
+                    lm.clone(),
+                    false,
+                    STARTING_PAGE_CAPACITY,
+                ),
+            };
+            files.push(file.unwrap());
+        }
+        let extractor = KeyExtractor::default();
+        let indexes: Vec<CheckedIndex> = files.iter().map(|f| (f.c_id, t_id, extractor)).collect();
+        let mut rng = gen_small_rng_with_seed(23530);
+        let mut v_ids = Vec::new();
+        for (key, value) in gen_records_ascending_keys(1000, SearchKeyTypes::Random, &mut rng) {
+            let v_id = heap.insert_kv(&key, &value, &txn).unwrap();
+            for file in files.iter() {
+                file.add(&extractor.extract(&value), &v_id.to_fixed_bytes(), &txn)
+                    .unwrap();
+            }
+            v_ids.push(v_id);
+        }
+        let report = check(&bp, &lm, &indexes).unwrap();
+        assert!(report.is_clean(), "{:?}", report.violations);
+        assert_eq!(report.containers, 4);
+        let check_finds = |expected: Vec<Violation>| {
+            assert_eq!(check(&bp, &lm, &indexes).unwrap().violations, expected);
+        };
+
+        // A free slot past the capacity of a heap page
+        let heap_page = ValueId::new_page(t_id, 0);
+        let capacity = bp.get_page_for_read(&heap_page).unwrap().slot_capacity as usize;
+        bp.get_page_for_write(&heap_page).unwrap().free[capacity] = true;
+        check_finds(vec![Violation::FreeBitmap {
+            c_id: t_id,
+            p_id: 0,
+        }]);
+        bp.get_page_for_write(&heap_page).unwrap().free[capacity] = false;
+
+        // Keys out of order in a leaf, then a leaf that points back to itself
+        let tree = &files[0];
+        let c_id = tree.c_id;
+        let (path, leaf) = tree
+            .find_leaf(&tree.state(), &[0; SEARCH_KEY_SIZE], true)
+            .unwrap();
+        assert!(!path.is_empty());
+        let leaf_page = tree.page_id(leaf);
+        let entries = bp.get_page_for_read(&leaf_page).unwrap().entries();
+        let reversed: Vec<IndexEntry> = entries.iter().rev().copied().collect();
+        bp.get_page_for_write(&leaf_page)
+            .unwrap()
+            .set_entries(&reversed)
+            .unwrap();
+        check_finds(vec![Violation::UnorderedKeys { c_id, p_id: leaf }]);
+        bp.get_page_for_write(&leaf_page)
+            .unwrap()
+            .set_entries(&entries)
+            .unwrap();
+        let sibling = bp.get_page_for_read(&leaf_page).unwrap().page_pointer;
+        bp.get_page_for_write(&leaf_page).unwrap().page_pointer = Some(leaf);
+        check_finds(vec![Violation::SiblingCycle { c_id, p_id: leaf }]);
+        bp.get_page_for_write(&leaf_page).unwrap().page_pointer = None;
+        check_finds(vec![Violation::BrokenSiblingChain { c_id, p_id: leaf }]);
+        bp.get_page_for_write(&leaf_page).unwrap().page_pointer = sibling;
+
+        // A hash entry moved to the bucket after its own
+        let hash = &files[1];
+        let c_id = hash.c_id;
+        let directory = hash.state().directory;
+        let (from, to) = (hash.page_id(directory[0]), hash.page_id(directory[1]));
+        let (key, ptr) = bp.get_page_for_read(&from).unwrap().get_entry(0).unwrap();
+        bp.get_page_for_write(&from).unwrap().delete(0).unwrap();
+        let slot = bp
+            .get_page_for_write(&to)
+            .unwrap()
+            .add_entry(&key, &ptr)
+            .unwrap()
+            .unwrap();
+        check_finds(vec![Violation::MisplacedEntry {
+            c_id,
+            p_id: directory[1],
+            slot,
+        }]);
+        bp.get_page_for_write(&to).unwrap().delete(slot).unwrap();
+        bp.get_page_for_write(&from)
+            .unwrap()
+            .write(0, false, &key, &ptr)
+            .unwrap();
+        assert!(check(&bp, &lm, &indexes).unwrap().is_clean());
+
+        // A record deleted from the heap but not the indexes, and one added
+        // to the heap only
+        let (_key, value) = heap.get_kv(&v_ids[0], &txn).unwrap();
+        heap.delete_kv(&v_ids[0], &txn).unwrap();
+        let key = extractor.extract(&value);
+        check_finds(
+            files
+                .iter()
+                .map(|f| Violation::DanglingEntry {
+                    i_id: f.c_id,
+                    key,
+                    pointer: v_ids[0],
+                })
+                .collect(),
+        );
+        let v_id = heap.insert_kv(&[1; KEY_SIZE], &value, &txn).unwrap();
+        assert_eq!(v_id, v_ids[0]);
+        assert!(check(&bp, &lm, &indexes).unwrap().is_clean());
+        let v_id = heap.insert_kv(&[2; KEY_SIZE], &value, &txn).unwrap();
+        check_finds(
+            files
+                .iter()
+                .map(|f| Violation::MissingEntry { i_id: f.c_id, v_id })
+                .collect(),
+        );
+    }
+}
@@ -1,12 +1,18 @@
 use std::sync::Arc;
 
+use super::fixed_heap_iter::FixedHeapFileIter;
 use super::fixed_heap_page::HeapDataPage;
 use crate::buffer_pool::BufferPoolTrait;
+use crate::wal::log_record::{Record, TxnOp};
 use common::ids::AtomicPageId;
 use common::prelude::*;
 use std::sync::atomic::Ordering::Relaxed;
+use txn_manager::lm_trait::LockManagerTrait;
 use txn_manager::lockmanager::LockManager;
 
+/// A stored record with its value id, key and value
+pub type HeapRecord = (ValueId, Vec<u8>, Vec<u8>);
+
 #[allow(dead_code)]
 pub struct FixedHeapFile<T: BufferPoolTrait> {
     /// A reference to the buffer pool
@@ -24,99 +30,172 @@ pub struct FixedHeapFile<T: BufferPoolTrait> {
 #[allow(dead_code)]
 impl<T: BufferPoolTrait> FixedHeapFile<T> {
     pub fn new(c_id: ContainerId, bp: Arc<T>, lm: Arc<LockManager>) -> Self {
-        //Create first page. Assume that container has been registered
-        //TODO milestone idx2 - Check LM first
-        let (p_id, page) = bp.new_page(c_id).unwrap();
+        Self::create(c_id, bp, lm).expect("Unable to allocate the first heap page")
+    }
+
+    /// Create a heap file with its first page. Assumes the container has
+    /// been registered.
+    pub fn create(
+        c_id: ContainerId,
+        bp: Arc<T>,
+        lm: Arc<LockManager>,
+    ) -> Result<Self, CrustyError> {
+        let (p_id, page) = bp.new_page(c_id)?;
         assert_eq!(p_id, 0);
         drop(page);
-        FixedHeapFile {
+        Ok(FixedHeapFile {
             bp,
             lm,
             c_id,
             max_page: AtomicPageId::new(0),
             free_page_cache: AtomicPageId::new(0),
+        })
+    }
+
+    /// Reopen a heap file whose pages already exist in the container
+    pub fn open(c_id: ContainerId, bp: Arc<T>, lm: Arc<LockManager>, max_page: PageId) -> Self {
+        FixedHeapFile {
+            bp,
+            lm,
+            c_id,
+            max_page: AtomicPageId::new(max_page),
+            free_page_cache: AtomicPageId::new(0),
         }
     }
 
+    /// The largest page id in this container
+    pub fn max_page(&self) -> PageId {
+        self.max_page.load(Relaxed)
+    }
+
+    /// Whether `txn` could lock a free slot exclusively for a new record. A
+    /// slot freed by a transaction that has not finished is still locked by
+    /// it and is skipped, as it may need the slot back on abort.
+    fn lock_free_slot(&self, p_id: PageId, s_id: SlotId, txn: &TransactionId) -> bool {
+        self.lm.try_acquire_lock(
+            *txn,
+            ValueId::new_slot(self.c_id, p_id, s_id),
+            Permissions::ReadWrite,
+        )
+    }
+
+    /// Insert many records, filling each page under a single latch before
+    /// moving to the next
     pub fn bulk_insert_kv(
         &self,
         key_values: &[(&[u8], &[u8])],
         txn: &TransactionId,
     ) -> Result<Vec<ValueId>, CrustyError> {
-        // This is a poor/simple implementation. It should be batched.
-        let mut v_ids = Vec::new();
-        for (key, value) in key_values.iter() {
-            let v_id = self.insert_kv(key, value, txn)?;
-            v_ids.push(v_id);
+        let mut v_ids = Vec::with_capacity(key_values.len());
+        let mut remaining = key_values;
+        while !remaining.is_empty() {
+            let page_to_try = self.free_page_cache.load(Relaxed);
+            let action = self.bp.log().atomic();
+            let mut page = self
+                .bp
+                .get_page_for_write(&ValueId::new_page(self.c_id, page_to_try))?;
+            let mut added = 0;
+            for (key, value) in remaining {
+                let slot = page.add_where(key, value, |s_id| {
+                    self.lock_free_slot(page_to_try, s_id, txn)
+                })?;
+                match slot {
+                    Some(s_id) => {
+                        let v_id = ValueId::new_slot(self.c_id, page_to_try, s_id);
+                        page.log_txn_op(txn, TxnOp::HeapChange { v_id, before: None })?;
+                        v_ids.push(v_id);
+                        added += 1;
+                    }
+                    None => break,
+                }
+            }
+            drop(page);
+            drop(action);
+            remaining = &remaining[added..];
+            if !remaining.is_empty() {
+                self.move_past_full_page(page_to_try)?;
+            }
         }
         Ok(v_ids)
     }
 
+    /// Insert a record, holding an exclusive lock on it for `txn`
     pub fn insert_kv(
         &self,
         key: &[u8],
         val: &[u8],
-        _txn: &TransactionId,
+        txn: &TransactionId,
     ) -> Result<ValueId, CrustyError> {
         loop {
-            //TODO milestone idx2 - Check LM first
             let page_to_try = self.free_page_cache.load(Relaxed);
             let mut page_id = ValueId::new_page(self.c_id, page_to_try);
-            let mut page = self
-                .bp
-                .get_page(&page_id, Permissions::ReadWrite)
-                .expect("Error getting page");
-            let slot = page.add(key, val);
+            let action = self.bp.log().atomic();
+            let mut page = self.bp.get_page_for_write(&page_id)?;
+            let slot =
+                page.add_where(key, val, |s_id| self.lock_free_slot(page_to_try, s_id, txn))?;
+            if let Some(s_id) = slot {
+                page_id.slot_id = Some(s_id);
+                let op = TxnOp::HeapChange {
+                    v_id: page_id,
+                    before: None,
+                };
+                page.log_txn_op(txn, op)?;
+            }
             // Don't hold guard/latch long
             drop(page);
+            drop(action);
             match slot {
-                Some(s_id) => {
-                    page_id.slot_id = Some(s_id);
-                    return Ok(page_id);
-                }
-                None => {
-                    // There was no space
-                    // How do we ensure no one else bumps and adds the new page?
-                    //TODO milestone idx2 - Check LM first
-                    if page_to_try == self.max_page.load(Relaxed) {
-                        // need to make a new page
-                        let new_page_id = page_to_try + 1;
-                        let update = self.max_page.compare_exchange(
-                            page_to_try,
-                            new_page_id,
-                            Relaxed,
-                            Relaxed,
-                        );
-                        if update.is_ok() {
-                            // We need to make the new page
-                            self.bp.new_page(self.c_id).unwrap();
-                            // Update the free page cache so we try this page next time
-                            self.free_page_cache.store(new_page_id, Relaxed);
-                        } // if else someone else added the page
-                    } else {
-                        let _ = self.free_page_cache.compare_exchange(
-                            page_to_try,
-                            page_to_try + 1,
-                            Relaxed,
-                            Relaxed,
-                        );
-                    }
-                }
+                Some(_) => return Ok(page_id),
+                None => self.move_past_full_page(page_to_try)?,
             }
         }
     }
 
+    /// Point the free page cache past a page that had no space, adding a new
+    /// page if it was the last one
+    fn move_past_full_page(&self, page_to_try: PageId) -> Result<(), CrustyError> {
+        // How do we ensure no one else bumps and adds the new page?
+        if page_to_try == self.max_page.load(Relaxed) {
+            // need to make a new page
+            let new_page_id = page_to_try + 1;
+            let update = self
+                .max_page
+                .compare_exchange(page_to_try, new_page_id, Relaxed, Relaxed);
+            if update.is_ok() {
+                // We need to make the new page. A failed new page leaves the
+                // container as it was.
+                if let Err(e) = self.bp.new_page(self.c_id) {
+                    self.max_page.store(page_to_try, Relaxed);
+                    return Err(e);
+                }
+                // Update the free page cache so we try this page next time
+                self.free_page_cache.store(new_page_id, Relaxed);
+            } // if else someone else added the page
+        } else {
+            let _ = self.free_page_cache.compare_exchange(
+                page_to_try,
+                page_to_try + 1,
+                Relaxed,
+                Relaxed,
+            );
+        }
+        Ok(())
+    }
+
+    /// Read a record, holding a shared lock on it for `txn`
     pub fn get_kv(
         &self,
         v_id: &ValueId,
-        _txn: &TransactionId,
+        txn: &TransactionId,
     ) -> Result<(Vec<u8>, Vec<u8>), CrustyError> {
-        //TODO milestone idx2 Check LM first
-        match self.bp.get_page(v_id, Permissions::ReadOnly) {
-            Ok(page) => {
-                let (k, v) = page.get_kv(v_id.slot_id.unwrap()).unwrap();
-                Ok((k, v))
-            }
+        self.lm.acquire_lock(*txn, *v_id, Permissions::ReadOnly)?;
+        if v_id.page_id.is_none_or(|p_id| p_id > self.max_page()) {
+            return Err(CrustyError::ValueNotFound(*v_id));
+        }
+        match self.bp.get_page_for_read(v_id) {
+            Ok(page) => page
+                .get_kv(v_id.slot_id.unwrap())
+                .ok_or(CrustyError::ValueNotFound(*v_id)),
             Err(e) => {
                 error!("Error getting page {:?}", e);
                 Err(e)
@@ -124,43 +203,88 @@ impl<T: BufferPoolTrait> FixedHeapFile<T> {
         }
     }
 
+    /// Iterate over every record in the file in page and slot order
+    pub fn iter(&self, txn: &TransactionId) -> FixedHeapFileIter<T> {
+        FixedHeapFileIter::new(self.bp.clone(), self.c_id, self.max_page(), None, *txn)
+    }
+
+    /// Iterate over the records in the file from the page and slot of `start` onwards
+    pub fn iter_from(&self, start: &ValueId, txn: &TransactionId) -> FixedHeapFileIter<T> {
+        FixedHeapFileIter::new(
+            self.bp.clone(),
+            self.c_id,
+            self.max_page(),
+            Some(*start),
+            *txn,
+        )
+    }
+
+    /// Overwrite a record, holding an exclusive lock on it for `txn`
     pub fn update_kv(
         &self,
         v_id: &ValueId,
         key: &[u8],
         val: &[u8],
-        _txn: &TransactionId,
+        txn: &TransactionId,
     ) -> Result<(), CrustyError> {
-        //TODO milestone idx2  Check LM first
-        let mut page = self
-            .bp
-            .get_page(v_id, Permissions::ReadWrite)
-            .expect("Error getting page");
-        match page.write(v_id.slot_id.unwrap(), true, key, val) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
-        }
+        self.lm.acquire_lock(*txn, *v_id, Permissions::ReadWrite)?;
+        let _action = self.bp.log().atomic();
+        let mut page = self.bp.get_page_for_write(v_id)?;
+        let before = page.get_kv(v_id.slot_id.unwrap());
+        page.write(v_id.slot_id.unwrap(), true, key, val)?;
+        let op = TxnOp::HeapChange {
+            v_id: *v_id,
+            before,
+        };
+        page.log_txn_op(txn, op)
     }
 
-    pub fn delete_kv(&self, v_id: &ValueId, _txn: &TransactionId) -> Result<(), CrustyError> {
-        //TODO milestone idx2  Check LM first
-        let mut page = self
-            .bp
-            .get_page(v_id, Permissions::ReadWrite)
-            .expect("Error getting page");
-        page.delete(v_id.slot_id.unwrap());
+    /// Delete a record, holding an exclusive lock on its slot for `txn`
+    pub fn delete_kv(&self, v_id: &ValueId, txn: &TransactionId) -> Result<(), CrustyError> {
+        self.lm.acquire_lock(*txn, *v_id, Permissions::ReadWrite)?;
+        let _action = self.bp.log().atomic();
+        let mut page = self.bp.get_page_for_write(v_id)?;
+        let before = page.get_kv(v_id.slot_id.unwrap());
+        page.delete(v_id.slot_id.unwrap())?;
+        let op = TxnOp::HeapChange {
+            v_id: *v_id,
+            before,
+        };
+        page.log_txn_op(txn, op)?;
         self.free_page_cache
             .fetch_min(v_id.page_id.unwrap(), Relaxed);
         Ok(())
     }
+
+    /// Put back what a slot held before a transaction changed it, without
+    /// logging a change for any transaction. Used to roll back.
+    pub(crate) fn undo_change(
+        &self,
+        v_id: &ValueId,
+        before: Option<&Record>,
+    ) -> Result<(), CrustyError> {
+        let _action = self.bp.log().atomic();
+        let mut page = self.bp.get_page_for_write(v_id)?;
+        let slot = v_id.slot_id.unwrap();
+        match before {
+            Some((key, val)) => page.write(slot, true, key, val),
+            None => {
+                page.delete(slot)?;
+                self.free_page_cache
+                    .fetch_min(v_id.page_id.unwrap(), Relaxed);
+                Ok(())
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use crate::buffer_pool::BufferPool;
     use crate::prelude::*;
+    use crate::replacement_policy::ReplacementPolicyType;
+    use crate::test_util::gen_random_test_dir;
     use common::testutil::init;
-    use txn_manager::lm_trait::LockManagerTrait;
 
     use super::*;
 
@@ -168,7 +292,12 @@ mod test {
     fn test_data_file() {
         init();
         let lm = Arc::new(LockManager::new(500));
-        let bp = Arc::new(BufferPool::new(lm.clone()));
+        let dir = gen_random_test_dir();
+        let bp = Arc::new(BufferPool::new(
+            lm.clone(),
+            &dir,
+            ReplacementPolicyType::default(),
+        ));
         let txn = TransactionId::new();
         let c_id = bp
             .register_container(None, StateType::BaseTable)
@@ -184,12 +313,12 @@ mod test {
 
         let mut v_ids = Vec::new();
         for (key, value) in key_values.iter() {
-            let v_id = file.insert_kv(&key, &value, &txn).unwrap();
+            let v_id = file.insert_kv(key, value, &txn).unwrap();
             v_ids.push(v_id);
         }
 
         for (i, v_id) in v_ids.iter().enumerate() {
-            let data = file.get_kv(&v_id, &txn);
+            let data = file.get_kv(v_id, &txn);
             assert_eq!(data.unwrap(), key_values[i]);
         }
 
@@ -212,7 +341,7 @@ mod test {
         key_values.remove(d2_i - 1);
 
         for (i, v_id) in v_ids.iter().enumerate() {
-            let data = file.get_kv(&v_id, &txn);
+            let data = file.get_kv(v_id, &txn);
             if data.is_err() {
                 panic!("Failed at index {} vid {:?}", i, v_id);
             }
@@ -234,4 +363,101 @@ mod test {
             "Inserted value should take the place of deleted value"
         );
     }
+
+    #[test]
+    fn test_data_file_iter() {
+        init();
+        let lm = Arc::new(LockManager::new(500));
+        let dir = gen_random_test_dir();
+        let bp = Arc::new(BufferPool::new(
+            lm.clone(),
+            &dir,
+            ReplacementPolicyType::default(),
+        ));
+        let txn = TransactionId::new();
+        let c_id = bp
+            .register_container(None, StateType::BaseTable)
+            .expect("Got CID");
+        let file = FixedHeapFile::new(c_id, bp.clone(), lm.clone());
+        assert_eq!(file.iter(&txn).count(), 0);
+
+        let mut v_ids = Vec::new();
+        for i in 0..DATA_VALUE_COUNT * 3 + 5 {
+            let key = vec![i as u8; KEY_SIZE];
+            let value = vec![i as u8; VALUE_SIZE];
+            v_ids.push(file.insert_kv(&key, &value, &txn).unwrap());
+        }
+        // Leave holes on the first and a middle page
+        for i in [0, 1, DATA_VALUE_COUNT + 4] {
+            file.delete_kv(&v_ids[i], &txn).unwrap();
+        }
+        let expected: Vec<(usize, ValueId)> = v_ids
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(i, _)| ![0, 1, DATA_VALUE_COUNT + 4].contains(i))
+            .collect();
+
+        let all: Vec<HeapRecord> = file.iter(&txn).collect();
+        assert_eq!(all.len(), expected.len());
+        for ((v_id, k, v), (i, e_id)) in all.iter().zip(expected.iter()) {
+            assert_eq!(v_id, e_id);
+            assert_eq!(*k, vec![*i as u8; KEY_SIZE]);
+            assert_eq!(*v, vec![*i as u8; VALUE_SIZE]);
+        }
+
+        // Start mid page, and on a deleted slot
+        let start = v_ids[DATA_VALUE_COUNT + 2];
+        let from: Vec<ValueId> = file.iter_from(&start, &txn).map(|r| r.0).collect();
+        assert_eq!(from[0], start);
+        assert_eq!(from.len(), expected.len() - (DATA_VALUE_COUNT + 2 - 2));
+        let from_hole: Vec<ValueId> = file.iter_from(&v_ids[1], &txn).map(|r| r.0).collect();
+        assert_eq!(from_hole[0], v_ids[2]);
+        let from_page = ValueId::new_page(c_id, 1);
+        assert_eq!(
+            file.iter_from(&from_page, &txn).next().unwrap().0,
+            v_ids[DATA_VALUE_COUNT]
+        );
+        let past_end = ValueId::new_page(c_id, file.max_page() + 1);
+        assert_eq!(file.iter_from(&past_end, &txn).count(), 0);
+    }
+
+    #[test]
+    fn test_data_file_bulk_insert() {
+        init();
+        let lm = Arc::new(LockManager::new(500));
+        let dir = gen_random_test_dir();
+        let bp = Arc::new(BufferPool::new(
+            lm.clone(),
+            &dir,
+            ReplacementPolicyType::default(),
+        ));
+        let txn = TransactionId::new();
+        let c_id = bp
+            .register_container(None, StateType::BaseTable)
+            .expect("Got CID");
+        let file = FixedHeapFile::new(c_id, bp.clone(), lm.clone());
+        let first = file
+            .insert_kv(&[1; KEY_SIZE], &[1; VALUE_SIZE], &txn)
+            .unwrap();
+        let second = file
+            .insert_kv(&[2; KEY_SIZE], &[2; VALUE_SIZE], &txn)
+            .unwrap();
+        file.delete_kv(&first, &txn).unwrap();
+
+        let key_values: Vec<(Vec<u8>, Vec<u8>)> = (0..DATA_VALUE_COUNT * 3)
+            .map(|i| (vec![i as u8; KEY_SIZE], vec![i as u8; VALUE_SIZE]))
+            .collect();
+        let refs: Vec<(&[u8], &[u8])> = key_values.iter().map(|(k, v)| (&k[..], &v[..])).collect();
+        let v_ids = file.bulk_insert_kv(&refs, &txn).unwrap();
+        assert_eq!(v_ids.len(), key_values.len());
+        // The hole on the first page is filled first
+        assert_eq!(v_ids[0], first);
+        assert!(!v_ids.contains(&second));
+        assert_eq!(file.max_page(), 3);
+        for (v_id, kv) in v_ids.iter().zip(key_values.iter()) {
+            assert_eq!(file.get_kv(v_id, &txn).unwrap(), *kv);
+        }
+        assert_eq!(file.iter(&txn).count(), key_values.len() + 1);
+    }
 }
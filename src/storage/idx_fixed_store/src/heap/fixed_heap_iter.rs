@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use super::fixed_heap_file::HeapRecord;
+use crate::buffer_pool::BufferPoolTrait;
+use common::prelude::*;
+
+/// Iterates over the records of a heap file one page at a time.
+///
+/// Each page is latched only while its records are copied out, so no latch is
+/// held between calls to `next`. Pages added after the iterator was created
+/// are not visited.
+///
+/// Scans take no record locks, so they can see changes of transactions that
+/// have not committed. Locking a record while its page is latched could
+/// deadlock with a writer that holds the lock and waits for the latch.
+#[allow(dead_code)]
+pub struct FixedHeapFileIter<T: BufferPoolTrait> {
+    /// A reference to the buffer pool
+    bp: Arc<T>,
+    /// The container being scanned
+    c_id: ContainerId,
+    /// The next page to read
+    next_page: PageId,
+    /// The last page to read
+    max_page: PageId,
+    /// Records on the first page before this slot are skipped
+    start_slot: SlotId,
+    /// The remaining records of the current page
+    records: std::vec::IntoIter<HeapRecord>,
+    /// The transaction scanning the file
+    txn: TransactionId,
+}
+
+impl<T: BufferPoolTrait> FixedHeapFileIter<T> {
+    /// Create an iterator starting at the page and slot of `start`. A start
+    /// without a page begins at page 0, one without a slot at the page's first slot.
+    pub(crate) fn new(
+        bp: Arc<T>,
+        c_id: ContainerId,
+        max_page: PageId,
+        start: Option<ValueId>,
+        txn: TransactionId,
+    ) -> Self {
+        let (next_page, start_slot) = match start {
+            Some(v_id) => (v_id.page_id.unwrap_or(0), v_id.slot_id.unwrap_or(0)),
+            None => (0, 0),
+        };
+        FixedHeapFileIter {
+            bp,
+            c_id,
+            next_page,
+            max_page,
+            start_slot,
+            records: Vec::new().into_iter(),
+            txn,
+        }
+    }
+
+    /// Copy out the records of the next page, skipping free slots
+    fn load_page(&mut self) -> Result<(), CrustyError> {
+        let p_id = self.next_page;
+        let page = self
+            .bp
+            .get_page_for_read(&ValueId::new_page(self.c_id, p_id))?;
+        let start_slot = std::mem::take(&mut self.start_slot);
+        self.records = page
+            .get_kv_pairs()
+            .into_iter()
+            .filter(|(slot, _, _)| *slot >= start_slot)
+            .map(|(slot, k, v)| (ValueId::new_slot(self.c_id, p_id, slot), k, v))
+            .collect::<Vec<_>>()
+            .into_iter();
+        self.next_page += 1;
+        Ok(())
+    }
+}
+
+impl<T: BufferPoolTrait> Iterator for FixedHeapFileIter<T> {
+    type Item = HeapRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.next() {
+                return Some(record);
+            }
+            if self.next_page > self.max_page {
+                return None;
+            }
+            if let Err(e) = self.load_page() {
+                error!(
+                    "Error reading page {} of container {}: {:?}",
+                    self.next_page, self.c_id, e
+                );
+                self.next_page = self.max_page + 1;
+                return None;
+            }
+        }
+    }
+}
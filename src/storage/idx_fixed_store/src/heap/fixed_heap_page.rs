@@ -4,7 +4,15 @@ use common::prelude::*;
 
 pub trait HeapDataPage {
     fn new(p_id: PageId) -> Self;
-    fn add(&mut self, key: &[u8], value: &[u8]) -> Option<SlotId>;
+    /// Add a record in the first free slot. None if the page is full.
+    fn add(&mut self, key: &[u8], value: &[u8]) -> Result<Option<SlotId>, CrustyError>;
+    /// Add a record in the first free slot that `usable` accepts
+    fn add_where(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        usable: impl FnMut(SlotId) -> bool,
+    ) -> Result<Option<SlotId>, CrustyError>;
 }
 
 impl HeapDataPage for FixedPage {
@@ -12,18 +20,28 @@ impl HeapDataPage for FixedPage {
         FixedPage::new(p_id, KEY_SIZE, VALUE_SIZE)
     }
 
-    fn add(&mut self, key: &[u8], value: &[u8]) -> Option<SlotId> {
-        let first_slot = self.free.iter().position(|&x| x);
+    fn add(&mut self, key: &[u8], value: &[u8]) -> Result<Option<SlotId>, CrustyError> {
+        self.add_where(key, value, |_| true)
+    }
+
+    fn add_where(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        mut usable: impl FnMut(SlotId) -> bool,
+    ) -> Result<Option<SlotId>, CrustyError> {
+        let first_slot = self
+            .free
+            .iter()
+            .enumerate()
+            .position(|(s, &x)| x && usable(s as SlotId));
         match first_slot {
             Some(s) => {
                 let slot = s as SlotId;
-                if self.write(slot, false, key, value).is_err() {
-                    None
-                } else {
-                    Some(slot)
-                }
+                self.write(slot, false, key, value)?;
+                Ok(Some(slot))
             }
-            None => None,
+            None => Ok(None),
         }
     }
 }
@@ -56,7 +74,7 @@ mod test {
         let mut rng = test_util::gen_small_rng_with_seed(23530);
         let records = test_util::gen_records_ascending_keys(record_count, search_keys, &mut rng);
         for (i, (key, value)) in records.iter().enumerate() {
-            let slot = page.add(key, value);
+            let slot = page.add(key, value).unwrap();
             assert!(slot.is_some());
             assert_eq!(slot.unwrap(), i as SlotId);
         }
@@ -68,7 +86,7 @@ mod test {
         let mut page = <FixedPage as HeapDataPage>::new(1);
         let key = vec![0; KEY_SIZE];
         let value = vec![1; VALUE_SIZE];
-        let slot = page.add(&key, &value);
+        let slot = page.add(&key, &value).unwrap();
         assert!(slot.is_some());
         let data = page.get_kv(slot.unwrap()).unwrap();
         assert_eq!(data.0, key);
@@ -76,13 +94,13 @@ mod test {
 
         let key2 = vec![2; KEY_SIZE];
         let value2 = vec![3; VALUE_SIZE];
-        let slot2 = page.add(&key2, &value2);
+        let slot2 = page.add(&key2, &value2).unwrap();
         assert!(slot2.is_some());
         let data2 = page.get_kv(slot2.unwrap()).unwrap();
         assert_eq!(data2.0, key2);
         assert_eq!(data2.1, value2);
 
-        page.delete(slot.unwrap());
+        page.delete(slot.unwrap()).unwrap();
         let data = page.get_kv(slot.unwrap());
         assert!(data.is_none());
 
@@ -96,11 +114,11 @@ mod test {
         for i in 0..DATA_VALUE_COUNT {
             let key = vec![i as u8; KEY_SIZE];
             let value = vec![i as u8; VALUE_SIZE];
-            let slot = page.add(&key, &value);
+            let slot = page.add(&key, &value).unwrap();
             values.push((key, value));
             assert_eq!(slot.unwrap(), i as SlotId);
         }
-        let slot = page.add(&values[0].0, &values[0].1);
+        let slot = page.add(&values[0].0, &values[0].1).unwrap();
         assert!(slot.is_none());
 
         for (i, value) in values.iter().enumerate() {
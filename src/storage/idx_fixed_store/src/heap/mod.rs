@@ -1,2 +1,5 @@
 pub mod fixed_heap_file;
+pub mod fixed_heap_iter;
 pub mod fixed_heap_page;
+pub mod var_heap_file;
+pub mod var_heap_page;
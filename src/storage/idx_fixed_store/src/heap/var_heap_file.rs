@@ -0,0 +1,249 @@
+use std::sync::Arc;
+
+use super::var_heap_page::{VarHeapPage, MAX_VAR_RECORD_SIZE};
+use crate::buffer_pool::BufferPoolTrait;
+use common::ids::AtomicPageId;
+use common::prelude::*;
+use std::sync::atomic::Ordering::Relaxed;
+use txn_manager::lm_trait::LockManagerTrait;
+use txn_manager::lockmanager::LockManager;
+
+/// A heap file of variable size records kept in slotted pages. It takes the
+/// same calls and locks as `FixedHeapFile`, but keys and values can be any
+/// size up to `MAX_VAR_RECORD_SIZE` bytes added together. Its container must
+/// be registered as a `StateType::VarTable`.
+pub struct VarHeapFile<T: BufferPoolTrait> {
+    /// A reference to the buffer pool
+    bp: Arc<T>,
+    /// A reference to the lock manager
+    lm: Arc<LockManager>,
+    /// This container Id
+    c_id: ContainerId,
+    /// The largest page id in this container
+    max_page: AtomicPageId,
+    /// The page id of the first page that may have room for a record. A
+    /// record that does not fit moves it on, so a page skipped for a large
+    /// record may still have room for smaller ones.
+    free_page_cache: AtomicPageId,
+}
+
+impl<T: BufferPoolTrait> VarHeapFile<T> {
+    pub fn new(c_id: ContainerId, bp: Arc<T>, lm: Arc<LockManager>) -> Self {
+        let (p_id, page) = bp.new_page(c_id).unwrap();
+        assert_eq!(p_id, 0);
+        drop(page);
+        VarHeapFile {
+            bp,
+            lm,
+            c_id,
+            max_page: AtomicPageId::new(0),
+            free_page_cache: AtomicPageId::new(0),
+        }
+    }
+
+    /// Reopen a heap file whose pages already exist in the container
+    pub fn open(c_id: ContainerId, bp: Arc<T>, lm: Arc<LockManager>, max_page: PageId) -> Self {
+        VarHeapFile {
+            bp,
+            lm,
+            c_id,
+            max_page: AtomicPageId::new(max_page),
+            free_page_cache: AtomicPageId::new(0),
+        }
+    }
+
+    /// The largest page id in this container
+    pub fn max_page(&self) -> PageId {
+        self.max_page.load(Relaxed)
+    }
+
+    /// Insert a record, holding an exclusive lock on it for `txn`. Errors if
+    /// the record is too large for a page.
+    pub fn insert_kv(
+        &self,
+        key: &[u8],
+        val: &[u8],
+        txn: &TransactionId,
+    ) -> Result<ValueId, CrustyError> {
+        if key.len() + val.len() > MAX_VAR_RECORD_SIZE {
+            return Err(CrustyError::ValidationError(format!(
+                "Record of {} bytes is larger than a page can hold",
+                key.len() + val.len()
+            )));
+        }
+        loop {
+            let page_to_try = self.free_page_cache.load(Relaxed);
+            let mut page_id = ValueId::new_page(self.c_id, page_to_try);
+            let mut page = self.bp.get_page_for_write(&page_id)?;
+            // A slot freed by an unfinished transaction is still locked by it
+            let slot = page.add_record_where(key, val, |s_id| {
+                self.lm.try_acquire_lock(
+                    *txn,
+                    ValueId::new_slot(self.c_id, page_to_try, s_id),
+                    Permissions::ReadWrite,
+                )
+            })?;
+            drop(page);
+            match slot {
+                Some(s_id) => {
+                    page_id.slot_id = Some(s_id);
+                    return Ok(page_id);
+                }
+                None => self.move_past_full_page(page_to_try)?,
+            }
+        }
+    }
+
+    /// Point the free page cache past a page that had no room, adding a new
+    /// page if it was the last one
+    fn move_past_full_page(&self, page_to_try: PageId) -> Result<(), CrustyError> {
+        if page_to_try == self.max_page.load(Relaxed) {
+            let new_page_id = page_to_try + 1;
+            let update = self
+                .max_page
+                .compare_exchange(page_to_try, new_page_id, Relaxed, Relaxed);
+            if update.is_ok() {
+                // A failed new page leaves the container as it was
+                if let Err(e) = self.bp.new_page(self.c_id) {
+                    self.max_page.store(page_to_try, Relaxed);
+                    return Err(e);
+                }
+                self.free_page_cache.store(new_page_id, Relaxed);
+            }
+        } else {
+            let _ = self.free_page_cache.compare_exchange(
+                page_to_try,
+                page_to_try + 1,
+                Relaxed,
+                Relaxed,
+            );
+        }
+        Ok(())
+    }
+
+    /// Read a record, holding a shared lock on it for `txn`
+    pub fn get_kv(
+        &self,
+        v_id: &ValueId,
+        txn: &TransactionId,
+    ) -> Result<(Vec<u8>, Vec<u8>), CrustyError> {
+        self.lm.acquire_lock(*txn, *v_id, Permissions::ReadOnly)?;
+        self.bp
+            .get_page_for_read(v_id)?
+            .get_record(v_id.slot_id.unwrap())
+            .ok_or(CrustyError::ValueNotFound(*v_id))
+    }
+
+    /// Overwrite a record in place, holding an exclusive lock on it for `txn`.
+    /// Errors if the new record does not fit in the record's page.
+    pub fn update_kv(
+        &self,
+        v_id: &ValueId,
+        key: &[u8],
+        val: &[u8],
+        txn: &TransactionId,
+    ) -> Result<(), CrustyError> {
+        self.lm.acquire_lock(*txn, *v_id, Permissions::ReadWrite)?;
+        let mut page = self.bp.get_page_for_write(v_id)?;
+        page.update_record(v_id.slot_id.unwrap(), key, val)?;
+        self.free_page_cache
+            .fetch_min(v_id.page_id.unwrap(), Relaxed);
+        Ok(())
+    }
+
+    /// Delete a record, holding an exclusive lock on its slot for `txn`
+    pub fn delete_kv(&self, v_id: &ValueId, txn: &TransactionId) -> Result<(), CrustyError> {
+        self.lm.acquire_lock(*txn, *v_id, Permissions::ReadWrite)?;
+        let mut page = self.bp.get_page_for_write(v_id)?;
+        page.delete_record(v_id.slot_id.unwrap())?;
+        self.free_page_cache
+            .fetch_min(v_id.page_id.unwrap(), Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::buffer_pool::BufferPool;
+    use crate::replacement_policy::ReplacementPolicyType;
+    use crate::test_util::gen_random_test_dir;
+    use common::testutil::init;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn test_var_heap_file() {
+        init();
+        let lm = Arc::new(LockManager::new(500));
+        let dir = gen_random_test_dir();
+        let bp = Arc::new(BufferPool::new(
+            lm.clone(),
+            &dir,
+            ReplacementPolicyType::default(),
+        ));
+        let txn = TransactionId::new();
+        let c_id = bp
+            .register_container(None, StateType::VarTable)
+            .expect("Got CID");
+        let file = VarHeapFile::new(c_id, bp.clone(), lm.clone());
+
+        let mut rng = SmallRng::seed_from_u64(23530);
+        let mut records: Vec<(Vec<u8>, Vec<u8>)> = (0..500)
+            .map(|i| {
+                let key = vec![i as u8; rng.gen_range(1..20)];
+                let value = vec![i as u8; rng.gen_range(0..400)];
+                (key, value)
+            })
+            .collect();
+        let v_ids: Vec<ValueId> = records
+            .iter()
+            .map(|(key, value)| file.insert_kv(key, value, &txn).unwrap())
+            .collect();
+        assert!(file.max_page() > 10);
+        for (v_id, kv) in v_ids.iter().zip(records.iter()) {
+            assert_eq!(file.get_kv(v_id, &txn).unwrap(), *kv);
+        }
+
+        // Shrink and grow records, and delete some
+        for i in (0..records.len()).step_by(3) {
+            let value = vec![7; rng.gen_range(0..400)];
+            match file.update_kv(&v_ids[i], &records[i].0, &value, &txn) {
+                Ok(()) => records[i].1 = value,
+                Err(_) => assert!(value.len() > records[i].1.len()),
+            }
+        }
+        for v_id in v_ids.iter().skip(1).step_by(3) {
+            file.delete_kv(v_id, &txn).unwrap();
+            assert!(file.get_kv(v_id, &txn).is_err());
+        }
+        for (i, (v_id, kv)) in v_ids.iter().zip(records.iter()).enumerate() {
+            if i % 3 != 1 {
+                assert_eq!(file.get_kv(v_id, &txn).unwrap(), *kv);
+            }
+        }
+
+        // The freed space is used before new pages are added
+        let max_page = file.max_page();
+        let v_id = file.insert_kv(b"key", &[1; 300], &txn).unwrap();
+        assert!(v_id.page_id.unwrap() < max_page);
+        assert!(file
+            .insert_kv(b"key", &[1; MAX_VAR_RECORD_SIZE], &txn)
+            .is_err());
+        let big = file
+            .insert_kv(b"key", &[1; MAX_VAR_RECORD_SIZE - 3], &txn)
+            .unwrap();
+        assert_eq!(
+            file.get_kv(&big, &txn).unwrap().1.len(),
+            MAX_VAR_RECORD_SIZE - 3
+        );
+
+        // Records are read back from disk after the pages are evicted
+        bp.clear_cache().unwrap();
+        for (i, (v_id, kv)) in v_ids.iter().zip(records.iter()).enumerate() {
+            if i % 3 != 1 {
+                assert_eq!(file.get_kv(v_id, &txn).unwrap(), *kv);
+            }
+        }
+    }
+}
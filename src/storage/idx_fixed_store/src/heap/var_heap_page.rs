@@ -0,0 +1,370 @@
+use crate::fixed_page::FixedPage;
+use crate::prelude::*;
+use common::prelude::*;
+
+/// Bytes at the start of the data array for the slot count and the number of
+/// bytes taken by the record area at the end of the page
+const VAR_HEADER_SIZE: usize = 4;
+/// Bytes per slot directory entry: the record's offset, key length and value length
+const VAR_SLOT_SIZE: usize = 6;
+/// The largest key and value, added together, that fit on an empty page
+pub const MAX_VAR_RECORD_SIZE: usize = PAGE_SIZE - VAR_HEADER_SIZE - VAR_SLOT_SIZE;
+
+/// A slot directory entry. A free slot is all zeros, which is never a live
+/// record's offset as the header comes first.
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    offset: usize,
+    key_len: usize,
+    value_len: usize,
+}
+
+impl Slot {
+    fn len(&self) -> usize {
+        self.key_len + self.value_len
+    }
+}
+
+fn read_u16(data: &[u8], os: usize) -> usize {
+    u16::from_le_bytes([data[os], data[os + 1]]) as usize
+}
+
+/// Helpers for using a FixedPage as a slotted page of variable size records.
+/// Everything is kept in the data array, so the page is written and read
+/// like any other. The header and the slot directory grow from the start of
+/// the page and records grow down from its end. Deleting or shrinking a record
+/// leaves a hole that is reclaimed by compacting the page when a record would
+/// not fit otherwise. Records keep their slot through compaction, so a ValueId
+/// stays valid until its record is deleted. A zeroed data array is an empty page.
+/// Every change to the data array goes through `write_bytes` so it is logged,
+/// and the changes error if it cannot be.
+pub trait VarHeapPage {
+    fn new(p_id: PageId) -> Self;
+    /// The number of slot directory entries, free or not
+    fn slot_count(&self) -> SlotId;
+    /// The bytes left for records and slot directory entries once the page is compacted
+    fn free_space(&self) -> usize;
+    /// Add a record in the first free slot, or a new slot at the end of the
+    /// directory. Returns None if the record does not fit.
+    fn add_record(&mut self, key: &[u8], value: &[u8]) -> Result<Option<SlotId>, CrustyError>;
+    /// Add a record in the first free slot that `usable` accepts, or in a new
+    /// slot at the end of the directory if `usable` accepts that
+    fn add_record_where(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        usable: impl FnMut(SlotId) -> bool,
+    ) -> Result<Option<SlotId>, CrustyError>;
+    fn get_record(&self, slot: SlotId) -> Option<(Vec<u8>, Vec<u8>)>;
+    /// Replace the record in a slot. Errors if the slot is free or the new
+    /// record does not fit on the page.
+    fn update_record(&mut self, slot: SlotId, key: &[u8], value: &[u8]) -> Result<(), CrustyError>;
+    fn delete_record(&mut self, slot: SlotId) -> Result<(), CrustyError>;
+    /// Every record with its slot, in slot order
+    fn records(&self) -> Vec<(SlotId, Vec<u8>, Vec<u8>)>;
+    /// Move the records to the end of the page so all free space is in one piece
+    fn compact(&mut self) -> Result<(), CrustyError>;
+}
+
+impl FixedPage {
+    fn var_slot(&self, slot: SlotId) -> Option<Slot> {
+        if slot >= self.slot_count() {
+            return None;
+        }
+        let os = VAR_HEADER_SIZE + slot as usize * VAR_SLOT_SIZE;
+        let entry = Slot {
+            offset: read_u16(&self.data, os),
+            key_len: read_u16(&self.data, os + 2),
+            value_len: read_u16(&self.data, os + 4),
+        };
+        (entry.offset != 0).then_some(entry)
+    }
+
+    fn set_var_slot(&mut self, slot: SlotId, entry: Slot) -> Result<(), CrustyError> {
+        let os = VAR_HEADER_SIZE + slot as usize * VAR_SLOT_SIZE;
+        let mut buf = [0; VAR_SLOT_SIZE];
+        for (i, value) in [entry.offset, entry.key_len, entry.value_len]
+            .into_iter()
+            .enumerate()
+        {
+            buf[i * 2..i * 2 + 2].copy_from_slice(&(value as u16).to_le_bytes());
+        }
+        self.write_bytes(os, &buf)
+    }
+
+    fn set_slot_count(&mut self, count: usize) -> Result<(), CrustyError> {
+        self.write_bytes(0, &(count as u16).to_le_bytes())
+    }
+
+    /// Where the record area starts. Everything from here to the end of the
+    /// page is records or holes left by them.
+    fn record_start(&self) -> usize {
+        PAGE_SIZE - read_u16(&self.data, 2)
+    }
+
+    fn set_record_start(&mut self, start: usize) -> Result<(), CrustyError> {
+        self.write_bytes(2, &((PAGE_SIZE - start) as u16).to_le_bytes())
+    }
+
+    fn live_slots(&self) -> impl Iterator<Item = (SlotId, Slot)> + '_ {
+        (0..self.slot_count()).filter_map(|slot| Some((slot, self.var_slot(slot)?)))
+    }
+
+    /// The bytes in use by a directory of `slots` entries and the live records
+    fn used_with_slots(&self, slots: usize) -> usize {
+        let live: usize = self.live_slots().map(|(_, entry)| entry.len()).sum();
+        VAR_HEADER_SIZE + slots * VAR_SLOT_SIZE + live
+    }
+
+    /// Write a record below the record area, compacting first if the gap
+    /// between it and a directory of `slots` entries is too small. The caller
+    /// has checked that the record fits.
+    fn place_record(
+        &mut self,
+        slot: SlotId,
+        slots: usize,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), CrustyError> {
+        let len = key.len() + value.len();
+        if self.record_start() < VAR_HEADER_SIZE + slots * VAR_SLOT_SIZE + len {
+            self.compact()?;
+        }
+        let offset = self.record_start() - len;
+        self.write_bytes(offset, key)?;
+        self.write_bytes(offset + key.len(), value)?;
+        self.set_record_start(offset)?;
+        self.set_slot_count(slots)?;
+        self.set_var_slot(
+            slot,
+            Slot {
+                offset,
+                key_len: key.len(),
+                value_len: value.len(),
+            },
+        )
+    }
+}
+
+impl VarHeapPage for FixedPage {
+    fn new(p_id: PageId) -> Self {
+        let mut page = FixedPage::empty();
+        page.p_id = p_id;
+        page
+    }
+
+    fn slot_count(&self) -> SlotId {
+        read_u16(&self.data, 0) as SlotId
+    }
+
+    fn free_space(&self) -> usize {
+        PAGE_SIZE - self.used_with_slots(self.slot_count() as usize)
+    }
+
+    fn add_record(&mut self, key: &[u8], value: &[u8]) -> Result<Option<SlotId>, CrustyError> {
+        self.add_record_where(key, value, |_| true)
+    }
+
+    fn add_record_where(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        mut usable: impl FnMut(SlotId) -> bool,
+    ) -> Result<Option<SlotId>, CrustyError> {
+        let count = self.slot_count();
+        let free = self.free_space();
+        let len = key.len() + value.len();
+        if len <= free {
+            let reused = (0..count).find(|slot| self.var_slot(*slot).is_none() && usable(*slot));
+            if let Some(slot) = reused {
+                self.place_record(slot, count as usize, key, value)?;
+                return Ok(Some(slot));
+            }
+        }
+        if len + VAR_SLOT_SIZE <= free && usable(count) {
+            self.place_record(count, count as usize + 1, key, value)?;
+            return Ok(Some(count));
+        }
+        Ok(None)
+    }
+
+    fn get_record(&self, slot: SlotId) -> Option<(Vec<u8>, Vec<u8>)> {
+        let entry = self.var_slot(slot)?;
+        let key_end = entry.offset + entry.key_len;
+        Some((
+            self.data[entry.offset..key_end].to_vec(),
+            self.data[key_end..key_end + entry.value_len].to_vec(),
+        ))
+    }
+
+    fn update_record(&mut self, slot: SlotId, key: &[u8], value: &[u8]) -> Result<(), CrustyError> {
+        let entry = self
+            .var_slot(slot)
+            .ok_or_else(|| CrustyError::CrustyError(format!("No record in slot {}", slot)))?;
+        let len = key.len() + value.len();
+        if len <= entry.len() {
+            // Shrink in place, leaving the tail of the old record as a hole
+            self.write_bytes(entry.offset, key)?;
+            self.write_bytes(entry.offset + key.len(), value)?;
+            return self.set_var_slot(
+                slot,
+                Slot {
+                    offset: entry.offset,
+                    key_len: key.len(),
+                    value_len: value.len(),
+                },
+            );
+        }
+        if len > self.free_space() + entry.len() {
+            return Err(CrustyError::CrustyError(format!(
+                "Record of {} bytes does not fit in page {}",
+                len, self.p_id
+            )));
+        }
+        self.set_var_slot(slot, Slot::default())?;
+        self.place_record(slot, self.slot_count() as usize, key, value)
+    }
+
+    fn delete_record(&mut self, slot: SlotId) -> Result<(), CrustyError> {
+        if self.var_slot(slot).is_none() {
+            return Ok(());
+        }
+        self.set_var_slot(slot, Slot::default())?;
+        // Give back the directory entries of free slots at the end
+        let mut count = self.slot_count();
+        while count > 0 && self.var_slot(count - 1).is_none() {
+            count -= 1;
+        }
+        self.set_slot_count(count as usize)?;
+        if count == 0 {
+            self.set_record_start(PAGE_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn records(&self) -> Vec<(SlotId, Vec<u8>, Vec<u8>)> {
+        (0..self.slot_count())
+            .filter_map(|slot| {
+                let (key, value) = self.get_record(slot)?;
+                Some((slot, key, value))
+            })
+            .collect()
+    }
+
+    fn compact(&mut self) -> Result<(), CrustyError> {
+        let mut live: Vec<(SlotId, Slot)> = self.live_slots().collect();
+        // Move the records nearest the end first so none is overwritten before it moves
+        live.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.offset));
+        let mut start = PAGE_SIZE;
+        for (slot, mut entry) in live {
+            start -= entry.len();
+            let record = self.data[entry.offset..entry.offset + entry.len()].to_vec();
+            self.write_bytes(start, &record)?;
+            entry.offset = start;
+            self.set_var_slot(slot, entry)?;
+        }
+        self.set_record_start(start)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::testutil::init;
+
+    fn record(i: usize, len: usize) -> (Vec<u8>, Vec<u8>) {
+        (vec![i as u8; 1 + i % 5], vec![i as u8; len])
+    }
+
+    #[test]
+    fn test_var_page_add_get_delete() {
+        init();
+        let mut page = <FixedPage as VarHeapPage>::new(1);
+        assert_eq!(page.slot_count(), 0);
+        assert_eq!(page.free_space(), PAGE_SIZE - VAR_HEADER_SIZE);
+        let records: Vec<_> = (0..10).map(|i| record(i, 10 * i)).collect();
+        for (i, (key, value)) in records.iter().enumerate() {
+            assert_eq!(page.add_record(key, value).unwrap(), Some(i as SlotId));
+        }
+        for (i, kv) in records.iter().enumerate() {
+            assert_eq!(page.get_record(i as SlotId).as_ref(), Some(kv));
+        }
+        page.delete_record(3).unwrap();
+        assert!(page.get_record(3).is_none());
+        assert_eq!(page.slot_count(), 10);
+        // A freed slot is reused before the directory grows
+        assert_eq!(page.add_record(b"k", b"v").unwrap(), Some(3));
+        // Freeing the last slots shrinks the directory
+        page.delete_record(8).unwrap();
+        page.delete_record(9).unwrap();
+        assert_eq!(page.slot_count(), 8);
+        assert_eq!(page.records().len(), 8);
+        assert_eq!(page.records()[3], (3, b"k".to_vec(), b"v".to_vec()));
+        for slot in 0..8 {
+            page.delete_record(slot).unwrap();
+        }
+        assert_eq!(page.slot_count(), 0);
+        assert_eq!(page.free_space(), PAGE_SIZE - VAR_HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_var_page_fill_and_compact() {
+        init();
+        let mut page = <FixedPage as VarHeapPage>::new(1);
+        let mut slots = Vec::new();
+        while let Some(slot) = page.add_record(&[1; 4], &[2; 100]).unwrap() {
+            slots.push(slot);
+        }
+        assert_eq!(
+            slots.len(),
+            (PAGE_SIZE - VAR_HEADER_SIZE) / (104 + VAR_SLOT_SIZE)
+        );
+        // Free every other record, leaving holes too small for a larger record
+        for slot in slots.iter().step_by(2) {
+            page.delete_record(*slot).unwrap();
+        }
+        let (key, value) = record(7, 150);
+        let slot = page.add_record(&key, &value).unwrap().unwrap();
+        assert_eq!(slot, slots[0]);
+        assert_eq!(page.get_record(slot).unwrap(), (key, value));
+        for slot in slots.iter().skip(1).step_by(2) {
+            assert_eq!(page.get_record(*slot).unwrap(), (vec![1; 4], vec![2; 100]));
+        }
+        assert!(page
+            .add_record(&[0; 10], &[0; MAX_VAR_RECORD_SIZE])
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_var_page_update() {
+        init();
+        let mut page = <FixedPage as VarHeapPage>::new(1);
+        let a = page.add_record(b"a", &[1; 1000]).unwrap().unwrap();
+        let b = page.add_record(b"b", &[2; 2000]).unwrap().unwrap();
+        page.update_record(a, b"a", &[3; 10]).unwrap();
+        assert_eq!(page.get_record(a).unwrap(), (b"a".to_vec(), vec![3; 10]));
+        // Growing reuses the space it gave up when it shrank
+        page.update_record(a, b"aa", &[4; 1500]).unwrap();
+        assert_eq!(page.get_record(a).unwrap(), (b"aa".to_vec(), vec![4; 1500]));
+        assert_eq!(page.get_record(b).unwrap(), (b"b".to_vec(), vec![2; 2000]));
+        assert!(page.update_record(a, b"a", &[5; 3000]).is_err());
+        assert!(page.update_record(7, b"a", b"b").is_err());
+        assert_eq!(page.get_record(a).unwrap(), (b"aa".to_vec(), vec![4; 1500]));
+    }
+
+    #[test]
+    fn test_var_page_serialize() {
+        init();
+        let mut page = <FixedPage as VarHeapPage>::new(4);
+        for i in 0..20 {
+            let (key, value) = record(i, 3 * i);
+            page.add_record(&key, &value).unwrap().unwrap();
+        }
+        page.delete_record(5).unwrap();
+        let read = FixedPage::from_bytes(&page.to_bytes()).unwrap();
+        assert_eq!(read.p_id, 4);
+        assert_eq!(read.records(), page.records());
+        assert_eq!(read.free_space(), page.free_space());
+    }
+}
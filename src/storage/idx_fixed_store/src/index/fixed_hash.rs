@@ -0,0 +1,574 @@
+use crate::buffer_frame::FrameWriteGuard;
+use crate::buffer_pool::BufferPoolTrait;
+use crate::index::fixed_index_file::{entry_not_found, FixedIndexFile, IndexState};
+use crate::index::fixed_index_page::{IndexEntry, IndexPage};
+use crate::prelude::*;
+use common::prelude::*;
+
+/// Buckets whose entries agree on more hash bits than this are not split
+/// further, they grow an overflow chain instead.
+pub const MAX_GLOBAL_DEPTH: usize = 24;
+
+/// The depth limit for an ordered index. Its keys are placed by their leading
+/// bits, which are often shared by many keys, and this keeps the directory
+/// from doubling far past the number of buckets in use.
+pub const MAX_ORDERED_GLOBAL_DEPTH: usize = 12;
+
+/// FNV-1a over the search key. This is stable across runs, so an index that
+/// is reopened places keys in the same buckets.
+pub fn hash_key(key: &[u8; SEARCH_KEY_SIZE]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in key {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Where a key is placed in the directory, which uses the low bits. An
+/// ordered index places keys by their leading bits, reversed so the first bit
+/// of the key picks the first split. Each bucket then holds a contiguous
+/// range of keys.
+pub fn place_key(key: &[u8; SEARCH_KEY_SIZE], ordered: bool) -> u64 {
+    if ordered {
+        u64::from_be_bytes(*key).reverse_bits()
+    } else {
+        hash_key(key)
+    }
+}
+
+/// The lowest key after the range held by the bucket for `key`, if any
+pub(crate) fn bucket_after(
+    key: &[u8; SEARCH_KEY_SIZE],
+    local_depth: usize,
+) -> Option<[u8; SEARCH_KEY_SIZE]> {
+    let range_end = u64::from_be_bytes(*key) | (u64::MAX >> local_depth);
+    range_end.checked_add(1).map(u64::to_be_bytes)
+}
+
+/// A bucket from a split with its pages latched, waiting for its entries
+pub(crate) struct PendingBucket<'a> {
+    pages: Vec<FrameWriteGuard<'a>>,
+    entries: Vec<IndexEntry>,
+    local_depth: usize,
+}
+
+/// Extendible hashing operations for a non-range index.
+///
+/// The directory has 2^global_depth slots, each holding the head page of a
+/// bucket. A bucket's head page stores its local depth in `extra` and links
+/// any overflow pages through `overflow_pointer`. Entries are unordered,
+/// except in an ordered index where keys are placed by their leading bits
+/// and each bucket is kept sorted across its chain. An ordered index can
+/// answer range queries by walking the buckets in key order, but keys that
+/// share their leading bytes pile up in one bucket's chain.
+///
+/// The index state is the directory's latch. Lookups, deletes and adds that
+/// find room in their bucket latch it shared and then latch the bucket's
+/// pages, so they run alongside each other. Splits and overflow pages latch
+/// it exclusively while they allocate pages and update the directory, but
+/// latch the bucket first and move its entries after releasing the
+/// directory, so lookups of other buckets carry on. Adds to an ordered
+/// index rewrite the sorted bucket and hold the directory exclusively.
+impl<T: BufferPoolTrait> FixedIndexFile<T> {
+    pub(crate) fn init_hash(
+        &self,
+        state: &mut IndexState,
+        initial_page_capacity: PageId,
+    ) -> Result<(), CrustyError> {
+        assert!(
+            initial_page_capacity.is_power_of_two(),
+            "Hash index capacity must be a power of 2"
+        );
+        state.global_depth = initial_page_capacity.trailing_zeros() as usize;
+        for _ in 0..initial_page_capacity {
+            let (p_id, mut page) = self.alloc_page(state, true)?;
+            page.extra = state.global_depth;
+            state.directory.push(p_id);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn bucket_for(
+        state: &IndexState,
+        key: &[u8; SEARCH_KEY_SIZE],
+        ordered: bool,
+    ) -> PageId {
+        let mask = (1u64 << state.global_depth) - 1;
+        state.directory[(place_key(key, ordered) & mask) as usize]
+    }
+
+    /// Whether splitting a full bucket can separate its entries and a new key
+    fn can_split(
+        &self,
+        local_depth: usize,
+        entries: &[IndexEntry],
+        key: &[u8; SEARCH_KEY_SIZE],
+    ) -> bool {
+        let max_depth = if self.ordered {
+            MAX_ORDERED_GLOBAL_DEPTH
+        } else {
+            MAX_GLOBAL_DEPTH
+        };
+        // Only the bits up to the depth limit can be split on
+        let mask = (1u64 << max_depth) - 1;
+        let place = place_key(key, self.ordered) & mask;
+        local_depth < max_depth
+            && entries
+                .iter()
+                .any(|(k, _)| place_key(k, self.ordered) & mask != place)
+    }
+
+    /// Every entry in a bucket and its overflow chain
+    pub(crate) fn bucket_entries(&self, head: PageId) -> Result<Vec<IndexEntry>, CrustyError> {
+        let mut res = Vec::new();
+        let mut next = Some(head);
+        while let Some(p_id) = next {
+            let page = self.bp.get_page_for_read(&self.page_id(p_id))?;
+            res.extend(page.entries());
+            next = page.overflow_pointer;
+        }
+        Ok(res)
+    }
+
+    /// Add to the first free slot in a key's bucket, which only needs the
+    /// directory latched shared. Returns None if the bucket is full.
+    pub(crate) fn hash_try_add(
+        &self,
+        state: &IndexState,
+        key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<Option<ValueId>, CrustyError> {
+        let mut next = Some(Self::bucket_for(state, key, false));
+        while let Some(p_id) = next {
+            let mut page = self.bp.get_page_for_write(&self.page_id(p_id))?;
+            if let Some(slot) = page.add_entry(key, pointer)? {
+                return Ok(Some(self.entry_id(p_id, slot)));
+            }
+            next = page.overflow_pointer;
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn hash_add(
+        &self,
+        state: &mut IndexState,
+        key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<ValueId, CrustyError> {
+        if self.ordered {
+            return self.ordered_hash_add(state, key, pointer);
+        }
+        loop {
+            if let Some(v_id) = self.hash_try_add(state, key, pointer)? {
+                return Ok(v_id);
+            }
+            for bucket in self.grow_bucket(state, key)? {
+                self.fill_bucket(bucket)?;
+            }
+        }
+    }
+
+    /// Make room in the full bucket for a key, with the directory latched
+    /// exclusively. The bucket is split unless every entry has the same
+    /// hash, in which case its chain grows. A split returns the two halves
+    /// with their pages still latched, to be filled with `fill_bucket` once
+    /// the directory can be released.
+    pub(crate) fn grow_bucket(
+        &self,
+        state: &mut IndexState,
+        key: &[u8; SEARCH_KEY_SIZE],
+    ) -> Result<Vec<PendingBucket<'_>>, CrustyError> {
+        let head = Self::bucket_for(state, key, false);
+        let mut pages = Vec::new();
+        let mut next = Some(head);
+        while let Some(p_id) = next {
+            let page = self.bp.get_page_for_write(&self.page_id(p_id))?;
+            next = page.overflow_pointer;
+            pages.push(page);
+        }
+        if pages.iter().any(|page| !page.is_full()) {
+            // Entries were deleted since the add found the bucket full
+            return Ok(Vec::new());
+        }
+        let local_depth = pages[0].extra;
+        let entries: Vec<IndexEntry> = pages.iter().flat_map(|page| page.entries()).collect();
+        if !self.can_split(local_depth, &entries, key) {
+            let (new_id, _new_page) = self.alloc_page(state, true)?;
+            pages.last_mut().unwrap().overflow_pointer = Some(new_id);
+            return Ok(Vec::new());
+        }
+
+        let new_page = self.split_directory(state, head, local_depth)?;
+        let (moved, stay): (Vec<IndexEntry>, Vec<IndexEntry>) = entries
+            .into_iter()
+            .partition(|(k, _)| (place_key(k, self.ordered) >> local_depth) & 1 == 1);
+        // The chain was full, so its pages and the new one hold both halves
+        let capacity = pages[0].slot_capacity as usize;
+        let stay_len = stay.len().div_ceil(capacity).max(1);
+        let moved_len = moved.len().div_ceil(capacity).max(1);
+        let mut pages = pages.into_iter();
+        let stay_pages: Vec<FrameWriteGuard> = pages.by_ref().take(stay_len).collect();
+        let mut moved_pages = vec![new_page];
+        moved_pages.extend(pages.by_ref().take(moved_len - 1));
+        for mut page in pages {
+            self.free_page(state, &mut page)?;
+        }
+        Ok(vec![
+            PendingBucket {
+                pages: stay_pages,
+                entries: stay,
+                local_depth: local_depth + 1,
+            },
+            PendingBucket {
+                pages: moved_pages,
+                entries: moved,
+                local_depth: local_depth + 1,
+            },
+        ])
+    }
+
+    /// Make room in a key's full bucket for `add`. The directory is released
+    /// before the entries of a split bucket are moved.
+    pub(crate) fn make_room(&self, key: &[u8; SEARCH_KEY_SIZE]) -> Result<(), CrustyError> {
+        let _action = self.bp.log().atomic();
+        let mut state = self.state.write().unwrap();
+        let res = self.grow_bucket(&mut state, key);
+        self.write_header(&mut state)?;
+        drop(state);
+        for bucket in res? {
+            self.fill_bucket(bucket)?;
+        }
+        Ok(())
+    }
+
+    /// Write the entries of a bucket from a split across its latched pages
+    pub(crate) fn fill_bucket(&self, bucket: PendingBucket) -> Result<(), CrustyError> {
+        let PendingBucket {
+            mut pages,
+            entries,
+            local_depth,
+        } = bucket;
+        let capacity = pages[0].slot_capacity as usize;
+        let ids: Vec<PageId> = pages.iter().map(|page| page.p_id).collect();
+        for (i, page) in pages.iter_mut().enumerate() {
+            let start = entries.len().min(i * capacity);
+            let end = entries.len().min(start + capacity);
+            page.set_entries(&entries[start..end])?;
+            page.overflow_pointer = ids.get(i + 1).copied();
+        }
+        pages[0].extra = local_depth;
+        Ok(())
+    }
+
+    /// Add to an ordered index, inserting the entry in key order and
+    /// rewriting the bucket. A bucket that fills its head page is split if
+    /// that can separate its keys, otherwise its chain grows.
+    fn ordered_hash_add(
+        &self,
+        state: &mut IndexState,
+        key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<ValueId, CrustyError> {
+        loop {
+            let head = Self::bucket_for(state, key, true);
+            let page = self.bp.get_page_for_read(&self.page_id(head))?;
+            let (local_depth, capacity) = (page.extra, page.slot_capacity as usize);
+            drop(page);
+            let mut entries = self.bucket_entries(head)?;
+            if entries.len() >= capacity && self.can_split(local_depth, &entries, key) {
+                self.split_bucket(state, head, local_depth, entries)?;
+                continue;
+            }
+            let pos = entries.partition_point(|(k, _)| k <= key);
+            entries.insert(pos, (*key, *pointer));
+            self.write_bucket(state, head, &entries, local_depth)?;
+            // write_bucket fills each page of the chain in turn
+            let mut p_id = head;
+            for _ in 0..pos / capacity {
+                p_id = self
+                    .bp
+                    .get_page_for_read(&self.page_id(p_id))?
+                    .overflow_pointer
+                    .expect("Bucket chain is shorter than its entries");
+            }
+            return Ok(self.entry_id(p_id, (pos % capacity) as SlotId));
+        }
+    }
+
+    /// Point the directory slots of a bucket with hash bit `local_depth` set
+    /// at a new page, doubling the directory if needed. Returns the new page.
+    fn split_directory(
+        &self,
+        state: &mut IndexState,
+        head: PageId,
+        local_depth: usize,
+    ) -> Result<FrameWriteGuard<'_>, CrustyError> {
+        if local_depth == state.global_depth {
+            let directory = state.directory.clone();
+            state.directory.extend(directory);
+            state.global_depth += 1;
+        }
+        let (new_id, new_page) = self.alloc_page(state, true)?;
+        for (i, p_id) in state.directory.iter_mut().enumerate() {
+            if *p_id == head && (i >> local_depth) & 1 == 1 {
+                *p_id = new_id;
+            }
+        }
+        Ok(new_page)
+    }
+
+    /// Split a bucket on hash bit `local_depth`
+    fn split_bucket(
+        &self,
+        state: &mut IndexState,
+        head: PageId,
+        local_depth: usize,
+        entries: Vec<IndexEntry>,
+    ) -> Result<(), CrustyError> {
+        let new_id = self.split_directory(state, head, local_depth)?.p_id;
+        let (moved, stay): (Vec<IndexEntry>, Vec<IndexEntry>) = entries
+            .into_iter()
+            .partition(|(k, _)| (place_key(k, self.ordered) >> local_depth) & 1 == 1);
+        self.write_bucket(state, head, &stay, local_depth + 1)?;
+        self.write_bucket(state, new_id, &moved, local_depth + 1)
+    }
+
+    /// Replace the contents of a bucket, growing or shrinking its overflow chain to fit
+    fn write_bucket(
+        &self,
+        state: &mut IndexState,
+        head: PageId,
+        entries: &[IndexEntry],
+        local_depth: usize,
+    ) -> Result<(), CrustyError> {
+        let mut p_id = head;
+        let mut written = 0;
+        loop {
+            let mut page = self.bp.get_page_for_write(&self.page_id(p_id))?;
+            let end = entries.len().min(written + page.slot_capacity as usize);
+            page.set_entries(&entries[written..end])?;
+            written = end;
+            if p_id == head {
+                page.extra = local_depth;
+            }
+            if written == entries.len() {
+                // Free whatever is left of the chain
+                let mut next = page.overflow_pointer.take();
+                drop(page);
+                while let Some(p_id) = next {
+                    let mut page = self.bp.get_page_for_write(&self.page_id(p_id))?;
+                    next = page.overflow_pointer;
+                    self.free_page(state, &mut page)?;
+                }
+                return Ok(());
+            }
+            p_id = match page.overflow_pointer {
+                Some(next) => next,
+                None => {
+                    let (next, _next_page) = self.alloc_page(state, true)?;
+                    page.overflow_pointer = Some(next);
+                    next
+                }
+            };
+        }
+    }
+
+    pub(crate) fn hash_delete(
+        &self,
+        state: &IndexState,
+        key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<ValueId, CrustyError> {
+        let mut next = Some(Self::bucket_for(state, key, self.ordered));
+        while let Some(p_id) = next {
+            let mut page = self.bp.get_page_for_write(&self.page_id(p_id))?;
+            if let Some(slot) = page.find_entry(key, pointer) {
+                page.delete(slot)?;
+                return Ok(self.entry_id(p_id, slot));
+            }
+            next = page.overflow_pointer;
+        }
+        Err(entry_not_found())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buffer_pool::BufferPool;
+    use crate::index::fixed_index_tests::set_up_test_util;
+    use crate::index::fixed_index_trait::IndexFileTrait;
+    use crate::index::STARTING_PAGE_CAPACITY;
+    use crate::test_util::{gen_records_ascending_keys, gen_small_rng_with_seed, SearchKeyTypes};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    type Entry = ([u8; SEARCH_KEY_SIZE], ValueId);
+
+    /// Entries for seeded records, each pointing at its own slot
+    fn gen_entries(n: usize, search_key: SearchKeyTypes, seed: u64) -> Vec<Entry> {
+        let mut rng = gen_small_rng_with_seed(seed);
+        gen_records_ascending_keys(n, search_key, &mut rng)
+            .iter()
+            .enumerate()
+            .map(|(i, (_key, value))| {
+                let v_id = ValueId::new_slot(9, (i / 32) as PageId, (i % 32) as SlotId);
+                (*extract_search_key(value), v_id)
+            })
+            .collect()
+    }
+
+    /// Check every entry is in the bucket its hash selects and every
+    /// allocated page is in a bucket, free or a header page
+    fn check_buckets(idx: &FixedIndexFile<BufferPool>) {
+        let state = idx.state();
+        let mut reachable = HashSet::new();
+        for (i, head) in state.directory.iter().enumerate() {
+            let local_depth = idx.bp.get_page_for_read(&idx.page_id(*head)).unwrap().extra;
+            assert!(local_depth <= state.global_depth);
+            let mask = (1u64 << local_depth) - 1;
+            for (k, _ptr) in idx.bucket_entries(*head).unwrap() {
+                assert_eq!(hash_key(&k) & mask, i as u64 & mask);
+            }
+            let mut next = Some(*head);
+            while let Some(p_id) = next {
+                reachable.insert(p_id);
+                next = idx
+                    .bp
+                    .get_page_for_read(&idx.page_id(p_id))
+                    .unwrap()
+                    .overflow_pointer;
+            }
+        }
+        let header_pages = 1 + idx.header_chain().unwrap().len();
+        assert_eq!(
+            reachable.len() + state.free_pages.len() + header_pages,
+            state.pages_used
+        );
+    }
+
+    #[test]
+    fn test_hash_key_is_stable() {
+        assert_eq!(hash_key(&[0; SEARCH_KEY_SIZE]), 0xa8c7f832281a39c5);
+        assert_ne!(
+            hash_key(&[1; SEARCH_KEY_SIZE]),
+            hash_key(&[2; SEARCH_KEY_SIZE])
+        );
+    }
+
+    #[test]
+    fn test_ordered_placement() {
+        let key = |k: u64| k.to_be_bytes();
+        // The first bit of the key is the lowest bit of its place
+        assert_eq!(place_key(&key(1 << 63), true), 1);
+        assert_eq!(place_key(&key(1), true), 1 << 63);
+        assert_eq!(bucket_after(&key(0), 0), None);
+        assert_eq!(bucket_after(&key(5), 1), Some(key(1 << 63)));
+        assert_eq!(bucket_after(&key(u64::MAX), 3), None);
+        assert_eq!(bucket_after(&key(1 << 62), 2), Some(key(1 << 63)));
+    }
+
+    #[test]
+    fn test_concurrent_hash_adds_and_lookups() {
+        let (bp, lm, txn, _is_range, c_id, _dir) = set_up_test_util();
+        let idx = FixedIndexFile::new(c_id, bp, lm, false, STARTING_PAGE_CAPACITY);
+        let threads = 4;
+        let entries = gen_entries(20000, SearchKeyTypes::Random, 23530);
+        // Some entries are added first so readers always have keys to find
+        let (stable, rest) = entries.split_at(2000);
+        for (k, v_id) in stable.iter() {
+            idx.add(k, &v_id.to_fixed_bytes(), &txn).unwrap();
+        }
+
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let writers: Vec<_> = (0..threads)
+                .map(|t| {
+                    let (idx, txn) = (&idx, &txn);
+                    s.spawn(move || {
+                        for (k, v_id) in rest.iter().skip(t).step_by(threads) {
+                            idx.add(k, &v_id.to_fixed_bytes(), txn).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            // Lookups carry on while other buckets split
+            for r in 0..2 {
+                let (idx, txn, done) = (&idx, &txn, &done);
+                s.spawn(move || {
+                    let mut i = r;
+                    while !done.load(Ordering::Relaxed) {
+                        let (k, v_id) = &stable[i % stable.len()];
+                        assert!(idx.get_pointers_for_key(k, txn).unwrap().contains(v_id));
+                        i += 7;
+                    }
+                });
+            }
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        check_buckets(&idx);
+        assert!(idx.state().global_depth > STARTING_PAGE_CAPACITY.trailing_zeros() as usize);
+        assert_eq!(idx.entry_count() as usize, entries.len());
+        for (k, v_id) in entries.iter() {
+            assert!(idx.get_pointers_for_key(k, &txn).unwrap().contains(v_id));
+        }
+    }
+
+    #[test]
+    fn test_concurrent_hash_adds_and_deletes_with_duplicates() {
+        let (bp, lm, txn, _is_range, c_id, _dir) = set_up_test_util();
+        let idx = FixedIndexFile::new(c_id, bp, lm, false, STARTING_PAGE_CAPACITY);
+        let threads = 4;
+        // Few distinct keys, so buckets grow overflow chains they cannot split
+        let entries = gen_entries(12000, SearchKeyTypes::Card(40), 5);
+        std::thread::scope(|s| {
+            for t in 0..threads {
+                let (idx, txn, entries) = (&idx, &txn, &entries);
+                s.spawn(move || {
+                    let mine: Vec<&Entry> = entries.iter().skip(t).step_by(threads).collect();
+                    for (k, v_id) in mine.iter() {
+                        idx.add(k, &v_id.to_fixed_bytes(), txn).unwrap();
+                    }
+                    for (k, v_id) in mine.iter().step_by(3) {
+                        idx.delete_entry(k, &v_id.to_fixed_bytes(), txn).unwrap();
+                    }
+                    // Re-adding fills the slots the deletes left
+                    for (k, v_id) in mine.iter().step_by(6) {
+                        idx.add(k, &v_id.to_fixed_bytes(), txn).unwrap();
+                    }
+                });
+            }
+        });
+
+        check_buckets(&idx);
+        let mut expected: HashMap<[u8; SEARCH_KEY_SIZE], HashSet<[u8; INDEX_POINTER_SIZE]>> =
+            HashMap::new();
+        for (i, (k, v_id)) in entries.iter().enumerate() {
+            let pos = i / threads;
+            if pos % 3 != 0 || pos % 6 == 0 {
+                expected
+                    .entry(*k)
+                    .or_default()
+                    .insert(v_id.to_fixed_bytes());
+            }
+        }
+        assert_eq!(
+            idx.entry_count() as usize,
+            expected.values().map(HashSet::len).sum::<usize>()
+        );
+        for (k, pointers) in expected.iter() {
+            let found: HashSet<[u8; INDEX_POINTER_SIZE]> = idx
+                .get_pointers_for_key(k, &txn)
+                .unwrap()
+                .iter()
+                .map(|v_id| v_id.to_fixed_bytes())
+                .collect();
+            assert_eq!(&found, pointers);
+        }
+    }
+}
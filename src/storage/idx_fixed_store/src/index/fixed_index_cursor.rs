@@ -0,0 +1,294 @@
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+use crate::buffer_frame::FrameReadGuard;
+use crate::buffer_pool::BufferPoolTrait;
+use crate::index::fixed_hash::bucket_after;
+use crate::index::fixed_index_file::{FixedIndexFile, IndexState};
+use crate::index::fixed_index_page::{IndexEntry, IndexPage};
+use crate::prelude::*;
+use common::prelude::*;
+
+/// What a cursor walks over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorScan {
+    /// Tree leaves with keys between two bounds, in ascending key order or
+    /// descending with `reverse`
+    Tree {
+        start: Bound<[u8; SEARCH_KEY_SIZE]>,
+        end: Bound<[u8; SEARCH_KEY_SIZE]>,
+        reverse: bool,
+    },
+    /// A hash bucket and its overflow chain, keeping entries for one key
+    Bucket { key: [u8; SEARCH_KEY_SIZE] },
+    /// The sorted buckets of an ordered hash index with keys between two
+    /// bounds, in ascending key order
+    OrderedBuckets {
+        start: Bound<[u8; SEARCH_KEY_SIZE]>,
+        end: Bound<[u8; SEARCH_KEY_SIZE]>,
+    },
+}
+
+impl CursorScan {
+    /// Whether a search key is one the scan is for
+    pub(crate) fn contains(&self, search_key: &[u8; SEARCH_KEY_SIZE]) -> bool {
+        match self {
+            CursorScan::Tree { start, end, .. } | CursorScan::OrderedBuckets { start, end } => {
+                above_start(search_key, start) && below_end(search_key, end)
+            }
+            CursorScan::Bucket { key } => search_key == key,
+        }
+    }
+}
+
+/// Whether a key is at or after the start bound of a scan
+pub(crate) fn above_start(
+    key: &[u8; SEARCH_KEY_SIZE],
+    start: &Bound<[u8; SEARCH_KEY_SIZE]>,
+) -> bool {
+    match start {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    }
+}
+
+/// Whether a key is at or before the end bound of a scan
+pub(crate) fn below_end(key: &[u8; SEARCH_KEY_SIZE], end: &Bound<[u8; SEARCH_KEY_SIZE]>) -> bool {
+    match end {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    }
+}
+
+/// The lowest key a start bound allows
+pub(crate) fn lowest_key(start: &Bound<[u8; SEARCH_KEY_SIZE]>) -> [u8; SEARCH_KEY_SIZE] {
+    match start {
+        Bound::Included(key) | Bound::Excluded(key) => *key,
+        Bound::Unbounded => [0; SEARCH_KEY_SIZE],
+    }
+}
+
+/// The highest key an end bound allows
+pub(crate) fn highest_key(end: &Bound<[u8; SEARCH_KEY_SIZE]>) -> [u8; SEARCH_KEY_SIZE] {
+    match end {
+        Bound::Included(key) | Bound::Excluded(key) => *key,
+        Bound::Unbounded => [u8::MAX; SEARCH_KEY_SIZE],
+    }
+}
+
+/// Lazily yields the record pointers of an index lookup, a page at a time.
+///
+/// No page stays latched between calls to `next`, so the caller can wait on
+/// record locks or change the index while a cursor is open. The cursor
+/// copies the pointers it will return from a page, and finds where it left
+/// off again with the index state latched once they run out. A forward read
+/// carries on past the end of a page while the keys equal the last one read,
+/// so the next read starts at the first key after it. A reverse read carries
+/// on from the entry before the first entry of the last page read, starting
+/// from the first of them that was not deleted in between. An entry changed
+/// after its page was read may still be returned.
+pub struct IndexCursor<'a, T: BufferPoolTrait> {
+    bp: &'a T,
+    c_id: ContainerId,
+    /// Latched to find where to carry on reading
+    state: Arc<RwLock<IndexState>>,
+    scan: CursorScan,
+    /// The pointers read and not yet returned, in scan order
+    pending: VecDeque<ValueId>,
+    /// Where to carry on reading, or None once the scan has been read
+    resume: Option<Resume>,
+}
+
+/// Where a cursor carries on reading
+enum Resume {
+    /// The first entry with a key after the bound, for a forward scan
+    From(Bound<[u8; SEARCH_KEY_SIZE]>),
+    /// The entry before the first of these, which are the entries of the
+    /// last page a reverse scan read
+    Before(Vec<IndexEntry>),
+}
+
+impl<'a, T: BufferPoolTrait> IndexCursor<'a, T> {
+    /// Start a cursor on the first page to read, which should be latched
+    /// before the index state used to find it is released. For an ordered
+    /// bucket scan this is the head of the bucket for the start bound's key.
+    /// The first page is read, and released, before this returns.
+    pub(crate) fn new(
+        bp: &'a T,
+        c_id: ContainerId,
+        state: Arc<RwLock<IndexState>>,
+        first_page: FrameReadGuard<'a>,
+        scan: CursorScan,
+    ) -> Result<Self, CrustyError> {
+        let mut cursor = IndexCursor {
+            bp,
+            c_id,
+            state,
+            scan,
+            pending: VecDeque::new(),
+            resume: None,
+        };
+        match scan {
+            CursorScan::Tree { reverse: true, .. } => {
+                let slot = first_page.get_filled_slot_count() as SlotId;
+                cursor.read_reverse(first_page, slot);
+            }
+            CursorScan::Tree { start, .. } | CursorScan::OrderedBuckets { start, .. } => {
+                cursor.read_forward(first_page, start)?
+            }
+            CursorScan::Bucket { .. } => cursor.read_forward(first_page, Bound::Unbounded)?,
+        }
+        Ok(cursor)
+    }
+
+    /// Read the entries after `from` starting at a latched page, moving
+    /// along the leaf or overflow chain until a page has been read to its
+    /// end and the key after its last entry is reached. A bucket scan reads
+    /// its whole chain.
+    fn read_forward(
+        &mut self,
+        mut page: FrameReadGuard<'a>,
+        from: Bound<[u8; SEARCH_KEY_SIZE]>,
+    ) -> Result<(), CrustyError> {
+        let end = match self.scan {
+            CursorScan::Tree { end, .. } | CursorScan::OrderedBuckets { end, .. } => end,
+            CursorScan::Bucket { .. } => Bound::Unbounded,
+        };
+        let next_bucket = match self.scan {
+            CursorScan::OrderedBuckets { .. } => bucket_after(&lowest_key(&from), page.extra),
+            _ => None,
+        };
+        // The key of the last entry read, and whether a page ended after it
+        let mut last = None;
+        let mut page_ended = false;
+        loop {
+            for slot in 0..page.slot_capacity {
+                let Some((k, ptr)) = page.get_entry(slot) else {
+                    if let CursorScan::Tree { .. } = self.scan {
+                        // Leaf entries are packed, so the rest of the page is empty
+                        break;
+                    }
+                    continue;
+                };
+                if let CursorScan::Bucket { key } = self.scan {
+                    if k == key {
+                        self.pending.push_back(ValueId::from_bytes(&ptr));
+                    }
+                    continue;
+                }
+                if !above_start(&k, &from) {
+                    continue;
+                }
+                if !below_end(&k, &end) {
+                    return Ok(());
+                }
+                if page_ended && last != Some(k) {
+                    self.resume = last.map(|key| Resume::From(Bound::Excluded(key)));
+                    return Ok(());
+                }
+                self.pending.push_back(ValueId::from_bytes(&ptr));
+                last = Some(k);
+            }
+            page_ended = last.is_some();
+            let next = match self.scan {
+                CursorScan::Tree { .. } => page.page_pointer,
+                _ => page.overflow_pointer,
+            };
+            let Some(p_id) = next else {
+                // Every entry in the bucket was read, so carry on at the next one
+                if let CursorScan::OrderedBuckets { .. } = self.scan {
+                    self.resume = next_bucket
+                        .filter(|key| below_end(key, &end))
+                        .map(|key| Resume::From(Bound::Included(key)));
+                }
+                return Ok(());
+            };
+            // Latch the next page before the current one is released
+            page = self
+                .bp
+                .get_page_for_read(&ValueId::new_page(self.c_id, p_id))?;
+        }
+    }
+
+    /// Read the entries before `slot` on a latched leaf of a reverse scan
+    fn read_reverse(&mut self, page: FrameReadGuard<'a>, slot: SlotId) {
+        let CursorScan::Tree { start, end, .. } = self.scan else {
+            unreachable!("Only tree cursors run in reverse")
+        };
+        for slot in (0..slot).rev() {
+            let Some((k, ptr)) = page.get_entry(slot) else {
+                continue;
+            };
+            if !below_end(&k, &end) {
+                continue;
+            }
+            if !above_start(&k, &start) {
+                return;
+            }
+            self.pending.push_back(ValueId::from_bytes(&ptr));
+        }
+        let entries = page.entries();
+        if entries.first().is_some_and(|(k, _)| above_start(k, &start)) {
+            self.resume = Some(Resume::Before(entries));
+        }
+    }
+
+    /// Find where the last read left off with the index state latched
+    /// shared, and read on from there
+    fn read_more(&mut self) -> Result<(), CrustyError> {
+        let Some(resume) = self.resume.take() else {
+            return Ok(());
+        };
+        let state_latch = self.state.clone();
+        let state = state_latch.read().unwrap();
+        match resume {
+            Resume::Before(entries) => {
+                if let Some((page, slot)) =
+                    FixedIndexFile::<T>::entry_before(self.bp, self.c_id, &state, &entries)?
+                {
+                    drop(state);
+                    self.read_reverse(page, slot + 1);
+                }
+            }
+            Resume::From(from) => {
+                let key = lowest_key(&from);
+                let p_id = match self.scan {
+                    CursorScan::OrderedBuckets { .. } => {
+                        FixedIndexFile::<T>::bucket_for(&state, &key, true)
+                    }
+                    _ => {
+                        let mut path = Vec::new();
+                        FixedIndexFile::<T>::locate_key(
+                            self.bp, self.c_id, state.root, &key, &mut path,
+                        )?
+                        .0
+                    }
+                };
+                let page = self
+                    .bp
+                    .get_page_for_read(&ValueId::new_page(self.c_id, p_id))?;
+                drop(state);
+                self.read_forward(page, from)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: BufferPoolTrait> Iterator for IndexCursor<'_, T> {
+    type Item = Result<ValueId, CrustyError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            self.resume.as_ref()?;
+            if let Err(e) = self.read_more() {
+                self.resume = None;
+                return Some(Err(e));
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
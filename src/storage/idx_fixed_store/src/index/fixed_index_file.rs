@@ -1,19 +1,682 @@
+use crate::buffer_frame::FrameWriteGuard;
 use crate::buffer_pool::BufferPoolTrait;
+use crate::index::fixed_hash::hash_key;
+use crate::index::fixed_index_cursor::{highest_key, lowest_key, CursorScan, IndexCursor};
+use crate::index::fixed_index_page::{IndexEntry, IndexPage};
 use crate::index::fixed_index_trait::IndexFileTrait;
+use crate::index::BULK_LOAD_FILL_FACTOR;
 use crate::prelude::*;
+use crate::wal::log_record::TxnOp;
 use common::prelude::*;
-use std::{
-    collections::BTreeMap,
-    sync::{Arc, RwLock},
-};
+use std::collections::HashSet;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use txn_manager::lm_trait::LockManagerTrait;
 use txn_manager::lockmanager::LockManager;
 
+/// The state of an index that is not held in its tree or bucket pages. It is
+/// kept on the header pages so the index can be reopened from its container.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexState {
+    /// Tree: the page id of the root
+    pub root: PageId,
+    /// Hash: how many low bits of the hash select a directory slot
+    pub global_depth: usize,
+    /// Hash: the head bucket page for each directory slot
+    pub directory: Vec<PageId>,
+    /// Pages no longer in use that are handed out before allocating new ones
+    pub free_pages: Vec<PageId>,
+    /// The number of pages allocated from the buffer pool
+    pub pages_used: usize,
+}
+
+/// An index over fixed size search keys. A range index is a B+ tree and a
+/// non-range index is an extendible hash table, optionally ordered so it can
+/// answer range queries too. The tree and hash specific code is in
+/// `fixed_tree` and `fixed_hash`.
+#[allow(dead_code)]
 pub struct FixedIndexFile<T: BufferPoolTrait> {
-    bp: Arc<T>,
-    lm: Arc<LockManager>,
-    c_id: ContainerId,
-    supports_range: bool,
-    // TODO idx1 Add more fields here as needed
+    pub(crate) bp: Arc<T>,
+    pub(crate) lm: Arc<LockManager>,
+    pub(crate) c_id: ContainerId,
+    pub(crate) supports_range: bool,
+    /// Whether adding a search key that is already in the index is rejected
+    pub(crate) unique: bool,
+    /// For a hash index, whether keys are placed by their leading bits with
+    /// each bucket kept sorted
+    pub(crate) ordered: bool,
+    /// Latched shared by lookups and by changes confined to one leaf or bucket,
+    /// and exclusively by anything else that changes the index. Reverse
+    /// cursors hold a handle to latch it as they move.
+    pub(crate) state: Arc<RwLock<IndexState>>,
+    /// The number of entries in the index. It is kept outside the state as
+    /// writers holding the state latch shared change it.
+    pub(crate) entry_count: AtomicU64,
+}
+
+impl<T: BufferPoolTrait> FixedIndexFile<T> {
+    /// Create an index, allocating its initial pages. A unique index rejects
+    /// adding a search key it already holds with `CrustyError::DuplicateKey`.
+    pub fn create(
+        c_id: ContainerId,
+        bp: Arc<T>,
+        lm: Arc<LockManager>,
+        supports_range: bool,
+        unique: bool,
+        initial_page_capacity: PageId,
+    ) -> Result<Self, CrustyError> {
+        let idx = FixedIndexFile::with_state(
+            c_id,
+            bp,
+            lm,
+            supports_range,
+            unique,
+            false,
+            IndexState::default(),
+        );
+        idx.init(initial_page_capacity)?;
+        Ok(idx)
+    }
+
+    /// Create an ordered hash index. It places keys by their leading bits and
+    /// keeps each bucket sorted, so besides equality lookups it can answer
+    /// range queries by reading only the buckets in the range. It suits keys
+    /// whose leading bytes are spread out, as keys sharing them share a bucket.
+    pub fn create_ordered_hash(
+        c_id: ContainerId,
+        bp: Arc<T>,
+        lm: Arc<LockManager>,
+        unique: bool,
+        initial_page_capacity: PageId,
+    ) -> Result<Self, CrustyError> {
+        let idx =
+            FixedIndexFile::with_state(c_id, bp, lm, false, unique, true, IndexState::default());
+        idx.init(initial_page_capacity)?;
+        Ok(idx)
+    }
+
+    /// Allocate the header and initial pages
+    fn init(&self, initial_page_capacity: PageId) -> Result<(), CrustyError> {
+        self.modify(|state| {
+            self.init_header(state)?;
+            if self.supports_range {
+                self.init_tree(state)
+            } else {
+                self.init_hash(state, initial_page_capacity)
+            }
+        })
+    }
+
+    /// Reopen an index from the header page of its container
+    pub fn open(c_id: ContainerId, bp: Arc<T>, lm: Arc<LockManager>) -> Result<Self, CrustyError> {
+        let idx =
+            FixedIndexFile::with_state(c_id, bp, lm, false, false, false, IndexState::default());
+        let header = idx.read_header()?;
+        let idx = FixedIndexFile::with_state(
+            c_id,
+            idx.bp,
+            idx.lm,
+            header.supports_range,
+            header.unique,
+            header.ordered,
+            header.state,
+        );
+        idx.entry_count.store(header.entry_count, Ordering::Relaxed);
+        Ok(idx)
+    }
+
+    fn with_state(
+        c_id: ContainerId,
+        bp: Arc<T>,
+        lm: Arc<LockManager>,
+        supports_range: bool,
+        unique: bool,
+        ordered: bool,
+        state: IndexState,
+    ) -> Self {
+        FixedIndexFile {
+            bp,
+            lm,
+            c_id,
+            supports_range,
+            unique,
+            ordered,
+            state: Arc::new(RwLock::new(state)),
+            entry_count: AtomicU64::new(0),
+        }
+    }
+
+    /// A copy of the index state
+    pub fn state(&self) -> IndexState {
+        self.state.read().unwrap().clone()
+    }
+
+    /// The number of entries in the index
+    pub fn entry_count(&self) -> u64 {
+        self.entry_count.load(Ordering::Relaxed)
+    }
+
+    /// Make a change with the state latched exclusively
+    fn modify<R>(
+        &self,
+        change: impl FnOnce(&mut IndexState) -> Result<R, CrustyError>,
+    ) -> Result<R, CrustyError> {
+        let mut state = self.state.write().unwrap();
+        self.modify_latched(&mut state, change)
+    }
+
+    /// Make a change with the state already latched exclusively, then write
+    /// the header so it matches the pages even if the change failed part way.
+    /// Each change to the index is made in its own atomic action, so a crash
+    /// never leaves a split half logged. The action is kept to one change as
+    /// its pages cannot be evicted until it finishes.
+    fn modify_latched<R>(
+        &self,
+        state: &mut IndexState,
+        change: impl FnOnce(&mut IndexState) -> Result<R, CrustyError>,
+    ) -> Result<R, CrustyError> {
+        let _action = self.bp.log().atomic();
+        let res = change(state);
+        self.write_header(state)?;
+        res
+    }
+
+    /// Try a change confined to one leaf or bucket with the state latched
+    /// shared, so it runs alongside lookups and other such changes. Returns
+    /// None if the change has to be made with the state latched exclusively.
+    fn modify_shared(
+        &self,
+        added: bool,
+        change: impl FnOnce(&IndexState) -> Result<Option<ValueId>, CrustyError>,
+    ) -> Result<Option<ValueId>, CrustyError> {
+        let _action = self.bp.log().atomic();
+        let state = self.state.read().unwrap();
+        let Some(v_id) = change(&state)? else {
+            return Ok(None);
+        };
+        if added {
+            self.entry_count.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.entry_count.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.write_entry_count()?;
+        Ok(Some(v_id))
+    }
+
+    pub fn supports_range(&self) -> bool {
+        self.supports_range
+    }
+
+    pub fn is_unique(&self) -> bool {
+        self.unique
+    }
+
+    /// Whether this is an ordered hash index
+    pub fn is_ordered(&self) -> bool {
+        self.ordered
+    }
+
+    /// Add an entry with the state already latched, checking uniqueness first
+    fn add_latched(
+        &self,
+        state: &mut IndexState,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<ValueId, CrustyError> {
+        if self.unique && self.contains_key(state, search_key)? {
+            return Err(duplicate_key(search_key));
+        }
+        self.insert_latched(state, search_key, pointer)
+    }
+
+    /// Add an entry with the state already latched
+    fn insert_latched(
+        &self,
+        state: &mut IndexState,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<ValueId, CrustyError> {
+        let v_id = if self.supports_range {
+            self.tree_add(state, search_key, pointer)
+        } else {
+            self.hash_add(state, search_key, pointer)
+        }?;
+        self.entry_count.fetch_add(1, Ordering::Relaxed);
+        Ok(v_id)
+    }
+
+    /// Delete an entry with the state already latched
+    fn delete_latched(
+        &self,
+        state: &mut IndexState,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<ValueId, CrustyError> {
+        let v_id = if self.supports_range {
+            self.tree_delete(state, search_key, pointer)
+        } else {
+            self.hash_delete(state, search_key, pointer)
+        }?;
+        self.entry_count.fetch_sub(1, Ordering::Relaxed);
+        Ok(v_id)
+    }
+
+    /// Add many entries at once. An empty tree is built bottom-up from the
+    /// sorted entries with its pages `fill_factor` full, leaving room for
+    /// later inserts. Otherwise the entries are added one at a time. For a
+    /// unique index nothing is added if any key is a duplicate.
+    pub fn bulk_add_with_fill(
+        &self,
+        search_keys: Vec<&[u8; SEARCH_KEY_SIZE]>,
+        pointers: Vec<[u8; INDEX_POINTER_SIZE]>,
+        fill_factor: f64,
+    ) -> Result<Vec<ValueId>, CrustyError> {
+        if search_keys.len() != pointers.len() {
+            return Err(CrustyError::ValidationError(
+                "Bulk add needs a pointer for every search key".to_string(),
+            ));
+        }
+        if !(fill_factor > 0.0 && fill_factor <= 1.0) {
+            return Err(CrustyError::ValidationError(format!(
+                "Fill factor {} must be in (0, 1]",
+                fill_factor
+            )));
+        }
+        let mut state = self.state.write().unwrap();
+        if self.unique {
+            // Check the whole batch first so a duplicate adds nothing
+            let mut seen = HashSet::new();
+            for key in search_keys.iter() {
+                if !seen.insert(*key) || self.contains_key(&state, key)? {
+                    return Err(duplicate_key(key));
+                }
+            }
+        }
+        self.bulk_add_latched(&mut state, search_keys, pointers, fill_factor)
+    }
+
+    fn bulk_add_latched(
+        &self,
+        state: &mut IndexState,
+        search_keys: Vec<&[u8; SEARCH_KEY_SIZE]>,
+        pointers: Vec<[u8; INDEX_POINTER_SIZE]>,
+        fill_factor: f64,
+    ) -> Result<Vec<ValueId>, CrustyError> {
+        if self.supports_range && !search_keys.is_empty() && self.tree_is_empty(state)? {
+            let mut order: Vec<usize> = (0..search_keys.len()).collect();
+            order.sort_by_key(|i| search_keys[*i]);
+            let entries: Vec<IndexEntry> = order
+                .iter()
+                .map(|i| (*search_keys[*i], pointers[*i]))
+                .collect();
+            let (root, sorted_ids) = self.tree_bulk_load(state, &entries, fill_factor)?;
+            // The tree is switched to the new pages in one change at the end,
+            // so a crash part way through the load leaves it empty
+            self.modify_latched(state, |state| {
+                let mut old_root = self.bp.get_page_for_write(&self.page_id(state.root))?;
+                self.free_page(state, &mut old_root)?;
+                state.root = root;
+                self.entry_count
+                    .fetch_add(entries.len() as u64, Ordering::Relaxed);
+                Ok(())
+            })?;
+            let mut ids = vec![ValueId::new(self.c_id); order.len()];
+            for (i, v_id) in order.into_iter().zip(sorted_ids) {
+                ids[i] = v_id;
+            }
+            return Ok(ids);
+        }
+        search_keys
+            .into_iter()
+            .zip(pointers.iter())
+            .map(|(key, pointer)| {
+                self.modify_latched(state, |state| self.add_latched(state, key, pointer))
+            })
+            .collect()
+    }
+
+    /// Whether any entry has the search key
+    fn contains_key(
+        &self,
+        state: &IndexState,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+    ) -> Result<bool, CrustyError> {
+        let mut cursor = self.start_cursor(&self.bp, state, self.key_scan(search_key))?;
+        Ok(cursor.next().transpose()?.is_some())
+    }
+
+    /// The scan for the entries with a search key
+    pub(crate) fn key_scan(&self, search_key: &[u8; SEARCH_KEY_SIZE]) -> CursorScan {
+        if self.supports_range {
+            CursorScan::Tree {
+                start: Bound::Included(*search_key),
+                end: Bound::Included(*search_key),
+                reverse: false,
+            }
+        } else {
+            CursorScan::Bucket { key: *search_key }
+        }
+    }
+
+    /// Open a cursor at the first page of a scan. `bp` must be the index's
+    /// buffer pool, it is passed separately so the cursor can outlive `self`.
+    pub(crate) fn start_cursor<'a>(
+        &self,
+        bp: &'a T,
+        state: &IndexState,
+        scan: CursorScan,
+    ) -> Result<IndexCursor<'a, T>, CrustyError> {
+        let first = match scan {
+            CursorScan::Tree {
+                end, reverse: true, ..
+            } => self.find_leaf(state, &highest_key(&end), false)?.1,
+            CursorScan::Tree { start, .. } => self.find_leaf(state, &lowest_key(&start), true)?.1,
+            CursorScan::Bucket { key } => Self::bucket_for(state, &key, self.ordered),
+            CursorScan::OrderedBuckets { start, .. } => {
+                Self::bucket_for(state, &lowest_key(&start), true)
+            }
+        };
+        let page = bp.get_page_for_read(&self.page_id(first))?;
+        IndexCursor::new(bp, self.c_id, self.state.clone(), page, scan)
+    }
+
+    /// Open a cursor with the index state latched only while it finds its first page
+    pub(crate) fn cursor_in<'a>(
+        &self,
+        bp: &'a T,
+        scan: CursorScan,
+    ) -> Result<IndexCursor<'a, T>, CrustyError> {
+        let state = self.state.read().unwrap();
+        self.start_cursor(bp, &state, scan)
+    }
+
+    /// Lock a search key for `txn` until it commits or aborts. Changes lock
+    /// their keys exclusively and key lookups shared, so a transaction does
+    /// not see entries another one has added or removed for a key. Keys are
+    /// locked by their hash, so two keys can share a lock. Range scans do not
+    /// lock keys.
+    pub(crate) fn lock_key(
+        &self,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+        txn: &TransactionId,
+        perm: Permissions,
+    ) -> Result<(), CrustyError> {
+        let hash = hash_key(search_key);
+        let lock_id = ValueId::new_slot(self.c_id, (hash >> 16) as PageId, hash as SlotId);
+        self.lm.acquire_lock(*txn, lock_id, perm)
+    }
+
+    /// A cursor over the record pointers for a search key
+    pub fn key_cursor(
+        &self,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+        txn: &TransactionId,
+    ) -> Result<IndexCursor<'_, T>, CrustyError> {
+        self.lock_key(search_key, txn, Permissions::ReadOnly)?;
+        self.cursor_in(&self.bp, self.key_scan(search_key))
+    }
+
+    /// The scan for a range of search keys. Errors for a non-range index.
+    pub(crate) fn range_scan(
+        &self,
+        search_key_min_inclusive: &[u8; SEARCH_KEY_SIZE],
+        search_key_max_exclusive: &[u8; SEARCH_KEY_SIZE],
+    ) -> Result<CursorScan, CrustyError> {
+        self.bounded_scan(
+            Bound::Included(*search_key_min_inclusive),
+            Bound::Excluded(*search_key_max_exclusive),
+            false,
+        )
+    }
+
+    /// The scan for the search keys between two bounds. Errors for a
+    /// non-range index, or for a reverse scan of an ordered hash index.
+    pub(crate) fn bounded_scan(
+        &self,
+        min: Bound<[u8; SEARCH_KEY_SIZE]>,
+        max: Bound<[u8; SEARCH_KEY_SIZE]>,
+        reverse: bool,
+    ) -> Result<CursorScan, CrustyError> {
+        if self.supports_range {
+            Ok(CursorScan::Tree {
+                start: min,
+                end: max,
+                reverse,
+            })
+        } else if self.ordered && !reverse {
+            Ok(CursorScan::OrderedBuckets {
+                start: min,
+                end: max,
+            })
+        } else {
+            Err(CrustyError::InvalidOperation)
+        }
+    }
+
+    /// A cursor over the record pointers for a range of search keys, in key
+    /// order. Returns `CrustyError::InvalidOperation` for a non-range index.
+    pub fn range_cursor(
+        &self,
+        search_key_min_inclusive: &[u8; SEARCH_KEY_SIZE],
+        search_key_max_exclusive: &[u8; SEARCH_KEY_SIZE],
+        _txn: &TransactionId,
+    ) -> Result<IndexCursor<'_, T>, CrustyError> {
+        let scan = self.range_scan(search_key_min_inclusive, search_key_max_exclusive)?;
+        self.cursor_in(&self.bp, scan)
+    }
+
+    /// The scan for the search keys starting with a prefix. Errors for a
+    /// non-range index or a prefix longer than a search key.
+    pub(crate) fn prefix_scan(&self, prefix: &[u8]) -> Result<CursorScan, CrustyError> {
+        if prefix.len() > SEARCH_KEY_SIZE {
+            return Err(CrustyError::ValidationError(format!(
+                "Prefix of {} bytes is longer than a search key",
+                prefix.len()
+            )));
+        }
+        // Every key with the prefix is between it padded with 0x00 and with 0xFF
+        let mut min = [0; SEARCH_KEY_SIZE];
+        let mut max = [u8::MAX; SEARCH_KEY_SIZE];
+        min[..prefix.len()].copy_from_slice(prefix);
+        max[..prefix.len()].copy_from_slice(prefix);
+        self.bounded_scan(Bound::Included(min), Bound::Included(max), false)
+    }
+
+    /// A cursor over the record pointers for the search keys between two
+    /// bounds, either of which can be open. Pointers come in ascending key
+    /// order, or descending with `reverse`. Returns
+    /// `CrustyError::InvalidOperation` for a non-range index.
+    pub fn bounded_cursor(
+        &self,
+        min: Bound<[u8; SEARCH_KEY_SIZE]>,
+        max: Bound<[u8; SEARCH_KEY_SIZE]>,
+        reverse: bool,
+        _txn: &TransactionId,
+    ) -> Result<IndexCursor<'_, T>, CrustyError> {
+        let scan = self.bounded_scan(min, max, reverse)?;
+        self.cursor_in(&self.bp, scan)
+    }
+
+    /// Answer a range query by reading every entry of the index and keeping
+    /// those between the bounds. This is a full scan, costing as much as the
+    /// whole index whatever the range. It is a fallback for a hash index that
+    /// cannot serve ranges from its layout. Pointers come in search key order.
+    pub fn full_scan_key_range(
+        &self,
+        min: Bound<[u8; SEARCH_KEY_SIZE]>,
+        max: Bound<[u8; SEARCH_KEY_SIZE]>,
+        _txn: &TransactionId,
+    ) -> Result<Vec<ValueId>, CrustyError> {
+        let state = self.state.read().unwrap();
+        let mut entries = Vec::new();
+        if self.supports_range {
+            let mut next = Some(self.find_leaf(&state, &[0; SEARCH_KEY_SIZE], true)?.1);
+            while let Some(p_id) = next {
+                let page = self.bp.get_page_for_read(&self.page_id(p_id))?;
+                entries.extend(page.entries());
+                next = page.page_pointer;
+            }
+        } else {
+            // A bucket with a lower local depth is in several directory slots
+            let mut heads = state.directory.clone();
+            heads.sort_unstable();
+            heads.dedup();
+            for head in heads {
+                entries.extend(self.bucket_entries(head)?);
+            }
+        }
+        entries.retain(|(k, _)| (min, max).contains(k));
+        entries.sort_by_key(|(k, _)| *k);
+        Ok(entries
+            .iter()
+            .map(|(_, ptr)| ValueId::from_bytes(ptr))
+            .collect())
+    }
+
+    /// Get an empty page for the index, reusing a freed page if there is one
+    pub(crate) fn alloc_page(
+        &self,
+        state: &mut IndexState,
+        is_leaf: bool,
+    ) -> Result<(PageId, FrameWriteGuard<'_>), CrustyError> {
+        let (p_id, mut page) = match state.free_pages.pop() {
+            Some(p_id) => (
+                p_id,
+                self.bp
+                    .get_page_for_write(&ValueId::new_page(self.c_id, p_id))?,
+            ),
+            None => {
+                let (p_id, page) = self.bp.new_page(self.c_id)?;
+                state.pages_used += 1;
+                (p_id, page)
+            }
+        };
+        page.init_index_page(is_leaf)?;
+        Ok((p_id, page))
+    }
+
+    /// Return a page that is no longer referenced by the index
+    pub(crate) fn free_page(
+        &self,
+        state: &mut IndexState,
+        page: &mut FrameWriteGuard,
+    ) -> Result<(), CrustyError> {
+        page.init_index_page(false)?;
+        state.free_pages.push(page.p_id);
+        Ok(())
+    }
+
+    /// Log that `txn` is about to add or remove an entry, so the change can
+    /// be rolled back. It is logged first as a split can reach disk before
+    /// the change finishes.
+    fn log_entry_change(
+        &self,
+        txn: &TransactionId,
+        added: bool,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<(), CrustyError> {
+        let (c_id, search_key, pointer) = (self.c_id, *search_key, *pointer);
+        let op = if added {
+            TxnOp::IndexAdd {
+                c_id,
+                search_key,
+                pointer,
+            }
+        } else {
+            TxnOp::IndexDelete {
+                c_id,
+                search_key,
+                pointer,
+            }
+        };
+        self.bp.log().log_txn(*txn, op)?;
+        Ok(())
+    }
+
+    /// Add an entry without locking its key or logging it for a transaction
+    fn insert_entry(
+        &self,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<ValueId, CrustyError> {
+        // A unique index checks for the key and adds it under one exclusive
+        // latch, and an ordered hash index rewrites the sorted bucket
+        if self.unique || self.ordered {
+            return self.modify(|state| self.add_latched(state, search_key, pointer));
+        }
+        loop {
+            let added = self.modify_shared(true, |state| {
+                if self.supports_range {
+                    self.tree_try_add(state, search_key, pointer)
+                } else {
+                    self.hash_try_add(state, search_key, pointer)
+                }
+            })?;
+            if let Some(v_id) = added {
+                return Ok(v_id);
+            }
+            if self.supports_range {
+                return self.modify(|state| self.add_latched(state, search_key, pointer));
+            }
+            self.make_room(search_key)?;
+        }
+    }
+
+    /// Remove an entry without locking its key or logging it for a transaction
+    fn remove_entry(
+        &self,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<ValueId, CrustyError> {
+        let deleted = self.modify_shared(false, |state| {
+            if self.supports_range {
+                self.tree_try_delete(state, search_key, pointer)
+            } else {
+                self.hash_delete(state, search_key, pointer).map(Some)
+            }
+        })?;
+        if let Some(v_id) = deleted {
+            return Ok(v_id);
+        }
+        self.modify(|state| self.delete_latched(state, search_key, pointer))
+    }
+
+    /// Roll back adding an entry, removing it if it is in the index
+    pub(crate) fn undo_add(
+        &self,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<(), CrustyError> {
+        match self.remove_entry(search_key, pointer) {
+            Err(e) if e != entry_not_found() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Roll back removing an entry, adding it back if it is not in the index
+    pub(crate) fn undo_delete(
+        &self,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<(), CrustyError> {
+        let pointers: Vec<ValueId> = self
+            .cursor_in(&self.bp, self.key_scan(search_key))?
+            .collect::<Result<_, _>>()?;
+        if !pointers.contains(&ValueId::from_bytes(pointer)) {
+            self.insert_entry(search_key, pointer)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn page_id(&self, p_id: PageId) -> ValueId {
+        ValueId::new_page(self.c_id, p_id)
+    }
+
+    pub(crate) fn entry_id(&self, p_id: PageId, slot: SlotId) -> ValueId {
+        ValueId::new_slot(self.c_id, p_id, slot)
+    }
 }
 
 impl<T: BufferPoolTrait> IndexFileTrait<T> for FixedIndexFile<T> {
@@ -24,7 +687,8 @@ impl<T: BufferPoolTrait> IndexFileTrait<T> for FixedIndexFile<T> {
         supports_range: bool,
         initial_page_capacity: PageId,
     ) -> Self {
-        panic!("TODO milestone idx1");
+        FixedIndexFile::create(c_id, bp, lm, supports_range, false, initial_page_capacity)
+            .expect("Unable to allocate the initial index pages")
     }
 
     fn add(
@@ -33,7 +697,9 @@ impl<T: BufferPoolTrait> IndexFileTrait<T> for FixedIndexFile<T> {
         pointer: &[u8; INDEX_POINTER_SIZE],
         txn: &TransactionId,
     ) -> Result<ValueId, CrustyError> {
-        panic!("TODO milestone idx1");
+        self.lock_key(search_key, txn, Permissions::ReadWrite)?;
+        self.log_entry_change(txn, true, search_key, pointer)?;
+        self.insert_entry(search_key, pointer)
     }
 
     fn get_pointers_for_key(
@@ -41,7 +707,7 @@ impl<T: BufferPoolTrait> IndexFileTrait<T> for FixedIndexFile<T> {
         search_key: &[u8; SEARCH_KEY_SIZE],
         txn: &TransactionId,
     ) -> Result<Vec<ValueId>, CrustyError> {
-        panic!("TODO milestone idx1");
+        self.key_cursor(search_key, txn)?.collect()
     }
 
     fn get_pointers_for_key_range(
@@ -50,7 +716,8 @@ impl<T: BufferPoolTrait> IndexFileTrait<T> for FixedIndexFile<T> {
         search_key_max_exclusive: &[u8; SEARCH_KEY_SIZE],
         txn: &TransactionId,
     ) -> Result<Vec<ValueId>, CrustyError> {
-        panic!("TODO milestone idx1");
+        self.range_cursor(search_key_min_inclusive, search_key_max_exclusive, txn)?
+            .collect()
     }
 
     fn bulk_add(
@@ -59,7 +726,13 @@ impl<T: BufferPoolTrait> IndexFileTrait<T> for FixedIndexFile<T> {
         pointers: Vec<[u8; INDEX_POINTER_SIZE]>,
         txn: &TransactionId,
     ) -> Result<Vec<ValueId>, CrustyError> {
-        panic!("TODO milestone idx1");
+        for search_key in &search_keys {
+            self.lock_key(search_key, txn, Permissions::ReadWrite)?;
+        }
+        for (search_key, pointer) in search_keys.iter().zip(pointers.iter()) {
+            self.log_entry_change(txn, true, search_key, pointer)?;
+        }
+        self.bulk_add_with_fill(search_keys, pointers, BULK_LOAD_FILL_FACTOR)
     }
 
     fn update_key(
@@ -69,7 +742,20 @@ impl<T: BufferPoolTrait> IndexFileTrait<T> for FixedIndexFile<T> {
         pointer: &[u8; INDEX_POINTER_SIZE],
         txn: &TransactionId,
     ) -> Result<ValueId, CrustyError> {
-        panic!("TODO milestone idx1");
+        self.lock_key(old_search_key, txn, Permissions::ReadWrite)?;
+        self.lock_key(new_search_key, txn, Permissions::ReadWrite)?;
+        self.log_entry_change(txn, false, old_search_key, pointer)?;
+        self.log_entry_change(txn, true, new_search_key, pointer)?;
+        self.modify(|state| {
+            if self.unique
+                && old_search_key != new_search_key
+                && self.contains_key(state, new_search_key)?
+            {
+                return Err(duplicate_key(new_search_key));
+            }
+            self.delete_latched(state, old_search_key, pointer)?;
+            self.insert_latched(state, new_search_key, pointer)
+        })
     }
 
     fn delete_entry(
@@ -78,11 +764,621 @@ impl<T: BufferPoolTrait> IndexFileTrait<T> for FixedIndexFile<T> {
         pointer: &[u8; INDEX_POINTER_SIZE],
         txn: &TransactionId,
     ) -> Result<ValueId, CrustyError> {
-        panic!("TODO milestone idx1");
+        self.lock_key(search_key, txn, Permissions::ReadWrite)?;
+        self.log_entry_change(txn, false, search_key, pointer)?;
+        self.remove_entry(search_key, pointer)
+    }
+
+    fn get_pointers_for_prefix(
+        &self,
+        prefix: &[u8],
+        _txn: &TransactionId,
+    ) -> Result<Vec<ValueId>, CrustyError> {
+        let scan = self.prefix_scan(prefix)?;
+        self.cursor_in(&self.bp, scan)?.collect()
     }
 
     fn get_pages_used(&self) -> usize {
-        panic!("TODO milestone idx1");
+        self.state.read().unwrap().pages_used
+    }
+}
+
+/// The error returned when deleting an entry that is not in the index
+pub(crate) fn entry_not_found() -> CrustyError {
+    CrustyError::CrustyError("Index entry not found".to_string())
+}
+
+/// The error returned when a unique index already holds a search key
+pub(crate) fn duplicate_key(search_key: &[u8; SEARCH_KEY_SIZE]) -> CrustyError {
+    CrustyError::DuplicateKey(format!(
+        "Search key {:?} is already in the index",
+        search_key
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buffer_pool::BufferPool;
+    use crate::index::fixed_hash::MAX_ORDERED_GLOBAL_DEPTH;
+    use crate::index::fixed_index_tests::{gen_unique_search_keys_and_value_ids, set_up_test_util};
+    use crate::index::STARTING_PAGE_CAPACITY;
+    use rand::{rngs::SmallRng, seq::SliceRandom, Rng, SeedableRng};
+    use std::collections::HashMap;
+    use txn_manager::lm_trait::LockManagerTrait;
+
+    fn new_index(supports_range: bool) -> (FixedIndexFile<BufferPool>, TransactionId) {
+        let (bp, lm, txn, _is_range, c_id, _dir) = set_up_test_util();
+        let idx = FixedIndexFile::new(c_id, bp, lm, supports_range, STARTING_PAGE_CAPACITY);
+        (idx, txn)
+    }
+
+    fn key(i: u16) -> [u8; SEARCH_KEY_SIZE] {
+        let mut k = [0; SEARCH_KEY_SIZE];
+        k[6..].copy_from_slice(&i.to_be_bytes());
+        k
+    }
+
+    #[test]
+    fn test_many_keys_both_variants() {
+        for supports_range in [true, false] {
+            let (idx, txn) = new_index(supports_range);
+            let mut recs = gen_unique_search_keys_and_value_ids(3000, 32, 9);
+            for (i, rec) in recs.iter_mut().enumerate() {
+                rec.0 = key(i as u16);
+            }
+            let mut rng = SmallRng::seed_from_u64(23530);
+            recs.shuffle(&mut rng);
+            for (k, _v_id, pointer) in recs.iter() {
+                idx.add(k, pointer, &txn).unwrap();
+            }
+            assert!(idx.get_pages_used() > STARTING_PAGE_CAPACITY as usize);
+            for (k, v_id, _pointer) in recs.iter() {
+                assert_eq!(idx.get_pointers_for_key(k, &txn).unwrap(), vec![*v_id]);
+            }
+            // Delete every other record and check the rest are still found
+            for (i, (k, v_id, pointer)) in recs.iter().enumerate() {
+                if i % 2 == 0 {
+                    idx.delete_entry(k, pointer, &txn).unwrap();
+                    assert!(idx.get_pointers_for_key(k, &txn).unwrap().is_empty());
+                    assert!(idx.delete_entry(k, pointer, &txn).is_err());
+                } else {
+                    assert_eq!(idx.get_pointers_for_key(k, &txn).unwrap(), vec![*v_id]);
+                }
+            }
+        }
     }
 
+    #[test]
+    fn test_many_duplicates_both_variants() {
+        // Far more entries for one key than fit on a page
+        for supports_range in [true, false] {
+            let (idx, txn) = new_index(supports_range);
+            let recs = gen_unique_search_keys_and_value_ids(1000, 32, 9);
+            for (i, (_k, _v_id, pointer)) in recs.iter().enumerate() {
+                idx.add(&key((i % 3) as u16), pointer, &txn).unwrap();
+            }
+            for i in 0..3 {
+                let pointers = idx.get_pointers_for_key(&key(i), &txn).unwrap();
+                let expected = (0..recs.len()).filter(|j| j % 3 == i as usize).count();
+                assert_eq!(pointers.len(), expected);
+                for (j, (_k, v_id, _pointer)) in recs.iter().enumerate() {
+                    assert_eq!(pointers.contains(v_id), j % 3 == i as usize);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_tree_splits_inner_pages() {
+        let (idx, txn) = new_index(true);
+        // Ascending inserts leave half full leaves, so this needs three levels
+        let n = 30000;
+        let recs = gen_unique_search_keys_and_value_ids(n, 32, 9);
+        for (i, (_k, _v_id, pointer)) in recs.iter().enumerate() {
+            idx.add(&key(i as u16), pointer, &txn).unwrap();
+        }
+        let root = idx.state().root;
+        let page = idx.bp.get_page_for_read(&idx.page_id(root)).unwrap();
+        assert!(!page.is_leaf);
+        let child = page.page_pointer.unwrap();
+        drop(page);
+        assert!(
+            !idx.bp
+                .get_page_for_read(&idx.page_id(child))
+                .unwrap()
+                .is_leaf
+        );
+        let all = idx
+            .get_pointers_for_key_range(&key(0), &key(u16::MAX), &txn)
+            .unwrap();
+        let expected: Vec<ValueId> = recs.iter().map(|r| r.1).collect();
+        assert_eq!(all, expected);
+        for i in (0..n).step_by(997) {
+            assert_eq!(
+                idx.get_pointers_for_key(&key(i as u16), &txn).unwrap(),
+                vec![recs[i].1]
+            );
+        }
+    }
+
+    #[test]
+    fn test_range_and_update() {
+        let (idx, txn) = new_index(true);
+        let recs = gen_unique_search_keys_and_value_ids(500, 32, 9);
+        for (i, (_k, _v_id, pointer)) in recs.iter().enumerate() {
+            idx.add(&key(i as u16), pointer, &txn).unwrap();
+        }
+        let range = idx
+            .get_pointers_for_key_range(&key(100), &key(300), &txn)
+            .unwrap();
+        let expected: Vec<ValueId> = recs[100..300].iter().map(|r| r.1).collect();
+        assert_eq!(range, expected);
+        // Move record 150 past the end of the range
+        idx.update_key(&key(150), &key(1000), &recs[150].2, &txn)
+            .unwrap();
+        let range = idx
+            .get_pointers_for_key_range(&key(100), &key(300), &txn)
+            .unwrap();
+        assert_eq!(range.len(), 199);
+        assert!(!range.contains(&recs[150].1));
+        assert_eq!(
+            idx.get_pointers_for_key(&key(1000), &txn).unwrap(),
+            vec![recs[150].1]
+        );
+
+        let (hash_idx, txn) = new_index(false);
+        assert!(matches!(
+            hash_idx.get_pointers_for_key_range(&key(0), &key(1), &txn),
+            Err(CrustyError::InvalidOperation)
+        ));
+    }
+
+    #[test]
+    fn test_unique_both_variants() {
+        for supports_range in [true, false] {
+            let (bp, lm, txn, _is_range, c_id, _dir) = set_up_test_util();
+            let idx =
+                FixedIndexFile::create(c_id, bp, lm, supports_range, true, STARTING_PAGE_CAPACITY)
+                    .unwrap();
+            let recs = gen_unique_search_keys_and_value_ids(10, 32, 9);
+            for (i, (_k, _v_id, pointer)) in recs[..5].iter().enumerate() {
+                idx.add(&key(i as u16), pointer, &txn).unwrap();
+            }
+            assert!(matches!(
+                idx.add(&key(2), &recs[5].2, &txn),
+                Err(CrustyError::DuplicateKey(_))
+            ));
+            // A batch with a duplicate, inside it or against the index, adds nothing
+            for keys in [[key(5), key(6), key(5)], [key(5), key(6), key(0)]] {
+                let pointers = recs[5..8].iter().map(|r| r.2).collect();
+                assert!(matches!(
+                    idx.bulk_add(keys.iter().collect(), pointers, &txn),
+                    Err(CrustyError::DuplicateKey(_))
+                ));
+                assert!(idx.get_pointers_for_key(&key(6), &txn).unwrap().is_empty());
+            }
+            assert!(matches!(
+                idx.update_key(&key(0), &key(1), &recs[0].2, &txn),
+                Err(CrustyError::DuplicateKey(_))
+            ));
+            assert_eq!(
+                idx.get_pointers_for_key(&key(0), &txn).unwrap(),
+                vec![recs[0].1]
+            );
+            // Keeping the same key or moving to a free one is fine
+            idx.update_key(&key(0), &key(0), &recs[0].2, &txn).unwrap();
+            idx.update_key(&key(0), &key(9), &recs[0].2, &txn).unwrap();
+            idx.add(&key(0), &recs[5].2, &txn).unwrap();
+            idx.delete_entry(&key(1), &recs[1].2, &txn).unwrap();
+            idx.add(&key(1), &recs[6].2, &txn).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_cursors_pin_one_page() {
+        // Too few frames to pin every leaf at once
+        let lm = Arc::new(LockManager::new(100));
+        let dir = crate::test_util::gen_random_test_dir();
+        let bp = Arc::new(BufferPool::with_frames(
+            lm.clone(),
+            &dir,
+            crate::replacement_policy::ReplacementPolicyType::default(),
+            6,
+        ));
+        let txn = TransactionId::new();
+        for supports_range in [true, false] {
+            let c_id = bp.register_container(None, StateType::Tree).unwrap();
+            let idx = FixedIndexFile::new(c_id, bp.clone(), lm.clone(), supports_range, 2);
+            let recs = gen_unique_search_keys_and_value_ids(5000, 32, 9);
+            for (i, (_k, _v_id, pointer)) in recs.iter().enumerate() {
+                idx.add(&key((i % 2500) as u16), pointer, &txn).unwrap();
+            }
+            assert!(idx.get_pages_used() > 6);
+            let found: Vec<ValueId> = idx
+                .key_cursor(&key(7), &txn)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(found, vec![recs[7].1, recs[2507].1]);
+            if !supports_range {
+                assert!(idx.range_cursor(&key(0), &key(1), &txn).is_err());
+                continue;
+            }
+            let mut cursor = idx.range_cursor(&key(100), &key(2000), &txn).unwrap();
+            let first: Vec<ValueId> = cursor.by_ref().take(3).map(|r| r.unwrap()).collect();
+            assert_eq!(first, vec![recs[100].1, recs[2600].1, recs[101].1]);
+            assert_eq!(cursor.count(), 1900 * 2 - 3);
+            // A dropped cursor releases its page for writers
+            let mut cursor = idx.range_cursor(&key(0), &key(10), &txn).unwrap();
+            cursor.next().unwrap().unwrap();
+            drop(cursor);
+            idx.add(&key(0), &recs[0].2, &txn).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_tree_bulk_load() {
+        let (idx, txn) = new_index(true);
+        let n = 20000;
+        let mut recs = gen_unique_search_keys_and_value_ids(n, 32, 9);
+        for (i, rec) in recs.iter_mut().enumerate() {
+            // Pairs of duplicates
+            rec.0 = key((i / 2) as u16);
+        }
+        let mut rng = SmallRng::seed_from_u64(23530);
+        recs.shuffle(&mut rng);
+        assert!(idx
+            .bulk_add_with_fill(vec![&recs[0].0], vec![recs[0].2], 1.5)
+            .is_err());
+        let ids = idx
+            .bulk_add_with_fill(
+                recs.iter().map(|r| &r.0).collect(),
+                recs.iter().map(|r| r.2).collect(),
+                0.5,
+            )
+            .unwrap();
+        // Each returned id holds the entry given at its position
+        for ((k, _v_id, pointer), id) in recs.iter().zip(ids.iter()) {
+            let page = idx.bp.get_page_for_read(id).unwrap();
+            assert_eq!(page.get_entry(id.slot_id.unwrap()), Some((*k, *pointer)));
+        }
+        // Half full leaves, with a root above the inner level
+        let per_leaf = (INDEX_VALUE_COUNT as f64 * 0.5) as usize;
+        assert!(idx.get_pages_used() >= n / per_leaf);
+        let root = idx.state().root;
+        let page = idx.bp.get_page_for_read(&idx.page_id(root)).unwrap();
+        assert!(!page.is_leaf);
+        drop(page);
+
+        let all = idx
+            .get_pointers_for_key_range(&key(0), &key(u16::MAX), &txn)
+            .unwrap();
+        assert_eq!(all.len(), n);
+        for i in (0..n / 2).step_by(101) {
+            let mut found = idx.get_pointers_for_key(&key(i as u16), &txn).unwrap();
+            let mut expected: Vec<ValueId> = recs
+                .iter()
+                .filter(|r| r.0 == key(i as u16))
+                .map(|r| r.1)
+                .collect();
+            found.sort_by_key(|v| (v.page_id, v.slot_id));
+            expected.sort_by_key(|v| (v.page_id, v.slot_id));
+            assert_eq!(found, expected);
+        }
+        // The loaded tree takes ordinary inserts and deletes, and a second
+        // bulk add goes through them too
+        let extra = gen_unique_search_keys_and_value_ids(600, 32, 10);
+        idx.bulk_add(
+            extra.iter().map(|_| &recs[0].0).collect(),
+            extra.iter().map(|r| r.2).collect(),
+            &txn,
+        )
+        .unwrap();
+        assert_eq!(
+            idx.get_pointers_for_key(&recs[0].0, &txn).unwrap().len(),
+            602
+        );
+        idx.delete_entry(&recs[1].0, &recs[1].2, &txn).unwrap();
+        assert_eq!(
+            idx.get_pointers_for_key_range(&key(0), &key(u16::MAX), &txn)
+                .unwrap()
+                .len(),
+            n + 600 - 1
+        );
+    }
+
+    #[test]
+    fn test_bounded_and_reverse_scans() {
+        let (idx, txn) = new_index(true);
+        // Runs of 30 duplicates span leaves
+        let recs = gen_unique_search_keys_and_value_ids(12000, 32, 9);
+        let mut expected: Vec<([u8; SEARCH_KEY_SIZE], ValueId)> = Vec::new();
+        let mut rng = SmallRng::seed_from_u64(23530);
+        let mut order: Vec<usize> = (0..recs.len()).collect();
+        order.shuffle(&mut rng);
+        for i in order {
+            let k = key((i / 30) as u16);
+            idx.add(&k, &recs[i].2, &txn).unwrap();
+            expected.push((k, recs[i].1));
+        }
+        // Deletes leave some leaves merged or redistributed
+        for (i, (k, v_id)) in expected.clone().iter().enumerate() {
+            if i % 7 == 0 {
+                idx.delete_entry(k, &v_id.to_fixed_bytes(), &txn).unwrap();
+            }
+        }
+        let mut i = 0;
+        expected.retain(|_| {
+            i += 1;
+            (i - 1) % 7 != 0
+        });
+        let keys: HashMap<ValueId, [u8; SEARCH_KEY_SIZE]> =
+            expected.iter().map(|(k, v_id)| (*v_id, *k)).collect();
+
+        let bounds = [
+            Bound::Unbounded,
+            Bound::Included(key(0)),
+            Bound::Excluded(key(0)),
+            Bound::Included(key(17)),
+            Bound::Excluded(key(17)),
+            Bound::Included(key(250)),
+            Bound::Excluded(key(399)),
+            Bound::Included(key(500)),
+        ];
+        for min in bounds {
+            for max in bounds {
+                let forward: Vec<ValueId> = idx
+                    .bounded_cursor(min, max, false, &txn)
+                    .unwrap()
+                    .collect::<Result<_, _>>()
+                    .unwrap();
+                let mut reverse: Vec<ValueId> = idx
+                    .bounded_cursor(min, max, true, &txn)
+                    .unwrap()
+                    .collect::<Result<_, _>>()
+                    .unwrap();
+                // Reverse is exactly the forward order backwards
+                reverse.reverse();
+                assert_eq!(forward, reverse);
+                let mut want: Vec<([u8; SEARCH_KEY_SIZE], ValueId)> = expected
+                    .iter()
+                    .filter(|(k, _)| (min, max).contains(k))
+                    .copied()
+                    .collect();
+                want.sort_by_key(|(k, v_id)| (*k, v_id.to_fixed_bytes()));
+                let mut got: Vec<([u8; SEARCH_KEY_SIZE], ValueId)> =
+                    forward.iter().map(|v_id| (keys[v_id], *v_id)).collect();
+                assert!(got.windows(2).all(|w| w[0].0 <= w[1].0));
+                got.sort_by_key(|(k, v_id)| (*k, v_id.to_fixed_bytes()));
+                assert_eq!(got, want);
+            }
+        }
+
+        // Keys come back in descending order
+        let first: Vec<ValueId> = idx
+            .bounded_cursor(Bound::Unbounded, Bound::Unbounded, true, &txn)
+            .unwrap()
+            .take(5)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        for v_id in first {
+            assert_eq!(keys[&v_id], key(399));
+        }
+
+        let (hash, txn) = new_index(false);
+        assert!(hash
+            .bounded_cursor(Bound::Unbounded, Bound::Unbounded, true, &txn)
+            .is_err());
+    }
+
+    #[test]
+    fn test_prefix_search() {
+        let (idx, txn) = new_index(true);
+        // Two byte tenant ids, each with keys spread over the other bytes
+        let recs = gen_unique_search_keys_and_value_ids(6000, 32, 9);
+        let mut by_tenant: HashMap<u16, Vec<ValueId>> = HashMap::new();
+        for (i, (_k, v_id, pointer)) in recs.iter().enumerate() {
+            let tenant = [0x00, 0x01, 0xFE, 0xFF][i % 4] as u16 * 0x101;
+            let mut k = [0; SEARCH_KEY_SIZE];
+            k[..2].copy_from_slice(&tenant.to_be_bytes());
+            k[2..].copy_from_slice(&((i * 7919) as u64).to_be_bytes()[2..]);
+            idx.add(&k, pointer, &txn).unwrap();
+            by_tenant.entry(tenant).or_default().push(*v_id);
+        }
+        for (tenant, v_ids) in by_tenant.iter() {
+            let found = idx
+                .get_pointers_for_prefix(&tenant.to_be_bytes(), &txn)
+                .unwrap();
+            assert_eq!(found.len(), v_ids.len());
+            assert!(v_ids.iter().all(|v_id| found.contains(v_id)));
+            // A one byte prefix covers the same tenant here
+            let found = idx
+                .get_pointers_for_prefix(&tenant.to_be_bytes()[..1], &txn)
+                .unwrap();
+            assert_eq!(found.len(), v_ids.len());
+        }
+        assert!(idx
+            .get_pointers_for_prefix(&[0x02], &txn)
+            .unwrap()
+            .is_empty());
+        assert_eq!(idx.get_pointers_for_prefix(&[], &txn).unwrap().len(), 6000);
+        assert!(idx
+            .get_pointers_for_prefix(&[0; SEARCH_KEY_SIZE + 1], &txn)
+            .is_err());
+
+        let (hash, txn) = new_index(false);
+        assert!(matches!(
+            hash.get_pointers_for_prefix(&[0], &txn),
+            Err(CrustyError::InvalidOperation)
+        ));
+    }
+
+    /// Check a range result holds exactly the entries of `all` between the
+    /// bounds, in key order
+    fn check_range(
+        found: &[ValueId],
+        all: &[([u8; SEARCH_KEY_SIZE], ValueId)],
+        min: Bound<[u8; SEARCH_KEY_SIZE]>,
+        max: Bound<[u8; SEARCH_KEY_SIZE]>,
+    ) {
+        let keys: HashMap<ValueId, [u8; SEARCH_KEY_SIZE]> =
+            all.iter().map(|(k, v_id)| (*v_id, *k)).collect();
+        let found_keys: Vec<[u8; SEARCH_KEY_SIZE]> = found.iter().map(|v_id| keys[v_id]).collect();
+        assert!(found_keys.windows(2).all(|w| w[0] <= w[1]));
+        let mut want: Vec<ValueId> = all
+            .iter()
+            .filter(|(k, _)| (min, max).contains(k))
+            .map(|(_, v_id)| *v_id)
+            .collect();
+        let mut found = found.to_vec();
+        want.sort_by_key(|v_id| v_id.to_fixed_bytes());
+        found.sort_by_key(|v_id| v_id.to_fixed_bytes());
+        assert_eq!(found, want);
+    }
+
+    #[test]
+    fn test_ordered_hash() {
+        let (bp, lm, txn, _is_range, c_id, _dir) = set_up_test_util();
+        let idx =
+            FixedIndexFile::create_ordered_hash(c_id, bp.clone(), lm.clone(), false, 4).unwrap();
+        let mut rng = SmallRng::seed_from_u64(23530);
+        let recs = gen_unique_search_keys_and_value_ids(6000, 32, 9);
+        let mut all: Vec<([u8; SEARCH_KEY_SIZE], ValueId)> = Vec::new();
+        for (i, (_k, v_id, pointer)) in recs.iter().enumerate() {
+            // Some keys repeat
+            let k = if i % 5 == 4 {
+                all[i - 1].0
+            } else {
+                rng.gen::<u64>().to_be_bytes()
+            };
+            idx.add(&k, pointer, &txn).unwrap();
+            all.push((k, *v_id));
+        }
+        assert!(idx.state().global_depth > 2);
+        let mut sorted: Vec<[u8; SEARCH_KEY_SIZE]> = all.iter().map(|(k, _)| *k).collect();
+        sorted.sort();
+        let bounds = [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(sorted[100]), Bound::Excluded(sorted[3000])),
+            (Bound::Excluded(sorted[2999]), Bound::Included(sorted[3010])),
+            (Bound::Unbounded, Bound::Included(sorted[0])),
+            (Bound::Included(sorted[5990]), Bound::Unbounded),
+        ];
+        for (min, max) in bounds {
+            let found: Vec<ValueId> = idx
+                .bounded_cursor(min, max, false, &txn)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            check_range(&found, &all, min, max);
+        }
+        let found = idx
+            .get_pointers_for_key_range(&sorted[10], &sorted[20], &txn)
+            .unwrap();
+        check_range(
+            &found,
+            &all,
+            Bound::Included(sorted[10]),
+            Bound::Excluded(sorted[20]),
+        );
+        assert!(idx
+            .bounded_cursor(Bound::Unbounded, Bound::Unbounded, true, &txn)
+            .is_err());
+
+        // Deletes leave holes in the sorted buckets
+        for (k, v_id) in all.iter().step_by(3) {
+            idx.delete_entry(k, &v_id.to_fixed_bytes(), &txn).unwrap();
+        }
+        let mut i = 0;
+        all.retain(|_| {
+            i += 1;
+            (i - 1) % 3 != 0
+        });
+        for (k, v_id) in all.iter().take(100) {
+            assert!(idx.get_pointers_for_key(k, &txn).unwrap().contains(v_id));
+        }
+
+        // Reopened from its header, the index is still ordered
+        drop(idx);
+        let idx: FixedIndexFile<BufferPool> = FixedIndexFile::open(c_id, bp, lm).unwrap();
+        assert!(idx.is_ordered());
+        let found = idx
+            .get_pointers_for_key_range(&sorted[0], &sorted[5999], &txn)
+            .unwrap();
+        check_range(
+            &found,
+            &all,
+            Bound::Included(sorted[0]),
+            Bound::Excluded(sorted[5999]),
+        );
+    }
+
+    #[test]
+    fn test_ordered_hash_shared_leading_bytes() {
+        // Small integer keys share their leading bytes, so they end up in one
+        // bucket's chain, which must stay sorted
+        let (bp, lm, txn, _is_range, c_id, _dir) = set_up_test_util();
+        let idx = FixedIndexFile::create_ordered_hash(c_id, bp, lm, false, 4).unwrap();
+        let mut recs = gen_unique_search_keys_and_value_ids(1000, 32, 9);
+        let mut rng = SmallRng::seed_from_u64(23530);
+        recs.shuffle(&mut rng);
+        let mut all = Vec::new();
+        for (i, (_k, v_id, pointer)) in recs.iter().enumerate() {
+            let k = key(i as u16);
+            idx.add(&k, pointer, &txn).unwrap();
+            all.push((k, *v_id));
+        }
+        assert!(idx.state().global_depth <= MAX_ORDERED_GLOBAL_DEPTH);
+        let found = idx
+            .get_pointers_for_key_range(&key(250), &key(750), &txn)
+            .unwrap();
+        check_range(
+            &found,
+            &all,
+            Bound::Included(key(250)),
+            Bound::Excluded(key(750)),
+        );
+        for (k, v_id) in all.iter() {
+            assert_eq!(idx.get_pointers_for_key(k, &txn).unwrap(), vec![*v_id]);
+        }
+    }
+
+    #[test]
+    fn test_full_scan_key_range() {
+        let (idx, txn) = new_index(false);
+        let recs = gen_unique_search_keys_and_value_ids(3000, 32, 9);
+        let mut all = Vec::new();
+        for (i, (_k, v_id, pointer)) in recs.iter().enumerate() {
+            let k = key((i % 1000) as u16);
+            idx.add(&k, pointer, &txn).unwrap();
+            all.push((k, *v_id));
+        }
+        assert!(matches!(
+            idx.get_pointers_for_key_range(&key(0), &key(10), &txn),
+            Err(CrustyError::InvalidOperation)
+        ));
+        for (min, max) in [
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Included(key(10)), Bound::Excluded(key(500))),
+            (Bound::Excluded(key(998)), Bound::Unbounded),
+        ] {
+            let found = idx.full_scan_key_range(min, max, &txn).unwrap();
+            check_range(&found, &all, min, max);
+        }
+        // A tree gives the same answer
+        let (tree, txn) = new_index(true);
+        for (k, v_id) in all.iter() {
+            tree.add(k, &v_id.to_fixed_bytes(), &txn).unwrap();
+        }
+        let found = tree
+            .full_scan_key_range(Bound::Included(key(10)), Bound::Excluded(key(500)), &txn)
+            .unwrap();
+        check_range(
+            &found,
+            &all,
+            Bound::Included(key(10)),
+            Bound::Excluded(key(500)),
+        );
+    }
 }
@@ -0,0 +1,326 @@
+use crate::buffer_pool::BufferPoolTrait;
+use crate::index::fixed_index_file::{FixedIndexFile, IndexState};
+use crate::prelude::*;
+use common::prelude::*;
+
+/// Every index container starts with its header page
+pub const HEADER_PAGE_ID: PageId = 0;
+
+/// Bumped whenever the header layout changes
+pub const INDEX_FORMAT_VERSION: u16 = 1;
+
+/// Marks page 0 of a container as an index header
+const HEADER_MAGIC: &[u8; 4] = b"CIDX";
+
+const VERSION_OFFSET: usize = 4;
+const KIND_OFFSET: usize = 6;
+const UNIQUE_OFFSET: usize = 7;
+const ROOT_OFFSET: usize = 8;
+const DEPTH_OFFSET: usize = 12;
+const ENTRY_COUNT_OFFSET: usize = 16;
+const PAGES_USED_OFFSET: usize = 24;
+const DIRECTORY_LEN_OFFSET: usize = 28;
+const FREE_LEN_OFFSET: usize = 32;
+/// Where the page id list starts on the header page
+const LIST_OFFSET: usize = 36;
+
+const ID_SIZE: usize = std::mem::size_of::<PageId>();
+
+/// The index kinds stored in the header
+const KIND_HASH: u8 = 0;
+const KIND_TREE: u8 = 1;
+const KIND_ORDERED_HASH: u8 = 2;
+
+/// The configuration and state of an index read back from its header page
+pub struct IndexHeader {
+    pub version: u16,
+    pub supports_range: bool,
+    pub unique: bool,
+    pub ordered: bool,
+    pub entry_count: u64,
+    pub state: IndexState,
+}
+
+/// Header page operations.
+///
+/// Page 0 of an index container describes the index, so it can be reopened
+/// from the container alone. All values are little endian:
+///
+/// | bytes   | field                                   |
+/// |---------|-----------------------------------------|
+/// | 0..4    | magic `CIDX`                            |
+/// | 4..6    | format version                          |
+/// | 6       | 0 hash table, 1 tree, 2 ordered hash    |
+/// | 7       | 1 if the index is unique                |
+/// | 8..12   | tree root page                          |
+/// | 12..16  | hash global depth                       |
+/// | 16..24  | entry count                             |
+/// | 24..28  | pages used, including the header pages  |
+/// | 28..32  | hash directory length                   |
+/// | 32..36  | free page count                         |
+/// | 36..    | hash directory then free pages          |
+///
+/// The page id list continues on a chain of header pages linked through
+/// `overflow_pointer`, which use their whole data array for it. The chain
+/// grows as needed and is never shortened.
+impl<T: BufferPoolTrait> FixedIndexFile<T> {
+    /// Allocate the header page. It must be the first page of the container.
+    pub(crate) fn init_header(&self, state: &mut IndexState) -> Result<(), CrustyError> {
+        let (p_id, _page) = self.bp.new_page(self.c_id)?;
+        if p_id != HEADER_PAGE_ID {
+            return Err(CrustyError::CrustyError(format!(
+                "Index container {:?} already has pages",
+                self.c_id
+            )));
+        }
+        state.pages_used += 1;
+        Ok(())
+    }
+
+    /// The header continuation pages, in chain order
+    pub(crate) fn header_chain(&self) -> Result<Vec<PageId>, CrustyError> {
+        let mut chain = Vec::new();
+        let mut next = self
+            .bp
+            .get_page_for_read(&self.page_id(HEADER_PAGE_ID))?
+            .overflow_pointer;
+        while let Some(p_id) = next {
+            chain.push(p_id);
+            next = self
+                .bp
+                .get_page_for_read(&self.page_id(p_id))?
+                .overflow_pointer;
+        }
+        Ok(chain)
+    }
+
+    /// Write the state to the header pages, extending the chain if the page
+    /// id list no longer fits
+    pub(crate) fn write_header(&self, state: &mut IndexState) -> Result<(), CrustyError> {
+        let list_len = state.directory.len() + state.free_pages.len();
+        let first_capacity = (PAGE_SIZE - LIST_OFFSET) / ID_SIZE;
+        let needed = list_len
+            .saturating_sub(first_capacity)
+            .div_ceil(PAGE_SIZE / ID_SIZE);
+        let mut chain = self.header_chain()?;
+        while chain.len() < needed {
+            let (p_id, _page) = self.bp.new_page(self.c_id)?;
+            state.pages_used += 1;
+            let last = chain.last().copied().unwrap_or(HEADER_PAGE_ID);
+            self.bp
+                .get_page_for_write(&self.page_id(last))?
+                .overflow_pointer = Some(p_id);
+            chain.push(p_id);
+        }
+
+        let mut page = self.bp.get_page_for_write(&self.page_id(HEADER_PAGE_ID))?;
+        page.write_bytes(0, HEADER_MAGIC)?;
+        page.write_bytes(VERSION_OFFSET, &INDEX_FORMAT_VERSION.to_le_bytes())?;
+        let kind = match (self.supports_range, self.ordered) {
+            (true, _) => KIND_TREE,
+            (false, false) => KIND_HASH,
+            (false, true) => KIND_ORDERED_HASH,
+        };
+        page.write_bytes(KIND_OFFSET, &[kind])?;
+        page.write_bytes(UNIQUE_OFFSET, &[self.unique as u8])?;
+        let fields = [
+            (ROOT_OFFSET, state.root),
+            (DEPTH_OFFSET, state.global_depth as u32),
+            (PAGES_USED_OFFSET, state.pages_used as u32),
+            (DIRECTORY_LEN_OFFSET, state.directory.len() as u32),
+            (FREE_LEN_OFFSET, state.free_pages.len() as u32),
+        ];
+        for (os, value) in fields {
+            page.write_bytes(os, &value.to_le_bytes())?;
+        }
+        page.write_bytes(ENTRY_COUNT_OFFSET, &self.entry_count().to_le_bytes())?;
+
+        // The page id list fills the rest of the header page, then the chain pages
+        let ids: Vec<u8> = state
+            .directory
+            .iter()
+            .chain(state.free_pages.iter())
+            .flat_map(|p_id| p_id.to_le_bytes())
+            .collect();
+        let (first, mut rest) = ids.split_at(ids.len().min(first_capacity * ID_SIZE));
+        page.write_bytes(LIST_OFFSET, first)?;
+        drop(page);
+        for p_id in chain {
+            if rest.is_empty() {
+                break;
+            }
+            let (part, left) = rest.split_at(rest.len().min(PAGE_SIZE / ID_SIZE * ID_SIZE));
+            self.bp
+                .get_page_for_write(&self.page_id(p_id))?
+                .write_bytes(0, part)?;
+            rest = left;
+        }
+        Ok(())
+    }
+
+    /// Write only the entry count, after a change made with the state latched
+    /// shared. The count is read with the header page latched, so the last
+    /// of several concurrent writes has the latest count.
+    pub(crate) fn write_entry_count(&self) -> Result<(), CrustyError> {
+        let mut page = self.bp.get_page_for_write(&self.page_id(HEADER_PAGE_ID))?;
+        page.write_bytes(ENTRY_COUNT_OFFSET, &self.entry_count().to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Read the header pages of an index container
+    pub(crate) fn read_header(&self) -> Result<IndexHeader, CrustyError> {
+        let page = self.bp.get_page_for_read(&self.page_id(HEADER_PAGE_ID))?;
+        if &page.data[..VERSION_OFFSET] != HEADER_MAGIC {
+            return Err(CrustyError::SerializationError(format!(
+                "Container {:?} has no index header",
+                self.c_id
+            )));
+        }
+        let u32_at = |os: usize| u32::from_le_bytes(page.data[os..os + 4].try_into().unwrap());
+        let version =
+            u16::from_le_bytes(page.data[VERSION_OFFSET..KIND_OFFSET].try_into().unwrap());
+        if version != INDEX_FORMAT_VERSION {
+            return Err(CrustyError::SerializationError(format!(
+                "Index container {:?} has format version {}, expected {}",
+                self.c_id, version, INDEX_FORMAT_VERSION
+            )));
+        }
+        let directory_len = u32_at(DIRECTORY_LEN_OFFSET) as usize;
+        let free_len = u32_at(FREE_LEN_OFFSET) as usize;
+        let entry_count = u64::from_le_bytes(
+            page.data[ENTRY_COUNT_OFFSET..PAGES_USED_OFFSET]
+                .try_into()
+                .unwrap(),
+        );
+        let mut state = IndexState {
+            root: u32_at(ROOT_OFFSET),
+            global_depth: u32_at(DEPTH_OFFSET) as usize,
+            pages_used: u32_at(PAGES_USED_OFFSET) as usize,
+            ..IndexState::default()
+        };
+        let (supports_range, ordered) = match page.data[KIND_OFFSET] {
+            KIND_HASH => (false, false),
+            KIND_TREE => (true, false),
+            KIND_ORDERED_HASH => (false, true),
+            kind => {
+                return Err(CrustyError::SerializationError(format!(
+                    "Index container {:?} has unknown index kind {}",
+                    self.c_id, kind
+                )))
+            }
+        };
+        let unique = page.data[UNIQUE_OFFSET] == 1;
+
+        let mut ids = Vec::with_capacity(directory_len + free_len);
+        let mut os = LIST_OFFSET;
+        let mut page = page;
+        while ids.len() < directory_len + free_len {
+            if os + ID_SIZE > PAGE_SIZE {
+                let next = page.overflow_pointer.ok_or_else(|| {
+                    CrustyError::SerializationError(format!(
+                        "Index container {:?} header chain is too short",
+                        self.c_id
+                    ))
+                })?;
+                page = self.bp.get_page_for_read(&self.page_id(next))?;
+                os = 0;
+            }
+            ids.push(PageId::from_le_bytes(
+                page.data[os..os + ID_SIZE].try_into().unwrap(),
+            ));
+            os += ID_SIZE;
+        }
+        state.free_pages = ids.split_off(directory_len);
+        state.directory = ids;
+        Ok(IndexHeader {
+            version,
+            supports_range,
+            unique,
+            ordered,
+            entry_count,
+            state,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buffer_pool::BufferPool;
+    use crate::index::fixed_index_tests::{gen_unique_search_keys_and_value_ids, set_up_test_util};
+    use crate::index::fixed_index_trait::IndexFileTrait;
+    use crate::index::STARTING_PAGE_CAPACITY;
+
+    #[test]
+    fn test_open_from_header_both_variants() {
+        for supports_range in [true, false] {
+            let (bp, lm, txn, _is_range, c_id, _dir) = set_up_test_util();
+            let idx = FixedIndexFile::create(
+                c_id,
+                bp.clone(),
+                lm.clone(),
+                supports_range,
+                true,
+                STARTING_PAGE_CAPACITY,
+            )
+            .unwrap();
+            let mut recs = gen_unique_search_keys_and_value_ids(3000, 32, 9);
+            for (i, rec) in recs.iter_mut().enumerate() {
+                rec.0 = (i as u64).to_be_bytes();
+            }
+            for (k, _v_id, pointer) in recs.iter() {
+                idx.add(k, pointer, &txn).unwrap();
+            }
+            for (k, _v_id, pointer) in recs.iter().step_by(3) {
+                idx.delete_entry(k, pointer, &txn).unwrap();
+            }
+            assert_eq!(idx.entry_count(), 2000);
+            let state = idx.state();
+            drop(idx);
+
+            let idx: FixedIndexFile<BufferPool> = FixedIndexFile::open(c_id, bp, lm).unwrap();
+            assert_eq!(idx.state(), state);
+            assert_eq!(idx.entry_count(), 2000);
+            assert_eq!(idx.supports_range(), supports_range);
+            assert!(idx.is_unique());
+            for (i, (k, v_id, _pointer)) in recs.iter().enumerate() {
+                let expected = if i % 3 == 0 { vec![] } else { vec![*v_id] };
+                assert_eq!(idx.get_pointers_for_key(k, &txn).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_header_chain() {
+        let (bp, lm, _txn, _is_range, c_id, _dir) = set_up_test_util();
+        let idx: FixedIndexFile<BufferPool> =
+            FixedIndexFile::create(c_id, bp.clone(), lm.clone(), false, false, 4).unwrap();
+        assert!(idx.header_chain().unwrap().is_empty());
+        // More page ids than fit on two pages
+        {
+            let mut state = idx.state.write().unwrap();
+            state.free_pages.extend(1000..4000);
+            idx.write_header(&mut state).unwrap();
+        }
+        assert_eq!(idx.header_chain().unwrap().len(), 2);
+        let header = idx.read_header().unwrap();
+        assert_eq!(header.version, INDEX_FORMAT_VERSION);
+        assert!(!header.supports_range);
+        assert_eq!(header.state, idx.state());
+
+        // The chain is kept when the list shrinks again
+        {
+            let mut state = idx.state.write().unwrap();
+            state.free_pages.clear();
+            idx.write_header(&mut state).unwrap();
+        }
+        assert_eq!(idx.header_chain().unwrap().len(), 2);
+        assert_eq!(idx.read_header().unwrap().state, idx.state());
+
+        // An unknown format version is rejected
+        bp.get_page_for_write(&idx.page_id(HEADER_PAGE_ID))
+            .unwrap()
+            .data[VERSION_OFFSET] += 1;
+        assert!(FixedIndexFile::<BufferPool>::open(c_id, bp, lm).is_err());
+    }
+}
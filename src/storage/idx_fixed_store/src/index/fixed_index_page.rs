@@ -1,2 +1,199 @@
+use crate::fixed_page::FixedPage;
+use crate::prelude::*;
 use common::prelude::*;
 
+/// A search key and the pointer stored with it. For leaf and bucket pages the
+/// pointer is the data record's ValueId. For inner tree pages it is the child page.
+pub type IndexEntry = ([u8; SEARCH_KEY_SIZE], [u8; INDEX_POINTER_SIZE]);
+
+/// Helpers for using a FixedPage as an index page. Tree pages keep their
+/// entries sorted by search key and packed into the leading slots. Hash bucket
+/// pages place entries in any free slot.
+pub trait IndexPage {
+    /// Clear the page and its pointers for reuse as a leaf or inner/bucket page
+    fn init_index_page(&mut self, is_leaf: bool) -> Result<(), CrustyError>;
+    /// The entry in a slot, if the slot is filled
+    fn get_entry(&self, slot: SlotId) -> Option<IndexEntry>;
+    /// All entries in slot order
+    fn entries(&self) -> Vec<IndexEntry>;
+    /// Replace the contents of the page with the entries, packed from slot 0
+    fn set_entries(&mut self, entries: &[IndexEntry]) -> Result<(), CrustyError>;
+    fn is_full(&self) -> bool;
+    /// Add an entry to the first free slot. None if the page is full.
+    fn add_entry(
+        &mut self,
+        key: &[u8; SEARCH_KEY_SIZE],
+        ptr: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<Option<SlotId>, CrustyError>;
+    /// Insert an entry into a sorted page after any entries with an equal key.
+    /// None if the page is full.
+    fn insert_sorted(
+        &mut self,
+        key: &[u8; SEARCH_KEY_SIZE],
+        ptr: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<Option<SlotId>, CrustyError>;
+    /// Insert an entry at a slot of a sorted page, shifting the later entries right
+    fn insert_at(
+        &mut self,
+        slot: SlotId,
+        key: &[u8; SEARCH_KEY_SIZE],
+        ptr: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<(), CrustyError>;
+    /// Remove the entry at a slot of a sorted page, shifting the later entries left
+    fn remove_at(&mut self, slot: SlotId) -> Result<(), CrustyError>;
+    /// The slot holding an exact key and pointer pair
+    fn find_entry(
+        &self,
+        key: &[u8; SEARCH_KEY_SIZE],
+        ptr: &[u8; INDEX_POINTER_SIZE],
+    ) -> Option<SlotId>;
+}
+
+/// Decode the child page of an inner tree page entry
+pub fn pointer_to_page(ptr: &[u8; INDEX_POINTER_SIZE]) -> PageId {
+    ValueId::from_bytes(ptr)
+        .page_id
+        .expect("Inner index entry without a page")
+}
+
+impl IndexPage for FixedPage {
+    fn init_index_page(&mut self, is_leaf: bool) -> Result<(), CrustyError> {
+        self.delete_all()?;
+        self.is_leaf = is_leaf;
+        self.page_pointer = None;
+        self.overflow_pointer = None;
+        self.extra = 0;
+        Ok(())
+    }
+
+    fn get_entry(&self, slot: SlotId) -> Option<IndexEntry> {
+        let (k, v) = self.get_kv(slot)?;
+        Some((k.try_into().unwrap(), v.try_into().unwrap()))
+    }
+
+    fn entries(&self) -> Vec<IndexEntry> {
+        (0..self.slot_capacity)
+            .filter_map(|slot| self.get_entry(slot))
+            .collect()
+    }
+
+    fn set_entries(&mut self, entries: &[IndexEntry]) -> Result<(), CrustyError> {
+        assert!(entries.len() <= self.slot_capacity as usize);
+        self.delete_all()?;
+        for (slot, (k, v)) in entries.iter().enumerate() {
+            self.write(slot as SlotId, true, k, v)?;
+        }
+        Ok(())
+    }
+
+    fn is_full(&self) -> bool {
+        self.get_free_slot_count() == 0
+    }
+
+    fn add_entry(
+        &mut self,
+        key: &[u8; SEARCH_KEY_SIZE],
+        ptr: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<Option<SlotId>, CrustyError> {
+        let Some(slot) = (0..self.slot_capacity).find(|s| self.free[*s as usize]) else {
+            return Ok(None);
+        };
+        self.write(slot, false, key, ptr)?;
+        Ok(Some(slot))
+    }
+
+    fn insert_sorted(
+        &mut self,
+        key: &[u8; SEARCH_KEY_SIZE],
+        ptr: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<Option<SlotId>, CrustyError> {
+        if self.is_full() {
+            return Ok(None);
+        }
+        let count = self.get_filled_slot_count() as SlotId;
+        let slot = (0..count)
+            .find(|s| self.get_entry(*s).unwrap().0 > *key)
+            .unwrap_or(count);
+        self.insert_at(slot, key, ptr)?;
+        Ok(Some(slot))
+    }
+
+    fn insert_at(
+        &mut self,
+        slot: SlotId,
+        key: &[u8; SEARCH_KEY_SIZE],
+        ptr: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<(), CrustyError> {
+        if slot < self.slot_capacity && !self.free[slot as usize] && !self.shift_all_right(slot)? {
+            return Err(CrustyError::StorageError);
+        }
+        self.write(slot, false, key, ptr)
+    }
+
+    fn remove_at(&mut self, slot: SlotId) -> Result<(), CrustyError> {
+        if slot >= self.slot_capacity || self.free[slot as usize] {
+            return Err(CrustyError::CrustyError("Slot is empty".to_string()));
+        }
+        self.delete(slot)?;
+        self.shift_all_left(slot)?;
+        Ok(())
+    }
+
+    fn find_entry(
+        &self,
+        key: &[u8; SEARCH_KEY_SIZE],
+        ptr: &[u8; INDEX_POINTER_SIZE],
+    ) -> Option<SlotId> {
+        (0..self.slot_capacity).find(|s| {
+            self.get_entry(*s)
+                .is_some_and(|(k, v)| k == *key && v == *ptr)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(k: u8, v: u8) -> IndexEntry {
+        ([k; SEARCH_KEY_SIZE], [v; INDEX_POINTER_SIZE])
+    }
+
+    #[test]
+    fn test_sorted_insert_and_remove() {
+        let mut page = FixedPage::new(0, SEARCH_KEY_SIZE, INDEX_POINTER_SIZE);
+        page.init_index_page(true).unwrap();
+        for (k, v) in [(5, 0), (1, 1), (9, 2), (5, 3)] {
+            let (k, v) = entry(k, v);
+            assert!(page.insert_sorted(&k, &v).unwrap().is_some());
+        }
+        assert_eq!(
+            page.entries(),
+            vec![entry(1, 1), entry(5, 0), entry(5, 3), entry(9, 2)]
+        );
+        let (k, v) = entry(5, 0);
+        let slot = page.find_entry(&k, &v).unwrap();
+        assert_eq!(slot, 1);
+        page.remove_at(slot).unwrap();
+        assert_eq!(page.entries(), vec![entry(1, 1), entry(5, 3), entry(9, 2)]);
+        assert!(page.remove_at(3).is_err());
+    }
+
+    #[test]
+    fn test_full_index_page() {
+        let mut page = FixedPage::new(0, SEARCH_KEY_SIZE, INDEX_POINTER_SIZE);
+        page.init_index_page(true).unwrap();
+        let capacity = page.slot_capacity as usize;
+        let entries: Vec<IndexEntry> = (0..capacity).map(|i| entry(i as u8, 0)).collect();
+        page.set_entries(&entries).unwrap();
+        assert!(page.is_full());
+        let (k, v) = entry(0, 1);
+        assert!(page.insert_sorted(&k, &v).unwrap().is_none());
+        assert!(page.add_entry(&k, &v).unwrap().is_none());
+        page.remove_at(0).unwrap();
+        assert_eq!(
+            page.add_entry(&k, &v).unwrap(),
+            Some(capacity as SlotId - 1)
+        );
+    }
+}
@@ -2,6 +2,8 @@ use std::sync::Arc;
 
 use crate::buffer_pool::{BufferPool, BufferPoolTrait};
 use crate::prelude::{INDEX_POINTER_SIZE, SEARCH_KEY_SIZE};
+use crate::replacement_policy::ReplacementPolicyType;
+use crate::test_util::{gen_random_test_dir, TestDir};
 use common::ids::{ContainerId, SlotId, StateType, TransactionId, ValueId};
 use common::testutil::init;
 use rand::rngs::SmallRng;
@@ -15,10 +17,16 @@ pub fn set_up_test_util() -> (
     TransactionId,
     bool,
     ContainerId,
+    TestDir,
 ) {
     init();
     let lm = Arc::new(LockManager::new(100));
-    let bp = Arc::new(BufferPool::new(lm.clone()));
+    let dir = gen_random_test_dir();
+    let bp = Arc::new(BufferPool::new(
+        lm.clone(),
+        &dir,
+        ReplacementPolicyType::default(),
+    ));
     let c_id = bp
         .register_container(None, StateType::HashTable)
         .expect("Got CID");
@@ -28,7 +36,7 @@ pub fn set_up_test_util() -> (
         StateType::HashTable => false,
         _ => panic!("Unsupported index type"),
     };
-    (bp, lm, txn, is_range, c_id)
+    (bp, lm, txn, is_range, c_id, dir)
 }
 
 pub fn gen_unique_search_keys_and_value_ids(
@@ -100,7 +108,7 @@ mod test {
 
     #[test]
     fn test_single_key_no_dupes() {
-        let (bp, lm, txn, is_range, idx_c_id) = set_up_test_util();
+        let (bp, lm, txn, is_range, idx_c_id, _dir) = set_up_test_util();
         let idx = FixedIndexFile::new(
             idx_c_id,
             bp.clone(),
@@ -127,7 +135,7 @@ mod test {
 
     #[test]
     fn test_two_keys_no_dupes() {
-        let (bp, lm, txn, is_range, idx_c_id) = set_up_test_util();
+        let (bp, lm, txn, is_range, idx_c_id, _dir) = set_up_test_util();
         let idx = FixedIndexFile::new(
             idx_c_id,
             bp.clone(),
@@ -156,7 +164,7 @@ mod test {
 
     #[test]
     fn test_one_key_dupes() {
-        let (bp, lm, txn, is_range, idx_c_id) = set_up_test_util();
+        let (bp, lm, txn, is_range, idx_c_id, _dir) = set_up_test_util();
         let idx = FixedIndexFile::new(
             idx_c_id,
             bp.clone(),
@@ -179,7 +187,7 @@ mod test {
 
     #[test]
     fn test_gen_distinct_records_per_search_key() {
-        let (bp, lm, txn, is_range, idx_c_id) = set_up_test_util();
+        let (bp, lm, txn, is_range, idx_c_id, _dir) = set_up_test_util();
         // Create Index
         let idx = FixedIndexFile::new(
             idx_c_id,
@@ -214,7 +222,7 @@ mod test {
 
     #[test]
     fn test_gen_multiple_records_per_search_key() {
-        let (bp, lm, txn, is_range, idx_c_id) = set_up_test_util();
+        let (bp, lm, txn, is_range, idx_c_id, _dir) = set_up_test_util();
         // Create Index
         let idx = FixedIndexFile::new(
             idx_c_id,
@@ -140,6 +140,23 @@ pub trait IndexFileTrait<T: BufferPoolTrait> {
         txn: &TransactionId,
     ) -> Result<Vec<ValueId>, CrustyError>;
 
+    /// Get the pointers for every key in the index that starts with a prefix
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - The leading bytes of the search keys to find, at most SEARCH_KEY_SIZE long. An empty prefix matches every key
+    /// * `txn` - The transaction id for the operation
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<ValueId>)` The vector of value ids of the entries in the data file, in search key order
+    /// * `Err(CrustyError)` if the lookup cannot be performed. If called on a non-range index, this should return a Err(CrustyError::InvalidOperation).
+    fn get_pointers_for_prefix(
+        &self,
+        prefix: &[u8],
+        txn: &TransactionId,
+    ) -> Result<Vec<ValueId>, CrustyError>;
+
     /// Get the number of pages used by the index. These pages may be empty, but the index should
     /// have allocated them and considers them available for use. Used for testing purposes.
     fn get_pages_used(&self) -> usize;
@@ -0,0 +1,854 @@
+use crate::buffer_frame::{FrameReadGuard, FrameWriteGuard};
+use crate::buffer_pool::BufferPoolTrait;
+use crate::fixed_page::FixedPage;
+use crate::index::fixed_index_file::{entry_not_found, FixedIndexFile, IndexState};
+use crate::index::fixed_index_page::{pointer_to_page, IndexEntry, IndexPage};
+use crate::prelude::*;
+use common::prelude::*;
+
+/// B+ tree operations for a range index.
+///
+/// Leaf pages hold (search key, record pointer) entries sorted by key, and
+/// `page_pointer` links each leaf to its right sibling. Inner pages hold
+/// (separator, child) entries where the child holds keys up to the separator,
+/// with `page_pointer` as the rightmost child. Runs of duplicate keys can be
+/// split across leaves, so lookups descend to the leftmost leaf that can hold
+/// the key and scan right, while inserts descend to the rightmost one.
+/// Every page other than the root is kept at least half full: deletes borrow
+/// from or merge with a sibling, and merged away pages are freed for reuse.
+///
+/// Concurrent writers use optimistic latch crabbing. An add or delete first
+/// descends with the index state latched shared, latching each page before
+/// releasing its parent, and latches the leaf exclusively. If the change fits
+/// in the leaf it is made there, alongside lookups and other such changes.
+/// Splits and merges change the structure, so a change that needs one is
+/// retried with the state latched exclusively, which keeps out everything
+/// but cursors already on the leaves.
+impl<T: BufferPoolTrait> FixedIndexFile<T> {
+    pub(crate) fn init_tree(&self, state: &mut IndexState) -> Result<(), CrustyError> {
+        let (root, _page) = self.alloc_page(state, true)?;
+        state.root = root;
+        Ok(())
+    }
+
+    /// The child of an inner page to follow for a key. With `leftmost` a key
+    /// equal to a separator goes left, as duplicates of it may be on both sides.
+    fn child_for(page: &FixedPage, key: &[u8; SEARCH_KEY_SIZE], leftmost: bool) -> PageId {
+        for (sep, ptr) in page.entries() {
+            if (leftmost && *key <= sep) || (!leftmost && *key < sep) {
+                return pointer_to_page(&ptr);
+            }
+        }
+        page.page_pointer
+            .expect("Inner index page without a rightmost child")
+    }
+
+    /// Descend from the root to the leaf for a key. Returns the inner pages on
+    /// the path (root first) and the leaf.
+    pub(crate) fn find_leaf(
+        &self,
+        state: &IndexState,
+        key: &[u8; SEARCH_KEY_SIZE],
+        leftmost: bool,
+    ) -> Result<(Vec<PageId>, PageId), CrustyError> {
+        let mut path = Vec::new();
+        let mut p_id = state.root;
+        loop {
+            let page = self.bp.get_page_for_read(&self.page_id(p_id))?;
+            if page.is_leaf {
+                return Ok((path, p_id));
+            }
+            path.push(p_id);
+            p_id = Self::child_for(&page, key, leftmost);
+        }
+    }
+
+    /// Descend to the leaf for a key with the state latched shared, and
+    /// latch it exclusively. The shared state latch keeps out splits and
+    /// merges, so the leaf still covers the key when it is relatched.
+    fn latch_leaf(
+        &self,
+        state: &IndexState,
+        key: &[u8; SEARCH_KEY_SIZE],
+        leftmost: bool,
+    ) -> Result<(PageId, FrameWriteGuard<'_>), CrustyError> {
+        let mut p_id = state.root;
+        let mut page = self.bp.get_page_for_read(&self.page_id(p_id))?;
+        while !page.is_leaf {
+            p_id = Self::child_for(&page, key, leftmost);
+            // The child is latched before the parent is released
+            page = self.bp.get_page_for_read(&self.page_id(p_id))?;
+        }
+        drop(page);
+        Ok((p_id, self.bp.get_page_for_write(&self.page_id(p_id))?))
+    }
+
+    /// Add an entry with the state latched shared if it fits in its leaf.
+    /// Returns None if the leaf is full and has to be split.
+    pub(crate) fn tree_try_add(
+        &self,
+        state: &IndexState,
+        key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<Option<ValueId>, CrustyError> {
+        let (leaf_id, mut leaf) = self.latch_leaf(state, key, false)?;
+        Ok(leaf
+            .insert_sorted(key, pointer)?
+            .map(|slot| self.entry_id(leaf_id, slot)))
+    }
+
+    /// Delete an entry with the state latched shared if its leaf stays at
+    /// least half full. Returns None if the leaf needs rebalancing, or if the
+    /// entry is not found so the exclusive retry reports it.
+    pub(crate) fn tree_try_delete(
+        &self,
+        state: &IndexState,
+        key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<Option<ValueId>, CrustyError> {
+        let mut path = Vec::new();
+        let Some((leaf_id, _slot)) =
+            Self::locate_entry(&self.bp, self.c_id, state.root, key, pointer, &mut path)?
+        else {
+            return Ok(None);
+        };
+        let mut leaf = self.bp.get_page_for_write(&self.page_id(leaf_id))?;
+        // Changes to the leaf since it was located may have moved the entry
+        let Some(slot) = leaf.find_entry(key, pointer) else {
+            return Ok(None);
+        };
+        if leaf_id != state.root && leaf.get_filled_slot_count() <= min_entries(&leaf) {
+            return Ok(None);
+        }
+        leaf.remove_at(slot)?;
+        Ok(Some(self.entry_id(leaf_id, slot)))
+    }
+
+    pub(crate) fn tree_add(
+        &self,
+        state: &mut IndexState,
+        key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<ValueId, CrustyError> {
+        let (path, leaf_id) = self.find_leaf(state, key, false)?;
+        let mut leaf = self.bp.get_page_for_write(&self.page_id(leaf_id))?;
+        if let Some(slot) = leaf.insert_sorted(key, pointer)? {
+            return Ok(self.entry_id(leaf_id, slot));
+        }
+
+        // The leaf is full, split it in half with the new entry in place
+        let mut entries = leaf.entries();
+        let pos = entries.partition_point(|(k, _)| k <= key);
+        entries.insert(pos, (*key, *pointer));
+        let mid = entries.len() / 2;
+        let (right_id, mut right) = self.alloc_page(state, true)?;
+        right.set_entries(&entries[mid..])?;
+        right.page_pointer = leaf.page_pointer;
+        leaf.set_entries(&entries[..mid])?;
+        leaf.page_pointer = Some(right_id);
+        drop(right);
+        drop(leaf);
+        self.insert_into_parent(state, path, leaf_id, entries[mid].0, right_id)?;
+        if pos < mid {
+            Ok(self.entry_id(leaf_id, pos as SlotId))
+        } else {
+            Ok(self.entry_id(right_id, (pos - mid) as SlotId))
+        }
+    }
+
+    /// After `left` was split, add the separator and the new `right` page to
+    /// the parent at the end of `path`, splitting parents as needed.
+    fn insert_into_parent(
+        &self,
+        state: &mut IndexState,
+        mut path: Vec<PageId>,
+        left: PageId,
+        sep: [u8; SEARCH_KEY_SIZE],
+        right: PageId,
+    ) -> Result<(), CrustyError> {
+        let left_ptr = self.page_id(left).to_fixed_bytes();
+        let right_ptr = self.page_id(right).to_fixed_bytes();
+        let Some(parent_id) = path.pop() else {
+            // The root was split, grow the tree by a level
+            let (root_id, mut root) = self.alloc_page(state, false)?;
+            root.set_entries(&[(sep, left_ptr)])?;
+            root.page_pointer = Some(right);
+            state.root = root_id;
+            return Ok(());
+        };
+
+        let mut parent = self.bp.get_page_for_write(&self.page_id(parent_id))?;
+        let mut entries = parent.entries();
+        match entries.iter().position(|(_, child)| *child == left_ptr) {
+            Some(i) => {
+                entries[i].1 = right_ptr;
+                entries.insert(i, (sep, left_ptr));
+            }
+            None => {
+                // left was the rightmost child
+                entries.push((sep, left_ptr));
+                parent.page_pointer = Some(right);
+            }
+        }
+        if entries.len() <= parent.slot_capacity as usize {
+            parent.set_entries(&entries)?;
+            return Ok(());
+        }
+
+        // Split the inner page, the middle separator moves up
+        let mid = entries.len() / 2;
+        let (new_id, mut new_page) = self.alloc_page(state, false)?;
+        new_page.set_entries(&entries[mid + 1..])?;
+        new_page.page_pointer = parent.page_pointer;
+        parent.set_entries(&entries[..mid])?;
+        parent.page_pointer = Some(pointer_to_page(&entries[mid].1));
+        drop(new_page);
+        drop(parent);
+        self.insert_into_parent(state, path, parent_id, entries[mid].0, new_id)
+    }
+
+    pub(crate) fn tree_delete(
+        &self,
+        state: &mut IndexState,
+        key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+    ) -> Result<ValueId, CrustyError> {
+        let mut path = Vec::new();
+        let (leaf_id, slot) =
+            Self::locate_entry(&self.bp, self.c_id, state.root, key, pointer, &mut path)?
+                .ok_or_else(entry_not_found)?;
+        self.bp
+            .get_page_for_write(&self.page_id(leaf_id))?
+            .remove_at(slot)?;
+        self.rebalance(state, path, leaf_id)?;
+        Ok(self.entry_id(leaf_id, slot))
+    }
+
+    /// Find the leaf and slot of an entry below `p_id`, pushing the inner
+    /// pages and child positions followed onto `path`. A run of duplicates
+    /// can span several children, so each child whose range covers the key
+    /// is searched in turn.
+    fn locate_entry(
+        bp: &T,
+        c_id: ContainerId,
+        p_id: PageId,
+        key: &[u8; SEARCH_KEY_SIZE],
+        pointer: &[u8; INDEX_POINTER_SIZE],
+        path: &mut Vec<(PageId, usize)>,
+    ) -> Result<Option<(PageId, SlotId)>, CrustyError> {
+        let page = bp.get_page_for_read(&ValueId::new_page(c_id, p_id))?;
+        if page.is_leaf {
+            return Ok(page.find_entry(key, pointer).map(|slot| (p_id, slot)));
+        }
+        let (seps, children) = inner_children(&page);
+        drop(page);
+        for (i, child) in children.into_iter().enumerate() {
+            // Child i holds keys from separator i - 1 up to separator i, inclusive
+            if i > 0 && *key < seps[i - 1] {
+                continue;
+            }
+            if i < seps.len() && *key > seps[i] {
+                continue;
+            }
+            path.push((p_id, i));
+            if let Some(found) = Self::locate_entry(bp, c_id, child, key, pointer, path)? {
+                return Ok(Some(found));
+            }
+            path.pop();
+        }
+        Ok(None)
+    }
+
+    /// Descend to the leftmost leaf that can hold a key, pushing the inner
+    /// pages and child positions followed onto `path`. Returns the leaf and
+    /// the slot of its first entry not below the key, which is the entry
+    /// count if there is none.
+    pub(crate) fn locate_key(
+        bp: &T,
+        c_id: ContainerId,
+        root: PageId,
+        key: &[u8; SEARCH_KEY_SIZE],
+        path: &mut Vec<(PageId, usize)>,
+    ) -> Result<(PageId, SlotId), CrustyError> {
+        let mut p_id = root;
+        loop {
+            let page = bp.get_page_for_read(&ValueId::new_page(c_id, p_id))?;
+            if page.is_leaf {
+                let entries = page.entries();
+                let slot = entries.partition_point(|(k, _)| k < key);
+                return Ok((p_id, slot as SlotId));
+            }
+            let (seps, children) = inner_children(&page);
+            let i = seps.partition_point(|sep| sep < key);
+            path.push((p_id, i));
+            p_id = children[i];
+        }
+    }
+
+    /// The leaf position of the entry before `entries` in key order, for
+    /// reverse cursors. `entries` are the entries a cursor read from a leaf,
+    /// in order, and the position is found from the first still in the tree
+    /// as those before it may have been deleted. Returns the leaf latched
+    /// shared with the slot to read, or None at the start of the tree. If
+    /// none are left this is the position before the first entry with the
+    /// first key, which skips any other entries with that key before them.
+    pub(crate) fn entry_before<'a>(
+        bp: &'a T,
+        c_id: ContainerId,
+        state: &IndexState,
+        entries: &[IndexEntry],
+    ) -> Result<Option<(FrameReadGuard<'a>, SlotId)>, CrustyError> {
+        let mut path = Vec::new();
+        let mut found = None;
+        for (key, pointer) in entries {
+            path.clear();
+            found = Self::locate_entry(bp, c_id, state.root, key, pointer, &mut path)?;
+            if found.is_some() {
+                break;
+            }
+        }
+        let (leaf_id, slot) = match found {
+            Some(found) => found,
+            None => {
+                path.clear();
+                Self::locate_key(bp, c_id, state.root, &entries[0].0, &mut path)?
+            }
+        };
+        if slot > 0 {
+            let leaf = bp.get_page_for_read(&ValueId::new_page(c_id, leaf_id))?;
+            return Ok(Some((leaf, slot - 1)));
+        }
+        // The position is at the start of its leaf, so step back to the
+        // rightmost leaf of the nearest subtree to the left
+        while let Some((parent_id, pos)) = path.pop() {
+            if pos == 0 {
+                continue;
+            }
+            let parent = bp.get_page_for_read(&ValueId::new_page(c_id, parent_id))?;
+            let mut p_id = inner_children(&parent).1[pos - 1];
+            drop(parent);
+            loop {
+                let page = bp.get_page_for_read(&ValueId::new_page(c_id, p_id))?;
+                if page.is_leaf {
+                    // Only the root can be an empty leaf
+                    let last = page.get_filled_slot_count() as SlotId - 1;
+                    return Ok(Some((page, last)));
+                }
+                p_id = page
+                    .page_pointer
+                    .expect("Inner index page without a rightmost child");
+            }
+        }
+        Ok(None)
+    }
+
+    /// Restore the minimum occupancy of `node` after a delete, borrowing from
+    /// or merging with a sibling. Merges remove an entry from the parent, so
+    /// they continue up the path. An inner root left with one child is
+    /// replaced by that child.
+    fn rebalance(
+        &self,
+        state: &mut IndexState,
+        mut path: Vec<(PageId, usize)>,
+        mut node: PageId,
+    ) -> Result<(), CrustyError> {
+        while let Some((parent_id, pos)) = path.pop() {
+            let node_page = self.bp.get_page_for_read(&self.page_id(node))?;
+            if node_page.get_filled_slot_count() >= min_entries(&node_page) {
+                return Ok(());
+            }
+            let is_leaf = node_page.is_leaf;
+            drop(node_page);
+
+            let mut parent = self.bp.get_page_for_write(&self.page_id(parent_id))?;
+            let (mut seps, mut children) = inner_children(&parent);
+            if children.len() < 2 {
+                return Ok(());
+            }
+            // Pair the node with its left sibling, or its right if it is the first child
+            let left_pos = if pos > 0 { pos - 1 } else { pos };
+            let left_id = children[left_pos];
+            let right_id = children[left_pos + 1];
+            let mut left = self.bp.get_page_for_write(&self.page_id(left_id))?;
+            let mut right = self.bp.get_page_for_write(&self.page_id(right_id))?;
+
+            if is_leaf {
+                let mut entries = left.entries();
+                entries.extend(right.entries());
+                if entries.len() <= left.slot_capacity as usize {
+                    left.set_entries(&entries)?;
+                    left.page_pointer = right.page_pointer;
+                    self.free_page(state, &mut right)?;
+                    seps.remove(left_pos);
+                    children.remove(left_pos + 1);
+                } else {
+                    let mid = entries.len() / 2;
+                    left.set_entries(&entries[..mid])?;
+                    right.set_entries(&entries[mid..])?;
+                    seps[left_pos] = entries[mid].0;
+                    self.set_inner_children(&mut parent, &seps, &children)?;
+                    return Ok(());
+                }
+            } else {
+                // Pull the parent's separator down between the two pages' entries
+                let (left_seps, left_children) = inner_children(&left);
+                let (right_seps, right_children) = inner_children(&right);
+                let mut all_seps = left_seps;
+                all_seps.push(seps[left_pos]);
+                all_seps.extend(right_seps);
+                let mut all_children = left_children;
+                all_children.extend(right_children);
+                if all_seps.len() <= left.slot_capacity as usize {
+                    self.set_inner_children(&mut left, &all_seps, &all_children)?;
+                    self.free_page(state, &mut right)?;
+                    seps.remove(left_pos);
+                    children.remove(left_pos + 1);
+                } else {
+                    let mid = all_seps.len() / 2;
+                    self.set_inner_children(&mut left, &all_seps[..mid], &all_children[..=mid])?;
+                    self.set_inner_children(
+                        &mut right,
+                        &all_seps[mid + 1..],
+                        &all_children[mid + 1..],
+                    )?;
+                    seps[left_pos] = all_seps[mid];
+                    self.set_inner_children(&mut parent, &seps, &children)?;
+                    return Ok(());
+                }
+            }
+            self.set_inner_children(&mut parent, &seps, &children)?;
+            node = parent_id;
+        }
+
+        // The node is the root. Shrink the tree while the root has one child.
+        let mut root = self.bp.get_page_for_write(&self.page_id(state.root))?;
+        if !root.is_leaf && root.get_filled_slot_count() == 0 {
+            let child = root
+                .page_pointer
+                .expect("Inner index page without a rightmost child");
+            self.free_page(state, &mut root)?;
+            state.root = child;
+        }
+        Ok(())
+    }
+
+    /// Replace the entries of an inner page, with `children` one longer than `seps`
+    fn set_inner_children(
+        &self,
+        page: &mut FixedPage,
+        seps: &[[u8; SEARCH_KEY_SIZE]],
+        children: &[PageId],
+    ) -> Result<(), CrustyError> {
+        let entries: Vec<IndexEntry> = seps
+            .iter()
+            .zip(children.iter())
+            .map(|(sep, child)| (*sep, self.page_id(*child).to_fixed_bytes()))
+            .collect();
+        page.set_entries(&entries)?;
+        page.page_pointer = Some(children[children.len() - 1]);
+        Ok(())
+    }
+
+    /// Whether the tree holds no entries, which is when its root is an empty leaf
+    pub(crate) fn tree_is_empty(&self, state: &IndexState) -> Result<bool, CrustyError> {
+        let root = self.bp.get_page_for_read(&self.page_id(state.root))?;
+        Ok(root.is_leaf && root.get_filled_slot_count() == 0)
+    }
+
+    /// Build a tree bottom-up from entries sorted by key, in new pages that
+    /// nothing links to yet. Leaves and inner pages are packed to
+    /// `fill_factor` of their capacity, but at least half full. Returns the
+    /// new root and the entry ids in the order of `entries`. Entries must
+    /// not be empty.
+    pub(crate) fn tree_bulk_load(
+        &self,
+        state: &mut IndexState,
+        entries: &[IndexEntry],
+        fill_factor: f64,
+    ) -> Result<(PageId, Vec<ValueId>), CrustyError> {
+        let capacity = self
+            .bp
+            .get_page_for_read(&self.page_id(state.root))?
+            .slot_capacity;
+        // Pages are never packed below the minimum the tree keeps after deletes
+        let min = capacity as usize / 2;
+        let per_page = ((capacity as f64 * fill_factor) as usize).clamp(min, capacity as usize);
+
+        // The leaves
+        let mut ids = Vec::with_capacity(entries.len());
+        let mut level: Vec<([u8; SEARCH_KEY_SIZE], PageId)> = Vec::new();
+        let mut prev: Option<FrameWriteGuard> = None;
+        for chunk in even_chunks(entries, per_page, min) {
+            let (p_id, mut page) = self.alloc_page(state, true)?;
+            page.set_entries(chunk)?;
+            ids.extend((0..chunk.len()).map(|slot| self.entry_id(p_id, slot as SlotId)));
+            if let Some(mut prev) = prev {
+                prev.page_pointer = Some(p_id);
+            }
+            level.push((chunk[0].0, p_id));
+            prev = Some(page);
+        }
+        drop(prev);
+
+        // Inner levels, each page's children separated by their first keys
+        while level.len() > 1 {
+            let mut parents = Vec::new();
+            for children in even_chunks(&level, per_page + 1, min + 1) {
+                let (p_id, mut page) = self.alloc_page(state, false)?;
+                let last = children[children.len() - 1].1;
+                let seps: Vec<IndexEntry> = children
+                    .windows(2)
+                    .map(|pair| (pair[1].0, self.page_id(pair[0].1).to_fixed_bytes()))
+                    .collect();
+                page.set_entries(&seps)?;
+                page.page_pointer = Some(last);
+                parents.push((children[0].0, p_id));
+            }
+            level = parents;
+        }
+        Ok((level[0].1, ids))
+    }
+}
+
+/// The fewest entries a page other than the root may hold
+fn min_entries(page: &FixedPage) -> usize {
+    page.slot_capacity as usize / 2
+}
+
+/// The separators and children of an inner page, with the rightmost child last
+fn inner_children(page: &FixedPage) -> (Vec<[u8; SEARCH_KEY_SIZE]>, Vec<PageId>) {
+    let (seps, mut children): (Vec<_>, Vec<_>) = page
+        .entries()
+        .into_iter()
+        .map(|(sep, ptr)| (sep, pointer_to_page(&ptr)))
+        .unzip();
+    children.push(
+        page.page_pointer
+            .expect("Inner index page without a rightmost child"),
+    );
+    (seps, children)
+}
+
+/// Split items into chunks of at most `max` items with sizes as even as
+/// possible. Uses fewer, larger chunks if needed to keep each at `min` items.
+fn even_chunks<I>(items: &[I], max: usize, min: usize) -> Vec<&[I]> {
+    let mut count = items.len().div_ceil(max);
+    while count > 1 && items.len() / count < min {
+        count -= 1;
+    }
+    let (size, extra) = (items.len() / count, items.len() % count);
+    let mut res = Vec::with_capacity(count);
+    let mut start = 0;
+    for i in 0..count {
+        let end = start + size + usize::from(i < extra);
+        res.push(&items[start..end]);
+        start = end;
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buffer_pool::BufferPool;
+    use crate::index::fixed_index_tests::{gen_unique_search_keys_and_value_ids, set_up_test_util};
+    use crate::index::fixed_index_trait::IndexFileTrait;
+    use rand::{rngs::SmallRng, seq::SliceRandom, SeedableRng};
+    use std::collections::HashSet;
+    use std::ops::Bound;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn key(i: u16) -> [u8; SEARCH_KEY_SIZE] {
+        let mut k = [0; SEARCH_KEY_SIZE];
+        k[6..].copy_from_slice(&i.to_be_bytes());
+        k
+    }
+
+    /// Check the subtree at `p_id` is ordered, within `low..=high` and at
+    /// least half full below the root. Returns its leaves in order.
+    fn check_subtree(
+        idx: &FixedIndexFile<BufferPool>,
+        p_id: PageId,
+        low: Option<[u8; SEARCH_KEY_SIZE]>,
+        high: Option<[u8; SEARCH_KEY_SIZE]>,
+        is_root: bool,
+    ) -> Vec<PageId> {
+        let page = idx.bp.get_page_for_read(&idx.page_id(p_id)).unwrap();
+        if !is_root {
+            assert!(page.get_filled_slot_count() >= min_entries(&page));
+        }
+        let keys: Vec<[u8; SEARCH_KEY_SIZE]> = page.entries().iter().map(|e| e.0).collect();
+        assert!(keys.windows(2).all(|w| w[0] <= w[1]));
+        assert!(keys
+            .iter()
+            .all(|k| low.is_none_or(|l| l <= *k) && high.is_none_or(|h| *k <= h)));
+        if page.is_leaf {
+            return vec![p_id];
+        }
+        let (seps, children) = inner_children(&page);
+        drop(page);
+        let mut leaves = Vec::new();
+        for (i, child) in children.into_iter().enumerate() {
+            let child_low = if i == 0 { low } else { Some(seps[i - 1]) };
+            let child_high = if i < seps.len() { Some(seps[i]) } else { high };
+            leaves.extend(check_subtree(idx, child, child_low, child_high, false));
+        }
+        leaves
+    }
+
+    /// The header page and its continuation pages
+    fn header_pages(idx: &FixedIndexFile<BufferPool>) -> usize {
+        1 + idx.header_chain().unwrap().len()
+    }
+
+    /// Check the tree invariants and that every allocated page is either in
+    /// the tree, free or a header page
+    fn check_tree(idx: &FixedIndexFile<BufferPool>) {
+        let state = idx.state();
+        let leaves = check_subtree(idx, state.root, None, None, true);
+        let mut chain = vec![leaves[0]];
+        loop {
+            let page = idx
+                .bp
+                .get_page_for_read(&idx.page_id(*chain.last().unwrap()))
+                .unwrap();
+            match page.page_pointer {
+                Some(next) => chain.push(next),
+                None => break,
+            }
+        }
+        assert_eq!(chain, leaves);
+        let mut reachable = vec![state.root];
+        let mut i = 0;
+        while i < reachable.len() {
+            let page = idx
+                .bp
+                .get_page_for_read(&idx.page_id(reachable[i]))
+                .unwrap();
+            if !page.is_leaf {
+                reachable.extend(inner_children(&page).1);
+            }
+            i += 1;
+        }
+        assert_eq!(
+            reachable.len() + state.free_pages.len() + header_pages(idx),
+            state.pages_used
+        );
+    }
+
+    #[test]
+    fn test_tree_delete_rebalances() {
+        let (bp, lm, txn, _is_range, c_id, _dir) = set_up_test_util();
+        let idx = FixedIndexFile::new(c_id, bp, lm, true, 0);
+        let n = 20000;
+        let mut recs = gen_unique_search_keys_and_value_ids(n, 32, 9);
+        for (i, rec) in recs.iter_mut().enumerate() {
+            // Runs of 50 duplicates span leaves
+            rec.0 = key((i / 50) as u16);
+        }
+        let mut rng = SmallRng::seed_from_u64(23530);
+        recs.shuffle(&mut rng);
+        for (k, _v_id, pointer) in recs.iter() {
+            idx.add(k, pointer, &txn).unwrap();
+        }
+        check_tree(&idx);
+        let full_pages = idx.get_pages_used();
+
+        recs.shuffle(&mut rng);
+        let (gone, kept) = recs.split_at(n - 300);
+        for (i, (k, _v_id, pointer)) in gone.iter().enumerate() {
+            idx.delete_entry(k, pointer, &txn).unwrap();
+            if i % 2500 == 0 {
+                check_tree(&idx);
+            }
+        }
+        check_tree(&idx);
+        let state = idx.state();
+        // 300 entries fit in two leaves under a root
+        assert!(state.pages_used - state.free_pages.len() - header_pages(&idx) <= 3);
+        for (k, v_id, _pointer) in kept.iter() {
+            assert!(idx.get_pointers_for_key(k, &txn).unwrap().contains(v_id));
+        }
+        assert_eq!(
+            idx.get_pointers_for_key_range(&key(0), &key(u16::MAX), &txn)
+                .unwrap()
+                .len(),
+            300
+        );
+
+        for (k, _v_id, pointer) in kept.iter() {
+            idx.delete_entry(k, pointer, &txn).unwrap();
+        }
+        check_tree(&idx);
+        let state = idx.state();
+        assert_eq!(
+            state.free_pages.len(),
+            state.pages_used - header_pages(&idx) - 1
+        );
+        // Freed pages are reused before the container grows
+        for (k, _v_id, pointer) in recs.iter() {
+            idx.add(k, pointer, &txn).unwrap();
+        }
+        check_tree(&idx);
+        assert_eq!(idx.get_pages_used(), full_pages);
+    }
+
+    #[test]
+    fn test_entry_before() {
+        let (bp, lm, txn, _is_range, c_id, _dir) = set_up_test_util();
+        let idx = FixedIndexFile::new(c_id, bp, lm, true, 0);
+        let recs = gen_unique_search_keys_and_value_ids(3000, 32, 9);
+        for (i, (_k, _v_id, pointer)) in recs.iter().enumerate() {
+            idx.add(&key((i / 3) as u16), pointer, &txn).unwrap();
+        }
+        let state = idx.state();
+        let entry_before = |entries: &[IndexEntry]| {
+            FixedIndexFile::entry_before(&*idx.bp, c_id, &state, entries)
+                .unwrap()
+                .map(|(page, slot)| page.get_entry(slot).unwrap())
+        };
+
+        // Walk every leaf from its first entry back to the previous leaf's last
+        let (_path, mut p_id) = idx.find_leaf(&state, &key(0), true).unwrap();
+        let mut prev_last: Option<IndexEntry> = None;
+        loop {
+            let page = idx.bp.get_page_for_read(&idx.page_id(p_id)).unwrap();
+            let entries = page.entries();
+            assert_eq!(entry_before(&entries), prev_last);
+            assert_eq!(entry_before(&entries[2..]), Some(entries[1]));
+            prev_last = entries.last().copied();
+            match page.page_pointer {
+                Some(next) => p_id = next,
+                None => break,
+            }
+        }
+
+        // Missing entries are skipped for the first one still in the tree
+        let (_path, leaf_id) = idx.find_leaf(&state, &key(500), false).unwrap();
+        let page = idx.bp.get_page_for_read(&idx.page_id(leaf_id)).unwrap();
+        let present = page.get_entry(1).unwrap();
+        let expected = page.get_entry(0);
+        drop(page);
+        let missing = (present.0, [0xff; INDEX_POINTER_SIZE]);
+        assert_eq!(entry_before(&[missing, present]), expected);
+        // With none left it resumes before the first entry with the first key
+        let missing = (key(500), [0xff; INDEX_POINTER_SIZE]);
+        assert_eq!(entry_before(&[missing]).unwrap().0, key(499));
+        let missing = (key(0), [0xff; INDEX_POINTER_SIZE]);
+        assert_eq!(entry_before(&[missing]), None);
+    }
+
+    #[test]
+    fn test_concurrent_adds_deletes_and_scans() {
+        let (bp, lm, txn, _is_range, c_id, _dir) = set_up_test_util();
+        let idx = FixedIndexFile::new(c_id, bp, lm, true, 0);
+        let threads = 4;
+        let n = 24000;
+        let mut recs = gen_unique_search_keys_and_value_ids(n, 32, 9);
+        for (i, rec) in recs.iter_mut().enumerate() {
+            rec.0 = key((i / 3) as u16);
+        }
+        let mut rng = SmallRng::seed_from_u64(23530);
+        recs.shuffle(&mut rng);
+        // A quarter of the entries are added first and never deleted
+        let (stable, rest) = recs.split_at(n / 4);
+        for (k, _v_id, pointer) in stable.iter() {
+            idx.add(k, pointer, &txn).unwrap();
+        }
+
+        let done = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let writers: Vec<_> = (0..threads)
+                .map(|t| {
+                    let (idx, txn) = (&idx, &txn);
+                    s.spawn(move || {
+                        let mine: Vec<_> = rest.iter().skip(t).step_by(threads).collect();
+                        for (k, _v_id, pointer) in mine.iter() {
+                            idx.add(k, pointer, txn).unwrap();
+                        }
+                        // Deleting half again merges some of the pages just split
+                        for (k, _v_id, pointer) in mine.iter().step_by(2) {
+                            idx.delete_entry(k, pointer, txn).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            // Scans in both directions never see an entry twice or miss a stable one
+            for reverse in [false, true] {
+                let (idx, txn, done) = (&idx, &txn, &done);
+                s.spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        let found: Vec<ValueId> = idx
+                            .bounded_cursor(Bound::Unbounded, Bound::Unbounded, reverse, txn)
+                            .unwrap()
+                            .collect::<Result<_, _>>()
+                            .unwrap();
+                        let distinct: HashSet<[u8; INDEX_POINTER_SIZE]> =
+                            found.iter().map(|v_id| v_id.to_fixed_bytes()).collect();
+                        assert_eq!(distinct.len(), found.len());
+                        assert!(stable
+                            .iter()
+                            .all(|(_k, _v_id, pointer)| distinct.contains(pointer)));
+                    }
+                });
+            }
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+
+        check_tree(&idx);
+        let deleted: HashSet<usize> = (0..threads)
+            .flat_map(|t| (t..rest.len()).step_by(threads).step_by(2))
+            .collect();
+        assert_eq!(idx.entry_count() as usize, n - deleted.len());
+        for (k, v_id, _pointer) in stable.iter() {
+            assert!(idx.get_pointers_for_key(k, &txn).unwrap().contains(v_id));
+        }
+        for (i, (k, v_id, _pointer)) in rest.iter().enumerate() {
+            let pointers = idx.get_pointers_for_key(k, &txn).unwrap();
+            assert_eq!(pointers.contains(v_id), !deleted.contains(&i));
+        }
+    }
+
+    #[test]
+    fn test_concurrent_adds_unique() {
+        let (bp, lm, txn, _is_range, c_id, _dir) = set_up_test_util();
+        let idx = FixedIndexFile::create(c_id, bp, lm, true, true, 0).unwrap();
+        let threads = 4;
+        let recs = gen_unique_search_keys_and_value_ids(4000, 32, 9);
+        // Every thread tries to add every key, each key once per thread
+        let added: Vec<usize> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..threads)
+                .map(|t| {
+                    let (idx, txn, recs) = (&idx, &txn, &recs);
+                    s.spawn(move || {
+                        let mut added = 0;
+                        for i in 0..recs.len() {
+                            let i = (i + t * 1000) % recs.len();
+                            let pointer = &recs[(i + t) % recs.len()].2;
+                            match idx.add(&key(i as u16), pointer, txn) {
+                                Ok(_) => added += 1,
+                                Err(CrustyError::DuplicateKey(_)) => {}
+                                Err(e) => panic!("Unexpected error {:?}", e),
+                            }
+                        }
+                        added
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(added.iter().sum::<usize>(), recs.len());
+        check_tree(&idx);
+        for i in 0..recs.len() {
+            assert_eq!(
+                idx.get_pointers_for_key(&key(i as u16), &txn)
+                    .unwrap()
+                    .len(),
+                1
+            );
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::prelude::*;
+use common::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Where an index finds its search key in a record's value: `len` bytes
+/// starting at `offset`. Keys shorter than `SEARCH_KEY_SIZE` are padded with
+/// trailing zeros, which keeps them in the same order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyExtractor {
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl KeyExtractor {
+    pub fn new(offset: usize, len: usize) -> Result<Self, CrustyError> {
+        let extractor = KeyExtractor { offset, len };
+        extractor.validate()?;
+        Ok(extractor)
+    }
+
+    /// Check the byte range fits in a value and the key fits in a search key
+    pub fn validate(&self) -> Result<(), CrustyError> {
+        if self.len == 0 || self.len > SEARCH_KEY_SIZE {
+            return Err(CrustyError::ValidationError(format!(
+                "Search key length {} must be between 1 and {}",
+                self.len, SEARCH_KEY_SIZE
+            )));
+        }
+        if self.offset + self.len > VALUE_SIZE {
+            return Err(CrustyError::ValidationError(format!(
+                "Search key bytes {}..{} are past the end of a {} byte value",
+                self.offset,
+                self.offset + self.len,
+                VALUE_SIZE
+            )));
+        }
+        Ok(())
+    }
+
+    /// The search key of a value
+    pub fn extract(&self, value: &[u8]) -> [u8; SEARCH_KEY_SIZE] {
+        let mut key = [0; SEARCH_KEY_SIZE];
+        key[..self.len].copy_from_slice(&value[self.offset..self.offset + self.len]);
+        key
+    }
+}
+
+/// The last `SEARCH_KEY_SIZE` bytes of the value, as in `extract_search_key`
+impl Default for KeyExtractor {
+    fn default() -> Self {
+        KeyExtractor {
+            offset: VALUE_SIZE - SEARCH_KEY_SIZE,
+            len: SEARCH_KEY_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_extractor() {
+        let value: Vec<u8> = (0..VALUE_SIZE).map(|i| i as u8).collect();
+        assert_eq!(
+            KeyExtractor::default().extract(&value),
+            *extract_search_key(&value)
+        );
+        assert_eq!(
+            KeyExtractor::new(4, 3).unwrap().extract(&value),
+            [4, 5, 6, 0, 0, 0, 0, 0]
+        );
+        assert!(KeyExtractor::new(0, 0).is_err());
+        assert!(KeyExtractor::new(0, SEARCH_KEY_SIZE + 1).is_err());
+        assert!(KeyExtractor::new(VALUE_SIZE - 2, 3).is_err());
+    }
+}
@@ -1,12 +1,20 @@
 use common::ids::{PageId, StateType};
 
-//TODO milestone idx1 - CHANGE THIS TO CHANGE THE INDEX USED
-pub const INDEX_TYPE: StateType = StateType::HashTable;
+/// The index used by the storage manager. Tree supports range queries, HashTable does not.
+pub const INDEX_TYPE: StateType = StateType::Tree;
+
+/// How full `bulk_add` packs the pages of a tree it builds from scratch
+pub const BULK_LOAD_FILL_FACTOR: f64 = 0.9;
 
 // This must be a power of 2 for extendible hashing to work
 pub const STARTING_PAGE_CAPACITY: PageId = 8;
 
+pub mod fixed_hash;
+pub mod fixed_index_cursor;
 pub mod fixed_index_file;
+pub mod fixed_index_header;
 pub mod fixed_index_page;
 pub mod fixed_index_tests;
 pub mod fixed_index_trait;
+pub mod fixed_tree;
+pub mod key_extractor;
@@ -3,11 +3,18 @@ extern crate log;
 
 pub mod buffer_frame;
 pub mod buffer_pool;
+pub mod container_file;
+mod fault;
 pub mod fixed_page;
+pub mod fsck;
 pub mod heap;
 pub mod index;
+pub mod manifest;
+pub mod replacement_policy;
+pub mod storage_adapter;
 pub mod storage_manager;
 pub mod test_util;
+pub mod wal;
 
 pub mod prelude {
     use common::prelude::*;
@@ -28,6 +35,13 @@ pub mod prelude {
     /// Making fixed size pages easy with limiting slots
     pub const PAGE_SLOT_LIMIT: usize = 1024;
 
+    /// Bytes reserved ahead of the data array for a serialized page's metadata
+    pub const PAGE_HEADER_SIZE: usize = 168;
+    /// The size of a page on disk (metadata header plus the data array)
+    pub const SERIALIZED_PAGE_SIZE: usize = PAGE_HEADER_SIZE + PAGE_SIZE;
+    /// The version of the on-disk page format written to each page's header
+    pub const PAGE_FORMAT_VERSION: u16 = 1;
+
     /// A hash chain should be at most this many pages long
     pub const MAX_CHAIN_LENGTH: usize = 3;
 
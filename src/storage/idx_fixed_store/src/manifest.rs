@@ -0,0 +1,30 @@
+use crate::fault;
+use common::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+
+/// Read a JSON manifest. Returns None if the file does not exist.
+pub fn read_manifest<M: DeserializeOwned>(path: &Path) -> Result<Option<M>, CrustyError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let bytes = fs::read(path)?;
+    serde_json::from_slice(&bytes)
+        .map(Some)
+        .map_err(|e| CrustyError::SerializationError(format!("{:?}: {}", path, e)))
+}
+
+/// Write a JSON manifest. The manifest is written to a temporary file that is
+/// renamed over the old one, so a crash leaves either the old or new version.
+pub fn write_manifest<M: Serialize>(path: &Path, manifest: &M) -> Result<(), CrustyError> {
+    let bytes = serde_json::to_vec_pretty(manifest)
+        .map_err(|e| CrustyError::SerializationError(e.to_string()))?;
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    fault::write_all(&mut file, &bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
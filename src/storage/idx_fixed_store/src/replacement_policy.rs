@@ -0,0 +1,343 @@
+use common::ids::ValueId;
+use std::collections::VecDeque;
+
+/// The replacement policies a buffer pool can be created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplacementPolicyType {
+    /// Evict the least recently used frame
+    Lru,
+    /// Second chance sweep over the frames using a reference bit
+    #[default]
+    Clock,
+    /// Evict the frame with the largest backward distance to its Kth most recent access
+    LruK(usize),
+    /// A FIFO queue for pages seen once and an LRU queue for pages seen again
+    TwoQ,
+}
+
+impl ReplacementPolicyType {
+    /// Create the policy for a pool with `frame_count` frames
+    pub fn build(self, frame_count: usize) -> Box<dyn ReplacementPolicy> {
+        match self {
+            ReplacementPolicyType::Lru => Box::new(LruPolicy::new(frame_count)),
+            ReplacementPolicyType::Clock => Box::new(ClockPolicy::new(frame_count)),
+            ReplacementPolicyType::LruK(k) => Box::new(LruKPolicy::new(frame_count, k)),
+            ReplacementPolicyType::TwoQ => Box::new(TwoQPolicy::new(frame_count)),
+        }
+    }
+}
+
+/// Tracks accesses to the frames of a buffer pool and picks which frame to
+/// replace. Frames are identified by their offset in the pool. Only frames that
+/// currently hold a page are tracked, and the pool decides which of those can
+/// be evicted (e.g. are unpinned).
+pub trait ReplacementPolicy: Send {
+    /// A page was read or created into a frame
+    fn frame_loaded(&mut self, frame_id: usize, page: ValueId);
+    /// A page already in a frame was requested again
+    fn frame_accessed(&mut self, frame_id: usize);
+    /// The page was removed from the frame (evicted or its container dropped)
+    fn frame_removed(&mut self, frame_id: usize);
+    /// Choose a tracked frame to evict among those `evictable` allows.
+    /// Does not stop tracking the frame, the pool calls `frame_removed` once evicted.
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize>;
+}
+
+/// Least recently used, by a logical access clock per frame.
+pub struct LruPolicy {
+    tick: u64,
+    last_access: Vec<Option<u64>>,
+}
+
+impl LruPolicy {
+    pub fn new(frame_count: usize) -> Self {
+        LruPolicy {
+            tick: 0,
+            last_access: vec![None; frame_count],
+        }
+    }
+}
+
+impl ReplacementPolicy for LruPolicy {
+    fn frame_loaded(&mut self, frame_id: usize, _page: ValueId) {
+        self.frame_accessed(frame_id);
+    }
+
+    fn frame_accessed(&mut self, frame_id: usize) {
+        self.tick += 1;
+        self.last_access[frame_id] = Some(self.tick);
+    }
+
+    fn frame_removed(&mut self, frame_id: usize) {
+        self.last_access[frame_id] = None;
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        self.last_access
+            .iter()
+            .enumerate()
+            .filter_map(|(i, t)| t.map(|t| (i, t)))
+            .filter(|(i, _)| evictable(*i))
+            .min_by_key(|(_, t)| *t)
+            .map(|(i, _)| i)
+    }
+}
+
+/// CLOCK sweep. Each access sets the frame's reference bit and the hand
+/// clears bits as it passes, evicting the first frame found without one.
+pub struct ClockPolicy {
+    hand: usize,
+    tracked: Vec<bool>,
+    referenced: Vec<bool>,
+}
+
+impl ClockPolicy {
+    pub fn new(frame_count: usize) -> Self {
+        ClockPolicy {
+            hand: 0,
+            tracked: vec![false; frame_count],
+            referenced: vec![false; frame_count],
+        }
+    }
+}
+
+impl ReplacementPolicy for ClockPolicy {
+    fn frame_loaded(&mut self, frame_id: usize, _page: ValueId) {
+        self.tracked[frame_id] = true;
+        self.referenced[frame_id] = true;
+    }
+
+    fn frame_accessed(&mut self, frame_id: usize) {
+        self.referenced[frame_id] = true;
+    }
+
+    fn frame_removed(&mut self, frame_id: usize) {
+        self.tracked[frame_id] = false;
+        self.referenced[frame_id] = false;
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let frame_count = self.tracked.len();
+        // Two full turns: the first may only clear reference bits
+        for _ in 0..2 * frame_count {
+            let frame_id = self.hand;
+            self.hand = (self.hand + 1) % frame_count;
+            if !self.tracked[frame_id] || !evictable(frame_id) {
+                continue;
+            }
+            if self.referenced[frame_id] {
+                self.referenced[frame_id] = false;
+            } else {
+                return Some(frame_id);
+            }
+        }
+        None
+    }
+}
+
+/// LRU-K. Keeps the last K access times for each frame and evicts the frame
+/// whose Kth most recent access is oldest. Frames with fewer than K accesses
+/// have an infinite distance and are evicted first, oldest access first.
+pub struct LruKPolicy {
+    k: usize,
+    tick: u64,
+    history: Vec<Option<VecDeque<u64>>>,
+}
+
+impl LruKPolicy {
+    pub fn new(frame_count: usize, k: usize) -> Self {
+        assert!(k > 0, "LRU-K needs K of at least 1");
+        LruKPolicy {
+            k,
+            tick: 0,
+            history: vec![None; frame_count],
+        }
+    }
+}
+
+impl ReplacementPolicy for LruKPolicy {
+    fn frame_loaded(&mut self, frame_id: usize, _page: ValueId) {
+        self.history[frame_id] = Some(VecDeque::with_capacity(self.k));
+        self.frame_accessed(frame_id);
+    }
+
+    fn frame_accessed(&mut self, frame_id: usize) {
+        self.tick += 1;
+        if let Some(history) = self.history[frame_id].as_mut() {
+            if history.len() == self.k {
+                history.pop_front();
+            }
+            history.push_back(self.tick);
+        }
+    }
+
+    fn frame_removed(&mut self, frame_id: usize) {
+        self.history[frame_id] = None;
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        // The front of a full history is the Kth most recent access
+        self.history
+            .iter()
+            .enumerate()
+            .filter_map(|(i, h)| h.as_ref().map(|h| (i, h)))
+            .filter(|(i, _)| evictable(*i))
+            .min_by_key(|(_, h)| (h.len() >= self.k, h.front().copied().unwrap_or(0)))
+            .map(|(i, _)| i)
+    }
+}
+
+/// Simplified 2Q. New pages enter the FIFO `a1_in` queue. Pages evicted from
+/// `a1_in` are remembered in the `a1_out` ghost queue, and if one is loaded
+/// again it goes straight to the LRU `am` queue. Pages hit while in `am`
+/// are moved to the back of it.
+pub struct TwoQPolicy {
+    /// Max frames held by a1_in before it is preferred for eviction
+    kin: usize,
+    /// Max pages remembered by a1_out
+    kout: usize,
+    a1_in: VecDeque<usize>,
+    a1_out: VecDeque<ValueId>,
+    am: VecDeque<usize>,
+    pages: Vec<Option<ValueId>>,
+}
+
+impl TwoQPolicy {
+    pub fn new(frame_count: usize) -> Self {
+        TwoQPolicy {
+            kin: (frame_count / 4).max(1),
+            kout: (frame_count / 2).max(1),
+            a1_in: VecDeque::new(),
+            a1_out: VecDeque::new(),
+            am: VecDeque::new(),
+            pages: vec![None; frame_count],
+        }
+    }
+
+    fn first_evictable(
+        queue: &VecDeque<usize>,
+        evictable: &dyn Fn(usize) -> bool,
+    ) -> Option<usize> {
+        queue.iter().copied().find(|f| evictable(*f))
+    }
+}
+
+impl ReplacementPolicy for TwoQPolicy {
+    fn frame_loaded(&mut self, frame_id: usize, page: ValueId) {
+        self.pages[frame_id] = Some(page);
+        if let Some(pos) = self.a1_out.iter().position(|p| *p == page) {
+            self.a1_out.remove(pos);
+            self.am.push_back(frame_id);
+        } else {
+            self.a1_in.push_back(frame_id);
+        }
+    }
+
+    fn frame_accessed(&mut self, frame_id: usize) {
+        // Hits in a1_in are treated as correlated references and ignored
+        if let Some(pos) = self.am.iter().position(|f| *f == frame_id) {
+            self.am.remove(pos);
+            self.am.push_back(frame_id);
+        }
+    }
+
+    fn frame_removed(&mut self, frame_id: usize) {
+        let page = self.pages[frame_id].take();
+        if let Some(pos) = self.a1_in.iter().position(|f| *f == frame_id) {
+            self.a1_in.remove(pos);
+            if let Some(page) = page {
+                if self.a1_out.len() == self.kout {
+                    self.a1_out.pop_front();
+                }
+                self.a1_out.push_back(page);
+            }
+        } else if let Some(pos) = self.am.iter().position(|f| *f == frame_id) {
+            self.am.remove(pos);
+        }
+    }
+
+    fn victim(&mut self, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        if self.a1_in.len() > self.kin {
+            Self::first_evictable(&self.a1_in, evictable)
+                .or_else(|| Self::first_evictable(&self.am, evictable))
+        } else {
+            Self::first_evictable(&self.am, evictable)
+                .or_else(|| Self::first_evictable(&self.a1_in, evictable))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn page(p: u32) -> ValueId {
+        ValueId::new_page(0, p)
+    }
+
+    fn load_all(policy: &mut dyn ReplacementPolicy, frames: usize) {
+        for f in 0..frames {
+            policy.frame_loaded(f, page(f as u32));
+        }
+    }
+
+    #[test]
+    fn test_lru_victim() {
+        let mut policy = LruPolicy::new(4);
+        load_all(&mut policy, 4);
+        policy.frame_accessed(0);
+        policy.frame_accessed(1);
+        assert_eq!(policy.victim(&|_| true), Some(2));
+        assert_eq!(policy.victim(&|f| f != 2), Some(3));
+        policy.frame_removed(2);
+        policy.frame_removed(3);
+        assert_eq!(policy.victim(&|_| true), Some(0));
+        assert_eq!(policy.victim(&|_| false), None);
+    }
+
+    #[test]
+    fn test_clock_victim() {
+        let mut policy = ClockPolicy::new(3);
+        load_all(&mut policy, 3);
+        // All referenced, the first sweep clears them and comes back to frame 0
+        assert_eq!(policy.victim(&|_| true), Some(0));
+        policy.frame_accessed(1);
+        // Frame 1 gets a second chance
+        assert_eq!(policy.victim(&|_| true), Some(2));
+        assert_eq!(policy.victim(&|f| f == 1), Some(1));
+        assert_eq!(policy.victim(&|_| false), None);
+    }
+
+    #[test]
+    fn test_lru_k_victim() {
+        let mut policy = LruKPolicy::new(3, 2);
+        load_all(&mut policy, 3);
+        policy.frame_accessed(0);
+        policy.frame_accessed(2);
+        // Frame 1 only has one access so it has an infinite distance
+        assert_eq!(policy.victim(&|_| true), Some(1));
+        // Frame 0's second most recent access is older than frame 2's
+        assert_eq!(policy.victim(&|f| f != 1), Some(0));
+        policy.frame_accessed(0);
+        policy.frame_accessed(0);
+        assert_eq!(policy.victim(&|f| f != 1), Some(2));
+    }
+
+    #[test]
+    fn test_two_q_victim() {
+        let mut policy = TwoQPolicy::new(8);
+        load_all(&mut policy, 4);
+        // a1_in is over its limit of 2 so its oldest frame goes first
+        assert_eq!(policy.victim(&|_| true), Some(0));
+        policy.frame_removed(0);
+        assert_eq!(policy.victim(&|_| true), Some(1));
+        // Page 0 is remembered in a1_out so reloading it places it in am
+        policy.frame_loaded(0, page(0));
+        assert_eq!(policy.am, VecDeque::from(vec![0]));
+        policy.frame_removed(1);
+        policy.frame_removed(2);
+        // a1_in is at its limit, prefer am
+        assert_eq!(policy.victim(&|_| true), Some(0));
+        assert_eq!(policy.victim(&|f| f != 0), Some(3));
+    }
+}
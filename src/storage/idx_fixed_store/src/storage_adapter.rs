@@ -0,0 +1,688 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::RwLock;
+
+use common::prelude::*;
+use common::storage_trait::StorageTrait;
+use serde::{Deserialize, Serialize};
+
+use crate::buffer_pool::BufferPool;
+use crate::heap::fixed_heap_iter::FixedHeapFileIter;
+use crate::index::key_extractor::KeyExtractor;
+use crate::manifest::{read_manifest, write_manifest};
+use crate::prelude::*;
+use crate::storage_manager::StorageManager;
+use crate::test_util::{gen_random_test_dir, TestDir};
+
+/// The file in the storage directory mapping the adapter's container ids to tables
+pub const ADAPTER_MANIFEST: &str = "adapter.json";
+
+/// Lock timeout for the storage manager's lock manager
+const LOCK_TIMEOUT_MS: u64 = 1000;
+
+/// The bytes of the record key holding the value's unpadded length
+const LEN_OFFSET: usize = 8;
+
+/// A table of the storage manager as seen through the adapter
+struct AdapterTable {
+    /// The storage manager's container id for the table
+    t_id: ContainerId,
+    /// The next record key to hand out
+    next_key: AtomicU64,
+    /// The storage manager's index for each index container on the table
+    indexes: HashMap<ContainerId, ContainerId>,
+}
+
+/// An adapter table as saved in the adapter manifest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedTable {
+    c_id: ContainerId,
+    t_id: ContainerId,
+    next_key: u64,
+    #[serde(default)]
+    indexes: Vec<(ContainerId, ContainerId)>,
+}
+
+/// Implements `StorageTrait` on top of the fixed record `StorageManager`.
+///
+/// Callers choose their own container ids, which are mapped to the storage
+/// manager's tables. ValueIds handed out carry the caller's container id.
+/// Records are stored with a generated key holding a sequence number and the
+/// value's length. Values are padded to `VALUE_SIZE`, so a value can be at
+/// most `VALUE_SIZE` bytes. A table has no index unless a `Tree` or
+/// `HashTable` container depending on it is created, which indexes each
+/// value's last `SEARCH_KEY_SIZE` bytes once padded.
+///
+/// Calls run under the caller's `tid` and hold their locks until the
+/// transaction is ended with `transaction_finished`. Scans take no locks.
+pub struct FixedStorageAdapter {
+    sm: StorageManager,
+    tables: RwLock<HashMap<ContainerId, AdapterTable>>,
+    /// A test storage manager's directory, removed after the storage manager is dropped
+    temp_dir: Option<TestDir>,
+}
+
+impl FixedStorageAdapter {
+    fn open(storage_dir: &Path, temp_dir: Option<TestDir>) -> Result<Self, CrustyError> {
+        let sm = StorageManager::open(storage_dir, LOCK_TIMEOUT_MS)?;
+        let saved: Vec<SavedTable> =
+            read_manifest(&storage_dir.join(ADAPTER_MANIFEST))?.unwrap_or_default();
+        let tables = saved
+            .into_iter()
+            .map(|t| {
+                // Keys handed out after the manifest was last saved are in the heap
+                let txn = TransactionId::new();
+                let next_key = sm
+                    .get_iterator(&t.t_id, None, &txn)?
+                    .map(|(_, key, _)| Self::key_seq(&key) + 1)
+                    .fold(t.next_key, u64::max);
+                let table = AdapterTable {
+                    t_id: t.t_id,
+                    next_key: AtomicU64::new(next_key),
+                    indexes: t.indexes.into_iter().collect(),
+                };
+                Ok((t.c_id, table))
+            })
+            .collect::<Result<_, CrustyError>>()?;
+        Ok(FixedStorageAdapter {
+            sm,
+            tables: RwLock::new(tables),
+            temp_dir,
+        })
+    }
+
+    fn save_manifest(
+        &self,
+        tables: &HashMap<ContainerId, AdapterTable>,
+    ) -> Result<(), CrustyError> {
+        let saved: Vec<SavedTable> = tables
+            .iter()
+            .map(|(c_id, t)| SavedTable {
+                c_id: *c_id,
+                t_id: t.t_id,
+                next_key: t.next_key.load(Relaxed),
+                indexes: t.indexes.iter().map(|(c, i)| (*c, *i)).collect(),
+            })
+            .collect();
+        write_manifest(&self.sm.get_storage_dir().join(ADAPTER_MANIFEST), &saved)
+    }
+
+    /// The storage manager's table for a container id
+    fn table_id(&self, c_id: ContainerId) -> Result<ContainerId, CrustyError> {
+        self.tables
+            .read()
+            .unwrap()
+            .get(&c_id)
+            .map(|t| t.t_id)
+            .ok_or(CrustyError::ContainerDoesNotExist)
+    }
+
+    /// Translate a ValueId from the caller's container id to the table's
+    fn to_table_v_id(&self, id: ValueId) -> Result<(ContainerId, ValueId), CrustyError> {
+        let t_id = self.table_id(id.container_id)?;
+        Ok((
+            t_id,
+            ValueId {
+                container_id: t_id,
+                ..id
+            },
+        ))
+    }
+
+    /// Report a missing record with the caller's ValueId rather than the table's
+    fn with_caller_id(id: ValueId) -> impl Fn(CrustyError) -> CrustyError {
+        move |e| match e {
+            CrustyError::ValueNotFound(_) => CrustyError::ValueNotFound(id),
+            e => e,
+        }
+    }
+
+    /// Pad a value to the stored value size
+    fn pad_value(value: &[u8]) -> Result<[u8; VALUE_SIZE], CrustyError> {
+        if value.len() > VALUE_SIZE {
+            return Err(CrustyError::ValidationError(format!(
+                "Value of {} bytes is larger than the maximum of {}",
+                value.len(),
+                VALUE_SIZE
+            )));
+        }
+        let mut padded = [0; VALUE_SIZE];
+        padded[..value.len()].copy_from_slice(value);
+        Ok(padded)
+    }
+
+    /// Build the stored key and padded value for a new value
+    fn to_record(
+        &self,
+        c_id: ContainerId,
+        value: &[u8],
+    ) -> Result<([u8; KEY_SIZE], [u8; VALUE_SIZE]), CrustyError> {
+        let padded = Self::pad_value(value)?;
+        let tables = self.tables.read().unwrap();
+        let table = tables
+            .get(&c_id)
+            .ok_or(CrustyError::ContainerDoesNotExist)?;
+        let mut key = [0; KEY_SIZE];
+        key[..LEN_OFFSET].copy_from_slice(&table.next_key.fetch_add(1, Relaxed).to_be_bytes());
+        key[LEN_OFFSET..LEN_OFFSET + 2].copy_from_slice(&(value.len() as u16).to_be_bytes());
+        Ok((key, padded))
+    }
+
+    /// The sequence number of a stored key
+    fn key_seq(key: &[u8]) -> u64 {
+        u64::from_be_bytes(key[..LEN_OFFSET].try_into().unwrap())
+    }
+
+    /// Whether a container id is taken by a table or an index
+    fn contains(tables: &HashMap<ContainerId, AdapterTable>, c_id: ContainerId) -> bool {
+        tables.contains_key(&c_id) || tables.values().any(|t| t.indexes.contains_key(&c_id))
+    }
+
+    /// Strip the padding from a stored value using the length in its key
+    fn from_record(key: &[u8], mut value: Vec<u8>) -> Vec<u8> {
+        let len = u16::from_be_bytes(key[LEN_OFFSET..LEN_OFFSET + 2].try_into().unwrap());
+        value.truncate(len as usize);
+        value
+    }
+
+    fn iter(
+        &self,
+        container_id: ContainerId,
+        start: Option<&ValueId>,
+        tid: TransactionId,
+    ) -> AdapterIter {
+        let t_id = self
+            .table_id(container_id)
+            .expect("Container does not exist");
+        let inner = self
+            .sm
+            .get_iterator(&t_id, start, &tid)
+            .expect("Error scanning table");
+        AdapterIter {
+            inner,
+            container_id,
+        }
+    }
+}
+
+/// Iterates over an adapter container, stripping the padding from values and
+/// giving back ValueIds with the adapter's container id
+pub struct AdapterIter {
+    inner: FixedHeapFileIter<BufferPool>,
+    container_id: ContainerId,
+}
+
+impl Iterator for AdapterIter {
+    type Item = (Vec<u8>, ValueId);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (v_id, k, v) = self.inner.next()?;
+        let v_id = ValueId {
+            container_id: self.container_id,
+            ..v_id
+        };
+        Some((FixedStorageAdapter::from_record(&k, v), v_id))
+    }
+}
+
+impl StorageTrait for FixedStorageAdapter {
+    type ValIterator = AdapterIter;
+
+    fn new(storage_dir: &Path) -> Self {
+        Self::open(storage_dir, None).expect("Unable to open storage directory")
+    }
+
+    fn new_test_sm() -> Self {
+        let dir = gen_random_test_dir();
+        let path = dir.to_path_buf();
+        Self::open(&path, Some(dir)).expect("Unable to create test storage")
+    }
+
+    /// Panics if the container does not exist or the value is too large,
+    /// as the trait gives no way to return an error.
+    fn insert_value(
+        &self,
+        container_id: ContainerId,
+        value: Vec<u8>,
+        tid: TransactionId,
+    ) -> ValueId {
+        let t_id = self.table_id(container_id).unwrap();
+        let (key, padded) = self.to_record(container_id, &value).unwrap();
+        let v_id = self.sm.insert_kv(&t_id, &key, &padded, &tid).unwrap();
+        ValueId {
+            container_id,
+            ..v_id
+        }
+    }
+
+    fn insert_values(
+        &self,
+        container_id: ContainerId,
+        values: Vec<Vec<u8>>,
+        tid: TransactionId,
+    ) -> Vec<ValueId> {
+        let t_id = self.table_id(container_id).unwrap();
+        let records: Vec<([u8; KEY_SIZE], [u8; VALUE_SIZE])> = values
+            .iter()
+            .map(|v| self.to_record(container_id, v).unwrap())
+            .collect();
+        let recs = records.iter().map(|(k, v)| (&k[..], &v[..])).collect();
+        self.sm
+            .insert_kvs(&t_id, recs, &tid)
+            .unwrap()
+            .into_iter()
+            .map(|v_id| ValueId {
+                container_id,
+                ..v_id
+            })
+            .collect()
+    }
+
+    fn delete_value(&self, id: ValueId, tid: TransactionId) -> Result<(), CrustyError> {
+        let (t_id, v_id) = self.to_table_v_id(id)?;
+        match self.sm.get_kv_by_val_id(&t_id, &v_id, &tid) {
+            Ok(_) => self.sm.delete_kv(&t_id, &v_id, &tid),
+            // Nothing stored at this id
+            Err(CrustyError::ValueNotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Updates in place, so the returned ValueId is always the one given
+    fn update_value(
+        &self,
+        value: Vec<u8>,
+        id: ValueId,
+        tid: TransactionId,
+    ) -> Result<ValueId, CrustyError> {
+        let (t_id, v_id) = self.to_table_v_id(id)?;
+        let padded = Self::pad_value(&value)?;
+        // Keep the record's sequence number, only its length changes
+        let (mut key, _old) = self
+            .sm
+            .get_kv_by_val_id(&t_id, &v_id, &tid)
+            .map_err(Self::with_caller_id(id))?;
+        key[LEN_OFFSET..LEN_OFFSET + 2].copy_from_slice(&(value.len() as u16).to_be_bytes());
+        self.sm.update_kv(&t_id, &v_id, &key, &padded, &tid)?;
+        Ok(id)
+    }
+
+    fn create_container(
+        &self,
+        container_id: ContainerId,
+        name: Option<String>,
+        container_type: StateType,
+        dependencies: Option<Vec<ContainerId>>,
+    ) -> Result<(), CrustyError> {
+        let mut tables = self.tables.write().unwrap();
+        if Self::contains(&tables, container_id) {
+            return Err(CrustyError::CrustyError(format!(
+                "Container {} already exists",
+                container_id
+            )));
+        }
+        match container_type {
+            StateType::BaseTable | StateType::MatView => {
+                let t_id = self.sm.create_table(name)?;
+                let table = AdapterTable {
+                    t_id,
+                    next_key: AtomicU64::new(0),
+                    indexes: HashMap::new(),
+                };
+                tables.insert(container_id, table);
+            }
+            // An index on the one table it depends on
+            StateType::HashTable | StateType::Tree => {
+                let table = match dependencies.as_deref() {
+                    Some([table]) => tables
+                        .get_mut(table)
+                        .ok_or(CrustyError::ContainerDoesNotExist)?,
+                    _ => return Err(CrustyError::InvalidOperation),
+                };
+                let i_id = self.sm.create_index(
+                    &table.t_id,
+                    name,
+                    KeyExtractor::default(),
+                    container_type,
+                    false,
+                )?;
+                table.indexes.insert(container_id, i_id);
+            }
+            // Values are stored padded to a fixed size
+            StateType::VarTable => return Err(CrustyError::InvalidOperation),
+        }
+        self.save_manifest(&tables)
+    }
+
+    fn create_table(&self, container_id: ContainerId) -> Result<(), CrustyError> {
+        self.create_container(container_id, None, StateType::BaseTable, None)
+    }
+
+    /// Removing a table removes its indexes too
+    fn remove_container(&self, container_id: ContainerId) -> Result<(), CrustyError> {
+        let mut tables = self.tables.write().unwrap();
+        if let Some(table) = tables.remove(&container_id) {
+            self.sm.drop_table(&table.t_id)?;
+        } else {
+            let i_id = tables
+                .values_mut()
+                .find_map(|t| t.indexes.remove(&container_id))
+                .ok_or(CrustyError::ContainerDoesNotExist)?;
+            self.sm.drop_index(&i_id)?;
+        }
+        self.save_manifest(&tables)
+    }
+
+    fn get_iterator(
+        &self,
+        container_id: ContainerId,
+        tid: TransactionId,
+        _perm: Permissions,
+    ) -> Self::ValIterator {
+        self.iter(container_id, None, tid)
+    }
+
+    fn get_iterator_from(
+        &self,
+        container_id: ContainerId,
+        tid: TransactionId,
+        _perm: Permissions,
+        start: ValueId,
+    ) -> Self::ValIterator {
+        self.iter(container_id, Some(&start), tid)
+    }
+
+    fn get_value(
+        &self,
+        id: ValueId,
+        tid: TransactionId,
+        _perm: Permissions,
+    ) -> Result<Vec<u8>, CrustyError> {
+        let (t_id, v_id) = self.to_table_v_id(id)?;
+        let (k, v) = self
+            .sm
+            .get_kv_by_val_id(&t_id, &v_id, &tid)
+            .map_err(Self::with_caller_id(id))?;
+        Ok(Self::from_record(&k, v))
+    }
+
+    fn transaction_finished(&self, tid: TransactionId, committed: bool) -> Result<(), CrustyError> {
+        if committed {
+            self.sm.commit_txn(&tid)
+        } else {
+            self.sm.abort_txn(&tid)
+        }
+    }
+
+    fn get_storage_path(&self) -> &Path {
+        self.sm.get_storage_dir()
+    }
+
+    fn reset(&self) -> Result<(), CrustyError> {
+        let mut tables = self.tables.write().unwrap();
+        for (_c_id, table) in tables.drain() {
+            self.sm.drop_table(&table.t_id)?;
+        }
+        self.save_manifest(&tables)
+    }
+
+    fn clear_cache(&self) {
+        self.sm
+            .clear_cache()
+            .expect("Error clearing the buffer pool");
+    }
+
+    /// Transactions that were never finished are rolled back
+    fn shutdown(&self) {
+        for txn in self.sm.active_txns() {
+            if let Err(e) = self.sm.abort_txn(&txn) {
+                error!("Error rolling back {:?} on shutdown: {:?}", txn, e);
+            }
+        }
+        let tables = self.tables.read().unwrap();
+        if let Err(e) = self.sm.shutdown().and_then(|_| self.save_manifest(&tables)) {
+            error!("Error shutting down storage: {:?}", e);
+        }
+    }
+}
+
+impl Drop for FixedStorageAdapter {
+    fn drop(&mut self) {
+        if self.temp_dir.is_none() {
+            self.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::testutil::init;
+
+    #[test]
+    fn test_adapter_insert_get_scan() {
+        init();
+        let sm = FixedStorageAdapter::new_test_sm();
+        let tid = TransactionId::new();
+        sm.create_table(7).unwrap();
+        assert!(sm.create_table(7).is_err());
+        let values: Vec<Vec<u8>> = (0..200u8).map(|i| vec![i; 1 + i as usize % 50]).collect();
+        let mut v_ids = vec![sm.insert_value(7, values[0].clone(), tid)];
+        v_ids.extend(sm.insert_values(7, values[1..].to_vec(), tid));
+        for (v_id, value) in v_ids.iter().zip(values.iter()) {
+            assert_eq!(v_id.container_id, 7);
+            assert_eq!(
+                sm.get_value(*v_id, tid, Permissions::ReadOnly).unwrap(),
+                *value
+            );
+        }
+        let scanned: Vec<(Vec<u8>, ValueId)> =
+            sm.get_iterator(7, tid, Permissions::ReadOnly).collect();
+        assert_eq!(scanned.len(), values.len());
+        for (value, v_id) in scanned.iter() {
+            let i = v_ids.iter().position(|v| v == v_id).unwrap();
+            assert_eq!(*value, values[i]);
+        }
+        let from: Vec<ValueId> = sm
+            .get_iterator_from(7, tid, Permissions::ReadOnly, v_ids[100])
+            .map(|(_v, v_id)| v_id)
+            .collect();
+        assert_eq!(from, v_ids[100..].to_vec());
+        let missing = ValueId::new_slot(8, 0, 0);
+        assert_eq!(
+            sm.get_value(missing, tid, Permissions::ReadOnly),
+            Err(CrustyError::ContainerDoesNotExist)
+        );
+        sm.remove_container(7).unwrap();
+        assert!(sm.get_value(v_ids[0], tid, Permissions::ReadOnly).is_err());
+    }
+
+    #[test]
+    fn test_adapter_update_delete() {
+        init();
+        let sm = FixedStorageAdapter::new_test_sm();
+        let tid = TransactionId::new();
+        sm.create_table(1).unwrap();
+        let v_ids = sm.insert_values(1, vec![vec![1; 10], vec![2; 20], vec![3; 30]], tid);
+        assert_eq!(
+            sm.update_value(vec![9; 5], v_ids[1], tid).unwrap(),
+            v_ids[1]
+        );
+        assert_eq!(
+            sm.get_value(v_ids[1], tid, Permissions::ReadOnly).unwrap(),
+            vec![9; 5]
+        );
+        assert!(sm
+            .update_value(vec![0; VALUE_SIZE + 1], v_ids[1], tid)
+            .is_err());
+        sm.delete_value(v_ids[0], tid).unwrap();
+        assert!(sm.get_value(v_ids[0], tid, Permissions::ReadOnly).is_err());
+        // Deleting a missing value is not an error
+        sm.delete_value(v_ids[0], tid).unwrap();
+        assert!(sm.update_value(vec![1], v_ids[0], tid).is_err());
+        let left: Vec<Vec<u8>> = sm
+            .get_iterator(1, tid, Permissions::ReadOnly)
+            .map(|(v, _)| v)
+            .collect();
+        assert_eq!(left, vec![vec![9; 5], vec![3; 30]]);
+    }
+
+    #[test]
+    fn test_adapter_delete_passes_up_lock_errors() {
+        init();
+        let sm = FixedStorageAdapter::new_test_sm();
+        sm.create_table(1).unwrap();
+        let inserter = TransactionId::new();
+        let v_id = sm.insert_value(1, vec![1; 10], inserter);
+        sm.transaction_finished(inserter, true).unwrap();
+        // Another transaction deletes the value and holds its lock
+        let holder = TransactionId::new();
+        sm.delete_value(v_id, holder).unwrap();
+        let tid = TransactionId::new();
+        assert_eq!(
+            sm.delete_value(v_id, tid),
+            Err(CrustyError::TransactionRollback(tid))
+        );
+        sm.transaction_finished(tid, false).unwrap();
+        sm.transaction_finished(holder, false).unwrap();
+        let tid = TransactionId::new();
+        sm.delete_value(v_id, tid).unwrap();
+        assert_eq!(
+            sm.get_value(v_id, tid, Permissions::ReadOnly),
+            Err(CrustyError::ValueNotFound(v_id))
+        );
+    }
+
+    #[test]
+    fn test_adapter_transactions() {
+        init();
+        let sm = FixedStorageAdapter::new_test_sm();
+        sm.create_table(1).unwrap();
+        // A transaction keeps its locks across calls until it finishes
+        let tid1 = TransactionId::new();
+        let v_id = sm.insert_value(1, vec![1; 10], tid1);
+        sm.update_value(vec![2; 10], v_id, tid1).unwrap();
+        let tid2 = TransactionId::new();
+        assert_eq!(
+            sm.get_value(v_id, tid2, Permissions::ReadOnly),
+            Err(CrustyError::TransactionRollback(tid2))
+        );
+        sm.transaction_finished(tid2, false).unwrap();
+        sm.transaction_finished(tid1, true).unwrap();
+
+        // An aborted transaction's changes are rolled back
+        let tid3 = TransactionId::new();
+        sm.delete_value(v_id, tid3).unwrap();
+        sm.transaction_finished(tid3, false).unwrap();
+        let tid4 = TransactionId::new();
+        assert_eq!(
+            sm.get_value(v_id, tid4, Permissions::ReadOnly).unwrap(),
+            vec![2; 10]
+        );
+        sm.transaction_finished(tid4, true).unwrap();
+        assert!(sm.sm.active_txns().is_empty());
+    }
+
+    #[test]
+    fn test_adapter_indexes() {
+        init();
+        let sm = FixedStorageAdapter::new_test_sm();
+        sm.create_table(1).unwrap();
+        let t_id = sm.table_id(1).unwrap();
+        // A table has no index of its own
+        assert!(sm.sm.get_index_ids(&t_id).unwrap().is_empty());
+        assert_eq!(
+            sm.create_container(2, None, StateType::Tree, None),
+            Err(CrustyError::InvalidOperation)
+        );
+        sm.create_container(2, None, StateType::Tree, Some(vec![1]))
+            .unwrap();
+        assert!(sm.create_table(2).is_err());
+        let tid = TransactionId::new();
+        let mut value = vec![0; VALUE_SIZE];
+        value[VALUE_SIZE - SEARCH_KEY_SIZE..].copy_from_slice(&[5; SEARCH_KEY_SIZE]);
+        sm.insert_value(1, value.clone(), tid);
+        let (i_id, _) = sm.sm.get_index_ids(&t_id).unwrap()[0];
+        let found = sm
+            .sm
+            .get_kvs_by_search_key_equality(&i_id, &[5; SEARCH_KEY_SIZE], &tid)
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].1, value);
+        sm.transaction_finished(tid, true).unwrap();
+        sm.remove_container(2).unwrap();
+        assert!(sm.sm.get_index_ids(&t_id).unwrap().is_empty());
+        assert_eq!(
+            sm.remove_container(2),
+            Err(CrustyError::ContainerDoesNotExist)
+        );
+    }
+
+    #[test]
+    fn test_adapter_keys_survive_crash() {
+        init();
+        let dir = gen_random_test_dir();
+        {
+            let sm = FixedStorageAdapter::new(&dir);
+            sm.create_table(1).unwrap();
+            let tid = TransactionId::new();
+            sm.insert_values(1, vec![vec![1], vec![2]], tid);
+            sm.transaction_finished(tid, true).unwrap();
+            // Crash without saving the manifest
+            std::mem::forget(sm);
+        }
+        let sm = FixedStorageAdapter::new(&dir);
+        let tid = TransactionId::new();
+        sm.insert_value(1, vec![3], tid);
+        let t_id = sm.table_id(1).unwrap();
+        let mut seqs: Vec<u64> = sm
+            .sm
+            .get_iterator(&t_id, None, &tid)
+            .unwrap()
+            .map(|(_, key, _)| FixedStorageAdapter::key_seq(&key))
+            .collect();
+        seqs.sort();
+        assert_eq!(seqs, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_adapter_reopen() {
+        init();
+        let dir = gen_random_test_dir();
+        let tid = TransactionId::new();
+        let v_ids = {
+            let sm = FixedStorageAdapter::new(&dir);
+            sm.create_table(3).unwrap();
+            sm.create_table(4).unwrap();
+            let v_ids = sm.insert_values(3, vec![vec![1, 2, 3], vec![4; VALUE_SIZE]], tid);
+            sm.remove_container(4).unwrap();
+            sm.transaction_finished(tid, true).unwrap();
+            sm.clear_cache();
+            v_ids
+        };
+        let sm = FixedStorageAdapter::new(&dir);
+        assert_eq!(
+            sm.get_value(v_ids[0], tid, Permissions::ReadOnly).unwrap(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            sm.get_value(v_ids[1], tid, Permissions::ReadOnly).unwrap(),
+            vec![4; VALUE_SIZE]
+        );
+        assert!(sm
+            .get_value(ValueId::new_slot(4, 0, 0), tid, Permissions::ReadOnly)
+            .is_err());
+        // Keys keep counting from where the last run stopped
+        let v_id = sm.insert_value(3, vec![5], tid);
+        assert_eq!(sm.get_iterator(3, tid, Permissions::ReadOnly).count(), 3);
+        assert_eq!(
+            sm.get_value(v_id, tid, Permissions::ReadOnly).unwrap(),
+            vec![5]
+        );
+        sm.reset().unwrap();
+        assert!(sm.get_value(v_id, tid, Permissions::ReadOnly).is_err());
+        drop(sm);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,211 +1,979 @@
 use std::{
     collections::HashMap,
+    ops::Bound,
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
 };
 
 use common::prelude::*;
+use serde::{Deserialize, Serialize};
 use txn_manager::{lm_trait::LockManagerTrait, lockmanager::LockManager};
 
 use crate::{
-    buffer_pool::{BufferPool, BufferPoolTrait},
-    heap::fixed_heap_file::FixedHeapFile,
-    index::{fixed_index_file::FixedIndexFile, fixed_index_trait::IndexFileTrait},
-    prelude::{extract_search_key, SEARCH_KEY_SIZE},
+    buffer_pool::{BufferPool, BufferPoolTrait, FRAMES},
+    fsck::{self, CheckedIndex, FsckReport},
+    heap::{fixed_heap_file::FixedHeapFile, fixed_heap_iter::FixedHeapFileIter},
+    index::{
+        fixed_index_cursor::{CursorScan, IndexCursor},
+        fixed_index_file::FixedIndexFile,
+        fixed_index_trait::IndexFileTrait,
+        key_extractor::KeyExtractor,
+        BULK_LOAD_FILL_FACTOR,
+    },
+    manifest::{read_manifest, write_manifest},
+    prelude::{INDEX_POINTER_SIZE, SEARCH_KEY_SIZE},
+    replacement_policy::ReplacementPolicyType,
+    wal::log_record::{LogRecord, TxnOp},
 };
+use common::ids::Lsn;
 
 type ResultKVs = Result<Vec<(Vec<u8>, Vec<u8>)>, CrustyError>;
 
+/// The file in the storage directory holding the storage manager's catalog
+pub const CATALOG_MANIFEST: &str = "catalog.json";
+
+/// An index as saved in the catalog manifest. The index itself is reopened
+/// from the header page of its container.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedIndex {
+    c_id: ContainerId,
+    t_id: ContainerId,
+    extractor: KeyExtractor,
+}
+
+/// The catalog as saved in the catalog manifest. The heap files need no
+/// state beyond their container's page count.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct SavedCatalog {
+    tables: Vec<ContainerId>,
+    indexes: Vec<SavedIndex>,
+}
+
+/// An index on a table and where it finds its search keys
+struct TableIndex<T: BufferPoolTrait> {
+    t_id: ContainerId,
+    extractor: KeyExtractor,
+    file: FixedIndexFile<T>,
+}
+
 struct Catalog<T: BufferPoolTrait> {
     tables: HashMap<ContainerId, FixedHeapFile<T>>,
-    indexes: HashMap<ContainerId, FixedIndexFile<T>>,
-    table_to_index: HashMap<ContainerId, ContainerId>,
+    indexes: HashMap<ContainerId, TableIndex<T>>,
+    /// The indexes on each table, in creation order
+    table_to_indexes: HashMap<ContainerId, Vec<ContainerId>>,
+}
+
+impl<T: BufferPoolTrait> Catalog<T> {
+    fn new() -> Self {
+        Catalog {
+            tables: HashMap::new(),
+            indexes: HashMap::new(),
+            table_to_indexes: HashMap::new(),
+        }
+    }
+
+    fn table(&self, t_id: &ContainerId) -> Result<&FixedHeapFile<T>, CrustyError> {
+        self.tables
+            .get(t_id)
+            .ok_or(CrustyError::ContainerDoesNotExist)
+    }
+
+    fn index(&self, i_id: &ContainerId) -> Result<&TableIndex<T>, CrustyError> {
+        self.indexes
+            .get(i_id)
+            .ok_or(CrustyError::ContainerDoesNotExist)
+    }
+
+    /// Every index on a table
+    fn table_indexes(&self, t_id: &ContainerId) -> Vec<&TableIndex<T>> {
+        self.table_to_indexes
+            .get(t_id)
+            .map(|i_ids| i_ids.iter().map(|i_id| &self.indexes[i_id]).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Add a record to every index, removing it again from all of them if any add fails
+fn index_record<T: BufferPoolTrait>(
+    indexes: &[&TableIndex<T>],
+    val: &[u8],
+    v_id: &ValueId,
+    txn: &TransactionId,
+) -> Result<(), CrustyError> {
+    let pointer = v_id.to_fixed_bytes();
+    for (i, index) in indexes.iter().enumerate() {
+        if let Err(e) = index.file.add(&index.extractor.extract(val), &pointer, txn) {
+            unindex_record(&indexes[..i], val, v_id, txn)?;
+            return Err(e);
+        }
+    }
+    Ok(())
 }
 
-#[allow(dead_code)]
-struct StorageManager {
+/// Remove a record from every index, adding it back to all of them if any removal fails
+fn unindex_record<T: BufferPoolTrait>(
+    indexes: &[&TableIndex<T>],
+    val: &[u8],
+    v_id: &ValueId,
+    txn: &TransactionId,
+) -> Result<(), CrustyError> {
+    let pointer = v_id.to_fixed_bytes();
+    for (i, index) in indexes.iter().enumerate() {
+        let deleted = index
+            .file
+            .delete_entry(&index.extractor.extract(val), &pointer, txn);
+        if let Err(e) = deleted {
+            index_record(&indexes[..i], val, v_id, txn)?;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Move a record's entries in every index whose search key changed, moving
+/// them all back if any move fails
+fn reindex_record<T: BufferPoolTrait>(
+    indexes: &[&TableIndex<T>],
+    old_val: &[u8],
+    new_val: &[u8],
+    v_id: &ValueId,
+    txn: &TransactionId,
+) -> Result<(), CrustyError> {
+    let pointer = v_id.to_fixed_bytes();
+    for (i, index) in indexes.iter().enumerate() {
+        let old_key = index.extractor.extract(old_val);
+        let new_key = index.extractor.extract(new_val);
+        if old_key == new_key {
+            continue;
+        }
+        if let Err(e) = index.file.update_key(&old_key, &new_key, &pointer, txn) {
+            reindex_record(&indexes[..i], new_val, old_val, v_id, txn)?;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// The transactions that had not committed or finished aborting by the end
+/// of the log, with their first and last records. The log is read from the
+/// checkpoint, which lists the transactions unfinished when it was taken.
+fn unfinished_txns(
+    records: &[(Lsn, LogRecord)],
+    checkpoint: Option<Lsn>,
+) -> HashMap<TransactionId, (Lsn, Lsn)> {
+    let mut active = HashMap::new();
+    for (lsn, record) in records.iter().filter(|(lsn, _)| Some(*lsn) >= checkpoint) {
+        match record {
+            LogRecord::Txn { txn, .. } => {
+                active.entry(*txn).or_insert((*lsn, *lsn)).1 = *lsn;
+            }
+            LogRecord::Commit { txn } | LogRecord::Abort { txn } => {
+                active.remove(txn);
+            }
+            LogRecord::Checkpoint { active: txns, .. } => {
+                for (txn, first, last) in txns {
+                    active.entry(*txn).or_insert((*first, *last));
+                }
+            }
+            LogRecord::Page { .. } | LogRecord::NewContainer { .. } => {}
+        }
+    }
+    active
+}
+
+/// The LSN that redo starts after: the earliest of where the checkpoint
+/// began and where each page it found dirty was last clean. `None` redoes
+/// the whole log.
+fn redo_start(records: &[(Lsn, LogRecord)], checkpoint: Option<Lsn>) -> Option<Lsn> {
+    checkpoint?;
+    let mut start = checkpoint;
+    let parts = records
+        .iter()
+        .skip_while(|(lsn, _)| Some(*lsn) < checkpoint);
+    for (_, record) in parts {
+        let LogRecord::Checkpoint { begin, dirty, .. } = record else {
+            break;
+        };
+        start = start.min(*begin);
+        for (_, _, rec_lsn) in dirty {
+            start = start.min(*rec_lsn);
+        }
+    }
+    start
+}
+
+/// Make again every logged page change after `start` that did not reach
+/// disk, returning how many were redone. Pages of unfinished transactions
+/// are redone too and rolled back afterwards.
+fn redo(
+    bp: &BufferPool,
+    records: &[(Lsn, LogRecord)],
+    start: Option<Lsn>,
+) -> Result<usize, CrustyError> {
+    let mut registered = HashMap::new();
+    for (lsn, record) in records {
+        if let LogRecord::NewContainer { c_id } = record {
+            registered.insert(*c_id, *lsn);
+        }
+    }
+    let mut redone = 0;
+    for (lsn, record) in records.iter().filter(|(lsn, _)| Some(*lsn) > start) {
+        let LogRecord::Page { c_id, p_id, op } = record else {
+            continue;
+        };
+        // Skip dropped containers, and an earlier container whose id was reused
+        if bp.get_container_info(*c_id).is_none()
+            || registered.get(c_id).is_some_and(|start| start > lsn)
+        {
+            continue;
+        }
+        let mut page = bp.get_page_for_redo(*c_id, *p_id)?;
+        if page.lsn < Some(*lsn) {
+            op.redo(&mut page, *lsn)?;
+            redone += 1;
+        }
+    }
+    Ok(redone)
+}
+
+/// Stores tables in fixed record heap files. Each table can have any number
+/// of indexes, each on its own byte range of the record's value.
+///
+/// Every change is logged. A transaction's changes are durable once
+/// `commit_txn` returns, and `abort_txn` rolls them back. Opening a storage
+/// manager that was not shut down recovers it: the log is replayed from the
+/// last checkpoint so pages match it, then transactions that had not
+/// committed are rolled back.
+pub struct StorageManager {
     lm: Arc<LockManager>,
     bp: Arc<BufferPool>,
     data_files: Arc<RwLock<Catalog<BufferPool>>>,
-    //TODO milestone idx1 Add any fields you need here
+    storage_dir: PathBuf,
 }
 
-#[allow(dead_code)]
 impl StorageManager {
-    pub fn new(timeout_ms: u64) -> Self {
+    pub fn new(storage_dir: &Path, timeout_ms: u64) -> Self {
+        Self::new_with_frames(storage_dir, timeout_ms, FRAMES)
+    }
+
+    /// Create a storage manager whose buffer pool has a specific number of frames
+    pub fn new_with_frames(storage_dir: &Path, timeout_ms: u64, frame_count: usize) -> Self {
         let lm = Arc::new(LockManager::new(timeout_ms));
-        let bp = Arc::new(BufferPool::new(lm.clone()));
-        let catalog = Catalog {
-            tables: HashMap::new(),
-            indexes: HashMap::new(),
-            table_to_index: HashMap::new(),
+        let bp = Arc::new(BufferPool::with_frames(
+            lm.clone(),
+            storage_dir,
+            ReplacementPolicyType::default(),
+            frame_count,
+        ));
+        let data_files = Arc::new(RwLock::new(Catalog::new()));
+        StorageManager {
+            lm,
+            bp,
+            data_files,
+            storage_dir: storage_dir.to_path_buf(),
+        }
+    }
+
+    /// Open a storage manager on a directory, reloading its tables and
+    /// indexes. If it was not shut down, committed changes that had not
+    /// reached disk are redone and unfinished transactions are rolled back.
+    /// The log holds changes rather than whole pages, so a page torn by a
+    /// crash while it was being written cannot be redone: opening fails with
+    /// `CrustyError::CorruptPage` and the page has to be restored by hand.
+    pub fn open(storage_dir: &Path, timeout_ms: u64) -> Result<Self, CrustyError> {
+        Self::open_with_frames(storage_dir, timeout_ms, FRAMES)
+    }
+
+    /// Open a storage manager whose buffer pool has a specific number of frames
+    pub fn open_with_frames(
+        storage_dir: &Path,
+        timeout_ms: u64,
+        frame_count: usize,
+    ) -> Result<Self, CrustyError> {
+        let lm = Arc::new(LockManager::new(timeout_ms));
+        let bp = Arc::new(BufferPool::open_with_frames(
+            lm.clone(),
+            storage_dir,
+            ReplacementPolicyType::default(),
+            frame_count,
+        )?);
+        let records = bp.log().read_flushed()?;
+        let checkpoint = bp.log().checkpoint_lsn();
+        let losers = unfinished_txns(&records, checkpoint);
+        let redone = redo(&bp, &records, redo_start(&records, checkpoint))?;
+        drop(records);
+        let mut saved: SavedCatalog =
+            read_manifest(&storage_dir.join(CATALOG_MANIFEST))?.unwrap_or_default();
+        let mut catalog = Catalog::new();
+        for t_id in saved.tables {
+            let (_name, _state_type, pages) = bp
+                .get_container_info(t_id)
+                .ok_or(CrustyError::ContainerDoesNotExist)?;
+            let table = FixedHeapFile::open(t_id, bp.clone(), lm.clone(), pages - 1);
+            catalog.tables.insert(t_id, table);
+        }
+        // Container ids are handed out in order, so this restores creation order
+        saved.indexes.sort_by_key(|idx| idx.c_id);
+        for idx in saved.indexes {
+            if bp.get_container_info(idx.c_id).is_none() {
+                return Err(CrustyError::ContainerDoesNotExist);
+            }
+            let file = FixedIndexFile::open(idx.c_id, bp.clone(), lm.clone())?;
+            catalog
+                .table_to_indexes
+                .entry(idx.t_id)
+                .or_default()
+                .push(idx.c_id);
+            let index = TableIndex {
+                t_id: idx.t_id,
+                extractor: idx.extractor,
+                file,
+            };
+            catalog.indexes.insert(idx.c_id, index);
+        }
+        info!(
+            "Opened storage manager in {:?} with {} tables, redoing {} changes and rolling back {} transactions",
+            storage_dir,
+            catalog.tables.len(),
+            redone,
+            losers.len()
+        );
+        let sm = StorageManager {
+            lm,
+            bp,
+            data_files: Arc::new(RwLock::new(catalog)),
+            storage_dir: storage_dir.to_path_buf(),
+        };
+        if redone == 0 && losers.is_empty() {
+            return Ok(sm);
+        }
+        let txns: Vec<TransactionId> = losers.keys().copied().collect();
+        sm.bp.log().set_active_txns(losers);
+        for txn in txns {
+            sm.rollback(&txn)?;
+        }
+        // Write the recovered pages out so they are not recovered again
+        sm.bp.flush_all()?;
+        sm.bp.log().flush_all()?;
+        Ok(sm)
+    }
+
+    /// Write the catalog to the storage directory. The log is flushed first,
+    /// so the pages of every container it names can be recovered.
+    fn save_catalog(&self, catalog: &Catalog<BufferPool>) -> Result<(), CrustyError> {
+        self.bp.log().flush_all()?;
+        let saved = SavedCatalog {
+            tables: catalog.tables.keys().copied().collect(),
+            indexes: catalog
+                .indexes
+                .iter()
+                .map(|(c_id, index)| SavedIndex {
+                    c_id: *c_id,
+                    t_id: index.t_id,
+                    extractor: index.extractor,
+                })
+                .collect(),
         };
-        let data_files = Arc::new(RwLock::new(catalog));
-        StorageManager { lm, bp, data_files }
+        write_manifest(&self.storage_dir.join(CATALOG_MANIFEST), &saved)
+    }
+
+    /// Write every dirty page and the catalog to disk so the storage manager
+    /// can be reopened from its directory with `open`.
+    pub fn shutdown(&self) -> Result<(), CrustyError> {
+        // Hold the catalog so nothing changes while the pages are flushed
+        let data_files = self.data_files.write().unwrap();
+        self.bp.flush_all()?;
+        self.save_catalog(&data_files)?;
+        // Nothing is dirty, so reopening has nothing to redo
+        self.checkpoint()
+    }
+
+    /// Commit a transaction, releasing every lock it holds once its changes
+    /// are durable
+    pub fn commit_txn(&self, txn: &TransactionId) -> Result<(), CrustyError> {
+        if self.bp.log().checkpoint_due() {
+            self.checkpoint()?;
+        }
+        self.bp.log().commit(*txn)?;
+        self.release_locks(txn);
+        Ok(())
+    }
+
+    /// Abort a transaction, rolling back its changes and then releasing
+    /// every lock it holds
+    pub fn abort_txn(&self, txn: &TransactionId) -> Result<(), CrustyError> {
+        self.rollback(txn)?;
+        self.release_locks(txn);
+        Ok(())
+    }
+
+    /// Take a fuzzy checkpoint, so recovery starts from here and the log
+    /// before what it needs is dropped. Commits take one once enough has been
+    /// logged since the last.
+    pub fn checkpoint(&self) -> Result<(), CrustyError> {
+        self.bp.checkpoint()
+    }
+
+    /// Check the invariants of every container and that each index holds
+    /// exactly one entry for every record of its table. Meant to run while
+    /// nothing else uses the storage manager, such as right after `open`.
+    pub fn check(&self) -> Result<FsckReport, CrustyError> {
+        // Hold the catalog so no table or index is created or dropped meanwhile
+        let data_files = self.data_files.write().unwrap();
+        let indexes: Vec<CheckedIndex> = data_files
+            .indexes
+            .iter()
+            .map(|(i_id, index)| (*i_id, index.t_id, index.extractor))
+            .collect();
+        fsck::check(&self.bp, &self.lm, &indexes)
     }
 
-    fn create_table_with_idx(
+    /// The transactions that have changed something and not committed or aborted
+    pub fn active_txns(&self) -> Vec<TransactionId> {
+        self.bp.log().active_txns()
+    }
+
+    /// Undo a transaction's changes newest first by following its chain of
+    /// log records, then log that it finished aborting. Each undo puts back
+    /// what was there before the change, so it is safe to repeat one that
+    /// was cut short by a crash.
+    fn rollback(&self, txn: &TransactionId) -> Result<(), CrustyError> {
+        let log = self.bp.log();
+        let data_files = self.data_files.read().unwrap();
+        let mut next = log.txn_last_lsn(*txn);
+        while let Some(lsn) = next {
+            let LogRecord::Txn { prev_lsn, op, .. } = log.read(lsn)? else {
+                return Err(CrustyError::CrustyError(format!(
+                    "Log record {:?} of {:?} is not a change",
+                    lsn, txn
+                )));
+            };
+            // Changes to a table or index dropped since have nothing to undo
+            match &op {
+                TxnOp::HeapChange { v_id, before } => {
+                    if let Some(table) = data_files.tables.get(&v_id.container_id) {
+                        table.undo_change(v_id, before.as_ref())?;
+                    }
+                }
+                TxnOp::IndexAdd {
+                    c_id,
+                    search_key,
+                    pointer,
+                } => {
+                    if let Some(index) = data_files.indexes.get(c_id) {
+                        index.file.undo_add(search_key, pointer)?;
+                    }
+                }
+                TxnOp::IndexDelete {
+                    c_id,
+                    search_key,
+                    pointer,
+                } => {
+                    if let Some(index) = data_files.indexes.get(c_id) {
+                        index.file.undo_delete(search_key, pointer)?;
+                    }
+                }
+            }
+            next = prev_lsn;
+        }
+        log.end_abort(*txn)
+    }
+
+    fn release_locks(&self, txn: &TransactionId) {
+        // Only fails for a transaction that never asked for a lock, which has nothing to release
+        let _ = self.lm.release_all_locks(*txn);
+    }
+
+    /// Create a table without any indexes
+    pub fn create_table(&self, name: Option<String>) -> Result<ContainerId, CrustyError> {
+        let mut data_files = self.data_files.write().unwrap();
+        let t_id = self.bp.register_container(name, StateType::BaseTable)?;
+        let table = match FixedHeapFile::create(t_id, self.bp.clone(), self.lm.clone()) {
+            Ok(table) => table,
+            Err(e) => {
+                self.bp.drop_container(t_id)?;
+                return Err(e);
+            }
+        };
+        data_files.tables.insert(t_id, table);
+        self.save_catalog(&data_files)?;
+        Ok(t_id)
+    }
+
+    /// Create a table with an `INDEX_TYPE` index on the search key at the end
+    /// of the value
+    pub fn create_table_with_idx(
         &self,
         name: Option<String>,
     ) -> Result<(ContainerId, ContainerId), CrustyError> {
         let i_name = name.as_ref().map(|n| format!("{}_idx", n));
-        let mut data_files = self.data_files.write().unwrap();
-        let t_id = self.bp.register_container(name, StateType::BaseTable)?;
-        let i_id = self
-            .bp
-            .register_container(i_name, crate::index::INDEX_TYPE)?;
-        data_files.tables.insert(
-            t_id,
-            FixedHeapFile::new(t_id, self.bp.clone(), self.lm.clone()),
-        );
-        match crate::index::INDEX_TYPE {
-            StateType::HashTable => {
-                data_files.indexes.insert(
-                    i_id,
-                    FixedIndexFile::new(
-                        i_id,
-                        self.bp.clone(),
-                        self.lm.clone(),
-                        false,
-                        crate::index::STARTING_PAGE_CAPACITY,
-                    ),
-                );
-            }
-            StateType::Tree => {
-                data_files.indexes.insert(
-                    i_id,
-                    FixedIndexFile::new(
-                        i_id,
-                        self.bp.clone(),
-                        self.lm.clone(),
-                        true,
-                        crate::index::STARTING_PAGE_CAPACITY,
-                    ),
-                );
-            }
-            StateType::BaseTable => {
+        let t_id = self.create_table(name)?;
+        let i_id = self.create_index(
+            &t_id,
+            i_name,
+            KeyExtractor::default(),
+            crate::index::INDEX_TYPE,
+            false,
+        )?;
+        Ok((t_id, i_id))
+    }
+
+    /// Create an index on a table, keyed on the bytes of the value given by
+    /// `extractor`. `index_type` is Tree for an index that supports range
+    /// lookups or HashTable for one that does not. A `unique` index rejects
+    /// records whose search key it already holds with `CrustyError::DuplicateKey`.
+    /// Records already in the table are added to the new index, and if they
+    /// hold a duplicate key for a unique index no index is created.
+    pub fn create_index(
+        &self,
+        t_id: &ContainerId,
+        name: Option<String>,
+        extractor: KeyExtractor,
+        index_type: StateType,
+        unique: bool,
+    ) -> Result<ContainerId, CrustyError> {
+        let supports_range = match index_type {
+            StateType::Tree => true,
+            StateType::HashTable => false,
+            StateType::BaseTable | StateType::MatView | StateType::VarTable => {
                 return Err(CrustyError::InvalidOperation);
             }
-            StateType::MatView => {
-                return Err(CrustyError::InvalidOperation);
+        };
+        self.build_index(t_id, name, extractor, index_type, |i_id| {
+            FixedIndexFile::create(
+                i_id,
+                self.bp.clone(),
+                self.lm.clone(),
+                supports_range,
+                unique,
+                crate::index::STARTING_PAGE_CAPACITY,
+            )
+        })
+    }
+
+    /// Create an ordered hash index on a table. Like a HashTable index it
+    /// answers equality lookups from one bucket, and its buckets are kept in
+    /// key order so it can answer range queries too. It suits search keys
+    /// whose leading bytes are spread out. See `create_index` for the rest.
+    pub fn create_ordered_hash_index(
+        &self,
+        t_id: &ContainerId,
+        name: Option<String>,
+        extractor: KeyExtractor,
+        unique: bool,
+    ) -> Result<ContainerId, CrustyError> {
+        self.build_index(t_id, name, extractor, StateType::HashTable, |i_id| {
+            FixedIndexFile::create_ordered_hash(
+                i_id,
+                self.bp.clone(),
+                self.lm.clone(),
+                unique,
+                crate::index::STARTING_PAGE_CAPACITY,
+            )
+        })
+    }
+
+    /// Register a container for a new index, create the index in it with
+    /// `create` and add the table's records to it
+    fn build_index(
+        &self,
+        t_id: &ContainerId,
+        name: Option<String>,
+        extractor: KeyExtractor,
+        index_type: StateType,
+        create: impl FnOnce(ContainerId) -> Result<FixedIndexFile<BufferPool>, CrustyError>,
+    ) -> Result<ContainerId, CrustyError> {
+        extractor.validate()?;
+        let mut data_files = self.data_files.write().unwrap();
+        let table = data_files.table(t_id)?;
+        let i_id = self.bp.register_container(name, index_type)?;
+        let built = create(i_id).and_then(|file| {
+            // Load the existing records in one batch so a tree is built
+            // bottom-up. No one else can see the index yet, so its keys are not locked.
+            let txn = TransactionId::new();
+            let (search_keys, pointers): (Vec<_>, Vec<_>) = table
+                .iter(&txn)
+                .map(|(v_id, _key, val)| (extractor.extract(&val), v_id.to_fixed_bytes()))
+                .unzip();
+            file.bulk_add_with_fill(
+                search_keys.iter().collect(),
+                pointers,
+                BULK_LOAD_FILL_FACTOR,
+            )?;
+            Ok(file)
+        });
+        let file = match built {
+            Ok(file) => file,
+            Err(e) => {
+                self.bp.drop_container(i_id)?;
+                return Err(e);
             }
+        };
+        let index = TableIndex {
+            t_id: *t_id,
+            extractor,
+            file,
+        };
+        data_files.indexes.insert(i_id, index);
+        data_files
+            .table_to_indexes
+            .entry(*t_id)
+            .or_default()
+            .push(i_id);
+        self.save_catalog(&data_files)?;
+        Ok(i_id)
+    }
+
+    /// Remove an index, deleting its container
+    pub fn drop_index(&self, i_id: &ContainerId) -> Result<(), CrustyError> {
+        let mut data_files = self.data_files.write().unwrap();
+        let index = data_files
+            .indexes
+            .remove(i_id)
+            .ok_or(CrustyError::ContainerDoesNotExist)?;
+        if let Some(i_ids) = data_files.table_to_indexes.get_mut(&index.t_id) {
+            i_ids.retain(|i| i != i_id);
         }
-        data_files.table_to_index.insert(t_id, i_id);
-        Ok((t_id, i_id))
+        // Save the catalog first so it never names a dropped container
+        self.save_catalog(&data_files)?;
+        self.bp.drop_container(*i_id)
     }
 
-    fn insert_kv(
+    /// Remove a table and its indexes, deleting their containers
+    pub fn drop_table(&self, t_id: &ContainerId) -> Result<(), CrustyError> {
+        let mut data_files = self.data_files.write().unwrap();
+        if data_files.tables.remove(t_id).is_none() {
+            return Err(CrustyError::ContainerDoesNotExist);
+        }
+        let i_ids = data_files.table_to_indexes.remove(t_id).unwrap_or_default();
+        for i_id in i_ids.iter() {
+            data_files.indexes.remove(i_id);
+        }
+        // Save the catalog first so it never names a dropped container
+        self.save_catalog(&data_files)?;
+        self.bp.drop_container(*t_id)?;
+        for i_id in i_ids {
+            self.bp.drop_container(i_id)?;
+        }
+        Ok(())
+    }
+
+    /// The ids of every table
+    pub fn get_table_ids(&self) -> Vec<ContainerId> {
+        self.data_files
+            .read()
+            .unwrap()
+            .tables
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    /// The ids of the indexes on a table with their key extractors, in creation order
+    pub fn get_index_ids(
+        &self,
+        t_id: &ContainerId,
+    ) -> Result<Vec<(ContainerId, KeyExtractor)>, CrustyError> {
+        let data_files = self.data_files.read().unwrap();
+        data_files.table(t_id)?;
+        Ok(data_files
+            .table_to_indexes
+            .get(t_id)
+            .map(|i_ids| {
+                i_ids
+                    .iter()
+                    .map(|i_id| (*i_id, data_files.indexes[i_id].extractor))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Iterate over the records of a table in page and slot order, starting
+    /// from the page and slot of `start` if given. The scan takes no locks.
+    pub fn get_iterator(
+        &self,
+        c_id: &ContainerId,
+        start: Option<&ValueId>,
+        txn: &TransactionId,
+    ) -> Result<FixedHeapFileIter<BufferPool>, CrustyError> {
+        let data_files = self.data_files.read().unwrap();
+        let table = data_files.table(c_id)?;
+        Ok(match start {
+            Some(start) => table.iter_from(start, txn),
+            None => table.iter(txn),
+        })
+    }
+
+    /// Write out every dirty page and drop all pages held in memory
+    pub fn clear_cache(&self) -> Result<(), CrustyError> {
+        self.bp.clear_cache()
+    }
+
+    pub fn get_storage_dir(&self) -> &Path {
+        &self.storage_dir
+    }
+
+    pub fn insert_kv(
         &self,
         c_id: &ContainerId,
         key: &[u8],
         val: &[u8],
         txn: &TransactionId,
     ) -> Result<ValueId, CrustyError> {
-        let search_key = extract_search_key(val);
         let data_files = self.data_files.read().unwrap();
-        let table = data_files
-            .tables
-            .get(c_id)
-            .ok_or(CrustyError::ContainerDoesNotExist)?;
-        let index = data_files
-            .indexes
-            .get(&data_files.table_to_index[c_id])
-            .ok_or(CrustyError::ContainerDoesNotExist)?;
+        let table = data_files.table(c_id)?;
+        let indexes = data_files.table_indexes(c_id);
         let v_id = table.insert_kv(key, val, txn)?;
-        let _i_v_id = index.add(search_key, &v_id.to_fixed_bytes(), txn)?;
+        if let Err(e) = index_record(&indexes, val, &v_id, txn) {
+            table.delete_kv(&v_id, txn)?;
+            return Err(e);
+        }
         Ok(v_id)
     }
 
-    fn insert_kvs(
+    pub fn insert_kvs(
         &self,
         c_id: &ContainerId,
         recs: Vec<(&[u8], &[u8])>,
         txn: &TransactionId,
     ) -> Result<Vec<ValueId>, CrustyError> {
-        let search_keys = recs.iter().map(|(_, v)| extract_search_key(v)).collect();
         let data_files = self.data_files.read().unwrap();
-        let table = data_files
-            .tables
-            .get(c_id)
-            .ok_or(CrustyError::ContainerDoesNotExist)?;
-        let index = data_files
-            .indexes
-            .get(&data_files.table_to_index[c_id])
-            .ok_or(CrustyError::ContainerDoesNotExist)?;
+        let table = data_files.table(c_id)?;
+        let indexes = data_files.table_indexes(c_id);
         let v_ids = table.bulk_insert_kv(&recs, txn)?;
-        let mut v_id_bytes = Vec::new(); //v_ids.iter().map(|v_id| v_id.to_fixed_bytes()).collect();
-        for v_id in &v_ids {
-            let fixed_bytes = v_id.to_fixed_bytes();
-            v_id_bytes.push(fixed_bytes);
+        let v_id_bytes: Vec<[u8; INDEX_POINTER_SIZE]> =
+            v_ids.iter().map(|v_id| v_id.to_fixed_bytes()).collect();
+        for (i, index) in indexes.iter().enumerate() {
+            let search_keys: Vec<[u8; SEARCH_KEY_SIZE]> = recs
+                .iter()
+                .map(|(_, v)| index.extractor.extract(v))
+                .collect();
+            let added = index
+                .file
+                .bulk_add(search_keys.iter().collect(), v_id_bytes.clone(), txn);
+            if let Err(e) = added {
+                for ((_, val), v_id) in recs.iter().zip(v_ids.iter()) {
+                    unindex_record(&indexes[..i], val, v_id, txn)?;
+                    // The failed index may hold only part of the batch
+                    let _ = unindex_record(&indexes[i..=i], val, v_id, txn);
+                    table.delete_kv(v_id, txn)?;
+                }
+                return Err(e);
+            }
         }
-        let _i_v_id = index.bulk_add(search_keys, v_id_bytes, txn)?;
         Ok(v_ids)
     }
 
-    fn get_kv_by_val_id(
+    /// Replace the key and value of a record. Every index whose search key
+    /// changed has the record's entry moved to the new search key.
+    pub fn update_kv(
         &self,
         c_id: &ContainerId,
         v_id: &ValueId,
+        key: &[u8],
+        val: &[u8],
         txn: &TransactionId,
-    ) -> Result<(Vec<u8>, Vec<u8>), CrustyError> {
+    ) -> Result<(), CrustyError> {
         let data_files = self.data_files.read().unwrap();
-        let table = data_files
-            .tables
-            .get(c_id)
-            .ok_or(CrustyError::ContainerDoesNotExist)?;
-        table.get_kv(v_id, txn)
+        let table = data_files.table(c_id)?;
+        let indexes = data_files.table_indexes(c_id);
+        // Lock the record before its index entries so a writer that cannot get it changes nothing
+        self.lm.acquire_lock(*txn, *v_id, Permissions::ReadWrite)?;
+        let (_old_key, old_val) = table.get_kv(v_id, txn)?;
+        // Move the index entries first, so a failed index update leaves the record unchanged
+        reindex_record(&indexes, &old_val, val, v_id, txn)?;
+        if let Err(e) = table.update_kv(v_id, key, val, txn) {
+            reindex_record(&indexes, val, &old_val, v_id, txn)?;
+            return Err(e);
+        }
+        Ok(())
     }
 
-    fn get_kvs_by_search_key_equality(
+    /// Delete a record and its index entries
+    pub fn delete_kv(
         &self,
         c_id: &ContainerId,
-        search_key: &[u8; SEARCH_KEY_SIZE],
+        v_id: &ValueId,
         txn: &TransactionId,
-    ) -> ResultKVs {
-        let mut res = Vec::new();
+    ) -> Result<(), CrustyError> {
         let data_files = self.data_files.read().unwrap();
-        let table = data_files
-            .tables
-            .get(c_id)
-            .ok_or(CrustyError::ContainerDoesNotExist)?;
-        let index = data_files
-            .indexes
-            .get(&data_files.table_to_index[c_id])
-            .ok_or(CrustyError::ContainerDoesNotExist)?;
-        let v_ids = index.get_pointers_for_key(search_key, txn)?;
-        for v_id in v_ids {
-            let kv = table.get_kv(&v_id, txn)?;
-            res.push(kv);
-        }
-        Ok(res)
+        let table = data_files.table(c_id)?;
+        self.lm.acquire_lock(*txn, *v_id, Permissions::ReadWrite)?;
+        let (_key, val) = table.get_kv(v_id, txn)?;
+        unindex_record(&data_files.table_indexes(c_id), &val, v_id, txn)?;
+        table.delete_kv(v_id, txn)
     }
 
-    fn get_kvs_by_search_key_range(
+    pub fn get_kv_by_val_id(
         &self,
         c_id: &ContainerId,
+        v_id: &ValueId,
+        txn: &TransactionId,
+    ) -> Result<(Vec<u8>, Vec<u8>), CrustyError> {
+        let data_files = self.data_files.read().unwrap();
+        data_files.table(c_id)?.get_kv(v_id, txn)
+    }
+
+    /// Open a lazy scan of a table through one of its index's cursors
+    fn index_scan(
+        &self,
+        i_id: &ContainerId,
+        scan: impl FnOnce(&FixedIndexFile<BufferPool>) -> Result<CursorScan, CrustyError>,
+        txn: &TransactionId,
+    ) -> Result<IndexScan<'_>, CrustyError> {
+        let data_files = self.data_files.read().unwrap();
+        let index = data_files.index(i_id)?;
+        let scan = scan(&index.file)?;
+        let cursor = index.file.cursor_in(&self.bp, scan)?;
+        Ok(IndexScan {
+            sm: self,
+            t_id: index.t_id,
+            extractor: index.extractor,
+            scan,
+            cursor,
+            txn: *txn,
+        })
+    }
+
+    /// Lazily scan the records whose search key in index `i_id` equals `search_key`
+    pub fn scan_by_search_key(
+        &self,
+        i_id: &ContainerId,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+        txn: &TransactionId,
+    ) -> Result<IndexScan<'_>, CrustyError> {
+        self.index_scan(
+            i_id,
+            |index| {
+                index.lock_key(search_key, txn, Permissions::ReadOnly)?;
+                Ok(index.key_scan(search_key))
+            },
+            txn,
+        )
+    }
+
+    /// Lazily scan the records whose search key in index `i_id` is in the range, in key order
+    pub fn scan_by_search_key_range(
+        &self,
+        i_id: &ContainerId,
         search_key_min_inclusive: &[u8; SEARCH_KEY_SIZE],
         search_key_max_exclusive: &[u8; SEARCH_KEY_SIZE],
         txn: &TransactionId,
+    ) -> Result<IndexScan<'_>, CrustyError> {
+        self.index_scan(
+            i_id,
+            |index| index.range_scan(search_key_min_inclusive, search_key_max_exclusive),
+            txn,
+        )
+    }
+
+    /// Lazily scan the records whose search key in index `i_id` is between
+    /// two bounds, either of which can be open. Records come in ascending key
+    /// order, or descending with `reverse`.
+    pub fn scan_by_search_key_bounds(
+        &self,
+        i_id: &ContainerId,
+        min: Bound<[u8; SEARCH_KEY_SIZE]>,
+        max: Bound<[u8; SEARCH_KEY_SIZE]>,
+        reverse: bool,
+        txn: &TransactionId,
+    ) -> Result<IndexScan<'_>, CrustyError> {
+        self.index_scan(i_id, |index| index.bounded_scan(min, max, reverse), txn)
+    }
+
+    /// The records whose search key in index `i_id` is between two bounds,
+    /// found by reading every entry of the index. This is a full scan for
+    /// hash indexes that cannot answer range queries otherwise. Records come
+    /// in key order.
+    pub fn full_scan_by_search_key_bounds(
+        &self,
+        i_id: &ContainerId,
+        min: Bound<[u8; SEARCH_KEY_SIZE]>,
+        max: Bound<[u8; SEARCH_KEY_SIZE]>,
+        txn: &TransactionId,
     ) -> ResultKVs {
-        let mut res = Vec::new();
-        let data_files = self.data_files.read().unwrap();
-        let table = data_files
-            .tables
-            .get(c_id)
-            .ok_or(CrustyError::ContainerDoesNotExist)?;
-        let index = data_files
-            .indexes
-            .get(&data_files.table_to_index[c_id])
-            .ok_or(CrustyError::ContainerDoesNotExist)?;
-        let v_ids = index.get_pointers_for_key_range(
+        let (t_id, v_ids) = {
+            let data_files = self.data_files.read().unwrap();
+            let index = data_files.index(i_id)?;
+            (index.t_id, index.file.full_scan_key_range(min, max, txn)?)
+        };
+        v_ids
+            .iter()
+            .map(|v_id| self.get_kv_by_val_id(&t_id, v_id, txn))
+            .collect()
+    }
+
+    /// The records whose search key in index `i_id` equals `search_key`
+    pub fn get_kvs_by_search_key_equality(
+        &self,
+        i_id: &ContainerId,
+        search_key: &[u8; SEARCH_KEY_SIZE],
+        txn: &TransactionId,
+    ) -> ResultKVs {
+        self.scan_by_search_key(i_id, search_key, txn)?.collect()
+    }
+
+    /// The records whose search key in index `i_id` is in the range, in key order
+    pub fn get_kvs_by_search_key_range(
+        &self,
+        i_id: &ContainerId,
+        search_key_min_inclusive: &[u8; SEARCH_KEY_SIZE],
+        search_key_max_exclusive: &[u8; SEARCH_KEY_SIZE],
+        txn: &TransactionId,
+    ) -> ResultKVs {
+        self.scan_by_search_key_range(
+            i_id,
             search_key_min_inclusive,
             search_key_max_exclusive,
             txn,
-        )?;
-        for v_id in v_ids {
-            let kv = table.get_kv(&v_id, txn)?;
-            res.push(kv);
+        )?
+        .collect()
+    }
+
+    /// The records whose search key in index `i_id` is between two bounds,
+    /// in ascending key order or descending with `reverse`
+    pub fn get_kvs_by_search_key_bounds(
+        &self,
+        i_id: &ContainerId,
+        min: Bound<[u8; SEARCH_KEY_SIZE]>,
+        max: Bound<[u8; SEARCH_KEY_SIZE]>,
+        reverse: bool,
+        txn: &TransactionId,
+    ) -> ResultKVs {
+        self.scan_by_search_key_bounds(i_id, min, max, reverse, txn)?
+            .collect()
+    }
+}
+
+/// Lazily yields the records an index lookup finds. The index cursor holds
+/// no latch between records and each record is read from the heap as it is
+/// reached, so the scan's thread can change the table while it is open.
+pub struct IndexScan<'a> {
+    sm: &'a StorageManager,
+    t_id: ContainerId,
+    extractor: KeyExtractor,
+    scan: CursorScan,
+    cursor: IndexCursor<'a, BufferPool>,
+    txn: TransactionId,
+}
+
+impl Iterator for IndexScan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>), CrustyError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let v_id = match self.cursor.next()? {
+                Ok(v_id) => v_id,
+                Err(e) => return Some(Err(e)),
+            };
+            match self.sm.get_kv_by_val_id(&self.t_id, &v_id, &self.txn) {
+                // The record was deleted, or its slot reused, after the
+                // cursor read its entry
+                Err(CrustyError::ValueNotFound(_)) => continue,
+                Ok((_, val)) if !self.scan.contains(&self.extractor.extract(&val)) => continue,
+                res => return Some(res),
+            }
         }
-        Ok(res)
     }
 }
 
@@ -213,15 +981,17 @@ impl StorageManager {
 mod tests {
     use rand::{rngs::SmallRng, SeedableRng};
 
-    use crate::test_util::{gen_records_ascending_keys, SearchKeyTypes};
+    use crate::prelude::{extract_search_key, KEY_SIZE, VALUE_SIZE};
+    use crate::test_util::{gen_random_test_dir, gen_records_ascending_keys, SearchKeyTypes};
 
     #[test]
     fn test_storage_manager_single_thread() {
         use super::*;
 
-        let sm = StorageManager::new(1000);
+        let dir = gen_random_test_dir();
+        let sm = StorageManager::new(&dir, 1000);
         let txn = TransactionId::new();
-        let (t_id, _i_id) = sm
+        let (t_id, i_id) = sm
             .create_table_with_idx(Some("test_table".to_string()))
             .unwrap();
 
@@ -235,30 +1005,933 @@ mod tests {
             HashMap::with_capacity(100);
         let mut v_ids_to_kv = HashMap::new();
         for (key, value) in &recs {
-            let v_id = sm.insert_kv(&t_id, &key, &value, &txn).unwrap();
+            let v_id = sm.insert_kv(&t_id, key, value, &txn).unwrap();
             assert!(v_ids_to_kv
                 .insert(v_id, (key.to_vec(), value.to_vec()))
                 .is_none());
             let search_key = extract_search_key(value);
             search_keys_to_vids
                 .entry(*search_key)
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(v_id);
             search_key_to_keys
                 .entry(*search_key)
-                .or_insert_with(Vec::new)
+                .or_default()
                 .push(key.to_vec());
         }
         for (search_key, keys) in search_key_to_keys {
             let kvs = sm
-                .get_kvs_by_search_key_equality(&t_id, &search_key, &txn)
+                .get_kvs_by_search_key_equality(&i_id, &search_key, &txn)
                 .unwrap();
             assert_eq!(kvs.len(), keys.len());
             for (k, _v) in kvs {
                 assert!(keys.contains(&k));
             }
         }
-        // TODO re-add after adding locks to lock manager
-        //assert!(sm.lm.release_all_locks(txn).is_ok());
+        assert!(sm.lm.release_all_locks(txn).is_ok());
+    }
+
+    #[test]
+    fn test_storage_manager_reopen() {
+        use super::*;
+
+        let dir = gen_random_test_dir();
+        let txn = TransactionId::new();
+        let mut rng = SmallRng::seed_from_u64(23530);
+        let recs = gen_records_ascending_keys(1000, SearchKeyTypes::Card(50), &mut rng);
+        let (t_id, i_id) = {
+            let sm = StorageManager::new(&dir, 1000);
+            let (t_id, i_id) = sm
+                .create_table_with_idx(Some("test_table".to_string()))
+                .unwrap();
+            for (key, value) in &recs[..500] {
+                sm.insert_kv(&t_id, key, value, &txn).unwrap();
+            }
+            sm.commit_txn(&txn).unwrap();
+            sm.shutdown().unwrap();
+            (t_id, i_id)
+        };
+
+        let sm = StorageManager::open(&dir, 1000).unwrap();
+        {
+            let data_files = sm.data_files.read().unwrap();
+            assert_eq!(data_files.table_to_indexes.get(&t_id), Some(&vec![i_id]));
+            assert!(data_files.tables.contains_key(&t_id));
+            assert!(data_files.indexes.contains_key(&i_id));
+        }
+        // New records go after the reopened ones and both are found through the index
+        for (key, value) in &recs[500..] {
+            sm.insert_kv(&t_id, key, value, &txn).unwrap();
+        }
+        let mut expected: HashMap<[u8; SEARCH_KEY_SIZE], Vec<Vec<u8>>> = HashMap::new();
+        for (key, value) in &recs {
+            expected
+                .entry(*extract_search_key(value))
+                .or_default()
+                .push(key.to_vec());
+        }
+        for (search_key, mut keys) in expected {
+            let mut found: Vec<Vec<u8>> = sm
+                .get_kvs_by_search_key_equality(&i_id, &search_key, &txn)
+                .unwrap()
+                .into_iter()
+                .map(|(k, _v)| k)
+                .collect();
+            found.sort();
+            keys.sort();
+            assert_eq!(found, keys);
+        }
+        sm.commit_txn(&txn).unwrap();
+        sm.shutdown().unwrap();
+        let sm = StorageManager::open(&dir, 1000).unwrap();
+        let all = sm
+            .get_kvs_by_search_key_range(
+                &i_id,
+                &[0; SEARCH_KEY_SIZE],
+                &[255; SEARCH_KEY_SIZE],
+                &txn,
+            )
+            .unwrap();
+        assert_eq!(all.len(), recs.len());
+    }
+
+    #[test]
+    fn test_storage_manager_update_delete() {
+        use super::*;
+
+        let dir = gen_random_test_dir();
+        let sm = StorageManager::new(&dir, 1000);
+        let txn = TransactionId::new();
+        let (t_id, i_id) = sm.create_table_with_idx(None).unwrap();
+        let mut rng = SmallRng::seed_from_u64(23530);
+        let recs = gen_records_ascending_keys(500, SearchKeyTypes::Card(20), &mut rng);
+        let mut v_ids = Vec::new();
+        for (key, value) in &recs {
+            v_ids.push(sm.insert_kv(&t_id, key, value, &txn).unwrap());
+        }
+        let count = |search_key: &[u8; SEARCH_KEY_SIZE]| {
+            sm.get_kvs_by_search_key_equality(&i_id, search_key, &txn)
+                .unwrap()
+                .len()
+        };
+
+        // Same search key, the value changes in place
+        let (key, mut value) = recs[0].clone();
+        let search_key = *extract_search_key(&value);
+        let before = count(&search_key);
+        value[0] ^= 0xff;
+        sm.update_kv(&t_id, &v_ids[0], &key, &value, &txn).unwrap();
+        assert_eq!(count(&search_key), before);
+        assert_eq!(
+            sm.get_kv_by_val_id(&t_id, &v_ids[0], &txn).unwrap(),
+            (key.clone(), value.clone())
+        );
+
+        // A new search key moves the index entry
+        let new_search_key = [0xee; SEARCH_KEY_SIZE];
+        let len = value.len();
+        value[len - SEARCH_KEY_SIZE..].copy_from_slice(&new_search_key);
+        sm.update_kv(&t_id, &v_ids[0], &key, &value, &txn).unwrap();
+        assert_eq!(count(&search_key), before - 1);
+        assert_eq!(
+            sm.get_kvs_by_search_key_equality(&i_id, &new_search_key, &txn)
+                .unwrap(),
+            vec![(key, value)]
+        );
+
+        // Deletes remove the index entry
+        let search_key = *extract_search_key(&recs[1].1);
+        let before = count(&search_key);
+        sm.delete_kv(&t_id, &v_ids[1], &txn).unwrap();
+        assert_eq!(count(&search_key), before - 1);
+        assert!(sm.get_kv_by_val_id(&t_id, &v_ids[1], &txn).is_err());
+        assert!(sm.delete_kv(&t_id, &v_ids[1], &txn).is_err());
+        assert!(sm
+            .update_kv(&t_id, &v_ids[1], &recs[1].0, &recs[1].1, &txn)
+            .is_err());
+        let all = sm
+            .get_kvs_by_search_key_range(
+                &i_id,
+                &[0; SEARCH_KEY_SIZE],
+                &[255; SEARCH_KEY_SIZE],
+                &txn,
+            )
+            .unwrap();
+        assert_eq!(all.len(), recs.len() - 1);
+    }
+
+    #[test]
+    fn test_storage_manager_multiple_indexes() {
+        use super::*;
+
+        let dir = gen_random_test_dir();
+        let txn = TransactionId::new();
+        let sm = StorageManager::new(&dir, 1000);
+        let t_id = sm.create_table(Some("multi".to_string())).unwrap();
+        // Byte 0 of the value holds i % 10 and bytes 1..3 hold i
+        let record = |i: u16| {
+            let mut value = vec![0; VALUE_SIZE];
+            value[0] = (i % 10) as u8;
+            value[1..3].copy_from_slice(&i.to_be_bytes());
+            (i.to_be_bytes().repeat(KEY_SIZE / 2), value)
+        };
+        for i in 0..300 {
+            let (k, v) = record(i);
+            sm.insert_kv(&t_id, &k, &v, &txn).unwrap();
+        }
+        // Existing records are added to a new index
+        let mod_idx = sm
+            .create_index(
+                &t_id,
+                None,
+                KeyExtractor::new(0, 1).unwrap(),
+                StateType::HashTable,
+                false,
+            )
+            .unwrap();
+        let recs: Vec<(Vec<u8>, Vec<u8>)> = (300..600).map(record).collect();
+        let recs_ref = recs.iter().map(|(k, v)| (&k[..], &v[..])).collect();
+        let v_ids = sm.insert_kvs(&t_id, recs_ref, &txn).unwrap();
+        let num_idx = sm
+            .create_index(
+                &t_id,
+                None,
+                KeyExtractor::new(1, 2).unwrap(),
+                StateType::Tree,
+                false,
+            )
+            .unwrap();
+        assert!(sm
+            .create_index(
+                &t_id,
+                None,
+                KeyExtractor { offset: 0, len: 9 },
+                StateType::Tree,
+                false
+            )
+            .is_err());
+        assert_eq!(
+            sm.get_index_ids(&t_id).unwrap(),
+            vec![
+                (mod_idx, KeyExtractor::new(0, 1).unwrap()),
+                (num_idx, KeyExtractor::new(1, 2).unwrap())
+            ]
+        );
+
+        let key = |bytes: &[u8]| {
+            let mut key = [0; SEARCH_KEY_SIZE];
+            key[..bytes.len()].copy_from_slice(bytes);
+            key
+        };
+        let eq = |i_id: &ContainerId, k: &[u8]| {
+            sm.get_kvs_by_search_key_equality(i_id, &key(k), &txn)
+                .unwrap()
+                .len()
+        };
+        assert_eq!(eq(&mod_idx, &[3]), 60);
+        let range = sm
+            .get_kvs_by_search_key_range(&num_idx, &key(&[0, 10]), &key(&[0, 20]), &txn)
+            .unwrap();
+        assert_eq!(range, (10..20).map(record).collect::<Vec<_>>());
+        assert!(sm
+            .get_kvs_by_search_key_range(&mod_idx, &key(&[0]), &key(&[1]), &txn)
+            .is_err());
+
+        // Updates and deletes keep every index in step
+        let (k, mut v) = record(300);
+        v[0] = 3;
+        v[1..3].copy_from_slice(&1000u16.to_be_bytes());
+        sm.update_kv(&t_id, &v_ids[0], &k, &v, &txn).unwrap();
+        assert_eq!(eq(&mod_idx, &[0]), 59);
+        assert_eq!(eq(&mod_idx, &[3]), 61);
+        assert_eq!(eq(&num_idx, &300u16.to_be_bytes()), 0);
+        assert_eq!(eq(&num_idx, &1000u16.to_be_bytes()), 1);
+        sm.delete_kv(&t_id, &v_ids[1], &txn).unwrap();
+        assert_eq!(eq(&mod_idx, &[1]), 59);
+        assert_eq!(eq(&num_idx, &301u16.to_be_bytes()), 0);
+
+        // Indexes come back on reopen, and dropping one leaves the others
+        sm.commit_txn(&txn).unwrap();
+        sm.shutdown().unwrap();
+        drop(sm);
+        let sm = StorageManager::open(&dir, 1000).unwrap();
+        assert_eq!(sm.get_index_ids(&t_id).unwrap().len(), 2);
+        sm.drop_index(&mod_idx).unwrap();
+        assert!(sm
+            .get_kvs_by_search_key_equality(&mod_idx, &key(&[3]), &txn)
+            .is_err());
+        assert_eq!(
+            sm.get_index_ids(&t_id).unwrap(),
+            vec![(num_idx, KeyExtractor::new(1, 2).unwrap())]
+        );
+        let (k, v) = record(600);
+        sm.insert_kv(&t_id, &k, &v, &txn).unwrap();
+        assert_eq!(
+            sm.get_kvs_by_search_key_equality(&num_idx, &key(&600u16.to_be_bytes()), &txn)
+                .unwrap(),
+            vec![(k, v)]
+        );
+        sm.drop_table(&t_id).unwrap();
+        assert!(sm.get_index_ids(&t_id).is_err());
+        assert!(sm
+            .get_kvs_by_search_key_equality(&num_idx, &key(&[0]), &txn)
+            .is_err());
+    }
+
+    #[test]
+    fn test_storage_manager_unique_index() {
+        use super::*;
+
+        let dir = gen_random_test_dir();
+        let sm = StorageManager::new(&dir, 1000);
+        let txn = TransactionId::new();
+        let t_id = sm.create_table(None).unwrap();
+        let record = |id: u8, search: u8| {
+            let mut value = vec![0; VALUE_SIZE];
+            value[0] = search;
+            (vec![id; KEY_SIZE], value)
+        };
+        let first = KeyExtractor::new(0, 1).unwrap();
+        for (id, search) in [(0, 0), (1, 1), (2, 1)] {
+            let (k, v) = record(id, search);
+            sm.insert_kv(&t_id, &k, &v, &txn).unwrap();
+        }
+        // Existing duplicates stop a unique index from being created
+        let err = sm.create_index(&t_id, None, first, StateType::Tree, true);
+        assert!(matches!(err, Err(CrustyError::DuplicateKey(_))));
+        assert!(sm.get_index_ids(&t_id).unwrap().is_empty());
+        let dup_v_id = ValueId::new_slot(t_id, 0, 2);
+        sm.delete_kv(&t_id, &dup_v_id, &txn).unwrap();
+
+        let i_id = sm
+            .create_index(&t_id, None, first, StateType::HashTable, true)
+            .unwrap();
+        let count = || sm.get_iterator(&t_id, None, &txn).unwrap().count();
+        // A rejected insert leaves no record in the heap
+        let (k, v) = record(3, 1);
+        let err = sm.insert_kv(&t_id, &k, &v, &txn);
+        assert!(matches!(err, Err(CrustyError::DuplicateKey(_))));
+        assert_eq!(count(), 2);
+        let batch = [record(4, 4), record(5, 5), record(6, 4)];
+        let err = sm.insert_kvs(
+            &t_id,
+            batch.iter().map(|(k, v)| (&k[..], &v[..])).collect(),
+            &txn,
+        );
+        assert!(matches!(err, Err(CrustyError::DuplicateKey(_))));
+        assert_eq!(count(), 2);
+        let batch = [record(4, 4), record(5, 5)];
+        sm.insert_kvs(
+            &t_id,
+            batch.iter().map(|(k, v)| (&k[..], &v[..])).collect(),
+            &txn,
+        )
+        .unwrap();
+        assert_eq!(count(), 4);
+
+        // An update onto a taken key leaves the record as it was
+        let v_id = ValueId::new_slot(t_id, 0, 0);
+        let (k, v) = record(0, 5);
+        let err = sm.update_kv(&t_id, &v_id, &k, &v, &txn);
+        assert!(matches!(err, Err(CrustyError::DuplicateKey(_))));
+        assert_eq!(
+            sm.get_kv_by_val_id(&t_id, &v_id, &txn).unwrap(),
+            record(0, 0)
+        );
+        let search = |b: u8| {
+            let mut key = [0; SEARCH_KEY_SIZE];
+            key[0] = b;
+            sm.get_kvs_by_search_key_equality(&i_id, &key, &txn)
+                .unwrap()
+        };
+        assert_eq!(search(0), vec![record(0, 0)]);
+        assert_eq!(search(5), vec![record(5, 5)]);
+    }
+
+    #[test]
+    fn test_storage_manager_lazy_scans() {
+        use super::*;
+
+        let dir = gen_random_test_dir();
+        let sm = StorageManager::new(&dir, 1000);
+        let txn = TransactionId::new();
+        let (t_id, i_id) = sm.create_table_with_idx(None).unwrap();
+        let mut rng = SmallRng::seed_from_u64(23530);
+        let recs = gen_records_ascending_keys(2000, SearchKeyTypes::Card(40), &mut rng);
+        for (key, value) in &recs {
+            sm.insert_kv(&t_id, key, value, &txn).unwrap();
+        }
+        let mut search_keys: Vec<[u8; SEARCH_KEY_SIZE]> =
+            recs.iter().map(|(_k, v)| *extract_search_key(v)).collect();
+        search_keys.sort();
+        search_keys.dedup();
+
+        let (min, max) = (search_keys[5], search_keys[30]);
+        let mut scan = sm
+            .scan_by_search_key_range(&i_id, &min, &max, &txn)
+            .unwrap();
+        let (_k, v) = scan.next().unwrap().unwrap();
+        assert_eq!(extract_search_key(&v), &min);
+        let mut last = min;
+        let mut count = 1;
+        for kv in scan {
+            let (_k, v) = kv.unwrap();
+            let search_key = *extract_search_key(&v);
+            assert!(search_key >= last && search_key < max);
+            last = search_key;
+            count += 1;
+        }
+        let expected = recs
+            .iter()
+            .filter(|(_k, v)| (min..max).contains(extract_search_key(v)))
+            .count();
+        assert_eq!(count, expected);
+
+        let eq: Vec<(Vec<u8>, Vec<u8>)> = sm
+            .scan_by_search_key(&i_id, &search_keys[0], &txn)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            eq,
+            sm.get_kvs_by_search_key_equality(&i_id, &search_keys[0], &txn)
+                .unwrap()
+        );
+        assert!(sm.scan_by_search_key(&t_id, &search_keys[0], &txn).is_err());
+    }
+
+    #[test]
+    fn test_storage_manager_scans_hold_no_latches() {
+        use super::*;
+        use std::thread;
+        use std::time::Duration;
+
+        let dir = gen_random_test_dir();
+        let sm = StorageManager::new(&dir, 2000);
+        let (t_id, i_id) = sm.create_table_with_idx(None).unwrap();
+        let mut rng = SmallRng::seed_from_u64(23530);
+        let recs = gen_records_ascending_keys(300, SearchKeyTypes::Distinct, &mut rng);
+        let setup = TransactionId::new();
+        for (key, value) in &recs[..200] {
+            sm.insert_kv(&t_id, key, value, &setup).unwrap();
+        }
+        sm.commit_txn(&setup).unwrap();
+        let (min, max) = ([0; SEARCH_KEY_SIZE], [u8::MAX; SEARCH_KEY_SIZE]);
+
+        // The scan's own thread can change the index part way through it
+        let txn = TransactionId::new();
+        let mut scan = sm
+            .scan_by_search_key_range(&i_id, &min, &max, &txn)
+            .unwrap();
+        scan.next().unwrap().unwrap();
+        let (key, value) = &recs[200];
+        sm.insert_kv(&t_id, key, value, &txn).unwrap();
+        assert!(scan.count() >= 199);
+        sm.commit_txn(&txn).unwrap();
+
+        // A scan waiting on a record lock does not keep the writer holding
+        // it from changing the index
+        let writer = TransactionId::new();
+        let (key, value) = &recs[201];
+        sm.insert_kv(&t_id, key, value, &writer).unwrap();
+        thread::scope(|s| {
+            let scanner = s.spawn(|| {
+                let txn = TransactionId::new();
+                let found = sm
+                    .scan_by_search_key_range(&i_id, &min, &max, &txn)
+                    .unwrap()
+                    .collect::<Result<Vec<_>, _>>();
+                sm.commit_txn(&txn).unwrap();
+                found
+            });
+            thread::sleep(Duration::from_millis(100));
+            for (key, value) in &recs[202..] {
+                sm.insert_kv(&t_id, key, value, &writer).unwrap();
+            }
+            sm.commit_txn(&writer).unwrap();
+            let found = scanner.join().unwrap().unwrap();
+            assert!(found.contains(&recs[201]));
+        });
+    }
+
+    #[test]
+    fn test_storage_manager_reverse_scans() {
+        use super::*;
+
+        let dir = gen_random_test_dir();
+        let sm = StorageManager::new(&dir, 1000);
+        let txn = TransactionId::new();
+        let (t_id, i_id) = sm.create_table_with_idx(None).unwrap();
+        let mut rng = SmallRng::seed_from_u64(23530);
+        let recs = gen_records_ascending_keys(2000, SearchKeyTypes::Distinct, &mut rng);
+        for (key, value) in &recs {
+            sm.insert_kv(&t_id, key, value, &txn).unwrap();
+        }
+        let mut search_keys: Vec<[u8; SEARCH_KEY_SIZE]> = recs
+            .iter()
+            .map(|(_key, value)| *extract_search_key(value))
+            .collect();
+        search_keys.sort();
+
+        // The latest 10 records by search key
+        let latest: Vec<[u8; SEARCH_KEY_SIZE]> = sm
+            .scan_by_search_key_bounds(&i_id, Bound::Unbounded, Bound::Unbounded, true, &txn)
+            .unwrap()
+            .take(10)
+            .map(|kv| *extract_search_key(&kv.unwrap().1))
+            .collect();
+        let want: Vec<[u8; SEARCH_KEY_SIZE]> = search_keys.iter().rev().take(10).copied().collect();
+        assert_eq!(latest, want);
+
+        // Open above, exclusive below
+        let below: Vec<[u8; SEARCH_KEY_SIZE]> = sm
+            .get_kvs_by_search_key_bounds(
+                &i_id,
+                Bound::Excluded(search_keys[100]),
+                Bound::Unbounded,
+                true,
+                &txn,
+            )
+            .unwrap()
+            .iter()
+            .map(|(_key, value)| *extract_search_key(value))
+            .collect();
+        let want: Vec<[u8; SEARCH_KEY_SIZE]> = search_keys[101..].iter().rev().copied().collect();
+        assert_eq!(below, want);
+    }
+
+    #[test]
+    fn test_storage_manager_hash_ranges() {
+        use super::*;
+
+        let dir = gen_random_test_dir();
+        let sm = StorageManager::new(&dir, 1000);
+        let txn = TransactionId::new();
+        let t_id = sm.create_table(None).unwrap();
+        let mut rng = SmallRng::seed_from_u64(23530);
+        let recs = gen_records_ascending_keys(1500, SearchKeyTypes::Random, &mut rng);
+        for (key, value) in &recs[..1000] {
+            sm.insert_kv(&t_id, key, value, &txn).unwrap();
+        }
+        let hash = sm
+            .create_index(
+                &t_id,
+                None,
+                KeyExtractor::default(),
+                StateType::HashTable,
+                false,
+            )
+            .unwrap();
+        let ordered = sm
+            .create_ordered_hash_index(&t_id, None, KeyExtractor::default(), false)
+            .unwrap();
+        for (key, value) in &recs[1000..] {
+            sm.insert_kv(&t_id, key, value, &txn).unwrap();
+        }
+        let mut search_keys: Vec<[u8; SEARCH_KEY_SIZE]> = recs
+            .iter()
+            .map(|(_key, value)| *extract_search_key(value))
+            .collect();
+        search_keys.sort();
+        let (min, max) = (search_keys[200], search_keys[1200]);
+
+        assert!(sm
+            .get_kvs_by_search_key_range(&hash, &min, &max, &txn)
+            .is_err());
+        let full = sm
+            .full_scan_by_search_key_bounds(&hash, Bound::Included(min), Bound::Excluded(max), &txn)
+            .unwrap();
+        let from_ordered = sm
+            .get_kvs_by_search_key_range(&ordered, &min, &max, &txn)
+            .unwrap();
+        let keys = |kvs: &[(Vec<u8>, Vec<u8>)]| -> Vec<[u8; SEARCH_KEY_SIZE]> {
+            kvs.iter()
+                .map(|(_key, value)| *extract_search_key(value))
+                .collect()
+        };
+        let expected: Vec<[u8; SEARCH_KEY_SIZE]> = search_keys
+            .iter()
+            .filter(|k| (min..max).contains(*k))
+            .copied()
+            .collect();
+        assert_eq!(keys(&full), expected);
+        assert_eq!(keys(&from_ordered), expected);
+        assert_eq!(
+            sm.get_kvs_by_search_key_equality(&ordered, &search_keys[7], &txn)
+                .unwrap()
+                .len(),
+            search_keys.iter().filter(|k| **k == search_keys[7]).count()
+        );
+    }
+
+    #[test]
+    fn test_storage_manager_txn_isolation() {
+        use super::*;
+
+        let dir = gen_random_test_dir();
+        let sm = StorageManager::new(&dir, 100);
+        let (t_id, i_id) = sm.create_table_with_idx(None).unwrap();
+        let mut rng = SmallRng::seed_from_u64(23530);
+        let recs = gen_records_ascending_keys(4, SearchKeyTypes::Distinct, &mut rng);
+        let setup = TransactionId::new();
+        let v_ids: Vec<ValueId> = recs
+            .iter()
+            .map(|(key, value)| sm.insert_kv(&t_id, key, value, &setup).unwrap())
+            .collect();
+        sm.commit_txn(&setup).unwrap();
+
+        // An update holds its record and search keys until the writer commits
+        let writer = TransactionId::new();
+        let reader = TransactionId::new();
+        let (key, value) = &recs[0];
+        let mut new_value = value.clone();
+        new_value[VALUE_SIZE - SEARCH_KEY_SIZE..].copy_from_slice(&[7; SEARCH_KEY_SIZE]);
+        sm.update_kv(&t_id, &v_ids[0], key, &new_value, &writer)
+            .unwrap();
+        assert_eq!(
+            sm.get_kv_by_val_id(&t_id, &v_ids[0], &reader),
+            Err(CrustyError::TransactionRollback(reader))
+        );
+        assert!(sm
+            .get_kvs_by_search_key_equality(&i_id, &[7; SEARCH_KEY_SIZE], &reader)
+            .is_err());
+        assert!(sm.get_kv_by_val_id(&t_id, &v_ids[1], &reader).is_ok());
+        sm.commit_txn(&writer).unwrap();
+        assert_eq!(
+            sm.get_kv_by_val_id(&t_id, &v_ids[0], &reader).unwrap(),
+            (key.clone(), new_value)
+        );
+
+        // A deleted slot is not reused until the deleting transaction ends
+        let deleter = TransactionId::new();
+        sm.delete_kv(&t_id, &v_ids[2], &deleter).unwrap();
+        // The deleter holds the record's search key too, so insert under another one
+        let (key, mut value) = recs[2].clone();
+        value[VALUE_SIZE - SEARCH_KEY_SIZE..].copy_from_slice(&[9; SEARCH_KEY_SIZE]);
+        let inserter = TransactionId::new();
+        let v_id = sm.insert_kv(&t_id, &key, &value, &inserter).unwrap();
+        assert_ne!(v_id, v_ids[2]);
+        sm.commit_txn(&deleter).unwrap();
+        sm.commit_txn(&inserter).unwrap();
+        let later = TransactionId::new();
+        let v_id = sm.insert_kv(&t_id, &key, &value, &later).unwrap();
+        assert_eq!(v_id, v_ids[2]);
+
+        // Aborting puts back the records and index entries a transaction
+        // changed, and removes the ones it added
+        let aborter = TransactionId::new();
+        let (key, value) = &recs[3];
+        let search_key = *extract_search_key(value);
+        let mut new_value = value.clone();
+        new_value[VALUE_SIZE - SEARCH_KEY_SIZE..].copy_from_slice(&[8; SEARCH_KEY_SIZE]);
+        let added = sm.insert_kv(&t_id, key, &new_value, &aborter).unwrap();
+        sm.delete_kv(&t_id, &v_ids[3], &aborter).unwrap();
+        sm.abort_txn(&aborter).unwrap();
+        let checker = TransactionId::new();
+        assert_eq!(
+            sm.get_kv_by_val_id(&t_id, &v_ids[3], &checker).unwrap(),
+            (key.clone(), value.clone())
+        );
+        assert!(sm.get_kv_by_val_id(&t_id, &added, &checker).is_err());
+        assert_eq!(
+            sm.get_kvs_by_search_key_equality(&i_id, &search_key, &checker)
+                .unwrap(),
+            vec![(key.clone(), value.clone())]
+        );
+        assert!(sm
+            .get_kvs_by_search_key_equality(&i_id, &[8; SEARCH_KEY_SIZE], &checker)
+            .unwrap()
+            .is_empty());
+        sm.commit_txn(&checker).unwrap();
+
+        // The reader still holds its shared lock, so the record cannot be
+        // deleted and its index entry is left in place
+        let (_key, value) = &recs[1];
+        let search_key = *extract_search_key(value);
+        assert!(sm.delete_kv(&t_id, &v_ids[1], &later).is_err());
+        assert!(sm
+            .get_kvs_by_search_key_equality(&i_id, &search_key, &later)
+            .unwrap()
+            .contains(&(recs[1].0.clone(), value.clone())));
+        sm.commit_txn(&reader).unwrap();
+        assert!(sm.delete_kv(&t_id, &v_ids[1], &later).is_ok());
+    }
+
+    #[test]
+    fn test_storage_manager_checkpoint() {
+        use super::*;
+
+        let dir = gen_random_test_dir();
+        let sm = StorageManager::new_with_frames(&dir, 100, 20);
+        let (t_id, i_id) = sm.create_table_with_idx(None).unwrap();
+        let mut rng = SmallRng::seed_from_u64(23530);
+        let recs = gen_records_ascending_keys(600, SearchKeyTypes::Distinct, &mut rng);
+        let insert = |recs: &[(Vec<u8>, Vec<u8>)], txn: &TransactionId| -> Vec<ValueId> {
+            recs.iter()
+                .map(|(key, value)| sm.insert_kv(&t_id, key, value, txn).unwrap())
+                .collect()
+        };
+        let committed = TransactionId::new();
+        let v_ids = insert(&recs[..300], &committed);
+        sm.commit_txn(&committed).unwrap();
+
+        // A transaction unfinished at the checkpoint is rolled back after a crash
+        let unfinished = TransactionId::new();
+        insert(&recs[300..400], &unfinished);
+        sm.delete_kv(&t_id, &v_ids[0], &unfinished).unwrap();
+        sm.checkpoint().unwrap();
+        let first_segment = sm.bp.log().first_segment();
+        assert!(first_segment > 0);
+        assert!(!dir.join("wal.0.log").exists());
+        let later = TransactionId::new();
+        insert(&recs[400..], &later);
+        sm.commit_txn(&later).unwrap();
+        drop(sm);
+
+        let sm = StorageManager::open_with_frames(&dir, 100, 20).unwrap();
+        assert!(sm.bp.log().first_segment() >= first_segment);
+        let txn = TransactionId::new();
+        let mut heap: Vec<(Vec<u8>, Vec<u8>)> = sm
+            .get_iterator(&t_id, None, &txn)
+            .unwrap()
+            .map(|(_, key, value)| (key, value))
+            .collect();
+        heap.sort();
+        let mut expected: Vec<_> = recs[..300].iter().chain(&recs[400..]).cloned().collect();
+        expected.sort();
+        assert_eq!(heap, expected);
+        for (i, (key, value)) in recs.iter().enumerate().step_by(7) {
+            let found = sm
+                .get_kvs_by_search_key_equality(&i_id, extract_search_key(value), &txn)
+                .unwrap();
+            let expected = match i {
+                300..400 => vec![],
+                _ => vec![(key.clone(), value.clone())],
+            };
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn test_storage_manager_index_larger_than_pool() {
+        use super::*;
+
+        let dir = gen_random_test_dir();
+        let frames = 20;
+        let sm = StorageManager::new_with_frames(&dir, 100, frames);
+        let t_id = sm.create_table(None).unwrap();
+        let mut rng = SmallRng::seed_from_u64(23530);
+        let recs = gen_records_ascending_keys(8000, SearchKeyTypes::Distinct, &mut rng);
+        let txn = TransactionId::new();
+        let recs_ref = recs.iter().map(|(k, v)| (&k[..], &v[..])).collect();
+        sm.insert_kvs(&t_id, recs_ref, &txn).unwrap();
+        sm.commit_txn(&txn).unwrap();
+
+        for index_type in [StateType::Tree, StateType::HashTable] {
+            let i_id = sm
+                .create_index(&t_id, None, KeyExtractor::default(), index_type, false)
+                .unwrap();
+            let data_files = sm.data_files.read().unwrap();
+            assert!(data_files.indexes[&i_id].file.get_pages_used() > frames);
+            drop(data_files);
+            let txn = TransactionId::new();
+            for (key, value) in recs.iter().step_by(97) {
+                let found = sm
+                    .get_kvs_by_search_key_equality(&i_id, extract_search_key(value), &txn)
+                    .unwrap();
+                assert_eq!(found, vec![(key.clone(), value.clone())]);
+            }
+            sm.commit_txn(&txn).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_storage_manager_torn_page_fails_open() {
+        use super::*;
+        use crate::container_file::ContainerFile;
+        use crate::prelude::SERIALIZED_PAGE_SIZE;
+        use std::io::{Seek, SeekFrom, Write};
+
+        let dir = gen_random_test_dir();
+        let sm = StorageManager::new(&dir, 100);
+        let t_id = sm.create_table(None).unwrap();
+        let txn = TransactionId::new();
+        sm.insert_kv(&t_id, &[1; KEY_SIZE], &[1; VALUE_SIZE], &txn)
+            .unwrap();
+        sm.commit_txn(&txn).unwrap();
+        sm.bp.flush_all().unwrap();
+        let txn = TransactionId::new();
+        sm.insert_kv(&t_id, &[2; KEY_SIZE], &[2; VALUE_SIZE], &txn)
+            .unwrap();
+        sm.commit_txn(&txn).unwrap();
+        drop(sm);
+
+        // Crash part way through writing the page back: only its first half
+        // reached the file
+        let path = ContainerFile::path_for(&dir, t_id);
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(SERIALIZED_PAGE_SIZE as u64 / 2))
+            .unwrap();
+        file.write_all(&[7; SERIALIZED_PAGE_SIZE / 2]).unwrap();
+        drop(file);
+
+        // Redo needs the page, and the log cannot rebuild it
+        assert!(matches!(
+            StorageManager::open(&dir, 100),
+            Err(CrustyError::CorruptPage(_))
+        ));
+    }
+
+    /// The committed records of the recovery test's table, by value id
+    type Model = std::collections::HashMap<common::ids::ValueId, (Vec<u8>, Vec<u8>)>;
+
+    /// A record whose key and both search keys come from `id`
+    fn crash_record(id: u16, rng: &mut SmallRng) -> (Vec<u8>, Vec<u8>) {
+        use rand::Rng;
+        let mut value: Vec<u8> = (0..VALUE_SIZE).map(|_| rng.gen()).collect();
+        value[0..2].copy_from_slice(&id.to_be_bytes());
+        value[2..4].copy_from_slice(&id.wrapping_mul(7919).to_be_bytes());
+        (id.to_be_bytes().repeat(KEY_SIZE / 2), value)
+    }
+
+    /// Run transactions of inserts, updates and deletes, some of which
+    /// abort, adding the changes of each one that commits to `model`.
+    /// Stops at the first error.
+    fn crash_workload(
+        sm: &super::StorageManager,
+        t_id: &common::ids::ContainerId,
+        model: &mut Model,
+        next_id: &mut u16,
+    ) -> Result<(), common::CrustyError> {
+        use common::ids::TransactionId;
+        use rand::Rng;
+
+        let mut rng = SmallRng::seed_from_u64(23530);
+        for _ in 0..30 {
+            let txn = TransactionId::new();
+            let mut changed = model.clone();
+            for _ in 0..20 {
+                let mut existing: Vec<_> = changed.keys().copied().collect();
+                existing.sort_by_key(|v_id| (v_id.page_id, v_id.slot_id));
+                let choice = rng.gen_range(0..10);
+                if existing.is_empty() || choice < 6 {
+                    let (key, value) = crash_record(*next_id, &mut rng);
+                    *next_id += 1;
+                    let v_id = sm.insert_kv(t_id, &key, &value, &txn)?;
+                    changed.insert(v_id, (key, value));
+                } else if choice < 8 {
+                    let v_id = existing[rng.gen_range(0..existing.len())];
+                    let (key, value) = crash_record(*next_id, &mut rng);
+                    *next_id += 1;
+                    sm.update_kv(t_id, &v_id, &key, &value, &txn)?;
+                    changed.insert(v_id, (key, value));
+                } else {
+                    let v_id = existing[rng.gen_range(0..existing.len())];
+                    sm.delete_kv(t_id, &v_id, &txn)?;
+                    changed.remove(&v_id);
+                }
+            }
+            if rng.gen_range(0..4) == 0 {
+                sm.abort_txn(&txn)?;
+            } else {
+                sm.commit_txn(&txn)?;
+                *model = changed;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_storage_manager_crash_recovery() {
+        use super::*;
+        use crate::fault::crash_after_writes;
+
+        // Crash at every 13th write until the workload finishes, with few
+        // enough frames that pages are written out in the middle of it
+        let dir = gen_random_test_dir();
+        let mut writes = 0;
+        loop {
+            // Start each run from an empty directory
+            let _ = std::fs::remove_dir_all(&dir);
+            let sm = StorageManager::new_with_frames(&dir, 100, 12);
+            let t_id = sm.create_table(None).unwrap();
+            let tree_idx = sm
+                .create_index(
+                    &t_id,
+                    None,
+                    KeyExtractor::new(0, 2).unwrap(),
+                    StateType::Tree,
+                    false,
+                )
+                .unwrap();
+            let hash_idx = sm
+                .create_index(
+                    &t_id,
+                    None,
+                    KeyExtractor::new(2, 2).unwrap(),
+                    StateType::HashTable,
+                    false,
+                )
+                .unwrap();
+            let mut model = Model::new();
+            let mut next_id = 0;
+            crash_after_writes(Some(writes));
+            let res = crash_workload(&sm, &t_id, &mut model, &mut next_id);
+            crash_after_writes(None);
+            if let Err(e) = &res {
+                assert_eq!(
+                    *e,
+                    CrustyError::IOError("Simulated crash".to_string()),
+                    "unexpected error crashing at write {writes}"
+                );
+            }
+            drop(sm);
+
+            let sm = StorageManager::open_with_frames(&dir, 100, 12).unwrap();
+            let txn = TransactionId::new();
+            let heap: Model = sm
+                .get_iterator(&t_id, None, &txn)
+                .unwrap()
+                .map(|(v_id, key, value)| (v_id, (key, value)))
+                .collect();
+            assert_eq!(heap, model, "heap differs after crashing at write {writes}");
+            let mut by_id: HashMap<u16, (Vec<u8>, Vec<u8>)> = HashMap::new();
+            for (key, value) in model.values() {
+                by_id.insert(
+                    u16::from_be_bytes([value[0], value[1]]),
+                    (key.clone(), value.clone()),
+                );
+            }
+            for id in 0..next_id {
+                let expected: Vec<_> = by_id.get(&id).cloned().into_iter().collect();
+                let mut tree_key = [0; SEARCH_KEY_SIZE];
+                tree_key[..2].copy_from_slice(&id.to_be_bytes());
+                let mut hash_key = [0; SEARCH_KEY_SIZE];
+                hash_key[..2].copy_from_slice(&id.wrapping_mul(7919).to_be_bytes());
+                assert_eq!(
+                    sm.get_kvs_by_search_key_equality(&tree_idx, &tree_key, &txn)
+                        .unwrap(),
+                    expected,
+                    "tree index differs after crashing at write {writes}"
+                );
+                assert_eq!(
+                    sm.get_kvs_by_search_key_equality(&hash_idx, &hash_key, &txn)
+                        .unwrap(),
+                    expected,
+                    "hash index differs after crashing at write {writes}"
+                );
+            }
+            let report = sm.check().unwrap();
+            assert!(
+                report.is_clean(),
+                "{:?} after crashing at write {writes}",
+                report.violations
+            );
+            sm.commit_txn(&txn).unwrap();
+            if res.is_ok() {
+                break;
+            }
+            writes += 13;
+        }
     }
 }
@@ -1,6 +1,9 @@
 use crate::prelude::*;
+use rand::distributions::Alphanumeric;
 use rand::rngs::SmallRng;
 use rand::{seq::SliceRandom, Rng, SeedableRng};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 
 #[derive(PartialEq)]
 pub enum SearchKeyTypes {
@@ -14,6 +17,45 @@ const VAL_SIZE_NO_SEARCH_KEY: usize = VALUE_SIZE - SEARCH_KEY_SIZE;
 pub fn gen_small_rng_with_seed(seed: u64) -> SmallRng {
     SmallRng::seed_from_u64(seed)
 }
+
+/// A test's directory, removed along with its files when dropped
+pub struct TestDir(PathBuf);
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A random directory under the system temp dir for a test's container files.
+/// Keep the guard alive for as long as the test uses the directory.
+pub fn gen_random_test_dir() -> TestDir {
+    let rand_string: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(char::from)
+        .collect();
+    TestDir(
+        std::env::temp_dir()
+            .join("crusty_idx_fixed_store")
+            .join(rand_string),
+    )
+}
+
 pub fn gen_records_ascending_keys(
     n: usize,
     search_key: SearchKeyTypes,
@@ -0,0 +1,874 @@
+use crate::fault;
+use crate::fixed_page::FixedPage;
+use crate::heap::var_heap_page::{VarHeapPage, MAX_VAR_RECORD_SIZE};
+use crate::manifest::{read_manifest, write_manifest};
+use crate::prelude::*;
+use crate::wal::log_record::{
+    LogRecord, TxnOp, CHECKPOINT_ACTIVE_BYTES, CHECKPOINT_DIRTY_BYTES, CHECKPOINT_HEADER_BYTES,
+};
+use common::ids::Lsn;
+use common::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+/// The name of the manifest holding where the log starts and ends
+pub const LOG_MANIFEST: &str = "wal.json";
+/// How many log pages each log segment file holds
+pub const LOG_SEGMENT_PAGES: PageId = 16;
+/// How many log pages can be added after a checkpoint before another is due
+pub const CHECKPOINT_PAGES: PageId = LOG_SEGMENT_PAGES;
+
+/// Where the log starts and ends, saved after each flush
+#[derive(Serialize, Deserialize)]
+struct LogManifest {
+    /// The first segment kept. Earlier ones were dropped by a checkpoint.
+    first_segment: PageId,
+    /// The LSN the log was last flushed to
+    end: Lsn,
+    /// The first record of the last checkpoint
+    checkpoint: Option<Lsn>,
+}
+
+struct LogState {
+    /// The segment file last read or written, with its segment number
+    segment: Option<(PageId, File)>,
+    /// Log pages that have records not yet flushed. The last one is the tail
+    /// that new records are added to.
+    pages: Vec<FixedPage>,
+    /// The page id of the first page in `pages`
+    first_page: PageId,
+    /// The first segment kept
+    first_segment: PageId,
+    /// The LSN of the last record added
+    last_lsn: Option<Lsn>,
+    /// The LSN of the last record written to disk
+    flushed_lsn: Option<Lsn>,
+    /// The first record of the last checkpoint on disk
+    checkpoint: Option<Lsn>,
+    /// The first and last record of each transaction that has logged a
+    /// change and not yet committed or finished aborting
+    active: HashMap<TransactionId, (Lsn, Lsn)>,
+    /// The unfinished atomic actions, by the thread making them
+    actions: HashMap<ThreadId, OpenAction>,
+}
+
+/// An unfinished atomic action. Actions started on a thread that already
+/// has one are part of it.
+struct OpenAction {
+    /// How many guards of the thread are not yet dropped
+    depth: usize,
+    /// The LSN before the action's first record. Nothing after it may be
+    /// flushed until the action finishes.
+    before: Option<Lsn>,
+    /// The LSN of the action's last record, or None if it has added none
+    last: Option<Lsn>,
+}
+
+impl LogState {
+    /// The last LSN that may be flushed, which is just before the first
+    /// record of the oldest unfinished atomic action
+    fn flushable(&self) -> Option<Lsn> {
+        self.actions
+            .values()
+            .filter(|action| action.last.is_some())
+            .map(|action| action.before)
+            .fold(self.last_lsn, Option::min)
+    }
+
+    /// Finish an atomic action. The log is flushed up to the start of
+    /// another action that added records in the middle of this one, so that
+    /// action now holds back this one's records as well.
+    fn finish_action(&mut self, thread: ThreadId) {
+        let action = self.actions.get_mut(&thread).unwrap();
+        action.depth -= 1;
+        if action.depth > 0 {
+            return;
+        }
+        let action = self.actions.remove(&thread).unwrap();
+        for other in self.actions.values_mut() {
+            if other.last.is_some() && other.before >= action.before && other.before < action.last {
+                other.before = action.before;
+            }
+        }
+    }
+}
+
+/// The write-ahead log. Records are added to slotted log pages in memory and
+/// written out when the log is flushed, so a record is durable once
+/// `flushed_lsn` has reached its LSN. Log pages are stored in segment files
+/// of `LOG_SEGMENT_PAGES` pages each, so log page `p` lives in segment
+/// `p / LOG_SEGMENT_PAGES`.
+///
+/// A change that spans several records, such as an index split, is made in
+/// an atomic action. The log is only flushed up to the first record of the
+/// oldest unfinished action, and the point it was last flushed to is kept in
+/// the log manifest, so a flush cut short by a crash is ignored. After a
+/// crash either all or none of an action's records are on disk.
+///
+/// A checkpoint logs the dirty pages and unfinished transactions, so
+/// recovery can start from it. Segments that only hold records from before
+/// what the last checkpoint needs are removed.
+pub struct LogManager {
+    storage_dir: PathBuf,
+    state: Mutex<LogState>,
+    /// Signalled when an atomic action finishes
+    action_done: Condvar,
+}
+
+impl LogManager {
+    /// Start a new, empty log in the storage directory. The log recorded by
+    /// a manifest there is removed on the first flush.
+    pub fn new(storage_dir: &Path) -> Self {
+        LogManager {
+            storage_dir: storage_dir.to_path_buf(),
+            state: Mutex::new(LogState {
+                segment: None,
+                pages: vec![<FixedPage as VarHeapPage>::new(0)],
+                first_page: 0,
+                first_segment: 0,
+                last_lsn: None,
+                flushed_lsn: None,
+                checkpoint: None,
+                active: HashMap::new(),
+                actions: HashMap::new(),
+            }),
+            action_done: Condvar::new(),
+        }
+    }
+
+    /// Open the log in the storage directory, adding new records after the
+    /// last one flushed. Records written after it by a flush that did not
+    /// finish are overwritten.
+    pub fn open(storage_dir: &Path) -> Result<Self, CrustyError> {
+        let lm = Self::new(storage_dir);
+        let Some(manifest) = read_manifest::<LogManifest>(&storage_dir.join(LOG_MANIFEST))? else {
+            return Ok(lm);
+        };
+        let end = manifest.end;
+        let mut state = lm.state();
+        let page = lm.read_page(&mut state, end.page_id)?;
+        let mut tail = <FixedPage as VarHeapPage>::new(end.page_id);
+        for slot in 0..=end.slot_id {
+            let (key, value) = page.get_record(slot).ok_or_else(|| {
+                CrustyError::CrustyError(format!("Log is missing its last record {:?}", end))
+            })?;
+            tail.add_record(&key, &value)?;
+        }
+        state.pages = vec![tail];
+        state.first_page = end.page_id;
+        state.first_segment = manifest.first_segment;
+        state.last_lsn = Some(end);
+        state.flushed_lsn = Some(end);
+        state.checkpoint = manifest.checkpoint;
+        drop(state);
+        Ok(lm)
+    }
+
+    fn state(&self) -> MutexGuard<'_, LogState> {
+        self.state.lock().unwrap()
+    }
+
+    fn segment_path(&self, segment: PageId) -> PathBuf {
+        self.storage_dir.join(format!("wal.{}.log", segment))
+    }
+
+    /// The file of a segment, opening it if it is not the one open
+    fn segment_file<'s>(
+        &self,
+        state: &'s mut LogState,
+        segment: PageId,
+    ) -> Result<&'s mut File, CrustyError> {
+        if state
+            .segment
+            .as_ref()
+            .is_none_or(|(open, _)| *open != segment)
+        {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(self.segment_path(segment))?;
+            state.segment = Some((segment, file));
+        }
+        Ok(&mut state.segment.as_mut().unwrap().1)
+    }
+
+    /// Read a log page that was written out
+    fn read_page(&self, state: &mut LogState, p_id: PageId) -> Result<FixedPage, CrustyError> {
+        let file = self.segment_file(state, p_id / LOG_SEGMENT_PAGES)?;
+        let mut page = FixedPage::empty();
+        file.seek(SeekFrom::Start(
+            (p_id % LOG_SEGMENT_PAGES) as u64 * PAGE_SIZE as u64,
+        ))?;
+        file.read_exact(&mut page.data)?;
+        Ok(page)
+    }
+
+    /// Save where the log starts and ends. Records after `end` are ignored
+    /// when the log is opened.
+    fn save_manifest(&self, state: &LogState, end: Lsn) -> Result<(), CrustyError> {
+        let manifest = LogManifest {
+            first_segment: state.first_segment,
+            end,
+            checkpoint: state.checkpoint,
+        };
+        write_manifest(&self.storage_dir.join(LOG_MANIFEST), &manifest)
+    }
+
+    /// Add a record to the end of the log and return its LSN. The record is
+    /// not durable until the log is flushed up to it. Errors if the record
+    /// does not fit on a log page.
+    pub fn append(&self, record: &LogRecord) -> Result<Lsn, CrustyError> {
+        Self::append_locked(&mut self.state(), record)
+    }
+
+    fn append_locked(state: &mut LogState, record: &LogRecord) -> Result<Lsn, CrustyError> {
+        let bytes = record.to_bytes();
+        if bytes.len() > MAX_VAR_RECORD_SIZE {
+            return Err(CrustyError::CrustyError(format!(
+                "Log record of {} bytes is larger than a log page",
+                bytes.len()
+            )));
+        }
+        let slot_id = match state.pages.last_mut().unwrap().add_record(&[], &bytes)? {
+            Some(slot_id) => slot_id,
+            None => {
+                let p_id = state.first_page + state.pages.len() as PageId;
+                let mut page = <FixedPage as VarHeapPage>::new(p_id);
+                let slot_id = page.add_record(&[], &bytes)?.unwrap();
+                state.pages.push(page);
+                slot_id
+            }
+        };
+        let lsn = Lsn {
+            page_id: state.first_page + state.pages.len() as PageId - 1,
+            slot_id,
+        };
+        let before = state.last_lsn;
+        if let Some(action) = state.actions.get_mut(&thread::current().id()) {
+            if action.last.is_none() {
+                action.before = before;
+            }
+            action.last = Some(lsn);
+        }
+        state.last_lsn = Some(lsn);
+        Ok(lsn)
+    }
+
+    /// Log a change made by a transaction, chained to its previous record
+    pub fn log_txn(&self, txn: TransactionId, op: TxnOp) -> Result<Lsn, CrustyError> {
+        let mut state = self.state();
+        let prev_lsn = state.active.get(&txn).map(|(_, last)| *last);
+        let lsn = Self::append_locked(&mut state, &LogRecord::Txn { txn, prev_lsn, op })?;
+        state.active.entry(txn).or_insert((lsn, lsn)).1 = lsn;
+        Ok(lsn)
+    }
+
+    /// Log that a transaction committed and flush the log so the commit is
+    /// durable. Does nothing for a transaction that logged no changes.
+    pub fn commit(&self, txn: TransactionId) -> Result<(), CrustyError> {
+        let lsn = {
+            let mut state = self.state();
+            if state.active.remove(&txn).is_none() {
+                return Ok(());
+            }
+            Self::append_locked(&mut state, &LogRecord::Commit { txn })?
+        };
+        self.flush(lsn)
+    }
+
+    /// Log that every change of an aborted transaction has been undone
+    pub fn end_abort(&self, txn: TransactionId) -> Result<(), CrustyError> {
+        let mut state = self.state();
+        if state.active.remove(&txn).is_some() {
+            Self::append_locked(&mut state, &LogRecord::Abort { txn })?;
+        }
+        Ok(())
+    }
+
+    /// The last record of a transaction that has not finished
+    pub fn txn_last_lsn(&self, txn: TransactionId) -> Option<Lsn> {
+        self.state().active.get(&txn).map(|(_, last)| *last)
+    }
+
+    /// The transactions that have logged changes and not finished
+    pub fn active_txns(&self) -> Vec<TransactionId> {
+        self.state().active.keys().copied().collect()
+    }
+
+    /// Track transactions found unfinished by recovery, with their first and
+    /// last records, so they can be rolled back
+    pub fn set_active_txns(&self, active: HashMap<TransactionId, (Lsn, Lsn)>) {
+        self.state().active = active;
+    }
+
+    /// Read the record at an LSN, whether or not it has been flushed
+    pub fn read(&self, lsn: Lsn) -> Result<LogRecord, CrustyError> {
+        let mut state = self.state();
+        let not_found = || CrustyError::CrustyError(format!("No log record at {:?}", lsn));
+        if lsn.page_id >= state.first_page {
+            let page = state
+                .pages
+                .get((lsn.page_id - state.first_page) as usize)
+                .ok_or_else(not_found)?;
+            let (_, bytes) = page.get_record(lsn.slot_id).ok_or_else(not_found)?;
+            return LogRecord::from_bytes(&bytes);
+        }
+        if lsn.page_id / LOG_SEGMENT_PAGES < state.first_segment {
+            return Err(not_found());
+        }
+        let page = self.read_page(&mut state, lsn.page_id)?;
+        let (_, bytes) = page.get_record(lsn.slot_id).ok_or_else(not_found)?;
+        LogRecord::from_bytes(&bytes)
+    }
+
+    /// Start an atomic action. The records this thread adds are not flushed
+    /// until the returned guard is dropped.
+    pub fn atomic(&self) -> AtomicAction<'_> {
+        let thread = thread::current().id();
+        let mut state = self.state();
+        let action = state.actions.entry(thread).or_insert(OpenAction {
+            depth: 0,
+            before: None,
+            last: None,
+        });
+        action.depth += 1;
+        AtomicAction { log: self, thread }
+    }
+
+    /// Whether the log can be flushed up to `lsn` without waiting for an
+    /// atomic action to finish
+    pub fn can_flush(&self, lsn: Lsn) -> bool {
+        self.state().flushable() >= Some(lsn)
+    }
+
+    /// Make sure every record up to and including `up_to` is on disk,
+    /// waiting for atomic actions to finish if it was added during them.
+    /// Flushes as much of the log as it can if it is not.
+    pub fn flush(&self, up_to: Lsn) -> Result<(), CrustyError> {
+        let mut state = self.state();
+        while state.flushed_lsn < Some(up_to) {
+            if state.flushable() >= Some(up_to) {
+                self.write_out(&mut state)?;
+            } else {
+                state = self.action_done.wait(state).unwrap();
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush every record added so far
+    pub fn flush_all(&self) -> Result<(), CrustyError> {
+        match self.last_lsn() {
+            Some(lsn) => self.flush(lsn),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the log out up to the last record that may be flushed
+    fn write_out(&self, state: &mut LogState) -> Result<(), CrustyError> {
+        let Some(limit) = state.flushable() else {
+            return Ok(());
+        };
+        if state.flushed_lsn.is_none() {
+            self.remove_old_log(state)?;
+        }
+        for p_id in state.first_page..=limit.page_id {
+            let page = &state.pages[(p_id - state.first_page) as usize];
+            let data = if p_id < limit.page_id || limit.slot_id + 1 == page.slot_count() {
+                page.data
+            } else {
+                // Leave out the records after the limit
+                let mut trimmed = <FixedPage as VarHeapPage>::new(limit.page_id);
+                for slot in 0..=limit.slot_id {
+                    let (key, value) = page.get_record(slot).unwrap();
+                    trimmed.add_record(&key, &value)?;
+                }
+                trimmed.data
+            };
+            let file = self.segment_file(state, p_id / LOG_SEGMENT_PAGES)?;
+            file.seek(SeekFrom::Start(
+                (p_id % LOG_SEGMENT_PAGES) as u64 * PAGE_SIZE as u64,
+            ))?;
+            fault::write_all(file, &data)?;
+            if p_id == limit.page_id || (p_id + 1) % LOG_SEGMENT_PAGES == 0 {
+                file.sync_data()?;
+            }
+        }
+        self.save_manifest(state, limit)?;
+        let full_pages = (limit.page_id - state.first_page) as usize;
+        state.pages.drain(..full_pages);
+        state.first_page = limit.page_id;
+        state.flushed_lsn = Some(limit);
+        Ok(())
+    }
+
+    /// Remove the old log in the storage directory, if any: its manifest and
+    /// the segments the manifest names. Other files are left alone.
+    fn remove_old_log(&self, state: &mut LogState) -> Result<(), CrustyError> {
+        state.segment = None;
+        if !self.storage_dir.exists() {
+            std::fs::create_dir_all(&self.storage_dir)?;
+            return Ok(());
+        }
+        let path = self.storage_dir.join(LOG_MANIFEST);
+        let Some(manifest) = read_manifest::<LogManifest>(&path)? else {
+            return Ok(());
+        };
+        std::fs::remove_file(path)?;
+        for segment in manifest.first_segment..=manifest.end.page_id / LOG_SEGMENT_PAGES {
+            let path = self.segment_path(segment);
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Log a checkpoint and flush it. `begin` is the last LSN added before
+    /// the checkpoint started and `dirty` holds each dirty page with the last
+    /// LSN before its first change that is not on disk. Segments recovery
+    /// will not need are then removed.
+    pub fn checkpoint(
+        &self,
+        begin: Option<Lsn>,
+        dirty: Vec<(ContainerId, PageId, Option<Lsn>)>,
+    ) -> Result<(), CrustyError> {
+        // Recovery redoes the records after this, and rolls back from the
+        // first record of each unfinished transaction
+        let mut keep_from = dirty
+            .iter()
+            .map(|(_, _, lsn)| *lsn)
+            .fold(begin, Option::min);
+        let (first, last) = {
+            let mut state = self.state();
+            let active: Vec<(TransactionId, Lsn, Lsn)> = state
+                .active
+                .iter()
+                .map(|(txn, (first, last))| (*txn, *first, *last))
+                .collect();
+            if let Some(oldest) = active.iter().map(|(_, first, _)| *first).min() {
+                keep_from = keep_from.min(Some(oldest));
+            }
+            // Split the checkpoint over as many records as it needs. Each
+            // record has room for `per_record` dirty pages and active transactions.
+            let per_record = (MAX_VAR_RECORD_SIZE - CHECKPOINT_HEADER_BYTES)
+                / (CHECKPOINT_DIRTY_BYTES + CHECKPOINT_ACTIVE_BYTES);
+            let records = dirty.len().max(active.len()).div_ceil(per_record).max(1);
+            let part = |i: usize, len: usize| i * per_record..((i + 1) * per_record).min(len);
+            let lsns = (0..records)
+                .map(|i| {
+                    let record = LogRecord::Checkpoint {
+                        begin,
+                        dirty: dirty.get(part(i, dirty.len())).unwrap_or_default().to_vec(),
+                        active: active
+                            .get(part(i, active.len()))
+                            .unwrap_or_default()
+                            .to_vec(),
+                    };
+                    Self::append_locked(&mut state, &record)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            (lsns[0], lsns[lsns.len() - 1])
+        };
+        self.flush(last)?;
+        let mut state = self.state();
+        state.checkpoint = Some(first);
+        let first_segment = keep_from.map_or(0, |lsn| lsn.page_id / LOG_SEGMENT_PAGES);
+        let old_first = state.first_segment;
+        state.first_segment = state.first_segment.max(first_segment);
+        let end = state.flushed_lsn.unwrap();
+        self.save_manifest(&state, end)?;
+        for segment in old_first..state.first_segment {
+            let path = self.segment_path(segment);
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        if state
+            .segment
+            .as_ref()
+            .is_some_and(|(open, _)| *open < state.first_segment)
+        {
+            state.segment = None;
+        }
+        Ok(())
+    }
+
+    /// The first record of the last checkpoint on disk
+    pub fn checkpoint_lsn(&self) -> Option<Lsn> {
+        self.state().checkpoint
+    }
+
+    /// Whether enough has been logged since the last checkpoint that another is due
+    pub fn checkpoint_due(&self) -> bool {
+        let state = self.state();
+        let since = state.checkpoint.map_or(0, |lsn| lsn.page_id);
+        state
+            .last_lsn
+            .is_some_and(|lsn| lsn.page_id >= since + CHECKPOINT_PAGES)
+    }
+
+    /// The LSN of the last record added
+    pub fn last_lsn(&self) -> Option<Lsn> {
+        self.state().last_lsn
+    }
+
+    /// The LSN of the last record written to disk
+    pub fn flushed_lsn(&self) -> Option<Lsn> {
+        self.state().flushed_lsn
+    }
+
+    /// The first segment still kept
+    pub fn first_segment(&self) -> PageId {
+        self.state().first_segment
+    }
+
+    /// Read back every flushed record in the segments still kept, in log order
+    pub fn read_flushed(&self) -> Result<Vec<(Lsn, LogRecord)>, CrustyError> {
+        let mut state = self.state();
+        let Some(end) = state.flushed_lsn else {
+            return Ok(vec![]);
+        };
+        let mut res = vec![];
+        for p_id in state.first_segment * LOG_SEGMENT_PAGES..=end.page_id {
+            let page = self.read_page(&mut state, p_id)?;
+            for (slot_id, _, value) in page.records() {
+                let lsn = Lsn {
+                    page_id: p_id,
+                    slot_id,
+                };
+                if lsn > end {
+                    break;
+                }
+                res.push((lsn, LogRecord::from_bytes(&value)?));
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// An unfinished atomic action, which holds back the log from being flushed
+/// past the start of the records its thread adds until it is dropped
+pub struct AtomicAction<'a> {
+    log: &'a LogManager,
+    thread: ThreadId,
+}
+
+impl Drop for AtomicAction<'_> {
+    fn drop(&mut self) {
+        self.log.state().finish_action(self.thread);
+        self.log.action_done.notify_all();
+    }
+}
+
+/// What a page in the buffer pool needs to log its changes
+#[derive(Clone)]
+pub struct PageLog {
+    /// The container the page belongs to
+    pub c_id: ContainerId,
+    pub log: Arc<LogManager>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::gen_random_test_dir;
+    use crate::wal::log_record::PageOp;
+
+    fn record(i: usize) -> LogRecord {
+        LogRecord::Page {
+            c_id: 1,
+            p_id: i as PageId,
+            op: PageOp::Write {
+                slot: i as SlotId,
+                key: vec![i as u8; 16],
+                value: vec![i as u8; 128],
+            },
+        }
+    }
+
+    #[test]
+    fn test_log_append_flush_and_reopen() {
+        let dir = gen_random_test_dir();
+        let log = LogManager::new(&dir);
+        let lsns: Vec<Lsn> = (0..100).map(|i| log.append(&record(i)).unwrap()).collect();
+        // The records spill over several log pages, in order
+        assert!(lsns.windows(2).all(|w| w[0] < w[1]));
+        assert!(lsns[99].page_id > 2);
+        assert_eq!(log.last_lsn(), Some(lsns[99]));
+        assert_eq!(log.flushed_lsn(), None);
+        assert!(log.read_flushed().unwrap().is_empty());
+
+        // Flushing up to a record writes out everything added so far
+        log.flush(lsns[50]).unwrap();
+        assert_eq!(log.flushed_lsn(), Some(lsns[99]));
+        let lsn = log.append(&record(100)).unwrap();
+        let flushed = log.read_flushed().unwrap();
+        assert_eq!(flushed.len(), 100);
+        for (i, (lsn, rec)) in flushed.into_iter().enumerate() {
+            assert_eq!(lsn, lsns[i]);
+            assert_eq!(rec, record(i));
+        }
+        log.flush(lsn).unwrap();
+        drop(log);
+
+        // A reopened log adds records after the flushed ones
+        let log = LogManager::open(&dir).unwrap();
+        assert_eq!(log.flushed_lsn(), Some(lsn));
+        let next = log.append(&record(101)).unwrap();
+        assert!(next > lsn);
+        log.flush(next).unwrap();
+        let flushed = log.read_flushed().unwrap();
+        assert_eq!(flushed.len(), 102);
+        assert_eq!(flushed[101], (next, record(101)));
+    }
+
+    #[test]
+    fn test_log_atomic_actions_and_cut_short_flush() {
+        use crate::fault::{crash_after_writes, tear_write_after};
+
+        let dir = gen_random_test_dir();
+        let log = LogManager::new(&dir);
+        let before = log.append(&record(0)).unwrap();
+        // Actions started on the same thread are held back until both finish
+        let first = log.atomic();
+        log.append(&record(1)).unwrap();
+        let second = log.atomic();
+        let inside = log.append(&record(2)).unwrap();
+        drop(first);
+        assert!(log.can_flush(before));
+        assert!(!log.can_flush(inside));
+        log.flush(before).unwrap();
+        assert_eq!(log.flushed_lsn(), Some(before));
+        drop(second);
+        assert!(log.can_flush(inside));
+
+        // A flush that crashes before it finishes is ignored on reopen
+        let lsns: Vec<Lsn> = (3..100).map(|i| log.append(&record(i)).unwrap()).collect();
+        crash_after_writes(Some(2));
+        assert!(log.flush(lsns[96]).is_err());
+        crash_after_writes(None);
+        drop(log);
+        let log = LogManager::open(&dir).unwrap();
+        assert_eq!(log.flushed_lsn(), Some(before));
+        assert_eq!(log.read_flushed().unwrap(), vec![(before, record(0))]);
+        let next = log.append(&record(1)).unwrap();
+        log.flush(next).unwrap();
+        assert_eq!(log.read_flushed().unwrap().len(), 2);
+
+        // So is one torn part way through a page that holds flushed records
+        let flushed = log.read_flushed().unwrap();
+        let lsns: Vec<Lsn> = (2..100).map(|i| log.append(&record(i)).unwrap()).collect();
+        tear_write_after(0);
+        assert!(log.flush(lsns[97]).is_err());
+        crash_after_writes(None);
+        drop(log);
+        let log = LogManager::open(&dir).unwrap();
+        assert_eq!(log.flushed_lsn(), Some(next));
+        assert_eq!(log.read_flushed().unwrap(), flushed);
+    }
+
+    #[test]
+    fn test_log_flushes_up_to_oldest_action() {
+        use std::sync::Barrier;
+
+        let dir = gen_random_test_dir();
+        let log = LogManager::new(&dir);
+        let barrier = Barrier::new(2);
+        log.append(&record(0)).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                let first = log.atomic();
+                log.append(&record(1)).unwrap();
+                barrier.wait();
+                barrier.wait();
+                drop(first);
+                barrier.wait();
+
+                let third = log.atomic();
+                log.append(&record(3)).unwrap();
+                barrier.wait();
+                barrier.wait();
+                log.append(&record(5)).unwrap();
+                drop(third);
+                barrier.wait();
+            });
+
+            // An action that finishes while a later one is open can be flushed
+            barrier.wait();
+            let first_last = log.last_lsn().unwrap();
+            let second = log.atomic();
+            let second_first = log.append(&record(2)).unwrap();
+            barrier.wait();
+            barrier.wait();
+            assert!(log.can_flush(first_last));
+            assert!(!log.can_flush(second_first));
+            drop(second);
+            assert!(log.can_flush(second_first));
+
+            // One with records on both sides of a later one's first record is
+            // held back with it
+            barrier.wait();
+            let third_first = log.last_lsn().unwrap();
+            let fourth = log.atomic();
+            log.append(&record(4)).unwrap();
+            barrier.wait();
+            barrier.wait();
+            assert!(log.can_flush(second_first));
+            assert!(!log.can_flush(third_first));
+            drop(fourth);
+            assert!(log.can_flush(log.last_lsn().unwrap()));
+        });
+    }
+
+    #[test]
+    fn test_log_checkpoint_drops_old_segments() {
+        let dir = gen_random_test_dir();
+        let log = LogManager::new(&dir);
+        let txn = TransactionId::new();
+        let old = TransactionId::new();
+        let op = TxnOp::HeapChange {
+            v_id: ValueId::new_page(1, 0),
+            before: None,
+        };
+        let first = log.log_txn(old, op.clone()).unwrap();
+        for i in 0..1200 {
+            log.append(&record(i)).unwrap();
+        }
+        log.flush_all().unwrap();
+        assert!(log.flushed_lsn().unwrap().page_id > 2 * LOG_SEGMENT_PAGES);
+
+        // An unfinished transaction keeps the log from its first record
+        let dirty = vec![(1, 0, log.last_lsn())];
+        log.checkpoint(log.last_lsn(), dirty.clone()).unwrap();
+        assert_eq!(log.first_segment(), 0);
+        log.commit(old).unwrap();
+        let last = log.log_txn(txn, op.clone()).unwrap();
+        let begin = log.last_lsn();
+        log.checkpoint(begin, dirty.clone()).unwrap();
+        assert_eq!(
+            log.first_segment(),
+            begin.unwrap().page_id / LOG_SEGMENT_PAGES
+        );
+        assert!(!dir.join("wal.0.log").exists());
+        assert!(log.read(first).is_err());
+        assert!(log.read(last).is_ok());
+        let checkpoint = log.checkpoint_lsn().unwrap();
+        assert_eq!(
+            log.read(checkpoint).unwrap(),
+            LogRecord::Checkpoint {
+                begin,
+                dirty,
+                active: vec![(txn, last, last)],
+            }
+        );
+        drop(log);
+
+        // The reopened log starts at the first segment kept
+        let log = LogManager::open(&dir).unwrap();
+        assert_eq!(log.checkpoint_lsn(), Some(checkpoint));
+        let records = log.read_flushed().unwrap();
+        assert_eq!(
+            records[0].0.page_id,
+            log.first_segment() * LOG_SEGMENT_PAGES
+        );
+        assert_eq!(records.last().unwrap().0, checkpoint);
+    }
+
+    #[test]
+    fn test_log_large_checkpoint_is_split() {
+        let dir = gen_random_test_dir();
+        let log = LogManager::new(&dir);
+        let dirty: Vec<_> = (0..500).map(|p| (1, p, None)).collect();
+        let active: Vec<_> = (0..300)
+            .map(|_| {
+                let lsn = log
+                    .log_txn(
+                        TransactionId::new(),
+                        TxnOp::IndexAdd {
+                            c_id: 2,
+                            search_key: [0; SEARCH_KEY_SIZE],
+                            pointer: [0; INDEX_POINTER_SIZE],
+                        },
+                    )
+                    .unwrap();
+                (lsn, lsn)
+            })
+            .collect();
+        log.checkpoint(None, dirty.clone()).unwrap();
+        let records = log.read_flushed().unwrap();
+        let parts: Vec<_> = records
+            .iter()
+            .skip_while(|(lsn, _)| Some(*lsn) < log.checkpoint_lsn())
+            .collect();
+        assert!(parts.len() > 1);
+        let (mut all_dirty, mut all_active) = (vec![], vec![]);
+        for (_, record) in parts {
+            let LogRecord::Checkpoint { dirty, active, .. } = record else {
+                panic!("Expected a checkpoint record, got {:?}", record);
+            };
+            let len = record.to_bytes().len();
+            assert_eq!(
+                len,
+                CHECKPOINT_HEADER_BYTES
+                    + dirty.len() * CHECKPOINT_DIRTY_BYTES
+                    + active.len() * CHECKPOINT_ACTIVE_BYTES
+            );
+            assert!(len <= MAX_VAR_RECORD_SIZE);
+            all_dirty.extend_from_slice(dirty);
+            all_active.extend(active.iter().map(|(_, first, last)| (*first, *last)));
+        }
+        assert_eq!(all_dirty, dirty);
+        all_active.sort();
+        assert_eq!(all_active, active);
+    }
+
+    #[test]
+    fn test_log_rejects_oversized_record() {
+        let dir = gen_random_test_dir();
+        let log = LogManager::new(&dir);
+        let txn = TransactionId::new();
+        let op = TxnOp::HeapChange {
+            v_id: ValueId::new(1),
+            before: Some((vec![0; 16], vec![0; MAX_VAR_RECORD_SIZE])),
+        };
+        assert!(log.log_txn(txn, op).is_err());
+        // Nothing was added, so the log carries on as before
+        assert_eq!(log.last_lsn(), None);
+        let lsn = log.append(&record(0)).unwrap();
+        log.flush(lsn).unwrap();
+        assert_eq!(log.read_flushed().unwrap(), vec![(lsn, record(0))]);
+    }
+
+    #[test]
+    fn test_log_new_removes_only_old_segments() {
+        let dir = gen_random_test_dir();
+        let log = LogManager::new(&dir);
+        let lsns: Vec<Lsn> = (0..500).map(|i| log.append(&record(i)).unwrap()).collect();
+        log.flush(lsns[499]).unwrap();
+        let last_segment = lsns[499].page_id / LOG_SEGMENT_PAGES;
+        assert!(last_segment > 0);
+        drop(log);
+        let stray = dir.join(format!("wal.{}.log", last_segment + 10));
+        std::fs::write(&stray, b"not ours").unwrap();
+
+        // The first flush of a new log removes the old one's segments only
+        let log = LogManager::new(&dir);
+        let lsn = log.append(&record(0)).unwrap();
+        log.flush(lsn).unwrap();
+        for segment in 1..=last_segment {
+            assert!(!log.segment_path(segment).exists());
+        }
+        assert_eq!(std::fs::read(&stray).unwrap(), b"not ours");
+        drop(log);
+        let log = LogManager::open(&dir).unwrap();
+        assert_eq!(log.read_flushed().unwrap(), vec![(lsn, record(0))]);
+    }
+}
@@ -0,0 +1,569 @@
+use crate::fixed_page::FixedPage;
+use crate::heap::var_heap_page::VarHeapPage;
+use crate::prelude::*;
+use common::ids::Lsn;
+use common::prelude::*;
+
+/// A record's key and value
+pub type Record = (Vec<u8>, Vec<u8>);
+
+/// Bytes of an optional LSN as written by `push_lsn`
+const LSN_BYTES: usize = 1 + size_of::<PageId>() + size_of::<SlotId>();
+/// Bytes of a checkpoint record without its entries: the tag, the begin LSN
+/// and the counts of dirty pages and active transactions
+pub const CHECKPOINT_HEADER_BYTES: usize = 1 + LSN_BYTES + 2 * size_of::<u16>();
+/// Bytes of each dirty page of a checkpoint record
+pub const CHECKPOINT_DIRTY_BYTES: usize =
+    size_of::<ContainerId>() + size_of::<PageId>() + LSN_BYTES;
+/// Bytes of each active transaction of a checkpoint record
+pub const CHECKPOINT_ACTIVE_BYTES: usize = size_of::<u64>() + 2 * LSN_BYTES;
+
+/// A change made to one page, with what is needed to make the change again
+#[derive(Debug, Clone, PartialEq)]
+pub enum PageOp {
+    Write {
+        slot: SlotId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        slot: SlotId,
+    },
+    DeleteAll,
+    MoveIfEmpty {
+        from: SlotId,
+        to: SlotId,
+    },
+    ShiftRight {
+        slot: SlotId,
+    },
+    ShiftLeft {
+        slot: SlotId,
+    },
+    /// The page was added to its container. Sizes of zero are a page of
+    /// variable size records.
+    Format {
+        key_size: usize,
+        value_size: usize,
+    },
+    /// The metadata kept outside of the data array changed
+    SetHeader {
+        page_pointer: PagePointer,
+        overflow_pointer: PagePointer,
+        is_leaf: bool,
+        extra: usize,
+    },
+    /// Bytes of the data array were written directly
+    WriteBytes {
+        offset: usize,
+        bytes: Vec<u8>,
+    },
+}
+
+impl PageOp {
+    /// Make the change again on a page, leaving the page's LSN at `lsn`.
+    /// The change is not logged a second time.
+    pub fn redo(&self, page: &mut FixedPage, lsn: Lsn) -> Result<(), CrustyError> {
+        let log = page.log.take();
+        let res = match self {
+            PageOp::Write { slot, key, value } => page.write(*slot, true, key, value),
+            PageOp::Delete { slot } => page.delete(*slot),
+            PageOp::DeleteAll => page.delete_all(),
+            PageOp::MoveIfEmpty { from, to } => page.move_if_empty(*from, *to),
+            PageOp::ShiftRight { slot } => page.shift_all_right(*slot).map(|_| ()),
+            PageOp::ShiftLeft { slot } => page.shift_all_left(*slot).map(|_| ()),
+            PageOp::Format {
+                key_size,
+                value_size,
+            } => {
+                *page = match key_size + value_size {
+                    0 => <FixedPage as VarHeapPage>::new(page.p_id),
+                    _ => FixedPage::new(page.p_id, *key_size, *value_size),
+                };
+                Ok(())
+            }
+            PageOp::SetHeader {
+                page_pointer,
+                overflow_pointer,
+                is_leaf,
+                extra,
+            } => {
+                page.page_pointer = *page_pointer;
+                page.overflow_pointer = *overflow_pointer;
+                page.is_leaf = *is_leaf;
+                page.extra = *extra;
+                Ok(())
+            }
+            PageOp::WriteBytes { offset, bytes } => page.write_bytes(*offset, bytes),
+        };
+        page.mark_header_logged();
+        page.log = log;
+        page.lsn = Some(lsn);
+        res
+    }
+}
+
+/// A change made by a transaction, with what is needed to undo it. Undoing
+/// puts back what was there before the change, so undoing a change again
+/// does no harm.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxnOp {
+    /// A heap record was added, changed or removed. `before` is the record
+    /// the slot held, if any.
+    HeapChange {
+        v_id: ValueId,
+        before: Option<Record>,
+    },
+    /// An entry is being added to index `c_id`
+    IndexAdd {
+        c_id: ContainerId,
+        search_key: [u8; SEARCH_KEY_SIZE],
+        pointer: [u8; INDEX_POINTER_SIZE],
+    },
+    /// An entry is being removed from index `c_id`
+    IndexDelete {
+        c_id: ContainerId,
+        search_key: [u8; SEARCH_KEY_SIZE],
+        pointer: [u8; INDEX_POINTER_SIZE],
+    },
+}
+
+/// A record in the write-ahead log
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogRecord {
+    /// A change to page `p_id` of container `c_id`
+    Page {
+        c_id: ContainerId,
+        p_id: PageId,
+        op: PageOp,
+    },
+    /// A container was registered. Earlier page records for its id belong to
+    /// a dropped container.
+    NewContainer {
+        c_id: ContainerId,
+    },
+    /// A change made by a transaction. `prev_lsn` is the transaction's
+    /// record before this one.
+    Txn {
+        txn: TransactionId,
+        prev_lsn: Option<Lsn>,
+        op: TxnOp,
+    },
+    Commit {
+        txn: TransactionId,
+    },
+    /// The transaction's changes have all been undone
+    Abort {
+        txn: TransactionId,
+    },
+    /// A fuzzy checkpoint. `begin` is the last LSN added before it started,
+    /// `dirty` the pages that were dirty with the last LSN before their first
+    /// change not on disk, and `active` the unfinished transactions with
+    /// their first and last records. A checkpoint too large for one record is
+    /// split over several in a row.
+    Checkpoint {
+        begin: Option<Lsn>,
+        dirty: Vec<(ContainerId, PageId, Option<Lsn>)>,
+        active: Vec<(TransactionId, Lsn, Lsn)>,
+    },
+}
+
+/// Reads the fields of a serialized log record in order
+struct Reader<'a> {
+    bytes: &'a [u8],
+    os: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], CrustyError> {
+        let field = self.bytes.get(self.os..self.os + len).ok_or_else(|| {
+            CrustyError::SerializationError("Log record is truncated".to_string())
+        })?;
+        self.os += len;
+        Ok(field)
+    }
+
+    fn u8(&mut self) -> Result<u8, CrustyError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, CrustyError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, CrustyError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, CrustyError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, CrustyError> {
+        let len = self.u16()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], CrustyError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn txn(&mut self) -> Result<TransactionId, CrustyError> {
+        Ok(TransactionId::from_id(self.u64()?))
+    }
+
+    fn pointer(&mut self) -> Result<PagePointer, CrustyError> {
+        let is_some = self.u8()? == 1;
+        let p_id = self.u32()?;
+        Ok(is_some.then_some(p_id))
+    }
+
+    fn lsn(&mut self) -> Result<Option<Lsn>, CrustyError> {
+        let is_some = self.u8()? == 1;
+        let lsn = Lsn {
+            page_id: self.u32()?,
+            slot_id: self.u16()?,
+        };
+        Ok(is_some.then_some(lsn))
+    }
+
+    fn some_lsn(&mut self) -> Result<Lsn, CrustyError> {
+        self.lsn()?.ok_or_else(|| {
+            CrustyError::SerializationError("Log record is missing an LSN".to_string())
+        })
+    }
+
+    fn record(&mut self) -> Result<Option<Record>, CrustyError> {
+        if self.u8()? == 0 {
+            return Ok(None);
+        }
+        Ok(Some((self.bytes()?, self.bytes()?)))
+    }
+}
+
+fn push_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn push_pointer(buf: &mut Vec<u8>, pointer: PagePointer) {
+    buf.push(pointer.is_some() as u8);
+    buf.extend_from_slice(&pointer.unwrap_or(0).to_le_bytes());
+}
+
+fn push_lsn(buf: &mut Vec<u8>, lsn: Option<Lsn>) {
+    buf.push(lsn.is_some() as u8);
+    let lsn = lsn.unwrap_or(Lsn {
+        page_id: 0,
+        slot_id: 0,
+    });
+    buf.extend_from_slice(&lsn.page_id.to_le_bytes());
+    buf.extend_from_slice(&lsn.slot_id.to_le_bytes());
+}
+
+fn push_page_op(buf: &mut Vec<u8>, op: &PageOp) {
+    match op {
+        PageOp::Write { slot, key, value } => {
+            buf.push(0);
+            buf.extend_from_slice(&slot.to_le_bytes());
+            push_bytes(buf, key);
+            push_bytes(buf, value);
+        }
+        PageOp::Delete { slot } => {
+            buf.push(1);
+            buf.extend_from_slice(&slot.to_le_bytes());
+        }
+        PageOp::DeleteAll => buf.push(2),
+        PageOp::MoveIfEmpty { from, to } => {
+            buf.push(3);
+            buf.extend_from_slice(&from.to_le_bytes());
+            buf.extend_from_slice(&to.to_le_bytes());
+        }
+        PageOp::ShiftRight { slot } => {
+            buf.push(4);
+            buf.extend_from_slice(&slot.to_le_bytes());
+        }
+        PageOp::ShiftLeft { slot } => {
+            buf.push(5);
+            buf.extend_from_slice(&slot.to_le_bytes());
+        }
+        PageOp::Format {
+            key_size,
+            value_size,
+        } => {
+            buf.push(6);
+            buf.extend_from_slice(&(*key_size as u16).to_le_bytes());
+            buf.extend_from_slice(&(*value_size as u16).to_le_bytes());
+        }
+        PageOp::SetHeader {
+            page_pointer,
+            overflow_pointer,
+            is_leaf,
+            extra,
+        } => {
+            buf.push(7);
+            push_pointer(buf, *page_pointer);
+            push_pointer(buf, *overflow_pointer);
+            buf.push(*is_leaf as u8);
+            buf.extend_from_slice(&(*extra as u64).to_le_bytes());
+        }
+        PageOp::WriteBytes { offset, bytes } => {
+            buf.push(8);
+            buf.extend_from_slice(&(*offset as u16).to_le_bytes());
+            push_bytes(buf, bytes);
+        }
+    }
+}
+
+fn read_page_op(r: &mut Reader) -> Result<PageOp, CrustyError> {
+    Ok(match r.u8()? {
+        0 => PageOp::Write {
+            slot: r.u16()?,
+            key: r.bytes()?,
+            value: r.bytes()?,
+        },
+        1 => PageOp::Delete { slot: r.u16()? },
+        2 => PageOp::DeleteAll,
+        3 => PageOp::MoveIfEmpty {
+            from: r.u16()?,
+            to: r.u16()?,
+        },
+        4 => PageOp::ShiftRight { slot: r.u16()? },
+        5 => PageOp::ShiftLeft { slot: r.u16()? },
+        6 => PageOp::Format {
+            key_size: r.u16()? as usize,
+            value_size: r.u16()? as usize,
+        },
+        7 => PageOp::SetHeader {
+            page_pointer: r.pointer()?,
+            overflow_pointer: r.pointer()?,
+            is_leaf: r.u8()? == 1,
+            extra: r.u64()? as usize,
+        },
+        8 => PageOp::WriteBytes {
+            offset: r.u16()? as usize,
+            bytes: r.bytes()?,
+        },
+        tag => {
+            return Err(CrustyError::SerializationError(format!(
+                "Unknown page op {}",
+                tag
+            )))
+        }
+    })
+}
+
+fn push_txn_op(buf: &mut Vec<u8>, op: &TxnOp) {
+    match op {
+        TxnOp::HeapChange { v_id, before } => {
+            buf.push(0);
+            buf.extend_from_slice(&v_id.to_fixed_bytes());
+            buf.push(before.is_some() as u8);
+            if let Some((key, value)) = before {
+                push_bytes(buf, key);
+                push_bytes(buf, value);
+            }
+        }
+        TxnOp::IndexAdd {
+            c_id,
+            search_key,
+            pointer,
+        }
+        | TxnOp::IndexDelete {
+            c_id,
+            search_key,
+            pointer,
+        } => {
+            buf.push(if matches!(op, TxnOp::IndexAdd { .. }) {
+                1
+            } else {
+                2
+            });
+            buf.extend_from_slice(&c_id.to_le_bytes());
+            buf.extend_from_slice(search_key);
+            buf.extend_from_slice(pointer);
+        }
+    }
+}
+
+fn read_txn_op(r: &mut Reader) -> Result<TxnOp, CrustyError> {
+    Ok(match r.u8()? {
+        0 => TxnOp::HeapChange {
+            v_id: ValueId::from_bytes(&r.array::<10>()?),
+            before: r.record()?,
+        },
+        1 => TxnOp::IndexAdd {
+            c_id: r.u16()?,
+            search_key: r.array()?,
+            pointer: r.array()?,
+        },
+        2 => TxnOp::IndexDelete {
+            c_id: r.u16()?,
+            search_key: r.array()?,
+            pointer: r.array()?,
+        },
+        tag => {
+            return Err(CrustyError::SerializationError(format!(
+                "Unknown transaction op {}",
+                tag
+            )))
+        }
+    })
+}
+
+impl LogRecord {
+    /// Serialize the record for storing in a log page
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            LogRecord::Page { c_id, p_id, op } => {
+                buf.push(0);
+                buf.extend_from_slice(&c_id.to_le_bytes());
+                buf.extend_from_slice(&p_id.to_le_bytes());
+                push_page_op(&mut buf, op);
+            }
+            LogRecord::NewContainer { c_id } => {
+                buf.push(1);
+                buf.extend_from_slice(&c_id.to_le_bytes());
+            }
+            LogRecord::Txn { txn, prev_lsn, op } => {
+                buf.push(2);
+                buf.extend_from_slice(&txn.id().to_le_bytes());
+                push_lsn(&mut buf, *prev_lsn);
+                push_txn_op(&mut buf, op);
+            }
+            LogRecord::Commit { txn } => {
+                buf.push(3);
+                buf.extend_from_slice(&txn.id().to_le_bytes());
+            }
+            LogRecord::Abort { txn } => {
+                buf.push(4);
+                buf.extend_from_slice(&txn.id().to_le_bytes());
+            }
+            LogRecord::Checkpoint {
+                begin,
+                dirty,
+                active,
+            } => {
+                buf.push(5);
+                push_lsn(&mut buf, *begin);
+                buf.extend_from_slice(&(dirty.len() as u16).to_le_bytes());
+                for (c_id, p_id, rec_lsn) in dirty {
+                    buf.extend_from_slice(&c_id.to_le_bytes());
+                    buf.extend_from_slice(&p_id.to_le_bytes());
+                    push_lsn(&mut buf, *rec_lsn);
+                }
+                buf.extend_from_slice(&(active.len() as u16).to_le_bytes());
+                for (txn, first, last) in active {
+                    buf.extend_from_slice(&txn.id().to_le_bytes());
+                    push_lsn(&mut buf, Some(*first));
+                    push_lsn(&mut buf, Some(*last));
+                }
+            }
+        }
+        buf
+    }
+
+    /// Deserialize a record that was written with `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CrustyError> {
+        let mut r = Reader { bytes, os: 0 };
+        Ok(match r.u8()? {
+            0 => LogRecord::Page {
+                c_id: r.u16()?,
+                p_id: r.u32()?,
+                op: read_page_op(&mut r)?,
+            },
+            1 => LogRecord::NewContainer { c_id: r.u16()? },
+            2 => LogRecord::Txn {
+                txn: r.txn()?,
+                prev_lsn: r.lsn()?,
+                op: read_txn_op(&mut r)?,
+            },
+            3 => LogRecord::Commit { txn: r.txn()? },
+            4 => LogRecord::Abort { txn: r.txn()? },
+            5 => {
+                let begin = r.lsn()?;
+                let dirty = (0..r.u16()?)
+                    .map(|_| Ok((r.u16()?, r.u32()?, r.lsn()?)))
+                    .collect::<Result<_, CrustyError>>()?;
+                let active = (0..r.u16()?)
+                    .map(|_| Ok((r.txn()?, r.some_lsn()?, r.some_lsn()?)))
+                    .collect::<Result<_, CrustyError>>()?;
+                LogRecord::Checkpoint {
+                    begin,
+                    dirty,
+                    active,
+                }
+            }
+            tag => {
+                return Err(CrustyError::SerializationError(format!(
+                    "Unknown log record type {}",
+                    tag
+                )))
+            }
+        })
+    }
+
+    /// The transaction the record belongs to, if any
+    pub fn txn(&self) -> Option<TransactionId> {
+        match self {
+            LogRecord::Txn { txn, .. } | LogRecord::Commit { txn } | LogRecord::Abort { txn } => {
+                Some(*txn)
+            }
+            LogRecord::Page { .. }
+            | LogRecord::NewContainer { .. }
+            | LogRecord::Checkpoint { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::gen_random_test_dir;
+    use crate::wal::log_manager::{LogManager, PageLog};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_redo_page_ops() {
+        let dir = gen_random_test_dir();
+        let log = Arc::new(LogManager::new(&dir));
+        let mut page = FixedPage::new(3, 256, 256);
+        page.log = Some(PageLog {
+            c_id: 2,
+            log: log.clone(),
+        });
+        for i in 0..5u8 {
+            page.write(i as SlotId, false, &[i; 256], &[i; 256])
+                .unwrap();
+        }
+        page.delete(1).unwrap();
+        page.shift_all_left(1).unwrap();
+        page.shift_all_right(0).unwrap();
+        page.move_if_empty(4, 7).unwrap();
+        // Changes that fail are not logged
+        assert!(page.move_if_empty(4, 6).is_err());
+        page.write(2, true, &[9; 256], &[9; 256]).unwrap();
+        let last = log.last_lsn().unwrap();
+        assert_eq!(page.lsn, Some(last));
+        log.flush(last).unwrap();
+
+        // Replaying the log on a fresh page gives the same page
+        let records = log.read_flushed().unwrap();
+        assert_eq!(records.len(), 10);
+        let mut replayed = FixedPage::new(3, 256, 256);
+        for (lsn, record) in records {
+            let bytes = record.to_bytes();
+            assert_eq!(LogRecord::from_bytes(&bytes).unwrap(), record);
+            let LogRecord::Page { c_id, p_id, op } = record else {
+                panic!("Expected a page record, got {:?}", record);
+            };
+            assert_eq!((c_id, p_id), (2, 3));
+            op.redo(&mut replayed, lsn).unwrap();
+        }
+        assert_eq!(replayed.lsn, page.lsn);
+        assert_eq!(replayed.get_kv_pairs(), page.get_kv_pairs());
+        assert_eq!(replayed.free, page.free);
+        assert_eq!(log.last_lsn(), Some(last));
+    }
+}
@@ -0,0 +1,4 @@
+//! Write-ahead logging. Every change made to a fixed page in the buffer pool
+//! is logged before the page can be written back to its container.
+pub mod log_manager;
+pub mod log_record;
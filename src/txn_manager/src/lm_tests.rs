@@ -686,4 +686,27 @@ mod test {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_lm_clear_while_waiting() -> Result<(), CrustyError> {
+        init();
+        let w = Wrapper2::new();
+        assert!(w
+            .l1
+            .acquire_lock(w.txn1, w.vid1, Permissions::ReadWrite)
+            .is_ok());
+        let t2 = thread::spawn(move || {
+            w.b2.wait();
+            assert!(w
+                .l2
+                .acquire_lock(w.txn2, w.vid1, Permissions::ReadWrite)
+                .is_ok());
+        });
+        w.b1.wait();
+        sleep(Duration::from_millis(50));
+        // The waiting request's lock entry is dropped, and it is granted
+        w.l1.clear();
+        t2.join().unwrap();
+        Ok(())
+    }
 }
@@ -1,3 +1,7 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
 use common::ids::Permissions;
 use common::ids::TransactionId;
 use common::ids::ValueId;
@@ -5,11 +9,130 @@ use common::CrustyError;
 
 use crate::lm_trait::LockManagerTrait;
 
-/// Implementation of the lock manager. This holds any state needed by your
-/// lock manager. This struct should implement the LockManagerTrait.
+/// Whether a lock in mode `held` lets another transaction take one in mode `wanted`
+fn compatible(held: Permissions, wanted: Permissions) -> bool {
+    held == Permissions::ReadOnly && wanted == Permissions::ReadOnly
+}
+
+/// The holders of one value's lock and the requests waiting for it, oldest first.
+/// An upgrade waits at the front of the queue while its transaction keeps
+/// holding the shared lock.
+#[derive(Default)]
+struct LockEntry {
+    holders: HashMap<TransactionId, Permissions>,
+    queue: VecDeque<(TransactionId, Permissions)>,
+}
+
+impl LockEntry {
+    /// Whether `tid` can take the lock in mode `perm` now. Requests are granted
+    /// in order, so a request must also be compatible with every request queued
+    /// ahead of it. A new request counts everything queued as ahead of it.
+    fn grantable(&self, tid: TransactionId, perm: Permissions) -> bool {
+        self.blockers(tid, perm).next().is_none()
+    }
+
+    /// The transactions `tid` has to wait for to take the lock in mode `perm`
+    fn blockers(
+        &self,
+        tid: TransactionId,
+        perm: Permissions,
+    ) -> impl Iterator<Item = TransactionId> + '_ {
+        let upgrade = self.holders.contains_key(&tid);
+        let holders = self
+            .holders
+            .iter()
+            .filter(move |(t, held)| **t != tid && !compatible(**held, perm))
+            .map(|(t, _)| *t);
+        // An upgrade only waits for the other holders to leave
+        let ahead = self
+            .queue
+            .iter()
+            .take_while(move |(t, _)| *t != tid)
+            .filter(move |(_, queued)| !upgrade && !compatible(*queued, perm))
+            .map(|(t, _)| *t);
+        holders.chain(ahead)
+    }
+
+    fn dequeue(&mut self, tid: TransactionId) {
+        self.queue.retain(|(t, _)| *t != tid);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.holders.is_empty() && self.queue.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct LockTable {
+    locks: HashMap<ValueId, LockEntry>,
+    /// The locks each transaction holds. A transaction is known from its first
+    /// lock request, even one that failed, until it releases all of its locks.
+    held: HashMap<TransactionId, HashSet<ValueId>>,
+    /// The lock each blocked transaction is waiting for
+    waiting: HashMap<TransactionId, (ValueId, Permissions)>,
+}
+
+impl LockTable {
+    fn grant(&mut self, tid: TransactionId, vid: ValueId, perm: Permissions) {
+        let entry = self.locks.entry(vid).or_default();
+        entry.dequeue(tid);
+        entry.holders.insert(tid, perm);
+        self.held.entry(tid).or_default().insert(vid);
+        self.waiting.remove(&tid);
+    }
+
+    /// Take back a request `tid` gave up waiting on
+    fn withdraw(&mut self, tid: TransactionId, vid: ValueId) {
+        if let Some(entry) = self.locks.get_mut(&vid) {
+            entry.dequeue(tid);
+            if entry.is_empty() {
+                self.locks.remove(&vid);
+            }
+        }
+        self.waiting.remove(&tid);
+    }
+
+    fn release(&mut self, tid: TransactionId, vid: ValueId) {
+        if let Some(entry) = self.locks.get_mut(&vid) {
+            entry.holders.remove(&tid);
+            if entry.is_empty() {
+                self.locks.remove(&vid);
+            }
+        }
+    }
+
+    /// Whether `tid` waiting on its queued request would close a cycle in the
+    /// waits-for graph
+    fn deadlocked(&self, tid: TransactionId) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = vec![tid];
+        while let Some(t) = stack.pop() {
+            let Some((vid, perm)) = self.waiting.get(&t) else {
+                continue;
+            };
+            for blocker in self.locks[vid].blockers(t, *perm) {
+                if blocker == tid {
+                    return true;
+                }
+                if seen.insert(blocker) {
+                    stack.push(blocker);
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Strict two phase lock manager over values. Shared locks are `ReadOnly` and
+/// exclusive locks are `ReadWrite`. Requests on a value are granted in the
+/// order they arrive, so readers cannot starve a waiting writer. A request that
+/// would deadlock fails right away and one that waits longer than the timeout
+/// fails when the timeout runs out, both with `TransactionRollback`.
 pub struct LockManager {
-    // Add any needed state here
     timeout_ms: u64,
+    table: Mutex<LockTable>,
+    /// Signalled whenever a lock is released or weakened
+    released: Condvar,
 }
 
 impl Default for LockManager {
@@ -18,14 +141,92 @@ impl Default for LockManager {
     }
 }
 
+impl LockManager {
+    fn table(&self) -> MutexGuard<'_, LockTable> {
+        self.table.lock().unwrap()
+    }
+
+    /// Take a lock only if it can be granted without waiting. Returns whether
+    /// `tid` now holds the lock in mode `perm` or stronger.
+    pub fn try_acquire_lock(&self, tid: TransactionId, vid: ValueId, perm: Permissions) -> bool {
+        let mut table = self.table();
+        table.held.entry(tid).or_default();
+        let entry = table.locks.entry(vid).or_default();
+        match entry.holders.get(&tid) {
+            Some(Permissions::ReadWrite) => return true,
+            Some(Permissions::ReadOnly) if perm == Permissions::ReadOnly => return true,
+            _ => {}
+        }
+        if entry.grantable(tid, perm) {
+            table.grant(tid, vid, perm);
+            true
+        } else {
+            if entry.is_empty() {
+                table.locks.remove(&vid);
+            }
+            false
+        }
+    }
+
+    /// Queue a request and block until it is granted, it would deadlock or it times out
+    fn wait_for_lock(
+        &self,
+        mut table: MutexGuard<'_, LockTable>,
+        tid: TransactionId,
+        vid: ValueId,
+        perm: Permissions,
+    ) -> Result<(), CrustyError> {
+        let entry = table.locks.entry(vid).or_default();
+        if entry.grantable(tid, perm) {
+            table.grant(tid, vid, perm);
+            return Ok(());
+        }
+        if entry.holders.contains_key(&tid) {
+            entry.queue.push_front((tid, perm));
+        } else {
+            entry.queue.push_back((tid, perm));
+        }
+        table.waiting.insert(tid, (vid, perm));
+        let deadline = Instant::now() + Duration::from_millis(self.timeout_ms);
+        loop {
+            if table.deadlocked(tid) {
+                debug!("Lock request by {:?} on {:?} would deadlock", tid, vid);
+                table.withdraw(tid, vid);
+                self.released.notify_all();
+                return Err(CrustyError::TransactionRollback(tid));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let (guard, wait) = self.released.wait_timeout(table, remaining).unwrap();
+            table = guard;
+            if wait.timed_out() {
+                // Nothing is woken here, so the requests queued behind this one
+                // still wait for a release rather than jumping ahead of it
+                debug!("Lock request by {:?} on {:?} timed out", tid, vid);
+                table.withdraw(tid, vid);
+                return Err(CrustyError::TransactionRollback(tid));
+            }
+            // `clear` may have dropped the entry while this request waited
+            if table.locks.entry(vid).or_default().grantable(tid, perm) {
+                table.grant(tid, vid, perm);
+                return Ok(());
+            }
+        }
+    }
+}
+
 impl LockManagerTrait for LockManager {
     fn new(timeout_ms: u64) -> Self {
-        //TODO Add any initialization code here
-        Self { timeout_ms }
+        Self {
+            timeout_ms,
+            table: Mutex::new(LockTable::default()),
+            released: Condvar::new(),
+        }
     }
 
+    /// Drop every lock and queued request
     fn clear(&self) {
-        panic!("TODO milestone lm");
+        *self.table() = LockTable::default();
+        self.released.notify_all();
     }
 
     fn acquire_lock(
@@ -34,26 +235,81 @@ impl LockManagerTrait for LockManager {
         vid: ValueId,
         perm: Permissions,
     ) -> Result<(), CrustyError> {
-        panic!("TODO milestone lm");
+        let mut table = self.table();
+        table.held.entry(tid).or_default();
+        let held = table.locks.get(&vid).and_then(|e| e.holders.get(&tid));
+        match held {
+            Some(Permissions::ReadWrite) => Ok(()),
+            Some(Permissions::ReadOnly) if perm == Permissions::ReadOnly => Ok(()),
+            _ => self.wait_for_lock(table, tid, vid, perm),
+        }
     }
 
     fn locks_held(&self, tid: TransactionId) -> Vec<ValueId> {
-        panic!("TODO milestone lm");
+        self.table()
+            .held
+            .get(&tid)
+            .map(|vids| vids.iter().copied().collect())
+            .unwrap_or_default()
     }
 
     fn release_lock(&self, tid: TransactionId, vid: ValueId) -> Result<(), CrustyError> {
-        panic!("TODO milestone lm");
+        let mut table = self.table();
+        let held = table.held.get_mut(&tid).ok_or_else(|| {
+            CrustyError::ExecutionError(format!("Transaction {:?} holds no locks", tid))
+        })?;
+        if !held.remove(&vid) {
+            return Err(CrustyError::ExecutionError(format!(
+                "Transaction {:?} does not hold a lock on {:?}",
+                tid, vid
+            )));
+        }
+        table.release(tid, vid);
+        self.released.notify_all();
+        Ok(())
     }
 
     fn release_all_locks(&self, tid: TransactionId) -> Result<(), CrustyError> {
-        panic!("TODO milestone lm");
+        let mut table = self.table();
+        let held = table.held.remove(&tid).ok_or_else(|| {
+            CrustyError::ExecutionError(format!("Transaction {:?} holds no locks", tid))
+        })?;
+        for vid in held {
+            table.release(tid, vid);
+        }
+        self.released.notify_all();
+        Ok(())
     }
 
     fn upgrade_lock(&self, tid: TransactionId, vid: ValueId) -> Result<(), CrustyError> {
-        panic!("TODO milestone lm");
+        let table = self.table();
+        let held = table.locks.get(&vid).and_then(|e| e.holders.get(&tid));
+        match held {
+            Some(Permissions::ReadWrite) => Ok(()),
+            Some(Permissions::ReadOnly) => {
+                self.wait_for_lock(table, tid, vid, Permissions::ReadWrite)
+            }
+            None => Err(CrustyError::ExecutionError(format!(
+                "Transaction {:?} does not hold a lock on {:?} to upgrade",
+                tid, vid
+            ))),
+        }
     }
 
     fn downgrade_lock(&self, tid: TransactionId, vid: ValueId) -> Result<(), CrustyError> {
-        panic!("TODO milestone lm");
+        let mut table = self.table();
+        let held = table
+            .locks
+            .get_mut(&vid)
+            .and_then(|e| e.holders.get_mut(&tid))
+            .ok_or_else(|| {
+                CrustyError::ExecutionError(format!(
+                    "Transaction {:?} does not hold a lock on {:?} to downgrade",
+                    tid, vid
+                ))
+            })?;
+        *held = Permissions::ReadOnly;
+        self.released.notify_all();
+        Ok(())
     }
 }